@@ -181,7 +181,7 @@ mod tests {
 
     use dbsp::{
         circuit::StorageCacheConfig,
-        storage::backend::{posixio_impl::PosixBackend, StoragePath},
+        storage::backend::{posix::PosixBackend, StoragePath},
     };
 
     use tempfile::TempDir;
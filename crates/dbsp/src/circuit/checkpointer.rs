@@ -129,7 +129,7 @@ impl Checkpointer {
         StorageError::ignore_notfound(self.backend.list(
             &StoragePath::default(),
             &mut |_path, file_type| {
-                if let StorageFileType::File { size } = file_type {
+                if let StorageFileType::File { size, .. } = file_type {
                     usage += size;
                 }
             },
@@ -172,7 +172,7 @@ impl Checkpointer {
                         tracing::warn!("Unable to remove old-checkpoint file {path}: {e} (the pipeline will try to delete the file again on a restart)");
                     }
             }
-            } else if let StorageFileType::File { size } = file_type {
+            } else if let StorageFileType::File { size, .. } = file_type {
                     usage += size;
             }
         })?;
@@ -1045,7 +1045,7 @@ pub(crate) mod tests {
         TypedBox, ZSetHandle, ZWeight,
     };
     use anyhow::anyhow;
-    use feldera_types::config::{StorageCacheConfig, StorageConfig, StorageOptions};
+    use feldera_types::config::{StorageConfig, StorageOptions};
     use tempfile::{tempdir, TempDir};
     use uuid::Uuid;
 
@@ -1297,7 +1297,7 @@ pub(crate) mod tests {
                 CircuitStorageConfig::for_config(
                     StorageConfig {
                         path: temp.path().to_string_lossy().into_owned(),
-                        cache: StorageCacheConfig::default(),
+                        ..StorageConfig::default()
                     },
                     StorageOptions {
                         min_storage_bytes: Some(0),
@@ -13,6 +13,17 @@ pub const FILES_CREATED: &str = "disk.total_files_created";
 /// Total number of files deleted.
 pub const FILES_DELETED: &str = "disk.total_files_deleted";
 
+/// Total number of bytes in files deleted, not counting files whose data other
+/// hard links keep alive.
+pub const BYTES_DELETED: &str = "disk.total_bytes_deleted";
+
+/// Total number of temporary files that could not be deleted when they were
+/// dropped, and so were left behind in storage.
+pub const FILES_DELETE_FAILED: &str = "disk.total_files_delete_failed";
+
+/// Total number of bytes in [FILES_DELETE_FAILED].
+pub const FILES_DELETE_FAILED_BYTES: &str = "disk.total_files_delete_failed_bytes";
+
 /// Total number of successful disk writes.
 pub const WRITES_SUCCESS: &str = "disk.total_writes_success";
 
@@ -37,6 +48,74 @@ pub const READ_LATENCY: &str = "disk.read_latency";
 /// Histogram of write latency.
 pub const WRITE_LATENCY: &str = "disk.write_latency";
 
+/// Total number of files completed.
+pub const FILES_COMPLETED: &str = "disk.total_files_completed";
+
+/// Histogram of the time to complete a file, including writing out what was
+/// still buffered, syncing, and renaming.
+pub const COMPLETE_LATENCY: &str = "disk.complete_latency";
+
+/// Histogram of the time to sync a file's data while completing it.
+pub const SYNC_LATENCY: &str = "disk.sync_latency";
+
+/// Histogram of the time to rename a completed file to its final name and
+/// sync its directory.
+pub const RENAME_LATENCY: &str = "disk.rename_latency";
+
+/// Number of flushes to disk currently in progress.
+pub const FLUSHES_ACTIVE: &str = "disk.flushes_active";
+
+/// Number of bytes that storage writers have buffered, waiting to be flushed.
+pub const WRITE_BUFFER_BYTES: &str = "disk.write_buffer_bytes";
+
+/// Number of bytes of storage in use, as reported by the backend's usage.
+pub const STORAGE_USAGE_BYTES: &str = "disk.storage_usage_bytes";
+
+/// Histogram of time spent waiting to start a flush to disk.
+pub const FLUSH_WAIT_LATENCY: &str = "disk.flush_wait_latency";
+
+/// Histogram of flush latency.
+pub const FLUSH_LATENCY: &str = "disk.flush_latency";
+
+/// Total number of bytes read only to fill gaps between coalesced reads, and
+/// then discarded.
+pub const READ_COALESCE_WASTED_BYTES: &str = "disk.read_coalesce_wasted_bytes";
+
+/// Total number of bytes that storage readers were asked to prefetch.
+pub const PREFETCH_BYTES: &str = "disk.total_prefetch_bytes";
+
+/// Total number of bytes read from storage that had been prefetched.
+pub const PREFETCH_HIT_BYTES: &str = "disk.total_prefetch_hit_bytes";
+
+/// Number of times that storage usage accounting would have gone negative.
+pub const USAGE_UNDERFLOW: &str = "disk.usage_underflow";
+
+/// Total number of storage operations retried after a transient error.
+pub const RETRIES: &str = "disk.total_retries";
+
+/// Number of bytes in use on the fast tier of a tiered storage backend.
+pub const FAST_TIER_USAGE_BYTES: &str = "disk.fast_tier_usage_bytes";
+
+/// Number of bytes in use on the slow tier of a tiered storage backend.
+pub const SLOW_TIER_USAGE_BYTES: &str = "disk.slow_tier_usage_bytes";
+
+/// Number of files that storage readers and writers hold open.
+pub const OPEN_FILES: &str = "disk.open_files";
+
+/// Total number of blocks read from storage's block cache.
+pub const BLOCK_CACHE_HIT: &str = "disk.block_cache_hit";
+
+/// Total number of blocks looked up in storage's block cache but not found.
+pub const BLOCK_CACHE_MISS: &str = "disk.block_cache_miss";
+
+/// Total number of storage reads that reused a buffer from the read buffer
+/// pool.
+pub const READ_POOL_HIT: &str = "disk.read_pool_hit";
+
+/// Total number of storage reads that found no buffer to reuse in the read
+/// buffer pool.
+pub const READ_POOL_MISS: &str = "disk.read_pool_miss";
+
 /// Total number of buffer cache hits.
 pub const BUFFER_CACHE_HIT: &str = "disk.buffer_cache_hit";
 
@@ -233,6 +312,20 @@ pub(crate) fn describe_metrics() {
     // Storage backend metrics.
     describe_counter!(FILES_CREATED, "total number of files created");
     describe_counter!(FILES_DELETED, "total number of files deleted");
+    describe_counter!(
+        BYTES_DELETED,
+        MetricUnit::Bytes,
+        "total number of bytes in deleted files"
+    );
+    describe_counter!(
+        FILES_DELETE_FAILED,
+        "total number of files that could not be deleted when dropped"
+    );
+    describe_counter!(
+        FILES_DELETE_FAILED_BYTES,
+        MetricUnit::Bytes,
+        "total number of bytes in files that could not be deleted when dropped"
+    );
     describe_counter!(WRITES_SUCCESS, "total number of disk writes");
     describe_counter!(WRITES_FAILED, "total number of failed writes");
     describe_counter!(READS_SUCCESS, "total number of disk reads");
@@ -251,6 +344,72 @@ pub(crate) fn describe_metrics() {
 
     describe_histogram!(READ_LATENCY, MetricUnit::Seconds, "Read request latency");
     describe_histogram!(WRITE_LATENCY, MetricUnit::Seconds, "Write request latency");
+    describe_counter!(FILES_COMPLETED, "total number of files completed");
+    describe_histogram!(
+        COMPLETE_LATENCY,
+        MetricUnit::Seconds,
+        "Time to complete a file"
+    );
+    describe_histogram!(
+        SYNC_LATENCY,
+        MetricUnit::Seconds,
+        "Time to sync a file while completing it"
+    );
+    describe_histogram!(
+        RENAME_LATENCY,
+        MetricUnit::Seconds,
+        "Time to rename a completed file and sync its directory"
+    );
+    describe_gauge!(FLUSHES_ACTIVE, "number of flushes to disk in progress");
+    describe_gauge!(
+        WRITE_BUFFER_BYTES,
+        MetricUnit::Bytes,
+        "number of bytes buffered by storage writers, waiting to be flushed"
+    );
+    describe_gauge!(
+        STORAGE_USAGE_BYTES,
+        MetricUnit::Bytes,
+        "number of bytes of storage in use"
+    );
+    describe_histogram!(
+        FLUSH_WAIT_LATENCY,
+        MetricUnit::Seconds,
+        "Time spent waiting to start a flush to disk"
+    );
+    describe_histogram!(FLUSH_LATENCY, MetricUnit::Seconds, "Flush latency");
+    describe_counter!(
+        READ_COALESCE_WASTED_BYTES,
+        MetricUnit::Bytes,
+        "total number of bytes read to fill gaps between coalesced reads"
+    );
+    describe_counter!(
+        USAGE_UNDERFLOW,
+        "number of times storage usage accounting would have gone negative"
+    );
+    describe_counter!(
+        PREFETCH_BYTES,
+        MetricUnit::Bytes,
+        "total number of bytes that storage readers were asked to prefetch"
+    );
+    describe_counter!(
+        PREFETCH_HIT_BYTES,
+        MetricUnit::Bytes,
+        "total number of bytes read from storage that had been prefetched"
+    );
+    describe_counter!(
+        RETRIES,
+        "total number of storage operations retried after a transient error"
+    );
+    describe_gauge!(
+        FAST_TIER_USAGE_BYTES,
+        MetricUnit::Bytes,
+        "number of bytes in use on the fast storage tier"
+    );
+    describe_gauge!(
+        SLOW_TIER_USAGE_BYTES,
+        MetricUnit::Bytes,
+        "number of bytes in use on the slow storage tier"
+    );
     describe_histogram!(
         OPERATOR_EVAL_DURATION,
         MetricUnit::Microseconds,
@@ -258,6 +417,23 @@ pub(crate) fn describe_metrics() {
     );
 
     // Buffer cache metrics.
+    describe_gauge!(
+        OPEN_FILES,
+        "number of files held open by storage readers and writers"
+    );
+    describe_counter!(BLOCK_CACHE_HIT, "total number of storage block cache hits");
+    describe_counter!(
+        BLOCK_CACHE_MISS,
+        "total number of storage block cache misses"
+    );
+    describe_counter!(
+        READ_POOL_HIT,
+        "total number of storage reads that reused a pooled buffer"
+    );
+    describe_counter!(
+        READ_POOL_MISS,
+        "total number of storage reads that allocated a new buffer despite the read buffer pool"
+    );
     describe_counter!(BUFFER_CACHE_HIT, "total number of buffer cache hits");
     describe_counter!(BUFFER_CACHE_MISS, "total number of buffer cache misses");
 
@@ -182,7 +182,7 @@ fn test_replay<I1, I2, I3, O1, O2, O3>(
 
     let config = StorageConfig {
         path: path.to_string_lossy().into_owned(),
-        cache: Default::default(),
+        ..Default::default()
     };
     let options = Default::default();
 
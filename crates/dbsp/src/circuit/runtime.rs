@@ -914,7 +914,7 @@ mod tests {
         operator::Generator,
         Circuit, RootCircuit,
     };
-    use feldera_types::config::{StorageCacheConfig, StorageConfig, StorageOptions};
+    use feldera_types::config::{StorageConfig, StorageOptions};
     use std::{cell::RefCell, rc::Rc, thread::sleep, time::Duration};
 
     #[test]
@@ -937,7 +937,7 @@ mod tests {
                 CircuitStorageConfig::for_config(
                     StorageConfig {
                         path: path.to_string_lossy().into_owned(),
-                        cache: StorageCacheConfig::default(),
+                        ..StorageConfig::default()
                     },
                     StorageOptions::default(),
                 )
@@ -0,0 +1,165 @@
+//! Cache of raw blocks read from storage, shared among a backend's readers.
+//!
+//! Unlike the [BufferCache] that sits above the backend and caches parsed
+//! blocks, a [BlockCache] caches blocks exactly as [FileReader::read_block]
+//! returns them, so that reading a block again doesn't need a system call (or,
+//! for remote storage, a round trip).  Blocks are keyed by the [FileId] of the
+//! reader that read them and their offset, and evicted in least-recently-used
+//! order once the cache holds more than its capacity in bytes.
+
+use super::{BlockLocation, FileId, FileReader};
+use crate::storage::buffer_cache::{BufferCache, CacheEntry, FBuf};
+use std::any::Any;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+/// A block in a [BlockCache].
+struct CachedBlock(Arc<FBuf>);
+
+impl CacheEntry for CachedBlock {
+    fn cost(&self) -> usize {
+        self.0.len()
+    }
+
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// A bounded LRU cache of blocks.  See the [module documentation](self).
+///
+/// A cache may be shared among several backends by passing the same
+/// `Arc<BlockCache>` to each of them.
+pub struct BlockCache(BufferCache);
+
+impl Debug for BlockCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("BlockCache").finish()
+    }
+}
+
+impl BlockCache {
+    /// Creates a new cache that holds up to `capacity` bytes of blocks.
+    pub fn new(capacity: usize) -> Self {
+        Self(BufferCache::new(capacity))
+    }
+
+    /// Returns the block at `location` in `file`, if it is cached.
+    pub fn get(&self, file: &dyn FileReader, location: BlockLocation) -> Option<Arc<FBuf>> {
+        let entry = self.0.get(file, location)?;
+        let block = entry.as_any().downcast::<CachedBlock>().ok()?;
+        (block.0.len() == location.size).then(|| block.0.clone())
+    }
+
+    /// Adds `block`, read at `offset` in the file with `file_id`, to the
+    /// cache.
+    pub fn insert(&self, file_id: FileId, offset: u64, block: Arc<FBuf>) {
+        self.0.insert(file_id, offset, Arc::new(CachedBlock(block)));
+    }
+
+    /// Removes all of the blocks for the file with `file_id` from the cache.
+    pub fn evict(&self, file_id: FileId) {
+        self.0.evict_file(file_id);
+    }
+
+    /// Returns `(used, capacity)`, the number of bytes of blocks in the cache
+    /// and the maximum.
+    pub fn occupancy(&self) -> (usize, usize) {
+        self.0.occupancy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockCache;
+    use crate::storage::backend::{
+        memory_impl::MemoryBackend, BlockLocation, FileReader, HasFileId, StorageBackend,
+    };
+    use crate::storage::buffer_cache::FBuf;
+    use std::sync::Arc;
+
+    /// Returns a reader for a 4 KiB file, to key blocks in the cache.
+    fn reader(backend: &MemoryBackend, name: &str) -> Arc<dyn FileReader> {
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 0);
+        backend.write(&name.into(), block).unwrap();
+        backend.open(&name.into()).unwrap()
+    }
+
+    fn block(size: usize, value: u8) -> Arc<FBuf> {
+        let mut block = FBuf::with_capacity(size);
+        block.resize(size, value);
+        Arc::new(block)
+    }
+
+    fn location(offset: u64, size: usize) -> BlockLocation {
+        BlockLocation::new(offset, size).unwrap()
+    }
+
+    /// Once the cache is full, inserting a block evicts the least recently
+    /// used ones, counting lookups as uses.
+    #[test]
+    fn evicts_least_recently_used() {
+        let backend = MemoryBackend::new();
+        let file = reader(&backend, "file");
+        let cache = BlockCache::new(2048);
+        for (i, offset) in [0, 512, 1024, 1536].into_iter().enumerate() {
+            cache.insert(file.file_id(), offset, block(512, i as u8));
+        }
+        assert!(cache.get(&*file, location(0, 512)).is_some());
+
+        cache.insert(file.file_id(), 2048, block(512, 4));
+        assert!(cache.get(&*file, location(512, 512)).is_none());
+        for offset in [0, 1024, 1536, 2048] {
+            assert!(cache.get(&*file, location(offset, 512)).is_some());
+        }
+
+        // A bigger block evicts as many as it needs to, oldest first.
+        cache.insert(file.file_id(), 2560, block(1024, 5));
+        assert_eq!(cache.occupancy(), (2048, 2048));
+        assert!(cache.get(&*file, location(0, 512)).is_none());
+        assert!(cache.get(&*file, location(1024, 512)).is_none());
+        for (offset, size) in [(1536, 512), (2048, 512), (2560, 1024)] {
+            assert!(cache.get(&*file, location(offset, size)).is_some());
+        }
+    }
+
+    /// Occupancy counts each cached block's bytes once, including when a
+    /// block is replaced, and drops to zero when the file is evicted.
+    #[test]
+    fn capacity_accounting() {
+        let backend = MemoryBackend::new();
+        let a = reader(&backend, "a");
+        let b = reader(&backend, "b");
+        let cache = BlockCache::new(1 << 20);
+        assert_eq!(cache.occupancy(), (0, 1 << 20));
+
+        cache.insert(a.file_id(), 0, block(512, 1));
+        cache.insert(a.file_id(), 512, block(1024, 2));
+        cache.insert(b.file_id(), 0, block(2048, 3));
+        assert_eq!(cache.occupancy().0, 3584);
+
+        cache.insert(a.file_id(), 512, block(512, 4));
+        assert_eq!(cache.occupancy().0, 3072);
+        assert_eq!(
+            cache.get(&*a, location(512, 512)).unwrap().as_slice(),
+            block(512, 4).as_slice()
+        );
+
+        cache.evict(a.file_id());
+        assert_eq!(cache.occupancy().0, 2048);
+        assert!(cache.get(&*a, location(0, 512)).is_none());
+        assert!(cache.get(&*b, location(0, 2048)).is_some());
+    }
+
+    /// A lookup with a different size than the cached block misses.
+    #[test]
+    fn size_mismatch_misses() {
+        let backend = MemoryBackend::new();
+        let file = reader(&backend, "file");
+        let cache = BlockCache::new(4096);
+        cache.insert(file.file_id(), 0, block(1024, 1));
+        assert!(cache.get(&*file, location(0, 512)).is_none());
+        assert!(cache.get(&*file, location(0, 1024)).is_some());
+    }
+}
@@ -0,0 +1,219 @@
+//! Index of the blocks in a file whose blocks are stored in a different form
+//! (e.g. compressed or encrypted) and therefore at different offsets than
+//! the ones that callers use to read them.
+//!
+//! The index is serialized as a pair of little-endian `u32`s for each block:
+//! its physical length, followed by its logical length.  Blocks are stored in
+//! order, one right after another, so that the offsets follow from the
+//! lengths.
+
+use super::{BlockLocation, StorageError};
+use crate::storage::buffer_cache::FBuf;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+pub(super) fn corrupt() -> StorageError {
+    StorageError::StdIo(ErrorKind::InvalidData)
+}
+
+pub(super) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(super) fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Where a logical block is stored in the underlying file.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct BlockEntry {
+    pub logical_offset: u64,
+    pub logical_len: usize,
+    pub physical_offset: u64,
+    pub physical_len: usize,
+}
+
+impl BlockEntry {
+    fn logical_end(&self) -> u64 {
+        self.logical_offset + self.logical_len as u64
+    }
+
+    /// Returns the location of the physical block.
+    pub fn physical_location(&self) -> Result<BlockLocation, StorageError> {
+        BlockLocation::new(self.physical_offset, self.physical_len).map_err(|_| corrupt())
+    }
+}
+
+/// The blocks in a file, in order.
+#[derive(Clone, Debug, Default)]
+pub(super) struct BlockIndex {
+    blocks: Vec<BlockEntry>,
+    logical_size: u64,
+    physical_size: u64,
+}
+
+impl BlockIndex {
+    /// Appends a block with the given lengths.
+    pub fn push(&mut self, logical_len: usize, physical_len: usize) {
+        self.blocks.push(BlockEntry {
+            logical_offset: self.logical_size,
+            logical_len,
+            physical_offset: self.physical_size,
+            physical_len,
+        });
+        self.logical_size += logical_len as u64;
+        self.physical_size += physical_len as u64;
+    }
+
+    /// Returns the number of blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the total logical length of the blocks.
+    pub fn logical_size(&self) -> u64 {
+        self.logical_size
+    }
+
+    /// Returns the logical length of each block, in order.
+    pub fn logical_lens(&self) -> Vec<usize> {
+        self.blocks.iter().map(|block| block.logical_len).collect()
+    }
+
+    /// Returns the total physical length of the blocks.
+    pub fn physical_size(&self) -> u64 {
+        self.physical_size
+    }
+
+    /// Returns the serialized index, without padding.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(self.blocks.len() * 8);
+        for block in &self.blocks {
+            encoded.extend_from_slice(&(block.physical_len as u32).to_le_bytes());
+            encoded.extend_from_slice(&(block.logical_len as u32).to_le_bytes());
+        }
+        encoded
+    }
+
+    /// Parses an index serialized by [encode](Self::encode).  Each physical
+    /// block must be a multiple of 512 bytes and at least `min_physical_len`
+    /// bytes long.
+    pub fn decode(encoded: &[u8], min_physical_len: usize) -> Result<Self, StorageError> {
+        if encoded.len() % 8 != 0 {
+            return Err(corrupt());
+        }
+        let mut index = Self::default();
+        for entry in encoded.chunks_exact(8) {
+            let physical_len = read_u32(entry, 0) as usize;
+            let logical_len = read_u32(entry, 4) as usize;
+            if physical_len < min_physical_len.max(512) || physical_len % 512 != 0 {
+                return Err(corrupt());
+            }
+            index.push(logical_len, physical_len);
+        }
+        Ok(index)
+    }
+
+    /// Returns the locations of the physical blocks that hold the logical
+    /// data in `locations`.
+    pub fn physical_locations(&self, locations: &[BlockLocation]) -> Vec<BlockLocation> {
+        let mut physical = Vec::new();
+        for location in locations {
+            let start = self
+                .blocks
+                .partition_point(|block| block.logical_end() <= location.offset);
+            physical.extend(
+                self.blocks[start..]
+                    .iter()
+                    .take_while(|block| block.logical_offset < location.after())
+                    .filter_map(|block| block.physical_location().ok()),
+            );
+        }
+        physical
+    }
+
+    /// Returns the number and entry of the block that `location` covers
+    /// exactly, if there is one.
+    pub fn exact(&self, location: BlockLocation) -> Option<(usize, &BlockEntry)> {
+        let index = self
+            .blocks
+            .partition_point(|block| block.logical_offset < location.offset);
+        self.blocks
+            .get(index)
+            .filter(|block| {
+                block.logical_offset == location.offset && block.logical_len == location.size
+            })
+            .map(|block| (index, block))
+    }
+
+    /// Reads the logical data at `location`, calling `read_entry` to read
+    /// each block that it needs by number.
+    pub fn read_block(
+        &self,
+        location: BlockLocation,
+        mut read_entry: impl FnMut(usize, &BlockEntry) -> Result<Arc<FBuf>, StorageError>,
+    ) -> Result<Arc<FBuf>, StorageError> {
+        // Reading exactly one block as it was written is the common case, and
+        // then we can return the block's data without copying.
+        if let Some((index, block)) = self.exact(location) {
+            return read_entry(index, block);
+        }
+
+        let mut buffer = FBuf::with_capacity(location.size);
+        buffer.resize(location.size, 0);
+        self.read_range(location.offset, buffer.as_mut_slice(), read_entry)?;
+        Ok(Arc::new(buffer))
+    }
+
+    /// Reads logical data starting at `offset` into `bufs`, with the same
+    /// semantics as [FileReader::read_scattered](super::FileReader::read_scattered).
+    pub fn read_scattered(
+        &self,
+        offset: u64,
+        bufs: &mut [&mut [u8]],
+        read_entry: impl FnMut(usize, &BlockEntry) -> Result<Arc<FBuf>, StorageError>,
+    ) -> Result<usize, StorageError> {
+        let total = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let n = self.logical_size.saturating_sub(offset).min(total as u64) as usize;
+        let mut data = vec![0; n];
+        self.read_range(offset, &mut data, read_entry)?;
+
+        let mut rest = data.as_slice();
+        for buf in bufs.iter_mut() {
+            let len = buf.len().min(rest.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            rest = &rest[len..];
+        }
+        Ok(n)
+    }
+
+    /// Reads the logical bytes starting at `offset` into `dst`, failing if
+    /// that would read past the end of the file.
+    fn read_range(
+        &self,
+        offset: u64,
+        dst: &mut [u8],
+        mut read_entry: impl FnMut(usize, &BlockEntry) -> Result<Arc<FBuf>, StorageError>,
+    ) -> Result<(), StorageError> {
+        let end = offset + dst.len() as u64;
+        if end > self.logical_size {
+            return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+        }
+
+        let mut pos = offset;
+        let mut index = self
+            .blocks
+            .partition_point(|block| block.logical_end() <= offset);
+        while pos < end {
+            let block = &self.blocks[index];
+            let data = read_entry(index, block)?;
+            let start = (pos - block.logical_offset) as usize;
+            let n = (block.logical_len - start).min((end - pos) as usize);
+            let dst_start = (pos - offset) as usize;
+            dst[dst_start..dst_start + n].copy_from_slice(&data[start..start + n]);
+            pos += n as u64;
+            index += 1;
+        }
+        Ok(())
+    }
+}
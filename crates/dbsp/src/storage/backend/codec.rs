@@ -0,0 +1,452 @@
+//! [StorageBackend] that stacks compression and encryption, and re-encodes
+//! existing files.
+//!
+//! [CodecBackend] wraps another backend in a [CompressedBackend], an
+//! [EncryptedBackend], or both, as chosen by [CodecParams].  With both, the
+//! compressed backend wraps the encrypted one, so that each block is
+//! compressed and then encrypted.
+//!
+//! [CodecBackend::recode] rewrites a file with different [CodecParams], for
+//! example to change the compression algorithm or to re-encrypt it with the
+//! current key after a key rotation.  It copies the file, block by block,
+//! into a temporary file, completes it, and then renames it over the
+//! original.  The temporary file's name is unique to the call and ends in
+//! `.mut`, so that it can't collide with the file that a writer creating the
+//! same name is writing:
+//!
+//! - Completing the copy makes it durable before the rename, so the original
+//!   file stays intact until then.
+//!
+//! - The rename replaces the original atomically, so that a crash leaves
+//!   either the original or the copy, never a mixture.  A crash before the
+//!   rename leaves a `.mut` file behind, which
+//!   [PosixBackend::recover](super::posix::PosixBackend::recover)
+//!   deletes at startup like any other incomplete file.
+//!
+//! Reading the original decrypts it with the key that its footer names,
+//! through [KeyProvider::key], whereas the copy is encrypted with
+//! [KeyProvider::current_key].  Thus, re-encoding with the same
+//! [KeyProvider] after its current key changes rotates the file to the new
+//! key.
+
+use super::compressed::CompressedBackend;
+use super::encrypted::{EncryptedBackend, EncryptionAlgorithm, KeyProvider};
+use super::{FileReader, FileWriter, StorageBackend, StorageError, StoragePath, MUTABLE_EXTENSION};
+use crate::storage::buffer_cache::FBuf;
+use feldera_storage::{StorageCapabilities, StorageFileType};
+use feldera_types::config::BlockCompressionConfig;
+use std::io::ErrorKind;
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Size of the blocks in which [CodecBackend::recode] copies a file that is
+/// neither compressed nor encrypted.
+const RECODE_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Encryption settings for [CodecParams].
+#[derive(Clone)]
+pub struct EncryptionParams {
+    /// Keys to encrypt and decrypt with.
+    pub keys: Arc<dyn KeyProvider>,
+
+    /// Algorithm to encrypt new files with.  Existing files are decrypted
+    /// with the algorithm recorded in their footers.
+    pub algorithm: EncryptionAlgorithm,
+}
+
+/// How a [CodecBackend] encodes files.
+#[derive(Clone, Default)]
+pub struct CodecParams {
+    /// Block compression, or `None` to write files uncompressed.
+    pub compression: Option<BlockCompressionConfig>,
+
+    /// Encryption, or `None` to write files in plaintext.
+    pub encryption: Option<EncryptionParams>,
+}
+
+/// A [StorageBackend] that compresses and encrypts files on another backend.
+/// See the [module documentation](self).
+pub struct CodecBackend {
+    inner: Arc<dyn StorageBackend>,
+    compressed: Option<Arc<CompressedBackend>>,
+    encrypted: Option<Arc<EncryptedBackend>>,
+
+    /// The outermost layer, which all operations go through.
+    outer: Arc<dyn StorageBackend>,
+}
+
+impl CodecBackend {
+    /// Returns a new backend that encodes files on `inner` according to
+    /// `params`.
+    pub fn new(inner: Arc<dyn StorageBackend>, params: CodecParams) -> Self {
+        let encrypted = params.encryption.map(|encryption| {
+            Arc::new(
+                EncryptedBackend::new(inner.clone(), encryption.keys)
+                    .with_algorithm(encryption.algorithm),
+            )
+        });
+        let below_compression: Arc<dyn StorageBackend> = match &encrypted {
+            Some(encrypted) => encrypted.clone(),
+            None => inner.clone(),
+        };
+        let compressed = params.compression.map(|compression| {
+            let mut compressed = CompressedBackend::new(below_compression, compression.algorithm);
+            if let Some(level) = compression.level {
+                compressed = compressed.with_level(level);
+            }
+            Arc::new(compressed)
+        });
+        let outer: Arc<dyn StorageBackend> = match (&compressed, &encrypted) {
+            (Some(compressed), _) => compressed.clone(),
+            (None, Some(encrypted)) => encrypted.clone(),
+            (None, None) => inner.clone(),
+        };
+        Self {
+            inner,
+            compressed,
+            encrypted,
+            outer,
+        }
+    }
+
+    /// Returns the wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn StorageBackend> {
+        &self.inner
+    }
+
+    /// Rewrites the file `name`, which must have been written by a backend
+    /// with this backend's encryption settings, so that it is encoded
+    /// according to `params`, and returns a reader for the result.  See the
+    /// [module documentation](self) for how this stays safe across crashes.
+    ///
+    /// The wrapped backend must support [StorageBackend::rename].  If this
+    /// fails, `name` is left as it was.  Readers that already have `name` open
+    /// may keep reading the original contents.
+    pub fn recode(
+        &self,
+        name: &StoragePath,
+        params: CodecParams,
+    ) -> Result<Arc<dyn FileReader>, StorageError> {
+        let target = Self::new(self.inner.clone(), params);
+        let temp = StoragePath::from(format!("{name}.{}{MUTABLE_EXTENSION}", Uuid::now_v7()));
+        self.copy_to(name, &target, &temp)?;
+        if let Err(error) = self.inner.rename(&temp, name) {
+            let _ = self.inner.delete(&temp);
+            return Err(error);
+        }
+        target.open(name)
+    }
+
+    /// Copies `name` to `temp` in `target`, preserving its block boundaries,
+    /// and marks the copy for a checkpoint so that it stays.
+    fn copy_to(
+        &self,
+        name: &StoragePath,
+        target: &CodecBackend,
+        temp: &StoragePath,
+    ) -> Result<(), StorageError> {
+        let reader = self.outer.open(name)?;
+        let lens = self.block_lens(name, reader.as_ref())?;
+        let mut writer = target.create_named(temp)?;
+        let mut offset = 0;
+        for len in lens {
+            let result = read_exact(reader.as_ref(), offset, len)
+                .and_then(|block| writer.write_block(block));
+            if let Err(error) = result {
+                let _ = writer.abort();
+                return Err(error);
+            }
+            offset += len as u64;
+        }
+        let (copy, _path) = writer.complete()?;
+        copy.mark_for_checkpoint();
+        Ok(())
+    }
+
+    /// Returns the logical length of each block in `name`, which `reader`
+    /// reads.
+    fn block_lens(
+        &self,
+        name: &StoragePath,
+        reader: &dyn FileReader,
+    ) -> Result<Vec<usize>, StorageError> {
+        if let Some(compressed) = &self.compressed {
+            if let Some(lens) = compressed.block_lens(name)? {
+                return Ok(lens);
+            }
+        }
+        if let Some(encrypted) = &self.encrypted {
+            return encrypted.block_lens(name);
+        }
+        let size = reader.get_size()?;
+        Ok((0..size)
+            .step_by(RECODE_BLOCK_SIZE)
+            .map(|offset| (size - offset).min(RECODE_BLOCK_SIZE as u64) as usize)
+            .collect())
+    }
+}
+
+/// Reads exactly `len` bytes at `offset` in `reader`.
+fn read_exact(reader: &dyn FileReader, offset: u64, len: usize) -> Result<FBuf, StorageError> {
+    let mut block = FBuf::with_capacity(len);
+    block.resize(len, 0);
+    if reader.read_scattered(offset, &mut [block.as_mut_slice()])? != len {
+        return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+    }
+    Ok(block)
+}
+
+impl StorageBackend for CodecBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        self.outer.create_named(name)
+    }
+
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        self.outer.open(name)
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        self.outer.list(parent, cb)
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.outer.delete(name)
+    }
+
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.outer.delete_recursive(name)
+    }
+
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.outer.rename_subtree(from, to)
+    }
+
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.outer.rename(from, to)
+    }
+
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.outer.copy(from, to)
+    }
+
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        self.outer.complete_group(writers)
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.outer.usage()
+    }
+
+    fn available_space(&self) -> Result<u64, StorageError> {
+        self.outer.available_space()
+    }
+
+    fn preferred_block_size(&self) -> usize {
+        self.outer.preferred_block_size()
+    }
+
+    fn min_block_size(&self) -> usize {
+        self.outer.min_block_size()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.outer.capabilities()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{CodecBackend, CodecParams, EncryptionParams};
+    use crate::storage::backend::encrypted::{EncryptionAlgorithm, EncryptionKey, KeyProvider};
+    use crate::storage::backend::{
+        posix::PosixBackend, BlockLocation, StorageBackend, StorageError,
+    };
+    use crate::storage::buffer_cache::FBuf;
+    use feldera_storage::StoragePath;
+    use feldera_types::config::{BlockCompression, BlockCompressionConfig, StorageCacheConfig};
+    use std::io::ErrorKind;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// A [KeyProvider] whose current key is `current` and that can still
+    /// decrypt with keys `oldest..=current`.  Key `id` is 32 bytes of `id`.
+    #[derive(Default)]
+    struct RotatingKeys {
+        current: AtomicU32,
+        oldest: AtomicU32,
+    }
+
+    impl KeyProvider for RotatingKeys {
+        fn current_key(&self) -> Result<(u32, EncryptionKey), StorageError> {
+            let id = self.current.load(Ordering::Relaxed);
+            Ok((id, EncryptionKey::new([id as u8; 32])))
+        }
+
+        fn key(&self, id: u32) -> Result<EncryptionKey, StorageError> {
+            if id < self.oldest.load(Ordering::Relaxed) || id > self.current.load(Ordering::Relaxed)
+            {
+                return Err(StorageError::StdIo(ErrorKind::NotFound));
+            }
+            Ok(EncryptionKey::new([id as u8; 32]))
+        }
+    }
+
+    /// A [KeyProvider] that has no keys.
+    struct NoKeys;
+
+    impl KeyProvider for NoKeys {
+        fn current_key(&self) -> Result<(u32, EncryptionKey), StorageError> {
+            Err(StorageError::StdIo(ErrorKind::NotFound))
+        }
+
+        fn key(&self, _id: u32) -> Result<EncryptionKey, StorageError> {
+            Err(StorageError::StdIo(ErrorKind::NotFound))
+        }
+    }
+
+    fn compression(algorithm: BlockCompression) -> Option<BlockCompressionConfig> {
+        Some(BlockCompressionConfig {
+            algorithm,
+            level: None,
+        })
+    }
+
+    fn encryption(keys: Arc<dyn KeyProvider>) -> Option<EncryptionParams> {
+        Some(EncryptionParams {
+            keys,
+            algorithm: EncryptionAlgorithm::default(),
+        })
+    }
+
+    /// Writes a file with blocks of 1024 bytes of 1s, 512 bytes of 2s, and
+    /// 2048 bytes of 3s to `backend`.
+    fn write_file(backend: &dyn StorageBackend, name: &StoragePath) {
+        let mut writer = backend.create_named(name).unwrap();
+        for (len, value) in [(1024, 1), (512, 2), (2048, 3)] {
+            let mut block = FBuf::with_capacity(len);
+            block.resize(len, value);
+            writer.write_block(block).unwrap();
+        }
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+    }
+
+    /// Checks that `name` in `backend` reads back as written by
+    /// [write_file].
+    fn check_file(backend: &dyn StorageBackend, name: &StoragePath) {
+        let reader = backend.open(name).unwrap();
+        assert_eq!(reader.get_size().unwrap(), 3584);
+        for (offset, len, value) in [(0, 1024, 1), (1024, 512, 2), (1536, 2048, 3)] {
+            let block = reader
+                .read_block(BlockLocation::new(offset, len).unwrap())
+                .unwrap();
+            assert!(block.iter().all(|byte| *byte == value));
+        }
+    }
+
+    /// Returns the names of the files in `backend`, sorted.
+    fn list(backend: &dyn StorageBackend) -> Vec<String> {
+        let mut names = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |path, _file_type| {
+                names.push(path.to_string())
+            })
+            .unwrap();
+        names.sort();
+        names
+    }
+
+    /// Re-encoding changes how a file is stored but not what it reads back
+    /// as, and leaves no temporary file behind.
+    #[test]
+    fn recode() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let inner: Arc<dyn StorageBackend> = Arc::new(PosixBackend::new(
+            tmpdir.path(),
+            StorageCacheConfig::default(),
+        ));
+        let name = StoragePath::from("file");
+        let snappy = CodecBackend::new(
+            inner.clone(),
+            CodecParams {
+                compression: compression(BlockCompression::Snappy),
+                encryption: None,
+            },
+        );
+        write_file(&snappy, &name);
+
+        let params = CodecParams {
+            compression: compression(BlockCompression::Zstd),
+            encryption: encryption(Arc::new(RotatingKeys::default())),
+        };
+        let reader = snappy.recode(&name, params.clone()).unwrap();
+        assert_eq!(reader.get_size().unwrap(), 3584);
+        assert!(!inner.read(&name).unwrap().windows(64).any(|w| w == [3; 64]));
+        assert_eq!(list(inner.as_ref()), ["file"]);
+        check_file(&CodecBackend::new(inner.clone(), params.clone()), &name);
+
+        // And back to plaintext.
+        let zstd = CodecBackend::new(inner.clone(), params);
+        zstd.recode(&name, CodecParams::default()).unwrap();
+        check_file(inner.as_ref(), &name);
+    }
+
+    /// Re-encoding with a provider whose current key has changed re-encrypts
+    /// a file with the new key, so the old key can be retired.
+    #[test]
+    fn rotate_key() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let inner: Arc<dyn StorageBackend> = Arc::new(PosixBackend::new(
+            tmpdir.path(),
+            StorageCacheConfig::default(),
+        ));
+        let keys = Arc::new(RotatingKeys::default());
+        let params = CodecParams {
+            compression: None,
+            encryption: encryption(keys.clone()),
+        };
+        let backend = CodecBackend::new(inner.clone(), params.clone());
+        let rotated = StoragePath::from("rotated");
+        let stale = StoragePath::from("stale");
+        write_file(&backend, &rotated);
+        write_file(&backend, &stale);
+
+        keys.current.store(1, Ordering::Relaxed);
+        backend.recode(&rotated, params).unwrap();
+        keys.oldest.store(1, Ordering::Relaxed);
+        check_file(&backend, &rotated);
+        assert!(backend.open(&stale).is_err());
+    }
+
+    /// A failed re-encoding leaves the original file as it was.
+    #[test]
+    fn failed_recode() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let inner: Arc<dyn StorageBackend> = Arc::new(PosixBackend::new(
+            tmpdir.path(),
+            StorageCacheConfig::default(),
+        ));
+        let backend = CodecBackend::new(inner.clone(), CodecParams::default());
+        let name = StoragePath::from("file");
+        write_file(&backend, &name);
+
+        let params = CodecParams {
+            compression: None,
+            encryption: encryption(Arc::new(NoKeys)),
+        };
+        backend.recode(&name, params).unwrap_err();
+        check_file(&backend, &name);
+        assert_eq!(list(inner.as_ref()), ["file"]);
+        backend
+            .recode(&StoragePath::from("missing"), CodecParams::default())
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,712 @@
+//! [StorageBackend] decorator that compresses each block.
+//!
+//! [CompressedBackend] compresses each block passed to
+//! [FileWriter::write_block] separately and writes it to the wrapped backend
+//! as a physical block that begins with a 16-byte header:
+//!
+//! | Bytes   | Contents                                            |
+//! |---------|-----------------------------------------------------|
+//! | 0..4    | Compressed length, as a little-endian `u32`.        |
+//! | 4..8    | Uncompressed length, as a little-endian `u32`.      |
+//! | 8       | Algorithm: 0 for none, 1 for Snappy, 2 for zstd.    |
+//! | 9..16   | Zero.                                               |
+//!
+//! followed by the compressed data, padded with zeros to a multiple of 512
+//! bytes.  A block that doesn't get smaller when compressed is stored
+//! uncompressed.
+//!
+//! Callers still address the file by logical, uncompressed offsets, because
+//! that is what a [BlockLocation] refers to.  To map logical offsets to
+//! physical ones, completing a file appends an index that holds the physical
+//! and logical length of each block, as pairs of little-endian `u32`s padded
+//! to a multiple of 512 bytes, followed by a 512-byte footer:
+//!
+//! | Bytes   | Contents                                            |
+//! |---------|-----------------------------------------------------|
+//! | 0..8    | Magic number `FELDCMP1`.                            |
+//! | 8..16   | Logical size of the file, as a little-endian `u64`. |
+//! | 16..24  | Offset of the index, as a little-endian `u64`.      |
+//! | 24..32  | Number of blocks, as a little-endian `u64`.         |
+//! | 32..36  | CRC32C of the index, as a little-endian `u32`.      |
+//! | 36..512 | Zero.                                               |
+//!
+//! Opening a file reads the footer and the index, so that
+//! [FileReader::get_size] reports the logical size and reads can find the
+//! blocks that cover any logical range.  A file that doesn't end in a footer,
+//! such as one written before compression was enabled, is read as is.
+//!
+//! Everything else, including [StorageBackend::list] and usage accounting,
+//! passes through to the wrapped backend and therefore reports physical,
+//! compressed sizes.
+//!
+//! To encrypt compressed files, wrap an
+//! [EncryptedBackend](super::encrypted::EncryptedBackend), so that on disk
+//! each block is compressed and then encrypted.  Reads then decrypt and
+//! decompress each block in one pass, as described there.
+
+use super::block_index::{corrupt, read_u32, read_u64, BlockEntry, BlockIndex};
+use super::{
+    BlockLocation, FileId, FileReader, FileWriter, HasFileId, StorageBackend, StorageError,
+};
+use crate::storage::buffer_cache::FBuf;
+use crc32c::crc32c;
+use feldera_storage::{FileStats, StorageCapabilities, StorageFileType, StoragePath};
+use feldera_types::config::BlockCompression;
+use snap::raw::{max_compress_len, Decoder, Encoder};
+use std::io::ErrorKind;
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Length of the header at the start of each physical block.
+const BLOCK_HEADER_LEN: usize = 16;
+
+/// Length of the footer at the end of each file.
+const FOOTER_LEN: usize = 512;
+
+/// How much shorter than the wrapped backend's maximum block size a logical
+/// block must be, so that it still fits if it doesn't compress and grows by
+/// its header and padding.
+const MAX_BLOCK_OVERHEAD: usize = BLOCK_HEADER_LEN.next_multiple_of(512);
+
+/// Magic number at the start of the footer.
+const FOOTER_MAGIC: [u8; 8] = *b"FELDCMP1";
+
+/// The zstd level to use if none is configured.
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Algorithm codes in block headers.
+const STORED: u8 = 0;
+const SNAPPY: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// A [StorageBackend] that compresses the blocks in files on another backend.
+/// See the [module documentation](self).
+pub struct CompressedBackend {
+    inner: Arc<dyn StorageBackend>,
+    algorithm: BlockCompression,
+    level: i32,
+}
+
+impl CompressedBackend {
+    /// Returns a new backend that compresses blocks written to `inner` with
+    /// `algorithm`, at the algorithm's default level.
+    pub fn new(inner: Arc<dyn StorageBackend>, algorithm: BlockCompression) -> Self {
+        Self {
+            inner,
+            algorithm,
+            level: DEFAULT_ZSTD_LEVEL,
+        }
+    }
+
+    /// Returns this backend, modified to compress at `level`, for algorithms
+    /// that have levels.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Returns the wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn StorageBackend> {
+        &self.inner
+    }
+
+    /// Returns the logical length of each block in `name`, in order, or
+    /// `None` if `name` isn't compressed.
+    pub(super) fn block_lens(
+        &self,
+        name: &StoragePath,
+    ) -> Result<Option<Vec<usize>>, StorageError> {
+        let inner = self.inner.open(name)?;
+        Ok(CompressedReader::read_index(inner.as_ref())?.map(|index| index.logical_lens()))
+    }
+}
+
+impl StorageBackend for CompressedBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        Ok(Box::new(CompressedWriter {
+            inner: self.inner.create_named(name)?,
+            algorithm: self.algorithm,
+            level: self.level,
+            encoder: Encoder::new(),
+            bounce: Vec::new(),
+            index: BlockIndex::default(),
+            footer_written: false,
+        }))
+    }
+
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        CompressedReader::open(self.inner.open(name)?)
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        self.inner.list(parent, cb)
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.inner.delete(name)
+    }
+
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.inner.delete_recursive(name)
+    }
+
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.rename_subtree(from, to)
+    }
+
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.rename(from, to)
+    }
+
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        // Copying the compressed data is faster and gives the same result.
+        self.inner.copy(from, to)
+    }
+
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        self.inner.complete_group(writers)
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.inner.usage()
+    }
+
+    fn available_space(&self) -> Result<u64, StorageError> {
+        self.inner.available_space()
+    }
+
+    fn preferred_block_size(&self) -> usize {
+        self.inner.preferred_block_size()
+    }
+
+    fn min_block_size(&self) -> usize {
+        self.inner.min_block_size()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let capabilities = self.inner.capabilities();
+        StorageCapabilities {
+            max_block_size: capabilities
+                .max_block_size
+                .map(|max_block_size| max_block_size.saturating_sub(MAX_BLOCK_OVERHEAD)),
+            ..capabilities
+        }
+    }
+}
+
+struct CompressedWriter {
+    inner: Box<dyn FileWriter>,
+    algorithm: BlockCompression,
+    level: i32,
+    encoder: Encoder,
+
+    /// Scratch space for compressed data.
+    bounce: Vec<u8>,
+
+    /// The blocks written so far.
+    index: BlockIndex,
+
+    /// Whether the index and footer have been written.
+    footer_written: bool,
+}
+
+impl CompressedWriter {
+    /// Returns `data` compressed into a physical block, with its header and
+    /// padding.
+    fn compress(&mut self, data: &[u8]) -> FBuf {
+        let compressed = match self.algorithm {
+            BlockCompression::Snappy => {
+                self.bounce.resize(max_compress_len(data.len()), 0);
+                match self.encoder.compress(data, &mut self.bounce) {
+                    Ok(len) => Some((SNAPPY, &self.bounce[..len])),
+                    Err(_) => None,
+                }
+            }
+            BlockCompression::Zstd => match zstd::bulk::compress(data, self.level) {
+                Ok(compressed) => {
+                    self.bounce = compressed;
+                    Some((ZSTD, self.bounce.as_slice()))
+                }
+                Err(_) => None,
+            },
+        };
+        let (algorithm, payload) = match compressed {
+            Some((algorithm, payload)) if payload.len() < data.len() => (algorithm, payload),
+            _ => (STORED, data),
+        };
+
+        let mut block =
+            FBuf::with_capacity((BLOCK_HEADER_LEN + payload.len()).next_multiple_of(512));
+        block.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block.push(algorithm);
+        block.resize(BLOCK_HEADER_LEN, 0);
+        block.extend_from_slice(payload);
+        block.resize(block.len().next_multiple_of(512), 0);
+        block
+    }
+
+    /// Writes the index and the footer, if they haven't been written yet.
+    fn write_footer(&mut self) -> Result<(), StorageError> {
+        if self.footer_written {
+            return Ok(());
+        }
+
+        let encoded = self.index.encode();
+        let checksum = crc32c(&encoded);
+        let mut index = FBuf::with_capacity(encoded.len().next_multiple_of(512));
+        index.extend_from_slice(&encoded);
+        index.resize(encoded.len().next_multiple_of(512), 0);
+
+        let mut footer = FBuf::with_capacity(FOOTER_LEN);
+        footer.extend_from_slice(&FOOTER_MAGIC);
+        footer.extend_from_slice(&self.index.logical_size().to_le_bytes());
+        footer.extend_from_slice(&self.index.physical_size().to_le_bytes());
+        footer.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        footer.extend_from_slice(&checksum.to_le_bytes());
+        footer.resize(FOOTER_LEN, 0);
+
+        if !index.is_empty() {
+            self.inner.write_block(index)?;
+        }
+        self.inner.write_block(footer)?;
+        self.footer_written = true;
+        Ok(())
+    }
+}
+
+impl HasFileId for CompressedWriter {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileWriter for CompressedWriter {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        if u32::try_from(data.len()).is_err() {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let block = self.compress(&data);
+        let physical_len = block.len();
+        self.inner.write_block(block)?;
+        self.index.push(data.len(), physical_len);
+        Ok(Arc::new(data))
+    }
+
+    fn recycle(&mut self) -> Vec<FBuf> {
+        self.inner.recycle()
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<(), StorageError> {
+        // The logical size is an upper bound on the physical size, give or
+        // take headers and padding, which is good enough for a hint.
+        self.inner.preallocate(size)
+    }
+
+    fn complete(mut self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.write_footer()?;
+        let CompressedWriter { inner, index, .. } = *self;
+        let (reader, name) = inner.complete()?;
+        Ok((CompressedReader::new(reader, index), name))
+    }
+
+    fn prepare_complete(&mut self) -> Result<(), StorageError> {
+        self.write_footer()?;
+        self.inner.prepare_complete()
+    }
+
+    fn complete_prepared(
+        mut self: Box<Self>,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.write_footer()?;
+        let CompressedWriter { inner, index, .. } = *self;
+        let (reader, name) = inner.complete_prepared()?;
+        Ok((CompressedReader::new(reader, index), name))
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        self.inner.abort()
+    }
+}
+
+struct CompressedReader {
+    inner: Arc<dyn FileReader>,
+    index: BlockIndex,
+}
+
+impl CompressedReader {
+    fn new(inner: Arc<dyn FileReader>, index: BlockIndex) -> Arc<dyn FileReader> {
+        Arc::new(Self { inner, index })
+    }
+
+    /// Reads the footer and index of `inner` and returns a reader for its
+    /// logical contents, or `inner` itself if it has no footer.
+    fn open(inner: Arc<dyn FileReader>) -> Result<Arc<dyn FileReader>, StorageError> {
+        match Self::read_index(inner.as_ref())? {
+            Some(index) => Ok(Self::new(inner, index)),
+            None => Ok(inner),
+        }
+    }
+
+    /// Reads the footer and index of `inner` and returns the index, or `None`
+    /// if it has no footer.
+    fn read_index(inner: &dyn FileReader) -> Result<Option<BlockIndex>, StorageError> {
+        let size = inner.get_size()?;
+        if size < FOOTER_LEN as u64 || size % 512 != 0 {
+            return Ok(None);
+        }
+        let location = BlockLocation::new(size - FOOTER_LEN as u64, FOOTER_LEN).unwrap();
+        let footer = inner.read_block(location)?;
+        if footer[..FOOTER_MAGIC.len()] != FOOTER_MAGIC {
+            return Ok(None);
+        }
+
+        let logical_size = read_u64(&footer, 8);
+        let index_offset = read_u64(&footer, 16);
+        let n_blocks = read_u64(&footer, 24);
+        let expected = read_u32(&footer, 32);
+
+        let index_len = n_blocks
+            .checked_mul(8)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(corrupt)?;
+        let padded_len = index_len.next_multiple_of(512);
+        if index_offset.checked_add(padded_len as u64) != Some(size - FOOTER_LEN as u64) {
+            return Err(corrupt());
+        }
+        let encoded = if padded_len > 0 {
+            let location = BlockLocation::new(index_offset, padded_len).map_err(|_| corrupt())?;
+            inner.read_block(location)?
+        } else {
+            Arc::new(FBuf::new())
+        };
+        let encoded = &encoded[..index_len];
+        let actual = crc32c(encoded);
+        if actual != expected {
+            return Err(StorageError::ChecksumMismatch {
+                offset: index_offset,
+                expected,
+                actual,
+            });
+        }
+
+        let index = BlockIndex::decode(encoded, BLOCK_HEADER_LEN)?;
+        if index.logical_size() != logical_size || index.physical_size() != index_offset {
+            return Err(corrupt());
+        }
+        Ok(Some(index))
+    }
+
+    /// Reads and decompresses `block`.  The wrapped reader passes us the
+    /// physical block without keeping it, so that, e.g., an
+    /// [EncryptedBackend](super::encrypted::EncryptedBackend) reader can
+    /// decrypt it into scratch space.
+    fn read_entry(&self, block: &BlockEntry) -> Result<Arc<FBuf>, StorageError> {
+        let mut decompressed = None;
+        self.inner
+            .read_block_with(block.physical_location()?, &mut |raw| {
+                decompressed = Some(Self::decompress(raw, block)?);
+                Ok(())
+            })?;
+        decompressed.map(Arc::new).ok_or_else(corrupt)
+    }
+
+    /// Decompresses `raw`, the physical block for `block`.
+    fn decompress(raw: &[u8], block: &BlockEntry) -> Result<FBuf, StorageError> {
+        if raw.len() < BLOCK_HEADER_LEN {
+            return Err(corrupt());
+        }
+        let compressed_len = read_u32(&raw, 0) as usize;
+        let uncompressed_len = read_u32(&raw, 4) as usize;
+        let algorithm = raw[8];
+        if uncompressed_len != block.logical_len {
+            return Err(corrupt());
+        }
+        let compressed = raw[BLOCK_HEADER_LEN..]
+            .get(..compressed_len)
+            .ok_or_else(corrupt)?;
+
+        let mut decompressed = FBuf::with_capacity(uncompressed_len);
+        if algorithm == STORED {
+            if compressed_len != uncompressed_len {
+                return Err(corrupt());
+            }
+            decompressed.extend_from_slice(compressed);
+        } else {
+            decompressed.resize(uncompressed_len, 0);
+            let result = match algorithm {
+                SNAPPY => Decoder::new()
+                    .decompress(compressed, decompressed.as_mut_slice())
+                    .ok(),
+                ZSTD => {
+                    zstd::bulk::decompress_to_buffer(compressed, decompressed.as_mut_slice()).ok()
+                }
+                _ => None,
+            };
+            if result != Some(uncompressed_len) {
+                return Err(corrupt());
+            }
+        }
+        Ok(decompressed)
+    }
+}
+
+impl HasFileId for CompressedReader {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileReader for CompressedReader {
+    fn mark_for_checkpoint(&self) {
+        self.inner.mark_for_checkpoint();
+    }
+
+    fn is_kept(&self) -> bool {
+        self.inner.is_kept()
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        self.index
+            .read_block(location, |_, block| self.read_entry(block))
+    }
+
+    fn read_scattered(&self, offset: u64, bufs: &mut [&mut [u8]]) -> Result<usize, StorageError> {
+        self.index
+            .read_scattered(offset, bufs, |_, block| self.read_entry(block))
+    }
+
+    fn advise_dontneed(&self) {
+        self.inner.advise_dontneed();
+    }
+
+    fn advise_sequential(&self) {
+        self.inner.advise_sequential();
+    }
+
+    fn prefetch(&self, locations: &[BlockLocation]) {
+        self.inner
+            .prefetch(&self.index.physical_locations(locations));
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        Ok(self.index.logical_size())
+    }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        self.inner.created_at()
+    }
+
+    fn stats(&self) -> FileStats {
+        // These count physical, compressed bytes.
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompressedBackend;
+    use crate::storage::backend::encrypted::{EncryptedBackend, EncryptionKey, StaticKeyProvider};
+    use crate::storage::backend::{
+        memory_impl::MemoryBackend, BlockLocation, StorageBackend, StorageError,
+    };
+    use crate::storage::buffer_cache::FBuf;
+    use feldera_storage::StoragePath;
+    use feldera_types::config::BlockCompression;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    /// Returns `len` bytes of data that doesn't compress.
+    fn random_block(len: usize, seed: u64) -> FBuf {
+        let mut state = seed;
+        let mut block = FBuf::with_capacity(len);
+        for _ in 0..len {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            block.push((state >> 56) as u8);
+        }
+        block
+    }
+
+    /// Returns `len` bytes of data that compresses well.
+    fn repetitive_block(len: usize, value: u8) -> FBuf {
+        let mut block = FBuf::with_capacity(len);
+        block.resize(len, value);
+        block
+    }
+
+    /// Writes a file with a mix of compressible and incompressible blocks to
+    /// `backend` and returns the logical contents of the file.
+    fn write_file(backend: &dyn StorageBackend, name: &StoragePath) -> Vec<u8> {
+        let blocks = [
+            repetitive_block(4096, 1),
+            random_block(1024, 1),
+            repetitive_block(512, 2),
+            random_block(8192, 2),
+        ];
+        let mut writer = backend.create_named(name).unwrap();
+        let mut contents = Vec::new();
+        for block in blocks {
+            contents.extend_from_slice(&block);
+            writer.write_block(block).unwrap();
+        }
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+        contents
+    }
+
+    /// Files read back exactly as written, and report their logical sizes,
+    /// with each algorithm.
+    #[test]
+    fn round_trip() {
+        for algorithm in [BlockCompression::Snappy, BlockCompression::Zstd] {
+            let inner = Arc::new(MemoryBackend::new());
+            let backend = CompressedBackend::new(inner.clone(), algorithm).with_level(5);
+            let name = StoragePath::from("file");
+            let contents = write_file(&backend, &name);
+
+            let reader = backend.open(&name).unwrap();
+            assert_eq!(reader.get_size().unwrap(), contents.len() as u64);
+            let mut offset = 0;
+            for size in [4096, 1024, 512, 8192] {
+                let block = reader
+                    .read_block(BlockLocation::new(offset, size).unwrap())
+                    .unwrap();
+                let offset_usize = offset as usize;
+                assert_eq!(
+                    block.as_slice(),
+                    &contents[offset_usize..offset_usize + size]
+                );
+                offset += size as u64;
+            }
+
+            // The blocks with repeated bytes compress to almost nothing, which
+            // more than makes up for the index and footer.
+            let physical_size = inner.open(&name).unwrap().get_size().unwrap();
+            assert!(physical_size < contents.len() as u64);
+        }
+    }
+
+    /// Reads that don't line up with the blocks that were written, including
+    /// unaligned scattered reads, return the right data.
+    #[test]
+    fn unaligned_reads() {
+        let backend =
+            CompressedBackend::new(Arc::new(MemoryBackend::new()), BlockCompression::Zstd);
+        let name = StoragePath::from("file");
+        let contents = write_file(&backend, &name);
+        let reader = backend.open(&name).unwrap();
+
+        // Spans the end of the first block, all of the second and third, and
+        // the start of the fourth.
+        let block = reader
+            .read_block(BlockLocation::new(3584, 2560).unwrap())
+            .unwrap();
+        assert_eq!(block.as_slice(), &contents[3584..6144]);
+
+        let mut a = [0; 100];
+        let mut b = [0; 5000];
+        let n = reader
+            .read_scattered(4000, &mut [&mut a[..], &mut b[..]])
+            .unwrap();
+        assert_eq!(n, 5100);
+        assert_eq!(&a[..], &contents[4000..4100]);
+        assert_eq!(&b[..], &contents[4100..9100]);
+
+        // A scattered read stops at the end of the file.
+        let mut buf = vec![0; 1000];
+        let offset = contents.len() - 300;
+        let n = reader
+            .read_scattered(offset as u64, &mut [&mut buf[..]])
+            .unwrap();
+        assert_eq!(n, 300);
+        assert_eq!(&buf[..300], &contents[offset..]);
+
+        // A block read doesn't.
+        let Err(error) = reader.read_block(BlockLocation::new(13312, 1024).unwrap()) else {
+            unreachable!()
+        };
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    /// Files written without compression are read as they are.
+    #[test]
+    fn uncompressed_files() {
+        let inner = Arc::new(MemoryBackend::new());
+        let block = random_block(2048, 3);
+        inner.write(&"file".into(), block.clone()).unwrap();
+
+        let backend = CompressedBackend::new(inner, BlockCompression::Snappy);
+        let reader = backend.open(&"file".into()).unwrap();
+        assert_eq!(reader.get_size().unwrap(), 2048);
+        let data = reader
+            .read_block(BlockLocation::new(0, 2048).unwrap())
+            .unwrap();
+        assert_eq!(data.as_slice(), block.as_slice());
+    }
+
+    /// A corrupted index is detected when the file is opened.
+    #[test]
+    fn corrupt_index() {
+        let inner = Arc::new(MemoryBackend::new());
+        let backend = CompressedBackend::new(inner.clone(), BlockCompression::Snappy);
+        let name = StoragePath::from("file");
+        write_file(&backend, &name);
+
+        // Flip a bit in the first index entry, which starts just after the
+        // data and 512 bytes before the footer.
+        let mut raw = Arc::unwrap_or_clone(inner.read(&name).unwrap());
+        let index_offset = raw.len() - 1024;
+        raw.as_mut_slice()[index_offset] ^= 0x80;
+        inner.delete(&name).unwrap();
+        inner.write(&name, raw).unwrap();
+
+        let Err(error) = backend.open(&name) else {
+            unreachable!()
+        };
+        assert!(matches!(error, StorageError::ChecksumMismatch { .. }));
+    }
+
+    /// Layered over encryption, blocks are compressed before they are
+    /// encrypted, and read back in one pass through both layers.
+    #[test]
+    fn over_encrypted() {
+        let inner = Arc::new(MemoryBackend::new());
+        let keys = Arc::new(StaticKeyProvider::new(EncryptionKey::new([7; 32])));
+        let encrypted = Arc::new(EncryptedBackend::new(inner.clone(), keys));
+        let backend = CompressedBackend::new(encrypted.clone(), BlockCompression::Zstd);
+        let name = StoragePath::from("file");
+        let contents = write_file(&backend, &name);
+
+        // Compressing first makes the file smaller than just encrypting it,
+        // and the repeated bytes aren't stored in plaintext.
+        write_file(encrypted.as_ref(), &"plain".into());
+        let raw = inner.read(&name).unwrap();
+        assert!(raw.len() < inner.read(&"plain".into()).unwrap().len());
+        assert!(!raw.windows(64).any(|window| window == [1; 64]));
+
+        let reader = backend.open(&name).unwrap();
+        assert_eq!(reader.get_size().unwrap(), contents.len() as u64);
+        let mut offset = 0;
+        for size in [4096, 1024, 512, 8192] {
+            let block = reader
+                .read_block(BlockLocation::new(offset as u64, size).unwrap())
+                .unwrap();
+            assert_eq!(block.as_slice(), &contents[offset..offset + size]);
+            offset += size;
+        }
+        let block = reader
+            .read_block(BlockLocation::new(3584, 2560).unwrap())
+            .unwrap();
+        assert_eq!(block.as_slice(), &contents[3584..6144]);
+    }
+}
@@ -0,0 +1,840 @@
+//! [StorageBackend] decorator that encrypts each block.
+//!
+//! [EncryptedBackend] encrypts each block passed to
+//! [FileWriter::write_block] separately, with AES-256-GCM or
+//! ChaCha20-Poly1305, and writes it to the wrapped backend as a physical
+//! block that consists of a random 12-byte nonce, the encrypted data, and a
+//! 16-byte authentication tag, padded with zeros to a multiple of 512 bytes.
+//!
+//! The authentication tag makes each physical block longer than the logical
+//! block that callers read and write, so, as in the
+//! [compression layer](super::compressed), completing a file appends an
+//! index of the physical and logical length of each block, padded to a
+//! multiple of 512 bytes, followed by a 512-byte footer:
+//!
+//! | Bytes   | Contents                                               |
+//! |---------|--------------------------------------------------------|
+//! | 0..8    | Magic number `FELDENC1`.                               |
+//! | 8       | Algorithm: 1 for AES-256-GCM, 2 for ChaCha20-Poly1305. |
+//! | 9..12   | Zero.                                                  |
+//! | 12..16  | ID of the key, as a little-endian `u32`.               |
+//! | 16..24  | Logical size of the file, as a little-endian `u64`.    |
+//! | 24..32  | Offset of the index, as a little-endian `u64`.         |
+//! | 32..40  | Number of blocks, as a little-endian `u64`.            |
+//! | 40..56  | Random salt that is unique to the file.                |
+//! | 56..68  | Nonce for authenticating the footer and the index.     |
+//! | 68..84  | Authentication tag for the footer and the index.       |
+//! | 84..512 | Zero.                                                  |
+//!
+//! Each block is authenticated along with the file's salt and the block's
+//! number, so that blocks can't be reordered or moved between files without
+//! detection, and the footer's tag covers the first 56 bytes of the footer
+//! and the whole index, so that blocks can't be dropped from the end of the
+//! file.  Reading a block that fails authentication returns
+//! [StorageError::DecryptionFailed].
+//!
+//! Keys come from a [KeyProvider], which identifies each key by a number that
+//! is recorded in the footer, so that keys can be rotated without losing
+//! access to files encrypted with older keys.  With 12-byte random nonces,
+//! a key should not encrypt more than about 2**32 blocks.
+//!
+//! To both compress and encrypt files, layer a
+//! [CompressedBackend](super::compressed::CompressedBackend) on top of an
+//! [EncryptedBackend], so that blocks are compressed and then encrypted on
+//! disk, since encrypted data doesn't compress.  Each physical block that
+//! the compression layer writes, including its index and footer, is then
+//! one logical block to the encryption layer, so each layer's index maps
+//! across its own change in size.  [FileReader::read_block_with] decrypts
+//! such a block into scratch space that the thread reuses, which the
+//! compression layer decompresses into the block that it returns, so that
+//! reading a block doesn't allocate a buffer for the decrypted data.
+//!
+//! Only file contents are encrypted.  File names, the number and sizes of
+//! blocks, and everything that passes through to the wrapped backend, such as
+//! [StorageBackend::list], are not.  Files that weren't written through an
+//! [EncryptedBackend] can't be opened through one.
+
+use super::block_index::{corrupt, read_u32, read_u64, BlockEntry, BlockIndex};
+use super::{
+    BlockLocation, FileId, FileReader, FileWriter, HasFileId, StorageBackend, StorageError,
+};
+use crate::storage::buffer_cache::FBuf;
+use feldera_storage::{FileStats, StorageCapabilities, StorageFileType, StoragePath};
+use ring::aead::{
+    Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::ErrorKind;
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Length of the authentication tag at the end of the data in each physical
+/// block.
+const TAG_LEN: usize = 16;
+
+/// Length of the salt in the footer.
+const SALT_LEN: usize = 16;
+
+/// Length of the footer at the end of each file.
+const FOOTER_LEN: usize = 512;
+
+/// How much shorter than the wrapped backend's maximum block size a logical
+/// block must be, so that it still fits after it grows by its nonce, its tag,
+/// and padding.
+const MAX_BLOCK_OVERHEAD: usize = (NONCE_LEN + TAG_LEN).next_multiple_of(512);
+
+/// Magic number at the start of the footer.
+const FOOTER_MAGIC: [u8; 8] = *b"FELDENC1";
+
+/// Length of the part of the footer that its tag authenticates.
+const FOOTER_AUTHENTICATED_LEN: usize = 56;
+
+/// A 256-bit key for [EncryptedBackend].
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Returns a key with the given bytes.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Supplies the keys that [EncryptedBackend] encrypts and decrypts files
+/// with, e.g. from a key management service.
+pub trait KeyProvider: Send + Sync {
+    /// Returns the key to encrypt new files with, and its ID.
+    fn current_key(&self) -> Result<(u32, EncryptionKey), StorageError>;
+
+    /// Returns the key with the given `id`, to decrypt a file that was
+    /// encrypted with it.
+    fn key(&self, id: u32) -> Result<EncryptionKey, StorageError>;
+}
+
+/// A [KeyProvider] with a single key, whose ID is 0.
+#[derive(Clone, Debug)]
+pub struct StaticKeyProvider(EncryptionKey);
+
+impl StaticKeyProvider {
+    /// Returns a provider for `key`.
+    pub fn new(key: EncryptionKey) -> Self {
+        Self(key)
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key(&self) -> Result<(u32, EncryptionKey), StorageError> {
+        Ok((0, self.0.clone()))
+    }
+
+    fn key(&self, id: u32) -> Result<EncryptionKey, StorageError> {
+        match id {
+            0 => Ok(self.0.clone()),
+            _ => Err(StorageError::StdIo(ErrorKind::NotFound)),
+        }
+    }
+}
+
+/// Algorithm for [EncryptedBackend].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EncryptionAlgorithm {
+    /// AES-256 in Galois/Counter Mode, which is fastest on CPUs with AES
+    /// instructions.
+    #[default]
+    Aes256Gcm,
+
+    /// ChaCha20-Poly1305, which is faster than AES-256-GCM on CPUs without
+    /// AES instructions.
+    ChaCha20Poly1305,
+}
+
+impl EncryptionAlgorithm {
+    fn code(&self) -> u8 {
+        match self {
+            EncryptionAlgorithm::Aes256Gcm => 1,
+            EncryptionAlgorithm::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(EncryptionAlgorithm::Aes256Gcm),
+            2 => Some(EncryptionAlgorithm::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn aead(&self) -> &'static Algorithm {
+        match self {
+            EncryptionAlgorithm::Aes256Gcm => &AES_256_GCM,
+            EncryptionAlgorithm::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+
+    fn key(&self, key: &EncryptionKey) -> Result<LessSafeKey, StorageError> {
+        UnboundKey::new(self.aead(), &key.0)
+            .map(LessSafeKey::new)
+            .map_err(|_| StorageError::StdIo(ErrorKind::InvalidInput))
+    }
+}
+
+thread_local! {
+    /// Scratch space for [EncryptedReader::read_block_with] to decrypt into.
+    /// It grows to the size of the largest block that the thread decrypts
+    /// this way.
+    static DECRYPTED: RefCell<FBuf> = RefCell::new(FBuf::new());
+}
+
+/// Returns `N` random bytes.
+fn random<const N: usize>(rng: &SystemRandom) -> Result<[u8; N], StorageError> {
+    let mut bytes = [0; N];
+    rng.fill(&mut bytes)
+        .map_err(|_| StorageError::StdIo(ErrorKind::Other))?;
+    Ok(bytes)
+}
+
+/// Returns the additional authenticated data for block number `index` in a
+/// file with the given `salt`.
+fn block_aad(salt: &[u8; SALT_LEN], index: usize) -> [u8; SALT_LEN + 8] {
+    let mut aad = [0; SALT_LEN + 8];
+    aad[..SALT_LEN].copy_from_slice(salt);
+    aad[SALT_LEN..].copy_from_slice(&(index as u64).to_le_bytes());
+    aad
+}
+
+/// A [StorageBackend] that encrypts the blocks in files on another backend.
+/// See the [module documentation](self).
+pub struct EncryptedBackend {
+    inner: Arc<dyn StorageBackend>,
+    keys: Arc<dyn KeyProvider>,
+    algorithm: EncryptionAlgorithm,
+    rng: SystemRandom,
+}
+
+impl EncryptedBackend {
+    /// Returns a new backend that encrypts files written to `inner` with
+    /// AES-256-GCM, using keys from `keys`.
+    pub fn new(inner: Arc<dyn StorageBackend>, keys: Arc<dyn KeyProvider>) -> Self {
+        Self {
+            inner,
+            keys,
+            algorithm: EncryptionAlgorithm::default(),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Returns this backend, modified to encrypt new files with `algorithm`.
+    /// Files are always decrypted with the algorithm that they were
+    /// encrypted with.
+    pub fn with_algorithm(mut self, algorithm: EncryptionAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Returns the wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn StorageBackend> {
+        &self.inner
+    }
+
+    /// Returns the logical length of each block in `name`, in order.
+    pub(super) fn block_lens(&self, name: &StoragePath) -> Result<Vec<usize>, StorageError> {
+        let inner = self.inner.open(name)?;
+        let (_key, _salt, index) = EncryptedReader::read_footer(inner.as_ref(), &*self.keys)?;
+        Ok(index.logical_lens())
+    }
+}
+
+impl StorageBackend for EncryptedBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let (key_id, key) = self.keys.current_key()?;
+        Ok(Box::new(EncryptedWriter {
+            key: self.algorithm.key(&key)?,
+            algorithm: self.algorithm,
+            key_id,
+            salt: random(&self.rng)?,
+            rng: self.rng.clone(),
+            inner: self.inner.create_named(name)?,
+            index: BlockIndex::default(),
+            footer_written: false,
+        }))
+    }
+
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        EncryptedReader::open(self.inner.open(name)?, &*self.keys)
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        self.inner.list(parent, cb)
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.inner.delete(name)
+    }
+
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.inner.delete_recursive(name)
+    }
+
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.rename_subtree(from, to)
+    }
+
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.rename(from, to)
+    }
+
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        // Files aren't bound to their names, so a copy of the encrypted data
+        // decrypts just like the original.
+        self.inner.copy(from, to)
+    }
+
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        self.inner.complete_group(writers)
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.inner.usage()
+    }
+
+    fn available_space(&self) -> Result<u64, StorageError> {
+        self.inner.available_space()
+    }
+
+    fn preferred_block_size(&self) -> usize {
+        self.inner.preferred_block_size()
+    }
+
+    fn min_block_size(&self) -> usize {
+        self.inner.min_block_size()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        let capabilities = self.inner.capabilities();
+        StorageCapabilities {
+            max_block_size: capabilities
+                .max_block_size
+                .map(|max_block_size| max_block_size.saturating_sub(MAX_BLOCK_OVERHEAD)),
+            ..capabilities
+        }
+    }
+}
+
+struct EncryptedWriter {
+    inner: Box<dyn FileWriter>,
+    key: LessSafeKey,
+    algorithm: EncryptionAlgorithm,
+    key_id: u32,
+    salt: [u8; SALT_LEN],
+    rng: SystemRandom,
+
+    /// The blocks written so far.
+    index: BlockIndex,
+
+    /// Whether the index and footer have been written.
+    footer_written: bool,
+}
+
+impl EncryptedWriter {
+    /// Returns `data` encrypted into a physical block, with its nonce, tag,
+    /// and padding.
+    fn encrypt(&self, data: &[u8]) -> Result<FBuf, StorageError> {
+        let nonce = random::<NONCE_LEN>(&self.rng)?;
+        let padded_len = (NONCE_LEN + data.len() + TAG_LEN).next_multiple_of(512);
+        let mut block = FBuf::with_capacity(padded_len);
+        block.extend_from_slice(&nonce);
+        block.extend_from_slice(data);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(block_aad(&self.salt, self.index.len())),
+                &mut block.as_mut_slice()[NONCE_LEN..],
+            )
+            .map_err(|_| StorageError::StdIo(ErrorKind::Other))?;
+        block.extend_from_slice(tag.as_ref());
+        block.resize(padded_len, 0);
+        Ok(block)
+    }
+
+    /// Writes the index and the footer, if they haven't been written yet.
+    fn write_footer(&mut self) -> Result<(), StorageError> {
+        if self.footer_written {
+            return Ok(());
+        }
+
+        let encoded = self.index.encode();
+        let mut index = FBuf::with_capacity(encoded.len().next_multiple_of(512));
+        index.extend_from_slice(&encoded);
+        index.resize(encoded.len().next_multiple_of(512), 0);
+
+        let mut footer = FBuf::with_capacity(FOOTER_LEN);
+        footer.extend_from_slice(&FOOTER_MAGIC);
+        footer.push(self.algorithm.code());
+        footer.resize(12, 0);
+        footer.extend_from_slice(&self.key_id.to_le_bytes());
+        footer.extend_from_slice(&self.index.logical_size().to_le_bytes());
+        footer.extend_from_slice(&self.index.physical_size().to_le_bytes());
+        footer.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        footer.extend_from_slice(&self.salt);
+
+        let nonce = random::<NONCE_LEN>(&self.rng)?;
+        let mut aad = footer.as_slice().to_vec();
+        aad.extend_from_slice(&encoded);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut [],
+            )
+            .map_err(|_| StorageError::StdIo(ErrorKind::Other))?;
+        footer.extend_from_slice(&nonce);
+        footer.extend_from_slice(tag.as_ref());
+        footer.resize(FOOTER_LEN, 0);
+
+        if !index.is_empty() {
+            self.inner.write_block(index)?;
+        }
+        self.inner.write_block(footer)?;
+        self.footer_written = true;
+        Ok(())
+    }
+}
+
+impl HasFileId for EncryptedWriter {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileWriter for EncryptedWriter {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        if u32::try_from(data.len()).is_err() {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let block = self.encrypt(&data)?;
+        let physical_len = block.len();
+        self.inner.write_block(block)?;
+        self.index.push(data.len(), physical_len);
+        Ok(Arc::new(data))
+    }
+
+    fn recycle(&mut self) -> Vec<FBuf> {
+        self.inner.recycle()
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<(), StorageError> {
+        self.inner.preallocate(size)
+    }
+
+    fn complete(mut self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.write_footer()?;
+        let EncryptedWriter {
+            inner,
+            key,
+            salt,
+            index,
+            ..
+        } = *self;
+        let (reader, name) = inner.complete()?;
+        Ok((EncryptedReader::new(reader, key, salt, index), name))
+    }
+
+    fn prepare_complete(&mut self) -> Result<(), StorageError> {
+        self.write_footer()?;
+        self.inner.prepare_complete()
+    }
+
+    fn complete_prepared(
+        mut self: Box<Self>,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.write_footer()?;
+        let EncryptedWriter {
+            inner,
+            key,
+            salt,
+            index,
+            ..
+        } = *self;
+        let (reader, name) = inner.complete_prepared()?;
+        Ok((EncryptedReader::new(reader, key, salt, index), name))
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        self.inner.abort()
+    }
+}
+
+struct EncryptedReader {
+    inner: Arc<dyn FileReader>,
+    key: LessSafeKey,
+    salt: [u8; SALT_LEN],
+    index: BlockIndex,
+}
+
+impl EncryptedReader {
+    fn new(
+        inner: Arc<dyn FileReader>,
+        key: LessSafeKey,
+        salt: [u8; SALT_LEN],
+        index: BlockIndex,
+    ) -> Arc<dyn FileReader> {
+        Arc::new(Self {
+            inner,
+            key,
+            salt,
+            index,
+        })
+    }
+
+    /// Reads and authenticates the footer and index of `inner`, with a key
+    /// from `keys`, and returns a reader for its logical contents.
+    fn open(
+        inner: Arc<dyn FileReader>,
+        keys: &dyn KeyProvider,
+    ) -> Result<Arc<dyn FileReader>, StorageError> {
+        let (key, salt, index) = Self::read_footer(inner.as_ref(), keys)?;
+        Ok(Self::new(inner, key, salt, index))
+    }
+
+    /// Reads and authenticates the footer and index of `inner`, with a key
+    /// from `keys`, and returns the key, the file's salt, and the index.
+    fn read_footer(
+        inner: &dyn FileReader,
+        keys: &dyn KeyProvider,
+    ) -> Result<(LessSafeKey, [u8; SALT_LEN], BlockIndex), StorageError> {
+        let size = inner.get_size()?;
+        let footer_offset = size.saturating_sub(FOOTER_LEN as u64);
+        let not_encrypted = StorageError::DecryptionFailed {
+            offset: footer_offset,
+        };
+        if size < FOOTER_LEN as u64 || size % 512 != 0 {
+            return Err(not_encrypted);
+        }
+        let footer = inner.read_block(BlockLocation::new(footer_offset, FOOTER_LEN).unwrap())?;
+        if footer[..FOOTER_MAGIC.len()] != FOOTER_MAGIC {
+            return Err(not_encrypted);
+        }
+
+        let algorithm = EncryptionAlgorithm::from_code(footer[8]).ok_or_else(corrupt)?;
+        let key = algorithm.key(&keys.key(read_u32(&footer, 12))?)?;
+        let logical_size = read_u64(&footer, 16);
+        let index_offset = read_u64(&footer, 24);
+        let n_blocks = read_u64(&footer, 32);
+        let salt: [u8; SALT_LEN] = footer[40..56].try_into().unwrap();
+        let nonce: [u8; NONCE_LEN] = footer[56..68].try_into().unwrap();
+
+        let index_len = n_blocks
+            .checked_mul(8)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(corrupt)?;
+        let padded_len = index_len.next_multiple_of(512);
+        if index_offset.checked_add(padded_len as u64) != Some(footer_offset) {
+            return Err(corrupt());
+        }
+        let encoded = if padded_len > 0 {
+            let location = BlockLocation::new(index_offset, padded_len).map_err(|_| corrupt())?;
+            inner.read_block(location)?
+        } else {
+            Arc::new(FBuf::new())
+        };
+        let encoded = &encoded[..index_len];
+
+        let mut aad = footer[..FOOTER_AUTHENTICATED_LEN].to_vec();
+        aad.extend_from_slice(encoded);
+        let mut tag = footer[68..68 + TAG_LEN].to_vec();
+        key.open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut tag,
+        )
+        .map_err(|_| StorageError::DecryptionFailed {
+            offset: footer_offset,
+        })?;
+
+        let index = BlockIndex::decode(encoded, NONCE_LEN + TAG_LEN)?;
+        if index.logical_size() != logical_size || index.physical_size() != index_offset {
+            return Err(corrupt());
+        }
+        Ok((key, salt, index))
+    }
+
+    /// Authenticates and decrypts `raw`, the physical block for `block`,
+    /// which is block number `index` in the file, into `data`, replacing
+    /// whatever `data` held.
+    fn decrypt(
+        &self,
+        index: usize,
+        block: &BlockEntry,
+        raw: &[u8],
+        data: &mut FBuf,
+    ) -> Result<(), StorageError> {
+        let nonce: [u8; NONCE_LEN] = raw[..NONCE_LEN].try_into().unwrap();
+        let sealed = raw[NONCE_LEN..]
+            .get(..block.logical_len + TAG_LEN)
+            .ok_or_else(corrupt)?;
+
+        data.clear();
+        data.extend_from_slice(sealed);
+        self.key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(block_aad(&self.salt, index)),
+                data.as_mut_slice(),
+            )
+            .map_err(|_| StorageError::DecryptionFailed {
+                offset: block.physical_offset,
+            })?;
+        data.resize(block.logical_len, 0);
+        Ok(())
+    }
+
+    /// Reads, authenticates, and decrypts `block`, which is block number
+    /// `index` in the file.
+    fn read_entry(&self, index: usize, block: &BlockEntry) -> Result<Arc<FBuf>, StorageError> {
+        let raw = self.inner.read_block(block.physical_location()?)?;
+        let mut data = FBuf::with_capacity(block.logical_len + TAG_LEN);
+        self.decrypt(index, block, &raw, &mut data)?;
+        Ok(Arc::new(data))
+    }
+}
+
+impl HasFileId for EncryptedReader {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileReader for EncryptedReader {
+    fn mark_for_checkpoint(&self) {
+        self.inner.mark_for_checkpoint();
+    }
+
+    fn is_kept(&self) -> bool {
+        self.inner.is_kept()
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        self.index
+            .read_block(location, |index, block| self.read_entry(index, block))
+    }
+
+    /// Decrypts a block that is read exactly as it was written into scratch
+    /// space that belongs to the thread, instead of into a new buffer.
+    fn read_block_with(
+        &self,
+        location: BlockLocation,
+        f: &mut dyn FnMut(&[u8]) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        let Some((index, block)) = self.index.exact(location) else {
+            return f(&self.read_block(location)?);
+        };
+        self.inner
+            .read_block_with(block.physical_location()?, &mut |raw| {
+                DECRYPTED.with(|scratch| match scratch.try_borrow_mut() {
+                    Ok(mut data) => {
+                        self.decrypt(index, block, raw, &mut data)?;
+                        f(&data)
+                    }
+                    Err(_) => {
+                        // `f` is itself reading through an encrypted reader,
+                        // which is using the scratch space.
+                        let mut data = FBuf::with_capacity(block.logical_len + TAG_LEN);
+                        self.decrypt(index, block, raw, &mut data)?;
+                        f(&data)
+                    }
+                })
+            })
+    }
+
+    fn read_scattered(&self, offset: u64, bufs: &mut [&mut [u8]]) -> Result<usize, StorageError> {
+        self.index
+            .read_scattered(offset, bufs, |index, block| self.read_entry(index, block))
+    }
+
+    fn advise_dontneed(&self) {
+        self.inner.advise_dontneed();
+    }
+
+    fn advise_sequential(&self) {
+        self.inner.advise_sequential();
+    }
+
+    fn prefetch(&self, locations: &[BlockLocation]) {
+        self.inner
+            .prefetch(&self.index.physical_locations(locations));
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        Ok(self.index.logical_size())
+    }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        self.inner.created_at()
+    }
+
+    fn stats(&self) -> FileStats {
+        // These count physical, encrypted bytes.
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EncryptedBackend, EncryptionAlgorithm, EncryptionKey, KeyProvider, StaticKeyProvider,
+    };
+    use crate::storage::backend::{
+        memory_impl::MemoryBackend, BlockLocation, StorageBackend, StorageError,
+    };
+    use crate::storage::buffer_cache::FBuf;
+    use feldera_storage::StoragePath;
+    use std::sync::Arc;
+
+    fn keys(byte: u8) -> Arc<dyn KeyProvider> {
+        Arc::new(StaticKeyProvider::new(EncryptionKey::new([byte; 32])))
+    }
+
+    /// Writes a file with blocks of 1024 bytes of 1s, 512 bytes of 2s, and
+    /// 2048 bytes of 3s to `backend`.
+    fn write_file(backend: &dyn StorageBackend, name: &StoragePath) {
+        let mut writer = backend.create_named(name).unwrap();
+        for (len, value) in [(1024, 1), (512, 2), (2048, 3)] {
+            let mut block = FBuf::with_capacity(len);
+            block.resize(len, value);
+            writer.write_block(block).unwrap();
+        }
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+    }
+
+    /// Replaces the file `name` in `backend` by the result of passing its
+    /// contents to `f`.
+    fn tamper(backend: &MemoryBackend, name: &StoragePath, f: impl FnOnce(&mut FBuf)) {
+        let mut raw = Arc::unwrap_or_clone(backend.read(name).unwrap());
+        f(&mut raw);
+        backend.delete(name).unwrap();
+        backend.write(name, raw).unwrap();
+    }
+
+    /// Files read back as they were written, with each algorithm, and aren't
+    /// stored in plaintext.
+    #[test]
+    fn round_trip() {
+        for algorithm in [
+            EncryptionAlgorithm::Aes256Gcm,
+            EncryptionAlgorithm::ChaCha20Poly1305,
+        ] {
+            let inner = Arc::new(MemoryBackend::new());
+            let backend = EncryptedBackend::new(inner.clone(), keys(7)).with_algorithm(algorithm);
+            let name = StoragePath::from("file");
+            write_file(&backend, &name);
+
+            let raw = inner.read(&name).unwrap();
+            assert!(!raw.windows(64).any(|window| window == [3; 64]));
+
+            let reader = backend.open(&name).unwrap();
+            assert_eq!(reader.get_size().unwrap(), 3584);
+            let block = reader
+                .read_block(BlockLocation::new(1024, 512).unwrap())
+                .unwrap();
+            assert_eq!(block.as_slice(), &[2; 512]);
+
+            // A read that spans blocks.
+            let block = reader
+                .read_block(BlockLocation::new(512, 1536).unwrap())
+                .unwrap();
+            assert_eq!(&block[..512], &[1; 512]);
+            assert_eq!(&block[512..1024], &[2; 512]);
+            assert_eq!(&block[1024..], &[3; 512]);
+
+            let mut buf = [0; 10];
+            let n = reader.read_scattered(1530, &mut [&mut buf[..]]).unwrap();
+            assert_eq!(n, 10);
+            assert_eq!(buf, [2, 2, 2, 2, 2, 2, 3, 3, 3, 3]);
+        }
+    }
+
+    /// Modifying a block makes reading it fail, without affecting the other
+    /// blocks.
+    #[test]
+    fn tampered_block() {
+        let inner = Arc::new(MemoryBackend::new());
+        let backend = EncryptedBackend::new(inner.clone(), keys(7));
+        let name = StoragePath::from("file");
+        write_file(&backend, &name);
+
+        // The first block takes 1536 bytes, the second one starts after it.
+        tamper(&inner, &name, |raw| raw.as_mut_slice()[1536 + 100] ^= 1);
+
+        let reader = backend.open(&name).unwrap();
+        let Err(error) = reader.read_block(BlockLocation::new(1024, 512).unwrap()) else {
+            unreachable!()
+        };
+        assert!(matches!(
+            error,
+            StorageError::DecryptionFailed { offset: 1536 }
+        ));
+        reader
+            .read_block(BlockLocation::new(0, 1024).unwrap())
+            .unwrap();
+    }
+
+    /// Modifying the index, or opening a file with the wrong key, fails.
+    #[test]
+    fn tampered_index_and_wrong_key() {
+        let inner = Arc::new(MemoryBackend::new());
+        let backend = EncryptedBackend::new(inner.clone(), keys(7));
+        let name = StoragePath::from("file");
+        write_file(&backend, &name);
+
+        let wrong_key = EncryptedBackend::new(inner.clone(), keys(8));
+        let Err(error) = wrong_key.open(&name) else {
+            unreachable!()
+        };
+        assert!(matches!(error, StorageError::DecryptionFailed { .. }));
+
+        // The index starts 512 bytes before the footer.  Changing the logical
+        // length of the first block hides the third block.
+        tamper(&inner, &name, |raw| {
+            let index_offset = raw.len() - 1024;
+            raw.as_mut_slice()[index_offset + 5] ^= 1;
+        });
+        let Err(error) = backend.open(&name) else {
+            unreachable!()
+        };
+        assert!(matches!(error, StorageError::DecryptionFailed { .. }));
+    }
+
+    /// Files that weren't encrypted can't be opened.
+    #[test]
+    fn unencrypted_file() {
+        let inner = Arc::new(MemoryBackend::new());
+        let mut block = FBuf::with_capacity(1024);
+        block.resize(1024, 1);
+        inner.write(&"file".into(), block).unwrap();
+
+        let backend = EncryptedBackend::new(inner, keys(7));
+        let Err(error) = backend.open(&"file".into()) else {
+            unreachable!()
+        };
+        assert!(matches!(error, StorageError::DecryptionFailed { .. }));
+    }
+}
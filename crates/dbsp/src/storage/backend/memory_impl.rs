@@ -1,22 +1,27 @@
 //! [StorageBackend] implementation in memory.
 //!
-//! This is useful for performance testing, not as part of a production system.
+//! This is useful for tests, performance testing, and ephemeral workloads, not
+//! as part of a production system that needs its data to outlive the process.
 
 use super::{
-    BlockLocation, FileId, FileReader, FileWriter, HasFileId, StorageBackend, StorageError,
+    release_usage, BlockHandle, BlockLocation, BlockRef, FileId, FileReader, FileWriter, HasFileId,
+    StorageBackend, StorageError,
 };
 use crate::circuit::metrics::{
     FILES_CREATED, READS_FAILED, READS_SUCCESS, TOTAL_BYTES_READ, TOTAL_BYTES_WRITTEN,
     WRITES_SUCCESS,
 };
 use crate::storage::buffer_cache::FBuf;
-use feldera_storage::{StorageFileType, StoragePath};
+use feldera_storage::clock::{StorageClock, SystemClock};
+use feldera_storage::{StorageBackendFactory, StorageFileType, StoragePath};
+use feldera_types::config::{StorageBackendConfig, StorageConfig};
 use metrics::counter;
 use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::{
     collections::HashMap,
     io::{Error as IoError, ErrorKind},
     sync::{Arc, RwLock},
+    time::SystemTime,
 };
 
 struct MemoryFile {
@@ -24,6 +29,29 @@ struct MemoryFile {
     path: StoragePath,
     blocks: Vec<(u64, Arc<FBuf>)>,
     size: u64,
+    created_at: SystemTime,
+}
+
+impl MemoryFile {
+    /// Copies data starting at `offset` into `buf`, returning the number of
+    /// bytes copied, which is less than `buf.len()` only at end of file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut index = self.blocks.partition_point(|(start, _)| *start <= offset) - 1;
+        let mut copied = 0;
+        while copied < len {
+            let (start, data) = &self.blocks[index];
+            let skip = (offset + copied as u64 - *start) as usize;
+            let n = (data.len() - skip).min(len - copied);
+            buf[copied..copied + n].copy_from_slice(&data[skip..skip + n]);
+            copied += n;
+            index += 1;
+        }
+        len
+    }
 }
 
 impl HasFileId for MemoryFile {
@@ -38,6 +66,9 @@ struct MemoryBackendInner {
 
     /// Tracks the total size of all the files.
     usage: Arc<AtomicI64>,
+
+    /// Source of timestamps.
+    clock: Arc<dyn StorageClock>,
 }
 
 /// State of the backend needed to satisfy the storage APIs.
@@ -46,10 +77,7 @@ pub struct MemoryBackend(Arc<MemoryBackendInner>);
 
 impl Default for MemoryBackend {
     fn default() -> Self {
-        Self(Arc::new(MemoryBackendInner {
-            files: RwLock::new(HashMap::new()),
-            usage: Arc::new(AtomicI64::new(0)),
-        }))
+        Self::with_clock(Arc::new(SystemClock))
     }
 }
 
@@ -58,12 +86,26 @@ impl MemoryBackend {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Creates and returns a new memory backend that takes timestamps from
+    /// `clock`.
+    pub fn with_clock(clock: Arc<dyn StorageClock>) -> Self {
+        Self(Arc::new(MemoryBackendInner {
+            files: RwLock::new(HashMap::new()),
+            usage: Arc::new(AtomicI64::new(0)),
+            clock,
+        }))
+    }
 }
 
 struct MemoryWriter {
     backend: MemoryBackend,
     file: MemoryFile,
     drop: DeleteOnDrop,
+
+    /// Offsets of blocks reserved with [FileWriter::reserve_block] that
+    /// haven't been filled yet.
+    reserved: Vec<u64>,
 }
 
 impl MemoryWriter {
@@ -74,11 +116,13 @@ impl MemoryWriter {
                 path: name.clone(),
                 blocks: Vec::new(),
                 size: 0,
+                created_at: backend.0.clock.now(),
             },
             drop: DeleteOnDrop {
                 usage: backend.0.usage.clone(),
                 size: 0,
             },
+            reserved: Vec::new(),
             backend,
         }
     }
@@ -108,7 +152,69 @@ impl FileWriter for MemoryWriter {
         Ok(data)
     }
 
+    fn reserve_block(&mut self, size: usize) -> Result<BlockHandle, StorageError> {
+        let location = BlockLocation::new(self.file.size, size)
+            .map_err(|_| StorageError::StdIo(ErrorKind::InvalidInput))?;
+        let mut placeholder = FBuf::with_capacity(size);
+        placeholder.resize(size, 0);
+        self.write_block(placeholder)?;
+        self.reserved.push(location.offset);
+        Ok(BlockHandle::new(location))
+    }
+
+    fn fill_reserved(&mut self, handle: BlockHandle, data: FBuf) -> Result<(), StorageError> {
+        let location = handle.location();
+        let index = self
+            .reserved
+            .iter()
+            .position(|offset| *offset == location.offset)
+            .filter(|_| data.len() == location.size)
+            .ok_or(StorageError::StdIo(ErrorKind::InvalidInput))?;
+        let block = self
+            .file
+            .blocks
+            .iter_mut()
+            .find(|(offset, _block)| *offset == location.offset)
+            .unwrap();
+        block.1 = Arc::new(data);
+        self.reserved.swap_remove(index);
+        Ok(())
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<(), StorageError> {
+        let straddles =
+            |offset: u64, block: &FBuf| offset < len && offset + block.len() as u64 > len;
+        if len > self.file.size
+            || self.reserved.iter().any(|reserved| {
+                self.file
+                    .blocks
+                    .iter()
+                    .any(|(offset, block)| offset == reserved && straddles(*offset, block))
+            })
+        {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        self.reserved.retain(|offset| *offset < len);
+        self.file.blocks.retain(|(offset, _block)| *offset < len);
+        if let Some((offset, block)) = self.file.blocks.last_mut() {
+            if straddles(*offset, block) {
+                let mut prefix = FBuf::with_capacity((len - *offset) as usize);
+                prefix.extend_from_slice(&block[..(len - *offset) as usize]);
+                *block = Arc::new(prefix);
+            }
+        }
+
+        let discarded = self.file.size - len;
+        self.file.size = len;
+        self.drop.size = len;
+        release_usage(&self.drop.usage, discarded, false);
+        Ok(())
+    }
+
     fn complete(mut self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        if let Some(offset) = self.reserved.iter().min() {
+            return Err(StorageError::UnfilledReservation { offset: *offset });
+        }
         let path = self.file.path.clone();
         self.drop.size = 0;
         let file = Arc::new(self.file);
@@ -125,6 +231,16 @@ impl FileWriter for MemoryWriter {
         });
         Ok((reader, path))
     }
+
+    /// Memory isn't durable, so there is nothing to do.
+    fn sync(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        // Dropping the writer releases its usage through `DeleteOnDrop`.
+        Ok(())
+    }
 }
 
 struct DeleteOnDrop {
@@ -134,7 +250,7 @@ struct DeleteOnDrop {
 
 impl Drop for DeleteOnDrop {
     fn drop(&mut self) {
-        self.usage.fetch_sub(self.size as i64, Ordering::Relaxed);
+        release_usage(&self.usage, self.size, false);
     }
 }
 
@@ -155,6 +271,10 @@ impl FileReader for MemoryReader {
         self.keep.store(true, Ordering::Relaxed);
     }
 
+    fn is_kept(&self) -> bool {
+        self.keep.load(Ordering::Relaxed)
+    }
+
     fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
         if location.after() > self.file.size {
             counter!(READS_FAILED).increment(1);
@@ -194,9 +314,52 @@ impl FileReader for MemoryReader {
         unreachable!();
     }
 
+    /// Borrows the block if it lies within a single block that was written,
+    /// and otherwise copies it like [FileReader::read_block].
+    fn read_block_ref(&self, location: BlockLocation) -> Result<BlockRef<'_>, StorageError> {
+        if location.after() > self.file.size {
+            counter!(READS_FAILED).increment(1);
+            return Err(IoError::from(ErrorKind::UnexpectedEof).into());
+        }
+
+        let index = self
+            .file
+            .blocks
+            .partition_point(|(offset, _)| *offset <= location.offset);
+        let (offset, data) = &self.file.blocks[index - 1];
+        if location.after() > *offset + data.len() as u64 {
+            return self.read_block(location).map(BlockRef::Owned);
+        }
+        counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+        counter!(READS_SUCCESS).increment(1);
+        let start = (location.offset - *offset) as usize;
+        Ok(BlockRef::Borrowed(&data[start..start + location.size]))
+    }
+
+    fn read_scattered(
+        &self,
+        mut offset: u64,
+        bufs: &mut [&mut [u8]],
+    ) -> Result<usize, StorageError> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let n = self.file.read_at(offset, buf);
+            total += n;
+            offset += n as u64;
+            if n < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
     fn get_size(&self) -> Result<u64, StorageError> {
         Ok(self.file.size)
     }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        Ok(self.file.created_at)
+    }
 }
 
 impl Drop for MemoryReader {
@@ -241,7 +404,13 @@ impl StorageBackend for MemoryBackend {
             .map(|(name, file)| (name.clone(), file.size))
             .collect::<Vec<_>>();
         for (path, size) in entries {
-            cb(&path, StorageFileType::File { size });
+            cb(
+                &path,
+                StorageFileType::File {
+                    size,
+                    allocated: size,
+                },
+            );
         }
         Ok(())
     }
@@ -250,7 +419,7 @@ impl StorageBackend for MemoryBackend {
         let mut files = self.0.files.write().unwrap();
         match files.remove(name) {
             Some(file) => {
-                self.0.usage.fetch_sub(file.size as i64, Ordering::Relaxed);
+                release_usage(&self.0.usage, file.size, false);
                 Ok(())
             }
             None => Err(StorageError::StdIo(ErrorKind::NotFound)),
@@ -271,14 +440,38 @@ impl StorageBackend for MemoryBackend {
     }
 }
 
+pub(crate) struct MemoryBackendFactory;
+impl StorageBackendFactory for MemoryBackendFactory {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    fn create(
+        &self,
+        _storage_config: &StorageConfig,
+        _backend_config: &StorageBackendConfig,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        Ok(Arc::new(MemoryBackend::new()))
+    }
+}
+
+inventory::submit! {
+    &MemoryBackendFactory as &dyn StorageBackendFactory
+}
+
 #[cfg(test)]
 mod tests {
     use feldera_storage::StorageBackend;
+    use feldera_types::config::{StorageBackendConfig, StorageConfig, StorageOptions};
     use std::{path::Path, sync::Arc};
 
-    use crate::storage::backend::{
-        memory_impl::MemoryBackend,
-        tests::{random_sizes, test_backend},
+    use crate::storage::{
+        backend::{
+            memory_impl::MemoryBackend,
+            tests::{random_sizes, test_backend, test_reserve},
+            BlockLocation, BlockRef, FileReader, FileWriter,
+        },
+        buffer_cache::FBuf,
     };
 
     fn create_memory_backend(_path: &Path) -> Arc<dyn StorageBackend> {
@@ -308,4 +501,76 @@ mod tests {
     fn empty() {
         test_backend(Box::new(create_memory_backend), &[], true);
     }
+
+    #[test]
+    fn reserve() {
+        test_reserve(Box::new(create_memory_backend));
+    }
+
+    /// Reads past the end of a file fail instead of panicking.
+    #[test]
+    fn read_out_of_range() {
+        let backend = create_memory_backend(Path::new(""));
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 1);
+        backend.write(&"file".into(), block).unwrap();
+
+        let reader = backend.open(&"file".into()).unwrap();
+        reader
+            .read_block(BlockLocation::new(0, 512).unwrap())
+            .unwrap();
+        for (offset, size) in [(512, 512), (0, 1024), (1024 * 1024, 512)] {
+            reader
+                .read_block(BlockLocation::new(offset, size).unwrap())
+                .unwrap_err();
+        }
+    }
+
+    /// The memory backend can be selected by name through the configuration.
+    #[test]
+    fn factory() {
+        let backend = <dyn StorageBackend>::new(
+            &StorageConfig::default(),
+            &StorageOptions {
+                backend: StorageBackendConfig::Memory,
+                ..StorageOptions::default()
+            },
+        )
+        .unwrap();
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 1);
+        backend.write(&"file".into(), block).unwrap();
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            512
+        );
+        assert_eq!(backend.read(&"file".into()).unwrap().len(), 512);
+    }
+
+    /// Reading a block within a single written block borrows it, and reading
+    /// across blocks copies it.
+    #[test]
+    fn read_block_ref() {
+        let backend = MemoryBackend::new();
+        let mut writer = backend.create().unwrap();
+        for value in [1, 2] {
+            let mut block = FBuf::with_capacity(1024);
+            block.resize(1024, value);
+            writer.write_block(block).unwrap();
+        }
+        let (reader, _name) = writer.complete().unwrap();
+
+        let location = |offset, size| BlockLocation::new(offset, size).unwrap();
+        let block = reader.read_block_ref(location(512, 512)).unwrap();
+        assert!(matches!(block, BlockRef::Borrowed(_)));
+        assert_eq!(block.as_slice(), &[1; 512]);
+
+        let block = reader.read_block_ref(location(512, 1024)).unwrap();
+        assert!(matches!(block, BlockRef::Owned(_)));
+        assert_eq!(&block[..512], &[1; 512]);
+        assert_eq!(&block[512..], &[2; 512]);
+        assert_eq!(block.into_owned().len(), 1024);
+
+        assert!(reader.read_block_ref(location(1536, 1024)).is_err());
+    }
 }
@@ -9,19 +9,40 @@
 //! The API also prevents reading from a file that is not completed.
 #![warn(missing_docs)]
 
-use feldera_types::config::StorageCacheConfig;
-use std::{fs::OpenOptions, path::PathBuf, sync::LazyLock};
+use crate::circuit::metrics::USAGE_UNDERFLOW;
+use feldera_types::config::{StorageCacheConfig, StorageOpenFlags};
+use metrics::counter;
+use std::{
+    fs::OpenOptions,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        LazyLock,
+    },
+};
 use tempfile::TempDir;
 use tracing::warn;
 
+pub mod block_cache;
+mod block_index;
+pub mod codec;
+pub mod compressed;
+pub mod encrypted;
 pub mod memory_impl;
-pub mod posixio_impl;
+#[cfg(unix)]
+pub mod posix;
+pub mod read_pool;
+pub mod retry;
+pub mod s3_impl;
+pub mod tiered;
+#[cfg(windows)]
+pub mod windows_impl;
 
 #[cfg(test)]
 mod tests;
 
 pub use feldera_storage::{
-    block::{BlockLocation, InvalidBlockLocation},
+    block::{BeforeStart, BlockHandle, BlockLocation, BlockRef, BlockSlice, InvalidBlockLocation},
     error::StorageError,
     file::FileId,
     file::HasFileId,
@@ -34,6 +55,33 @@ pub use feldera_storage::{
 /// `.mut` to its filename which is removed when we call `complete()`.
 const MUTABLE_EXTENSION: &str = ".mut";
 
+/// Returns the filesystem path to `name` within `base`.
+///
+/// Each [StoragePathPart] becomes one path component, in its percent-encoded
+/// form.  Fails with [StorageError::InvalidPath] if any part isn't exactly
+/// one normal component, so that the result always stays inside `base`.
+fn path_in(base: &Path, name: &StoragePath) -> Result<PathBuf, StorageError> {
+    let mut path = base.to_path_buf();
+    for part in name.parts() {
+        if !is_normal_component(part.as_ref()) {
+            return Err(StorageError::InvalidPath(PathBuf::from(name.as_ref())));
+        }
+        path.push(part.as_ref());
+    }
+    Ok(path)
+}
+
+/// Returns true if `part` is a single path component that names an entry
+/// within a directory: not empty, absolute, `.`, or `..`, and without a path
+/// separator.
+fn is_normal_component(part: &str) -> bool {
+    let mut components = Path::new(part).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !part.contains(std::path::is_separator)
+}
+
 /// Returns a per-thread temporary directory.
 pub fn tempdir_for_thread() -> PathBuf {
     thread_local! {
@@ -42,21 +90,76 @@ pub fn tempdir_for_thread() -> PathBuf {
     TEMPDIR.with(|dir| dir.path().to_path_buf())
 }
 
-trait StorageCacheFlags {
-    fn cache_flags(&mut self, cache: &StorageCacheConfig) -> &mut Self;
+trait StorageFlags {
+    fn storage_flags(&mut self, cache: &StorageCacheConfig, extra: &StorageOpenFlags) -> &mut Self;
 }
 
-impl StorageCacheFlags for OpenOptions {
-    fn cache_flags(&mut self, cache: &StorageCacheConfig) -> &mut Self {
+impl StorageFlags for OpenOptions {
+    fn storage_flags(&mut self, cache: &StorageCacheConfig, extra: &StorageOpenFlags) -> &mut Self {
         #[cfg(unix)]
         {
             use std::os::unix::fs::OpenOptionsExt;
-            self.custom_flags(cache.to_custom_open_flags());
+            self.custom_flags(cache.to_custom_open_flags() | extra.to_custom_open_flags());
         }
         self
     }
 }
 
+/// Subtracts `size` bytes from `usage`.
+///
+/// Usage should never go negative, but accounting can drift, e.g. when a file
+/// is deleted twice or was written outside the backend.  If subtracting `size`
+/// would make usage negative, this clamps it to zero, logs a warning, and
+/// increments [USAGE_UNDERFLOW].  If `strict` is true, it panics instead, to
+/// catch accounting bugs in tests.
+fn release_usage(usage: &AtomicI64, size: u64, strict: bool) {
+    let size = size as i64;
+    let old = usage
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+            Some((old - size).max(0))
+        })
+        .unwrap();
+    if old < size {
+        assert!(
+            !strict,
+            "storage usage underflow: releasing {size} bytes with only {old} in use"
+        );
+        warn!("storage usage underflow: releasing {size} bytes with only {old} in use");
+        counter!(USAGE_UNDERFLOW).increment(1);
+    }
+}
+
+/// How a backend sizes the buffers that it allocates for reads.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReadAllocation {
+    /// Allocate exactly the number of bytes read.
+    #[default]
+    Exact,
+
+    /// Round allocations up to the next power of two.
+    PowerOfTwo,
+
+    /// Round allocations up to the smallest of the given sizes that is big
+    /// enough, which must be in increasing order.  Reads bigger than the
+    /// largest size are allocated exactly.
+    Buckets(Vec<usize>),
+}
+
+impl ReadAllocation {
+    /// Returns the capacity to allocate for a read of `size` bytes.
+    pub fn capacity(&self, size: usize) -> usize {
+        match self {
+            ReadAllocation::Exact => size,
+            ReadAllocation::PowerOfTwo => size.checked_next_power_of_two().unwrap_or(size),
+            ReadAllocation::Buckets(buckets) => buckets
+                .iter()
+                .copied()
+                .find(|bucket| *bucket >= size)
+                .unwrap_or(size),
+        }
+    }
+}
+
 /// Maximum number of buffers that system calls accept in one operation.
 ///
 /// We only use multibuffer system calls on Linux, so the value is arbitrary
@@ -0,0 +1,291 @@
+//! Checkpoints for [PosixBackend].
+//!
+//! [PosixBackend::checkpoint] adds a file to a checkpoint directory with a
+//! hard link of its own, and a [Checkpoint] records the files in a checkpoint
+//! in a manifest that [PosixBackend::delete_checkpoint] uses to delete
+//! exactly those files.
+
+use super::{storage_error, sync_dir, PosixBackend};
+use crate::storage::{
+    backend::{FileReader, StorageError},
+    buffer_cache::FBuf,
+};
+use feldera_storage::{StorageBackend, StorageFileType, StoragePath};
+use feldera_types::config::DurabilityMode;
+use std::{collections::HashSet, fs, io::ErrorKind};
+
+/// Name of the manifest within a checkpoint directory.
+pub const CHECKPOINT_MANIFEST: &str = "CHECKPOINT_MANIFEST";
+
+fn manifest_name(dir: &StoragePath) -> StoragePath {
+    dir.parts()
+        .chain(StoragePath::from(CHECKPOINT_MANIFEST).parts())
+        .collect()
+}
+
+/// A checkpoint being assembled by [PosixBackend::open_checkpoint].
+///
+/// Each [FileReader] added with [Checkpoint::add] is marked for checkpoint and
+/// recorded.  [Checkpoint::commit] then writes the list of recorded files, one
+/// name per line, to a manifest in the checkpoint directory.  The manifest is
+/// written under a temporary name and renamed into place, so it is either
+/// complete or absent.  [PosixBackend::delete_checkpoint] uses the manifest
+/// to delete exactly the checkpoint's files.
+pub struct Checkpoint<'a> {
+    backend: &'a PosixBackend,
+    dir: StoragePath,
+    files: Vec<StoragePath>,
+}
+
+impl Checkpoint<'_> {
+    /// Returns the checkpoint's directory.
+    pub fn dir(&self) -> &StoragePath {
+        &self.dir
+    }
+
+    /// Marks `reader` for checkpoint and records its file in the manifest.
+    /// Fails with [ErrorKind::NotFound] if `reader` isn't a file that this
+    /// checkpoint's backend has open.
+    pub fn add(&mut self, reader: &dyn FileReader) -> Result<(), StorageError> {
+        let name = self
+            .backend
+            .path_for(reader.file_id())
+            .ok_or(StorageError::StdIo(ErrorKind::NotFound))?;
+        reader.mark_for_checkpoint();
+        if !self.files.contains(&name) {
+            self.files.push(name);
+        }
+        Ok(())
+    }
+
+    /// Writes the manifest and returns the names of the files it lists.
+    pub fn commit(self) -> Result<Vec<StoragePath>, StorageError> {
+        let mut content = FBuf::new();
+        for name in &self.files {
+            content.extend_from_slice(name.as_ref().as_bytes());
+            content.push(b'\n');
+        }
+
+        // Pad with empty lines, which reading ignores, to allow direct I/O.
+        let len = content.len().next_multiple_of(FBuf::ALIGNMENT);
+        content.resize(len, b'\n');
+        self.backend.write(&manifest_name(&self.dir), content)?;
+        Ok(self.files)
+    }
+}
+
+impl PosixBackend {
+    /// Adds file `name` to the checkpoint in directory `checkpoint_dir` by
+    /// creating a hard link to it there, with the same name relative to
+    /// `checkpoint_dir`, and returns the name of the link.
+    ///
+    /// Unlike [FileReader::mark_for_checkpoint], this gives each checkpoint
+    /// its own link, so deleting the file, or any one checkpoint's link to
+    /// it, leaves the data for the others.  The file's space stays in usage
+    /// until its last link is deleted.  If `checkpoint_dir` is on a
+    /// different filesystem, this copies the file instead, and the copy adds
+    /// to usage.
+    pub fn checkpoint(
+        &self,
+        name: &StoragePath,
+        checkpoint_dir: &StoragePath,
+    ) -> Result<StoragePath, StorageError> {
+        let link_name = checkpoint_dir
+            .parts()
+            .chain(name.parts())
+            .collect::<StoragePath>();
+        let path = self.fs_path(name)?;
+        let link_path = self.fs_path(&link_name)?;
+        if let Some(parent) = link_path.parent() {
+            self.create_dir_all(parent)
+                .map_err(|error| storage_error(error, &self.base))?;
+        }
+        match fs::hard_link(&path, &link_path) {
+            Err(error) if error.raw_os_error() == Some(libc::EXDEV) => {
+                self.copy_recursive(&path, &link_path)
+                    .map_err(|error| storage_error(error, &self.base))?;
+            }
+            Err(error) => return Err(storage_error(error, &self.base)),
+            Ok(()) => (),
+        }
+        if self.durability != DurabilityMode::None {
+            if let Some(parent) = link_path.parent() {
+                sync_dir(parent)?;
+            }
+        }
+        Ok(link_name)
+    }
+
+    /// Opens a new checkpoint in directory `dir`.  Files added to the
+    /// checkpoint are recorded in its manifest when it is committed.
+    pub fn open_checkpoint(&self, dir: &StoragePath) -> Checkpoint<'_> {
+        Checkpoint {
+            backend: self,
+            dir: dir.clone(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Returns the names of the checkpoints with a committed manifest, in
+    /// sorted order.  Checkpoints are directories directly under the base
+    /// directory.
+    pub fn list_checkpoints(&self) -> Result<Vec<StoragePath>, StorageError> {
+        let mut dirs = Vec::new();
+        self.list_filtered(&StoragePath::default(), None, &mut |name, file_type| {
+            if file_type == StorageFileType::Directory {
+                dirs.push(name.clone());
+            }
+        })?;
+        let mut checkpoints = Vec::new();
+        for dir in dirs {
+            if self.fs_path(&manifest_name(&dir))?.is_file() {
+                checkpoints.push(dir);
+            }
+        }
+        checkpoints.sort();
+        Ok(checkpoints)
+    }
+
+    /// Reads the manifest for checkpoint `dir` and returns the names of the
+    /// files that it lists.
+    pub fn read_manifest(&self, dir: &StoragePath) -> Result<Vec<StoragePath>, StorageError> {
+        let content = self.read(&manifest_name(dir))?;
+        let content = std::str::from_utf8(content.as_slice())
+            .map_err(|_| StorageError::StdIo(ErrorKind::InvalidData))?;
+        Ok(content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(StoragePath::from)
+            .collect())
+    }
+
+    /// Deletes checkpoint `dir`: the files listed in its manifest, except for
+    /// those that another checkpoint's manifest also lists, then the manifest
+    /// itself, then `dir` if that leaves it empty.  Deleted files are released
+    /// from usage.
+    pub fn delete_checkpoint(&self, dir: &StoragePath) -> Result<(), StorageError> {
+        let files = self.read_manifest(dir)?;
+        let mut shared = HashSet::new();
+        for other in self.list_checkpoints()? {
+            if &other != dir {
+                shared.extend(self.read_manifest(&other)?);
+            }
+        }
+        for file in files.iter().filter(|file| !shared.contains(*file)) {
+            match self.delete(file) {
+                Err(StorageError::NotFound(_)) => (),
+                result => result?,
+            }
+        }
+        self.delete(&manifest_name(dir))?;
+        if self.fs_path(dir)?.read_dir()?.next().is_none() {
+            self.delete_recursive(dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{posix::PosixBackend, tests::test_read},
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::{StorageBackend, StoragePath};
+    use feldera_types::config::StorageCacheConfig;
+
+    /// Each checkpoint's link keeps the data alive, and usage drops only
+    /// when the last link is deleted.
+    #[test]
+    fn checkpoint_links() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_strict_usage(true);
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+
+        let mut writer = backend.create_named(&"batch".into()).unwrap();
+        writer.write_block(block.clone()).unwrap();
+        let (reader, name) = writer.complete().unwrap();
+        let first = backend.checkpoint(&name, &"cp1".into()).unwrap();
+        let second = backend.checkpoint(&name, &"cp2".into()).unwrap();
+        assert_eq!(first, StoragePath::from("cp1/batch"));
+        assert_eq!(usage(), 4096);
+
+        // Dropping the reader deletes the original name only.
+        drop(reader);
+        assert!(!tmpdir.path().join("batch").exists());
+        assert_eq!(usage(), 4096);
+
+        backend.delete(&first).unwrap();
+        assert_eq!(usage(), 4096);
+        let reader = backend.open(&second).unwrap();
+        test_read(reader.as_ref(), &block);
+        drop(reader);
+
+        backend.delete_recursive(&"cp2".into()).unwrap();
+        assert_eq!(usage(), 0);
+    }
+
+    /// Deleting a checkpoint deletes the files in its manifest, except for
+    /// those that another checkpoint shares.
+    #[test]
+    fn checkpoint_manifest() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_strict_usage(true);
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+        let create = |name: &str| {
+            let mut writer = backend.create_named(&name.into()).unwrap();
+            writer.write_block(block.clone()).unwrap();
+            writer.complete().unwrap().0
+        };
+        let shared = create("shared");
+        let only1 = create("only1");
+        let only2 = create("only2");
+
+        let mut cp1 = backend.open_checkpoint(&"cp1".into());
+        cp1.add(shared.as_ref()).unwrap();
+        cp1.add(only1.as_ref()).unwrap();
+        assert_eq!(
+            cp1.commit().unwrap(),
+            vec![StoragePath::from("shared"), StoragePath::from("only1")]
+        );
+        let mut cp2 = backend.open_checkpoint(&"cp2".into());
+        cp2.add(shared.as_ref()).unwrap();
+        cp2.add(only2.as_ref()).unwrap();
+        cp2.commit().unwrap();
+        drop((shared, only1, only2));
+
+        // An uncommitted checkpoint isn't listed.
+        std::fs::create_dir(tmpdir.path().join("cp3")).unwrap();
+        assert_eq!(
+            backend.list_checkpoints().unwrap(),
+            vec![StoragePath::from("cp1"), StoragePath::from("cp2")]
+        );
+        assert_eq!(
+            backend.read_manifest(&"cp2".into()).unwrap(),
+            vec![StoragePath::from("shared"), StoragePath::from("only2")]
+        );
+        let manifests = usage() - 3 * 4096;
+
+        backend.delete_checkpoint(&"cp1".into()).unwrap();
+        assert!(!tmpdir.path().join("only1").exists());
+        assert!(tmpdir.path().join("shared").exists());
+        assert!(!tmpdir.path().join("cp1").exists());
+        assert_eq!(usage(), 2 * 4096 + manifests / 2);
+        assert_eq!(
+            backend.list_checkpoints().unwrap(),
+            vec![StoragePath::from("cp2")]
+        );
+
+        backend.delete_checkpoint(&"cp2".into()).unwrap();
+        assert!(!tmpdir.path().join("shared").exists());
+        assert!(!tmpdir.path().join("only2").exists());
+        assert_eq!(usage(), 0);
+        assert!(backend.list_checkpoints().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+//! Block checksums for [PosixBackend].
+//!
+//! With [PosixBackend::with_block_checksums], writers record a CRC32C for
+//! each block in a [BlockChecksums] trailer after the file's data, and
+//! readers verify blocks against it.
+
+use super::PosixBackend;
+use crate::storage::{
+    backend::{BlockLocation, StorageError},
+    buffer_cache::FBuf,
+};
+use std::{collections::HashMap, fs::File, io::Error as IoError};
+
+/// Magic number that ends a file with a [BlockChecksums] trailer.
+const CHECKSUM_MAGIC: [u8; 8] = *b"FLDRCRC1";
+
+/// Size of each `(offset, len, crc)` entry in a [BlockChecksums] trailer.
+const CHECKSUM_ENTRY_SIZE: usize = 20;
+
+/// Size of the footer at the end of a [BlockChecksums] trailer.
+const CHECKSUM_FOOTER_SIZE: usize = 32;
+
+/// CRC32C checksums for the blocks in a file, which are stored in a trailer
+/// after the file's data.  See [StorageConfig::block_checksums].
+///
+/// The trailer is a sequence of little-endian `(offset: u64, len: u32, crc:
+/// u32)` entries, then zero padding to make the file a multiple of 512 bytes
+/// long, then a footer of the number of entries as a `u64`, the size of the
+/// data as a `u64`, a CRC32C of the entries and those two fields as a `u32`,
+/// four zero bytes, and [CHECKSUM_MAGIC].
+///
+/// [StorageConfig::block_checksums]: feldera_types::config::StorageConfig::block_checksums
+pub(super) struct BlockChecksums {
+    /// Size of the data that precedes the trailer.
+    pub(super) data_size: u64,
+
+    /// Maps from a block's offset to its length and checksum.
+    pub(super) blocks: HashMap<u64, (usize, u32)>,
+}
+
+impl BlockChecksums {
+    /// Returns the trailer that records these checksums.
+    pub(super) fn trailer(&self) -> FBuf {
+        let entries = self.blocks.len() * CHECKSUM_ENTRY_SIZE;
+        let unpadded = self.data_size as usize + entries + CHECKSUM_FOOTER_SIZE;
+        let size = unpadded.next_multiple_of(512) - self.data_size as usize;
+        let mut trailer = FBuf::with_capacity(size);
+        for (offset, (len, crc)) in &self.blocks {
+            trailer.extend_from_slice(&offset.to_le_bytes());
+            trailer.extend_from_slice(&(*len as u32).to_le_bytes());
+            trailer.extend_from_slice(&crc.to_le_bytes());
+        }
+        let crc = crc32c::crc32c(&trailer);
+        trailer.resize(size - CHECKSUM_FOOTER_SIZE, 0);
+        let fields_start = trailer.len();
+        trailer.extend_from_slice(&(self.blocks.len() as u64).to_le_bytes());
+        trailer.extend_from_slice(&self.data_size.to_le_bytes());
+        let crc = crc32c::crc32c_append(crc, &trailer[fields_start..]);
+        trailer.extend_from_slice(&crc.to_le_bytes());
+        trailer.extend_from_slice(&[0; 4]);
+        trailer.extend_from_slice(&CHECKSUM_MAGIC);
+        trailer
+    }
+
+    /// Reads the checksums from the trailer of `file`, which is `size` bytes
+    /// long.  Returns `None` if the file doesn't have a trailer.
+    pub(super) fn read(file: &File, size: u64) -> Result<Option<Self>, IoError> {
+        if size < 512 || size % 512 != 0 {
+            return Ok(None);
+        }
+        let mut footer = FBuf::with_capacity(512);
+        footer.read_exact_at(file, size - 512, 512)?;
+        let fields = &footer[512 - CHECKSUM_FOOTER_SIZE..];
+        if fields[24..] != CHECKSUM_MAGIC {
+            return Ok(None);
+        }
+        let count = u64::from_le_bytes(fields[..8].try_into().unwrap()) as usize;
+        let data_size = u64::from_le_bytes(fields[8..16].try_into().unwrap());
+        let crc = u32::from_le_bytes(fields[16..20].try_into().unwrap());
+        let entries_size = count.saturating_mul(CHECKSUM_ENTRY_SIZE) as u64;
+        if data_size.saturating_add(entries_size) > size - CHECKSUM_FOOTER_SIZE as u64 {
+            // Not a trailer after all, just data that happens to end with the
+            // magic number.
+            return Ok(None);
+        }
+
+        // Read the entries starting from an aligned offset, in case the file
+        // was opened with `O_DIRECT`.
+        let start = data_size / 512 * 512;
+        let mut trailer = FBuf::with_capacity((size - start) as usize);
+        trailer.read_exact_at(file, start, (size - start) as usize)?;
+        let entries = &trailer[(data_size - start) as usize..][..entries_size as usize];
+        if crc32c::crc32c_append(crc32c::crc32c(entries), &fields[..16]) != crc {
+            // Likewise.
+            return Ok(None);
+        }
+        let blocks = entries
+            .chunks_exact(CHECKSUM_ENTRY_SIZE)
+            .map(|entry| {
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let len = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+                let crc = u32::from_le_bytes(entry[12..].try_into().unwrap());
+                (offset, (len as usize, crc))
+            })
+            .collect();
+        Ok(Some(Self { data_size, blocks }))
+    }
+
+    /// Checks `block`, read from `location`, against the checksum recorded
+    /// for that location.  Blocks read from locations other than those that
+    /// were written can't be checked, so they always pass.
+    pub(super) fn verify(&self, location: BlockLocation, block: &[u8]) -> Result<(), StorageError> {
+        match self.blocks.get(&location.offset) {
+            Some((len, expected)) if *len == location.size => {
+                let actual = crc32c::crc32c(block);
+                if actual != *expected {
+                    return Err(StorageError::ChecksumMismatch {
+                        offset: location.offset,
+                        expected: *expected,
+                        actual,
+                    });
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PosixBackend {
+    /// Returns this backend, modified to record a checksum for every block
+    /// that it writes and to verify it on read (if `block_checksums` is
+    /// true).  Only a backend with checksums enabled looks for them in the
+    /// files that it opens.  See [StorageConfig::block_checksums].
+    ///
+    /// [StorageConfig::block_checksums]: feldera_types::config::StorageConfig::block_checksums
+    pub fn with_block_checksums(mut self, block_checksums: bool) -> Self {
+        self.block_checksums = block_checksums;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CHECKSUM_MAGIC;
+    use crate::storage::{
+        backend::{posix::PosixBackend, tests::test_read, BlockLocation, StorageError},
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::StorageBackend;
+    use feldera_types::config::StorageCacheConfig;
+    use std::{fs::File, os::unix::fs::FileExt};
+
+    #[test]
+    fn block_checksums() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_block_checksums(true);
+        let block = |size: usize, value: u8| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, value);
+            block
+        };
+
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        let header = writer.reserve_block(512).unwrap();
+        writer.write_block(block(4096, 1)).unwrap();
+        writer.write_block(block(1024, 2)).unwrap();
+        writer.fill_reserved(header, block(512, 3)).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+        let mut expected = block(512, 3).to_vec();
+        expected.extend_from_slice(&block(4096, 1));
+        expected.extend_from_slice(&block(1024, 2));
+        test_read(reader.as_ref(), &expected);
+        drop(reader);
+
+        // The trailer follows the data.
+        let path = tmpdir.path().join("file");
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert!(size > expected.len() as u64 && size % 512 == 0);
+
+        // Reopening finds the checksums, and a corrupt block fails to read.
+        let reader = backend.open(&"file".into()).unwrap();
+        test_read(reader.as_ref(), &expected);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .write_all_at(&[0xff], 1000)
+            .unwrap();
+        let location = BlockLocation::new(512, 4096).unwrap();
+        for result in [
+            reader.read_block(location),
+            reader.read_blocks(&[location], 0).remove(0),
+            reader
+                .read_blocks(&[BlockLocation::new(0, 512).unwrap(), location], 0)
+                .remove(1),
+        ] {
+            let Err(StorageError::ChecksumMismatch { offset, .. }) = result else {
+                unreachable!()
+            };
+            assert_eq!(offset, 512);
+        }
+
+        // Blocks with good checksums still read fine.
+        let location = BlockLocation::new(4608, 1024).unwrap();
+        assert!(reader.read_block(location).unwrap().iter().all(|&b| b == 2));
+
+        // Files written without checksums still open cleanly.
+        let plain = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        plain.write(&"plain".into(), block(1024, 4)).unwrap();
+        let reader = backend.open(&"plain".into()).unwrap();
+        test_read(reader.as_ref(), &block(1024, 4));
+
+        // Even if they end in what looks like a footer, because it doesn't
+        // have the right checksum.
+        let mut fake = block(1024, 0);
+        fake.as_mut_slice()[1016..].copy_from_slice(&CHECKSUM_MAGIC);
+        plain.write(&"fake".into(), fake.clone()).unwrap();
+        let reader = backend.open(&"fake".into()).unwrap();
+        test_read(reader.as_ref(), &fake);
+
+        // Without checksums enabled, the backend doesn't look for them.
+        let reader = plain.open(&"file".into()).unwrap();
+        assert_eq!(reader.get_size().unwrap(), size);
+    }
+}
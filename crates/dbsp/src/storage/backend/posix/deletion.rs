@@ -0,0 +1,191 @@
+//! Background deletion for [PosixBackend].
+//!
+//! With [PosixBackend::with_background_deletion], a [DeletionQueue] thread
+//! unlinks the files of dropped readers and writers, so that the thread that
+//! drops them doesn't wait for the filesystem.
+
+use super::{is_last_link, subtract_usage, PosixBackend};
+use crate::circuit::metrics::{
+    BYTES_DELETED, FILES_DELETED, FILES_DELETE_FAILED, FILES_DELETE_FAILED_BYTES,
+};
+use crate::storage::backend::StorageError;
+use feldera_storage::StoragePath;
+use metrics::counter;
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::AtomicI64,
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+use tracing::warn;
+
+/// A file that a [DeleteOnDrop] is deleting.
+///
+/// [DeleteOnDrop]: super::DeleteOnDrop
+pub(super) struct Deletion {
+    pub(super) path: PathBuf,
+
+    /// The file's name in storage, for log messages.
+    pub(super) name: StoragePath,
+
+    /// The file's length.
+    pub(super) size: u64,
+
+    /// If the file counts toward usage, the usage to release, the number of
+    /// bytes to release from it, and whether usage is strict.
+    pub(super) usage: Option<(Arc<AtomicI64>, u64, bool)>,
+}
+
+impl Deletion {
+    /// Deletes the file, logging and counting failures, and releases its
+    /// usage unless other hard links keep its data alive.
+    pub(super) fn run(self) {
+        let last_link = is_last_link(&self.path);
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "Unable to delete file {} ({}): {e}",
+                self.name,
+                self.path.display()
+            );
+            counter!(FILES_DELETE_FAILED).increment(1);
+            counter!(FILES_DELETE_FAILED_BYTES).increment(self.size);
+        } else {
+            if last_link {
+                if let Some((usage, usage_size, strict_usage)) = &self.usage {
+                    subtract_usage(usage, *usage_size, *strict_usage);
+                }
+                counter!(BYTES_DELETED).increment(self.size);
+            }
+            counter!(FILES_DELETED).increment(1);
+        }
+    }
+}
+
+/// A thread that deletes files dropped by a [PosixBackend]'s readers and
+/// writers, so that the thread dropping them doesn't wait for the unlink.
+/// See [PosixBackend::with_background_deletion].
+pub(super) struct DeletionQueue {
+    /// Sends files to the thread, until [Self::shutdown].
+    sender: Mutex<Option<Sender<Deletion>>>,
+
+    /// The thread, until [Self::shutdown] joins it.
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl DeletionQueue {
+    /// Starts the deletion thread.
+    pub(super) fn new() -> Result<Self, StorageError> {
+        let (sender, receiver) = channel::<Deletion>();
+        let worker = std::thread::Builder::new()
+            .name(String::from("dbsp-storage-delete"))
+            .spawn(move || {
+                for deletion in receiver {
+                    deletion.run();
+                }
+            })?;
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            worker: Mutex::new(Some(worker)),
+        })
+    }
+
+    /// Queues `deletion` for the thread, or runs it right away if the thread
+    /// has shut down.
+    pub(super) fn push(&self, deletion: Deletion) {
+        let deletion = match &*self.sender.lock().unwrap() {
+            Some(sender) => match sender.send(deletion) {
+                Ok(()) => return,
+                Err(error) => error.0,
+            },
+            None => deletion,
+        };
+        deletion.run();
+    }
+
+    /// Waits for the thread to delete everything queued so far, then stops
+    /// it.  Files dropped afterward are deleted right away.
+    pub(super) fn shutdown(&self) {
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            if worker.join().is_err() {
+                warn!("storage deletion thread panicked");
+            }
+        }
+    }
+}
+
+impl PosixBackend {
+    /// Returns this backend, modified so that, if `background_deletion` is
+    /// true, temporary files whose readers and writers are dropped are
+    /// deleted by a dedicated thread instead of by the thread that dropped
+    /// them, which then doesn't have to wait for the filesystem.  Their usage
+    /// is released once the thread deletes them.  Dropping the backend waits
+    /// for the thread to delete what it has been sent, and files dropped
+    /// after that are deleted right away.  See
+    /// [StorageConfig::background_deletion].
+    ///
+    /// [StorageConfig::background_deletion]: feldera_types::config::StorageConfig::background_deletion
+    pub fn with_background_deletion(
+        mut self,
+        background_deletion: bool,
+    ) -> Result<Self, StorageError> {
+        if let Some(queue) = self.deletion_queue.take() {
+            queue.shutdown();
+        }
+        if background_deletion {
+            self.deletion_queue = Some(Arc::new(DeletionQueue::new()?));
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{backend::posix::PosixBackend, buffer_cache::FBuf};
+    use feldera_storage::StorageBackend;
+    use feldera_types::config::StorageCacheConfig;
+    use std::time::Duration;
+
+    /// With background deletion, dropped temporary files are deleted by the
+    /// deletion thread, and dropping the backend waits for it to finish.
+    #[test]
+    fn background_deletion() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_strict_usage(true)
+            .with_background_deletion(true)
+            .unwrap();
+        let usage = backend.usage();
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 9);
+        let readers = (0..10)
+            .map(|i| {
+                let mut writer = backend.create_named(&format!("file{i}").into()).unwrap();
+                writer.write_block(block.clone()).unwrap();
+                writer.complete().unwrap().0
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(usage.load(std::sync::atomic::Ordering::Relaxed), 40960);
+
+        drop(readers);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while usage.load(std::sync::atomic::Ordering::Relaxed) != 0 {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(tmpdir.path().read_dir().unwrap().next().is_none());
+
+        // Dropping the backend drains what is still queued.
+        let mut writer = backend.create_named(&"last".into()).unwrap();
+        writer.write_block(block).unwrap();
+        let reader = writer.complete().unwrap().0;
+        drop(reader);
+        drop(backend);
+        assert!(tmpdir.path().read_dir().unwrap().next().is_none());
+        assert_eq!(usage.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+}
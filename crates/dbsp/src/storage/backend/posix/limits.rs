@@ -0,0 +1,521 @@
+//! Limits on a [PosixBackend]'s writers and open files.
+
+use super::PosixBackend;
+use crate::circuit::metrics::{FLUSHES_ACTIVE, FLUSH_WAIT_LATENCY, WRITE_BUFFER_BYTES};
+use crate::storage::backend::StorageError;
+use metrics::{gauge, histogram};
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc, Condvar, Mutex},
+    thread::ThreadId,
+    time::{Duration, Instant},
+};
+
+/// Limits the number of concurrent flushes across all of a backend's writers,
+/// to bound the depth of the device queue.
+pub(super) struct FlushLimiter {
+    /// Maximum number of concurrent flushes.
+    limit: usize,
+
+    /// Number of flushes in progress.
+    active: Mutex<usize>,
+
+    /// Signaled when a flush completes.
+    cond: Condvar,
+}
+
+impl FlushLimiter {
+    pub(super) fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit: limit.unwrap_or(usize::MAX).max(1),
+            active: Mutex::new(0),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer than the maximum number of flushes are in progress,
+    /// then returns a permit that allows a flush to proceed until it is
+    /// dropped.
+    pub(super) fn acquire(self: &Arc<Self>) -> FlushPermit {
+        let wait_start = Instant::now();
+        let mut active = self
+            .cond
+            .wait_while(self.active.lock().unwrap(), |active| *active >= self.limit)
+            .unwrap();
+        *active += 1;
+        gauge!(FLUSHES_ACTIVE).set(*active as f64);
+        histogram!(FLUSH_WAIT_LATENCY).record(wait_start.elapsed().as_secs_f64());
+        FlushPermit(self.clone())
+    }
+}
+
+/// Permission to flush, obtained from [FlushLimiter::acquire].
+pub(super) struct FlushPermit(Arc<FlushLimiter>);
+
+impl Drop for FlushPermit {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().unwrap();
+        *active -= 1;
+        gauge!(FLUSHES_ACTIVE).set(*active as f64);
+        self.0.cond.notify_one();
+    }
+}
+
+/// How long [WriteBufferLimiter::acquire] waits for writers on other threads
+/// to release buffer space before it gives up and exceeds the limit.
+const WRITE_BUFFER_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Limits the total number of bytes that all of a backend's writers hold in
+/// their buffers, waiting to be flushed.
+///
+/// The limit is soft.  A writer can only wait for writers on other threads
+/// to release space, because a thread's other writers can't flush while it
+/// waits, and even those might never write or complete again.  Thus, a
+/// writer that doesn't fit waits only if space held by other threads could
+/// make it fit, and then only for [WRITE_BUFFER_WAIT_TIMEOUT], and otherwise
+/// exceeds the limit.
+pub(super) struct WriteBufferLimiter {
+    /// Maximum number of buffered bytes.
+    limit: usize,
+
+    /// Bytes buffered.
+    buffered: Mutex<BufferedBytes>,
+
+    /// Signaled when buffered bytes are released.
+    cond: Condvar,
+}
+
+/// Bytes buffered by a [WriteBufferLimiter]'s writers.
+#[derive(Default)]
+struct BufferedBytes {
+    /// Total number of bytes buffered.
+    total: usize,
+
+    /// Number of bytes buffered by writers on each thread, omitting threads
+    /// with none.
+    by_thread: HashMap<ThreadId, usize>,
+}
+
+impl BufferedBytes {
+    fn add(&mut self, thread: ThreadId, n: usize) {
+        self.total += n;
+        *self.by_thread.entry(thread).or_default() += n;
+        gauge!(WRITE_BUFFER_BYTES).set(self.total as f64);
+    }
+
+    fn subtract(&mut self, thread: ThreadId, n: usize) {
+        self.total -= n;
+        let held = self.by_thread.get_mut(&thread).unwrap();
+        *held -= n;
+        if *held == 0 {
+            self.by_thread.remove(&thread);
+        }
+        gauge!(WRITE_BUFFER_BYTES).set(self.total as f64);
+    }
+
+    /// Returns the number of bytes buffered by writers on `thread`.
+    fn held_by(&self, thread: ThreadId) -> usize {
+        self.by_thread.get(&thread).copied().unwrap_or(0)
+    }
+}
+
+impl WriteBufferLimiter {
+    pub(super) fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit: limit.unwrap_or(usize::MAX),
+            buffered: Mutex::new(BufferedBytes::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Returns true if `n` more bytes fit alongside `buffered`.  A block
+    /// bigger than the limit fits when nothing else is buffered, so that it
+    /// can still be written.
+    fn fits(&self, buffered: usize, n: usize) -> bool {
+        buffered == 0 || buffered.saturating_add(n) <= self.limit
+    }
+
+    /// Adds `n` bytes buffered by `thread` to the total, if they fit, and
+    /// returns whether they did.
+    fn try_acquire(&self, thread: ThreadId, n: usize) -> bool {
+        let mut buffered = self.buffered.lock().unwrap();
+        let fits = self.fits(buffered.total, n);
+        if fits {
+            buffered.add(thread, n);
+        }
+        fits
+    }
+
+    /// Adds `n` bytes buffered by `thread` to the total, first waiting for
+    /// them to fit as described in the [type documentation](Self).
+    fn acquire(&self, thread: ThreadId, n: usize) {
+        let deadline = Instant::now() + WRITE_BUFFER_WAIT_TIMEOUT;
+        let mut buffered = self.buffered.lock().unwrap();
+        while !self.fits(buffered.total, n) && self.fits(buffered.held_by(thread), n) {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            buffered = self.cond.wait_timeout(buffered, timeout).unwrap().0;
+        }
+        buffered.add(thread, n);
+    }
+
+    /// Subtracts `n` bytes buffered by `thread` from the total.
+    fn release(&self, thread: ThreadId, n: usize) {
+        if n > 0 {
+            self.buffered.lock().unwrap().subtract(thread, n);
+            self.cond.notify_all();
+        }
+    }
+
+    /// Moves `n` buffered bytes from thread `from` to thread `to`.
+    fn transfer(&self, from: ThreadId, to: ThreadId, n: usize) {
+        if n > 0 {
+            let mut buffered = self.buffered.lock().unwrap();
+            buffered.subtract(from, n);
+            buffered.add(to, n);
+            self.cond.notify_all();
+        }
+    }
+}
+
+/// A writer's share of a [WriteBufferLimiter], released when dropped.
+pub(super) struct WriteBufferPermit {
+    limiter: Arc<WriteBufferLimiter>,
+
+    /// Number of bytes held.
+    bytes: usize,
+
+    /// The thread that the bytes count against, which is the thread that
+    /// last buffered bytes through this permit.
+    thread: ThreadId,
+}
+
+impl WriteBufferPermit {
+    pub(super) fn new(limiter: Arc<WriteBufferLimiter>) -> Self {
+        Self {
+            limiter,
+            bytes: 0,
+            thread: std::thread::current().id(),
+        }
+    }
+
+    /// Counts the bytes held against the current thread and returns it.
+    fn current_thread(&mut self) -> ThreadId {
+        let thread = std::thread::current().id();
+        if thread != self.thread {
+            self.limiter.transfer(self.thread, thread, self.bytes);
+            self.thread = thread;
+        }
+        thread
+    }
+
+    /// Adds `n` bytes to those held, if they fit, and returns whether they
+    /// did.
+    pub(super) fn try_acquire(&mut self, n: usize) -> bool {
+        let thread = self.current_thread();
+        let fits = self.limiter.try_acquire(thread, n);
+        if fits {
+            self.bytes += n;
+        }
+        fits
+    }
+
+    /// Adds `n` bytes to those held, waiting for space if necessary, as
+    /// described for [WriteBufferLimiter].
+    pub(super) fn acquire(&mut self, n: usize) {
+        let thread = self.current_thread();
+        self.limiter.acquire(thread, n);
+        self.bytes += n;
+    }
+
+    /// Releases all of the bytes held.
+    pub(super) fn release(&mut self) {
+        self.limiter
+            .release(self.thread, std::mem::take(&mut self.bytes));
+    }
+
+    /// Releases `bytes` of the bytes held.
+    pub(super) fn shrink(&mut self, bytes: usize) {
+        self.bytes -= bytes;
+        self.limiter.release(self.thread, bytes);
+    }
+}
+
+impl Drop for WriteBufferPermit {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl PosixBackend {
+    /// Returns this backend, modified to allow at most `max_concurrent_flushes`
+    /// writers to flush data at the same time.  Additional writers wait for a
+    /// flush to complete before starting their own.  `None`, the default,
+    /// removes the limit.
+    pub fn with_max_concurrent_flushes(mut self, max_concurrent_flushes: Option<usize>) -> Self {
+        self.flush_limiter = Arc::new(FlushLimiter::new(max_concurrent_flushes));
+        self
+    }
+
+    /// Returns this backend, modified so that each of its writers flushes
+    /// before buffering more than `max_writer_buffer` bytes (if it is
+    /// `Some`), even if adaptive flushing has raised the flush threshold
+    /// higher.  See [StorageConfig::max_writer_buffer_bytes].
+    ///
+    /// [StorageConfig::max_writer_buffer_bytes]: feldera_types::config::StorageConfig::max_writer_buffer_bytes
+    pub fn with_max_writer_buffer(mut self, max_writer_buffer: Option<usize>) -> Self {
+        self.max_writer_buffer = max_writer_buffer;
+        self
+    }
+
+    /// Returns this backend, modified so that all of its writers together
+    /// buffer at most `max_total_write_buffer` bytes (if it is `Some`).  A
+    /// writer that would exceed the limit flushes its own buffers and then
+    /// waits, for up to [WRITE_BUFFER_WAIT_TIMEOUT], for writers on other
+    /// threads to flush theirs.  If that doesn't make room, or only writers
+    /// on its own thread hold the space, it exceeds the limit instead.  See
+    /// [StorageConfig::max_total_write_buffer_bytes].
+    ///
+    /// [StorageConfig::max_total_write_buffer_bytes]: feldera_types::config::StorageConfig::max_total_write_buffer_bytes
+    pub fn with_max_total_write_buffer(mut self, max_total_write_buffer: Option<usize>) -> Self {
+        self.write_buffer_limiter = Arc::new(WriteBufferLimiter::new(max_total_write_buffer));
+        self
+    }
+
+    /// Returns this backend, modified so that opening or creating a file
+    /// fails with [StorageError::TooManyOpenFiles] while its readers and
+    /// writers already hold `max_open_files` files open (if it is `Some`).
+    /// The limit is soft: concurrent opens can exceed it slightly.  See
+    /// [StorageConfig::max_open_files].
+    ///
+    /// [StorageConfig::max_open_files]: feldera_types::config::StorageConfig::max_open_files
+    pub fn with_max_open_files(mut self, max_open_files: Option<usize>) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Returns the number of files that this backend's readers and writers
+    /// currently hold open.
+    pub fn open_file_count(&self) -> usize {
+        self.open_files.load(Ordering::Relaxed)
+    }
+
+    /// Fails with [StorageError::TooManyOpenFiles] if opening another file
+    /// would exceed the limit on open files.
+    pub(super) fn check_open_files(&self) -> Result<(), StorageError> {
+        let open = self.open_file_count();
+        match self.max_open_files {
+            Some(limit) if open >= limit => Err(StorageError::TooManyOpenFiles { open }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WRITE_BUFFER_WAIT_TIMEOUT;
+    use crate::circuit::metrics::WRITE_BUFFER_BYTES;
+    use crate::storage::{
+        backend::{posix::PosixBackend, tests::test_read, StorageError},
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::StorageBackend;
+    use feldera_types::config::StorageCacheConfig;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    /// Tests that [PosixBackend::with_max_writer_buffer] makes a writer flush
+    /// before it buffers more than the maximum, and that the buffered bytes
+    /// show up in [WRITE_BUFFER_BYTES].
+    #[test]
+    fn max_writer_buffer() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_max_writer_buffer(Some(8192));
+        let block = || {
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, 1);
+            block
+        };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        let on_disk = || {
+            std::fs::metadata(tmpdir.path().join("file.mut"))
+                .unwrap()
+                .len()
+        };
+        metrics::with_local_recorder(&recorder, || {
+            writer.write_block(block()).unwrap();
+            writer.write_block(block()).unwrap();
+            assert_eq!(on_disk(), 0);
+
+            // The third block would take the buffer past the maximum.
+            writer.write_block(block()).unwrap();
+            assert_eq!(on_disk(), 8192);
+        });
+        let gauge = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .find_map(|(key, _, _, value)| match value {
+                DebugValue::Gauge(value) if key.key().name() == WRITE_BUFFER_BYTES => Some(value.0),
+                _ => None,
+            });
+        assert_eq!(gauge, Some(4096.0));
+
+        let (reader, _name) = writer.complete().unwrap();
+        test_read(reader.as_ref(), &[1; 3 * 4096]);
+    }
+
+    /// Tests that [PosixBackend::with_max_total_write_buffer] makes a writer
+    /// wait for space that another writer holds.
+    #[test]
+    fn max_total_write_buffer() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+                .with_max_total_write_buffer(Some(8192)),
+        );
+        let block = |size| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, 2);
+            block
+        };
+
+        let mut first = backend.create_named(&"first".into()).unwrap();
+        first.write_block(block(8192)).unwrap();
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let second = std::thread::spawn({
+            let backend = backend.clone();
+            let done = done.clone();
+            move || {
+                let mut second = backend.create_named(&"second".into()).unwrap();
+                second.write_block(block(4096)).unwrap();
+                done.store(true, std::sync::atomic::Ordering::Relaxed);
+                second.complete().unwrap()
+            }
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!done.load(std::sync::atomic::Ordering::Relaxed));
+
+        // Completing the first writer flushes its buffer, which lets the
+        // second writer proceed.
+        let (first, _name) = first.complete().unwrap();
+        let (second, _name) = second.join().unwrap();
+        test_read(first.as_ref(), &[2; 8192]);
+        test_read(second.as_ref(), &[2; 4096]);
+    }
+
+    /// Tests that [PosixBackend::with_max_total_write_buffer] doesn't make a
+    /// writer wait for space that writers on its own thread hold, which they
+    /// couldn't release while it waits.
+    #[test]
+    fn max_total_write_buffer_one_thread() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_max_total_write_buffer(Some(8192));
+        let block = |size, value| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, value);
+            block
+        };
+
+        let start = Instant::now();
+        let mut first = backend.create_named(&"first".into()).unwrap();
+        let mut second = backend.create_named(&"second".into()).unwrap();
+        for _ in 0..4 {
+            first.write_block(block(4096, 1)).unwrap();
+            second.write_block(block(4096, 2)).unwrap();
+        }
+        let (first, _name) = first.complete().unwrap();
+        let (second, _name) = second.complete().unwrap();
+        assert!(start.elapsed() < WRITE_BUFFER_WAIT_TIMEOUT);
+        test_read(first.as_ref(), &[1; 4 * 4096]);
+        test_read(second.as_ref(), &[2; 4 * 4096]);
+    }
+
+    /// Tests that [PosixBackend::with_max_total_write_buffer] stops waiting
+    /// for space that another thread holds after a while, since the writer
+    /// that holds it might never write or complete again.
+    #[test]
+    fn max_total_write_buffer_timeout() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+                .with_max_total_write_buffer(Some(8192)),
+        );
+        let block = |size| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, 2);
+            block
+        };
+
+        // The space that this writer holds counts against a thread that has
+        // exited.
+        let mut idle = std::thread::spawn({
+            let backend = backend.clone();
+            move || {
+                let mut idle = backend.create_named(&"idle".into()).unwrap();
+                idle.write_block(block(8192)).unwrap();
+                idle
+            }
+        })
+        .join()
+        .unwrap();
+
+        let start = Instant::now();
+        let mut writer = backend.create_named(&"writer".into()).unwrap();
+        writer.write_block(block(4096)).unwrap();
+        assert!(start.elapsed() >= WRITE_BUFFER_WAIT_TIMEOUT);
+
+        // Writing to the idle writer on this thread moves the space that it
+        // holds to this thread.
+        idle.write_block(block(4096)).unwrap();
+        writer.write_block(block(4096)).unwrap();
+
+        let (idle, _name) = idle.complete().unwrap();
+        let (writer, _name) = writer.complete().unwrap();
+        test_read(idle.as_ref(), &[2; 3 * 4096]);
+        test_read(writer.as_ref(), &[2; 2 * 4096]);
+    }
+
+    /// The open file count follows readers and writers as they are created,
+    /// completed, and dropped, and the limit rejects opens beyond it.
+    #[test]
+    fn open_file_count() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_max_open_files(Some(2));
+        backend.write(&"a".into(), FBuf::new()).unwrap();
+        assert_eq!(backend.open_file_count(), 0);
+
+        for _ in 0..3 {
+            let writer = backend.create_named(&"b".into()).unwrap();
+            assert_eq!(backend.open_file_count(), 1);
+            let (completed, _name) = writer.complete().unwrap();
+            assert_eq!(backend.open_file_count(), 1);
+            let opened = backend.open(&"a".into()).unwrap();
+            assert_eq!(backend.open_file_count(), 2);
+
+            assert!(matches!(
+                backend.open(&"a".into()),
+                Err(StorageError::TooManyOpenFiles { open: 2 })
+            ));
+            assert!(matches!(
+                backend.create_named(&"c".into()),
+                Err(StorageError::TooManyOpenFiles { open: 2 })
+            ));
+            drop(completed);
+            assert_eq!(backend.open_file_count(), 1);
+            drop(opened);
+            assert_eq!(backend.open_file_count(), 0);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Reading files that a [PosixBackend] is still writing.
+//!
+//! A writer registers its file in the backend's [LiveFiles] until it is
+//! dropped, so that opening the file by name before it is completed yields a
+//! reader limited to what the writer has flushed.
+//!
+//! [PosixBackend]: super::PosixBackend
+
+use feldera_storage::StoragePath;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+};
+
+/// The files that a [PosixBackend]'s writers are writing, by name, so that
+/// [StorageBackend::open] can read a file before it is completed.
+///
+/// [PosixBackend]: super::PosixBackend
+/// [StorageBackend::open]: feldera_storage::StorageBackend::open
+#[derive(Default)]
+pub(super) struct LiveFiles(Mutex<HashMap<StoragePath, Arc<LiveFile>>>);
+
+impl LiveFiles {
+    pub(super) fn get(&self, name: &StoragePath) -> Option<Arc<LiveFile>> {
+        self.0.lock().unwrap().get(name).cloned()
+    }
+}
+
+/// A file being written, shared between its writer and the readers opened on
+/// it before it was completed.
+pub(super) struct LiveFile {
+    /// The file's path while it is being written.
+    pub(super) path: PathBuf,
+
+    /// Number of bytes at the start of the file that its writer has flushed
+    /// and won't change, which are the bytes that readers may read.
+    pub(super) committed: AtomicU64,
+
+    /// Whether the writer completed the file, after which `committed` is the
+    /// file's final length.
+    pub(super) finished: AtomicBool,
+}
+
+/// A [LiveFile], listed in a [LiveFiles] until dropped.
+pub(super) struct LiveRegistration {
+    name: StoragePath,
+    pub(super) file: Arc<LiveFile>,
+    registry: Arc<LiveFiles>,
+}
+
+impl LiveRegistration {
+    pub(super) fn new(registry: &Arc<LiveFiles>, name: StoragePath, path: PathBuf) -> Self {
+        let file = Arc::new(LiveFile {
+            path,
+            committed: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        });
+        registry
+            .0
+            .lock()
+            .unwrap()
+            .insert(name.clone(), file.clone());
+        Self {
+            name,
+            file,
+            registry: registry.clone(),
+        }
+    }
+}
+
+impl Drop for LiveRegistration {
+    fn drop(&mut self) {
+        let mut files = self.registry.0.lock().unwrap();
+        // A newer writer for the same name may have replaced us.
+        if files
+            .get(&self.name)
+            .is_some_and(|file| Arc::ptr_eq(file, &self.file))
+        {
+            files.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{posix::PosixBackend, BlockLocation, StorageError},
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::{StorageBackend, StoragePath};
+    use feldera_types::config::StorageCacheConfig;
+
+    /// A reader opened while a file is being written can read what has been
+    /// flushed, but nothing beyond it, until the file is completed.
+    #[test]
+    fn read_while_writing() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        // Each block flushes the one before it.
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_flush_threshold(4096);
+        let name = StoragePath::from("live");
+        let block = |value| {
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, value);
+            block
+        };
+
+        assert!(matches!(
+            backend.open(&name),
+            Err(StorageError::NotFound(_))
+        ));
+        let mut writer = backend.create_named(&name).unwrap();
+        writer.write_block(block(1)).unwrap();
+        let reader = backend.open(&name).unwrap();
+        assert_eq!(reader.get_size().unwrap(), 0);
+        let first = BlockLocation::new(0, 4096).unwrap();
+        let second = BlockLocation::new(4096, 4096).unwrap();
+        assert!(matches!(
+            reader.read_block(first),
+            Err(StorageError::NotYetWritten {
+                end: 4096,
+                committed: 0
+            })
+        ));
+
+        writer.write_block(block(2)).unwrap();
+        std::thread::scope(|scope| {
+            let reader = reader.clone();
+            scope
+                .spawn(move || {
+                    assert_eq!(reader.get_size().unwrap(), 4096);
+                    assert_eq!(&reader.read_block(first).unwrap()[..], &block(1)[..]);
+                    assert!(matches!(
+                        reader.read_block(second),
+                        Err(StorageError::NotYetWritten { .. })
+                    ));
+                })
+                .join()
+                .unwrap();
+        });
+
+        let (_completed, _name) = writer.complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), 8192);
+        assert_eq!(&reader.read_block(second).unwrap()[..], &block(2)[..]);
+        assert!(matches!(
+            reader.read_block(BlockLocation::new(8192, 4096).unwrap()),
+            Err(StorageError::StdIo(std::io::ErrorKind::UnexpectedEof))
+        ));
+    }
+}
@@ -0,0 +1,148 @@
+//! Memory-mapped reads for [PosixBackend].
+//!
+//! With [PosixBackend::with_mmap_threshold], readers map small files into
+//! memory with a [Mapping] and copy blocks out of it.
+
+use super::PosixBackend;
+use crate::storage::backend::{BlockLocation, StorageError};
+use std::{
+    fs::File,
+    io::{Error as IoError, ErrorKind},
+};
+use tracing::warn;
+
+/// A read-only memory mapping of the whole of a file.
+pub(super) struct Mapping {
+    ptr: *const u8,
+    pub(super) len: usize,
+}
+
+// SAFETY: The mapping is read-only, so sharing it between threads is like
+// sharing a `&[u8]`.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the first `len` bytes of `file`.  `len` must not be zero.
+    pub(super) fn new(file: &File, len: usize) -> Result<Self, IoError> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: This creates a new mapping, at an address that the kernel
+        // chooses, so it can't overlap any memory that we already use.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(IoError::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *const u8,
+            len,
+        })
+    }
+
+    pub(super) fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` readable bytes for as long as the
+        // mapping exists.  We only map files that aren't being appended to.
+        // A writer that later appends to the file only changes it past
+        // `len`, and aborting that writer only truncates the file back to
+        // the length it had when the writer opened it, which is at least
+        // `len`.  So the contents don't change underneath us, and every page
+        // stays backed by the file.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Returns the data at `location`, or an error if it extends past the
+    /// end of the mapping.
+    pub(super) fn get(&self, location: BlockLocation) -> Result<&[u8], StorageError> {
+        usize::try_from(location.after())
+            .ok()
+            .and_then(|after| self.as_slice().get(location.offset as usize..after))
+            .ok_or(StorageError::StdIo(ErrorKind::UnexpectedEof))
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: We're unmapping exactly what we mapped, and `as_slice`
+        // borrows `self`, so no references into the mapping remain.
+        if unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) } != 0 {
+            warn!("munmap failed: {}", IoError::last_os_error());
+        }
+    }
+}
+
+impl PosixBackend {
+    /// Returns this backend, modified so that [StorageBackend::open] maps
+    /// files no bigger than `mmap_threshold` bytes (if it is `Some`) into
+    /// memory, so that reading a block from one copies it out of the mapping
+    /// instead of making a system call.  See
+    /// [StorageConfig::mmap_threshold_bytes].
+    ///
+    /// Blocks are still copied, because an [FBuf] always owns its memory.
+    ///
+    /// [StorageBackend::open]: feldera_storage::StorageBackend::open
+    /// [StorageConfig::mmap_threshold_bytes]: feldera_types::config::StorageConfig::mmap_threshold_bytes
+    /// [FBuf]: crate::storage::buffer_cache::FBuf
+    pub fn with_mmap_threshold(mut self, mmap_threshold: Option<u64>) -> Self {
+        self.mmap_threshold = mmap_threshold;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{posix::PosixBackend, tests::test_read, BlockLocation},
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::StorageBackend;
+    use feldera_types::config::StorageCacheConfig;
+
+    /// Tests that [PosixBackend::with_mmap_threshold] maps small files, and
+    /// only small files, and that reads from a mapping are bounds-checked.
+    #[test]
+    fn mmap_reader() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_mmap_threshold(Some(8192));
+        let data = (0..16384).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut expected = Vec::new();
+        for (name, size) in [("small", 4096), ("large", 16384)] {
+            let mut block = FBuf::with_capacity(size);
+            block.extend_from_slice(&data[..size]);
+            backend.write(&name.into(), block).unwrap();
+            expected.push((name, &data[..size]));
+        }
+
+        let readers = expected
+            .iter()
+            .map(|(name, _)| backend.open(&(*name).into()).unwrap())
+            .collect::<Vec<_>>();
+        #[cfg(target_os = "linux")]
+        {
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+            let base = tmpdir.path().canonicalize().unwrap();
+            let mapped = |name: &str| maps.contains(base.join(name).to_str().unwrap());
+            assert!(mapped("small"));
+            assert!(!mapped("large"));
+        }
+        for (reader, (_name, data)) in readers.iter().zip(&expected) {
+            test_read(reader.as_ref(), data);
+            let past_end = BlockLocation::new(data.len() as u64, 512).unwrap();
+            assert_eq!(
+                reader.read_block(past_end).unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof
+            );
+            let straddling = BlockLocation::new(data.len() as u64 - 512, 1024).unwrap();
+            assert!(reader.read_block(straddling).is_err());
+        }
+    }
+}
@@ -0,0 +1,7100 @@
+//! [StorageBackend] implementation using POSIX I/O.
+
+use super::{
+    block_cache::BlockCache, compressed::CompressedBackend, path_in, read_pool::ReadBufferPool,
+    release_usage, BlockHandle, BlockLocation, BlockRef, FileId, FileReader, FileWriter, HasFileId,
+    ReadAllocation, StorageError, StorageFlags, IOV_MAX, MUTABLE_EXTENSION,
+};
+use crate::circuit::metrics::{
+    BLOCK_CACHE_HIT, BLOCK_CACHE_MISS, BYTES_DELETED, COMPLETE_LATENCY, FILES_COMPLETED,
+    FILES_CREATED, FILES_DELETED, FLUSH_LATENCY, OPEN_FILES, PREFETCH_BYTES, PREFETCH_HIT_BYTES,
+    READS_FAILED, READS_SUCCESS, READ_COALESCE_WASTED_BYTES, READ_LATENCY, RENAME_LATENCY,
+    STORAGE_USAGE_BYTES, SYNC_LATENCY, TOTAL_BYTES_READ, TOTAL_BYTES_WRITTEN, WRITES_SUCCESS,
+    WRITE_LATENCY,
+};
+use crate::storage::{buffer_cache::FBuf, init};
+use feldera_storage::asynchronous::AsyncStorageBackend;
+use feldera_storage::clock::{StorageClock, SystemClock};
+use feldera_storage::commit::complete_in_two_phases;
+use feldera_storage::glob::Glob;
+use feldera_storage::{
+    append_to_path, paginate, FileStats, StorageBackend, StorageBackendFactory,
+    StorageCapabilities, StorageFileType, StoragePath, StoragePathPart,
+};
+use feldera_types::config::{
+    AdaptiveFlushConfig, DurabilityMode, OpenRetryConfig, StorageBackendConfig, StorageCacheConfig,
+    StorageConfig, StorageOpenFlags, UsagePolicy,
+};
+use metrics::{counter, gauge, histogram};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, DirEntry, Metadata};
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Seek, SeekFrom, Write};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Error as IoError,
+    os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, warn};
+
+mod checkpoint;
+mod checksum;
+mod deletion;
+mod limits;
+mod live;
+mod mmap;
+mod trash;
+
+pub use checkpoint::{Checkpoint, CHECKPOINT_MANIFEST};
+use checksum::BlockChecksums;
+use deletion::{Deletion, DeletionQueue};
+use limits::{FlushLimiter, WriteBufferLimiter, WriteBufferPermit};
+use live::{LiveFile, LiveFiles, LiveRegistration};
+use mmap::Mapping;
+use trash::is_trash;
+pub use trash::TRASH_DIRECTORY;
+
+/// Counters behind a file's [FileStats], shared by its writer and then its
+/// reader.  These use relaxed atomics, to keep the read path cheap.
+#[derive(Default)]
+struct FileCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_count: AtomicU64,
+    write_count: AtomicU64,
+    blocks_read: AtomicU64,
+}
+
+impl FileCounters {
+    /// Records a read operation that read `blocks` blocks totaling `bytes`
+    /// bytes.
+    fn record_read(&self, blocks: usize, bytes: u64) {
+        self.read_count.fetch_add(1, Ordering::Relaxed);
+        self.blocks_read.fetch_add(blocks as u64, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records writing a block of `bytes` bytes.
+    fn record_write(&self, bytes: usize) {
+        self.write_count.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> FileStats {
+        FileStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            read_count: self.read_count.load(Ordering::Relaxed),
+            write_count: self.write_count.load(Ordering::Relaxed),
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The files that a [PosixBackend] has open for reading or writing, with
+/// their names and counters, for [PosixBackend::top_files].
+#[derive(Default)]
+struct FileRegistry(Mutex<HashMap<FileId, (StoragePath, Arc<FileCounters>)>>);
+
+/// A file's counters, listed in a [FileRegistry] and counted among the
+/// backend's open files until dropped.
+///
+/// This moves from a file's writer to its reader when the file is completed,
+/// so that the reader's statistics include the writes and the file stays
+/// counted as open once.
+struct RegisteredFile {
+    file_id: FileId,
+    counters: Arc<FileCounters>,
+    registry: Arc<FileRegistry>,
+    open_files: Arc<AtomicUsize>,
+}
+
+impl RegisteredFile {
+    fn new(backend: &PosixBackend, file_id: FileId, name: StoragePath) -> Self {
+        let counters = Arc::new(FileCounters::default());
+        backend
+            .files
+            .0
+            .lock()
+            .unwrap()
+            .insert(file_id, (name, counters.clone()));
+        backend.open_files.fetch_add(1, Ordering::Relaxed);
+        gauge!(OPEN_FILES).increment(1.0);
+        Self {
+            file_id,
+            counters,
+            registry: backend.files.clone(),
+            open_files: backend.open_files.clone(),
+        }
+    }
+}
+
+impl Drop for RegisteredFile {
+    fn drop(&mut self) {
+        self.registry.0.lock().unwrap().remove(&self.file_id);
+        self.open_files.fetch_sub(1, Ordering::Relaxed);
+        gauge!(OPEN_FILES).decrement(1.0);
+    }
+}
+
+/// Readers returned by [StorageBackend::open], by name, so that opening a
+/// file that is already open returns the same reader.  See
+/// [PosixBackend::with_shared_readers].
+#[derive(Default)]
+struct SharedReaders(Mutex<HashMap<StoragePath, Weak<dyn FileReader>>>);
+
+impl SharedReaders {
+    /// Returns the live reader for `name`, if there is one.
+    fn get(&self, name: &StoragePath) -> Option<Arc<dyn FileReader>> {
+        self.0.lock().unwrap().get(name)?.upgrade()
+    }
+
+    /// Records `reader` as the reader for `name`, and forgets readers that
+    /// have been dropped.
+    fn insert(&self, name: &StoragePath, reader: &Arc<dyn FileReader>) {
+        let mut readers = self.0.lock().unwrap();
+        readers.retain(|_name, reader| reader.strong_count() > 0);
+        readers.insert(name.clone(), Arc::downgrade(reader));
+    }
+
+    /// Forgets the readers for `name` and everything under it, because the
+    /// files they have open are no longer the files by those names.
+    fn invalidate(&self, name: &StoragePath) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|reader_name, _reader| !reader_name.prefix_matches(name));
+    }
+}
+
+/// The files that a [PosixBackend]'s writers from
+/// [StorageBackend::open_append] are appending to, by name, with the number
+/// of writers for each.  Aborting such a writer truncates its file, so
+/// [StorageBackend::open] doesn't map files in this set into memory.
+#[derive(Default)]
+struct AppendingFiles(Mutex<HashMap<StoragePath, usize>>);
+
+/// A name listed in [AppendingFiles] until dropped.
+struct AppendRegistration {
+    name: StoragePath,
+    registry: Arc<AppendingFiles>,
+}
+
+impl AppendRegistration {
+    fn new(registry: &Arc<AppendingFiles>, name: StoragePath) -> Self {
+        *registry.0.lock().unwrap().entry(name.clone()).or_default() += 1;
+        Self {
+            name,
+            registry: registry.clone(),
+        }
+    }
+}
+
+impl Drop for AppendRegistration {
+    fn drop(&mut self) {
+        let mut files = self.registry.0.lock().unwrap();
+        if let Some(count) = files.get_mut(&self.name) {
+            *count -= 1;
+            if *count == 0 {
+                files.remove(&self.name);
+            }
+        }
+    }
+}
+
+pub(super) struct PosixReader {
+    file: Arc<File>,
+    file_id: FileId,
+    drop: DeleteOnDrop,
+
+    /// This file's statistics.
+    stats: RegisteredFile,
+    read_allocation: Arc<ReadAllocation>,
+
+    /// Checksums from the file's trailer, if it has one.
+    checksums: Option<BlockChecksums>,
+
+    /// Whether `file` was opened for direct I/O.
+    direct: bool,
+
+    /// A mapping of the whole file, if it was small enough.  See
+    /// [PosixBackend::with_mmap_threshold].
+    mapping: Option<Mapping>,
+
+    /// Locations passed to [FileReader::prefetch] that haven't been read yet,
+    /// oldest first, at most [MAX_PREFETCHED] of them.
+    prefetched: Mutex<VecDeque<BlockLocation>>,
+
+    /// Largest block that a read may request, if any.  See
+    /// [PosixBackend::with_max_block_size].
+    max_block_size: Option<usize>,
+
+    /// Cache of blocks shared with the backend's other readers, if any.  See
+    /// [PosixBackend::with_block_cache].
+    block_cache: Option<Arc<BlockCache>>,
+
+    /// Pool of buffers for reads, if any.  See
+    /// [PosixBackend::with_read_buffer_pool].
+    read_pool: Option<Arc<ReadBufferPool>>,
+
+    /// For a reader opened while the file was still being written, the
+    /// state shared with the writer, which limits what can be read.
+    live: Option<Arc<LiveFile>>,
+}
+
+/// Maximum number of prefetched locations that a [PosixReader] remembers, to
+/// count later reads of them as hits.
+const MAX_PREFETCHED: usize = 64;
+
+impl PosixReader {
+    fn new(
+        file: Arc<File>,
+        file_id: FileId,
+        drop: DeleteOnDrop,
+        stats: RegisteredFile,
+        read_allocation: Arc<ReadAllocation>,
+        checksums: Option<BlockChecksums>,
+    ) -> Self {
+        Self {
+            direct: is_direct(&file),
+            file,
+            file_id,
+            drop,
+            stats,
+            read_allocation,
+            checksums,
+            mapping: None,
+            prefetched: Mutex::new(VecDeque::new()),
+            max_block_size: None,
+            block_cache: None,
+            read_pool: None,
+            live: None,
+        }
+    }
+
+    /// Checks that reading `location` can succeed before we allocate a
+    /// buffer for it: it must be no bigger than the maximum block size and
+    /// lie entirely within the file, and within the part that has been
+    /// written if the file is still being written.
+    fn check_location(&self, location: BlockLocation) -> Result<(), StorageError> {
+        if let Some(max) = self.max_block_size {
+            if location.size > max {
+                return Err(StorageError::BlockTooLarge {
+                    requested: location.size,
+                    max,
+                });
+            }
+        }
+        if let Some(live) = &self.live {
+            let committed = live.committed.load(Ordering::Acquire);
+            if location.after() > committed && !live.finished.load(Ordering::Acquire) {
+                return Err(StorageError::NotYetWritten {
+                    end: location.after(),
+                    committed,
+                });
+            }
+        }
+        if location.after() > self.get_size()? {
+            return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+        }
+        Ok(())
+    }
+
+    /// Returns a [StorageError::ShortRead] for `location`, which came up short
+    /// because the file is shorter than we thought, for example because it
+    /// was truncated by a crash before it was synced.
+    fn short_read(&self, location: BlockLocation) -> StorageError {
+        match self.file.metadata() {
+            Ok(metadata) => StorageError::ShortRead {
+                requested: location.size,
+                available: metadata
+                    .len()
+                    .saturating_sub(location.offset)
+                    .min(location.size as u64) as usize,
+            },
+            Err(error) => error.into(),
+        }
+    }
+
+    /// Counts reading `location` as a prefetch hit if it lies within a
+    /// location that was prefetched, forgetting the latter once it has been
+    /// read through to its end.
+    fn record_prefetch_hit(&self, location: BlockLocation) {
+        let mut prefetched = self.prefetched.lock().unwrap();
+        if let Some(index) = prefetched.iter().position(|prefetched| {
+            prefetched.offset <= location.offset && location.after() <= prefetched.after()
+        }) {
+            counter!(PREFETCH_HIT_BYTES).increment(location.size as u64);
+            if location.after() == prefetched[index].after() {
+                prefetched.remove(index);
+            }
+        }
+    }
+
+    fn open(
+        path: PathBuf,
+        name: &StoragePath,
+        backend: &PosixBackend,
+    ) -> Result<Arc<dyn FileReader>, StorageError> {
+        backend.check_open_files()?;
+        let file = backend.open_file(OpenOptions::new().read(true), &path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.size();
+        // Only look for a trailer if we write them, to avoid an extra read
+        // per open otherwise.
+        let checksums = if backend.block_checksums {
+            BlockChecksums::read(&file, size)?
+        } else {
+            None
+        };
+        let mapping = if size > 0 && backend.mmap_threshold.is_some_and(|limit| size <= limit) {
+            // Holding the lock keeps a writer from starting to append to the
+            // file, and so from truncating it, while we check its size.
+            let appending = backend.appending_files.0.lock().unwrap();
+            if appending.contains_key(name) {
+                None
+            } else {
+                // The file may have shrunk since we first checked its size,
+                // if an appending writer aborted in between.
+                let len = size.min(file.metadata()?.size());
+                if len > 0 {
+                    Mapping::new(&file, len as usize)
+                        .inspect_err(|error| debug!("{}: unable to mmap: {error}", path.display()))
+                        .ok()
+                } else {
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let file_id = FileId::new();
+        let mut reader = Self::new(
+            Arc::new(file),
+            file_id,
+            DeleteOnDrop::new(path, name.clone(), true, size, true, backend)
+                .with_usage_size(backend.usage_size(&metadata)),
+            RegisteredFile::new(backend, file_id, name.clone()),
+            backend.read_allocation.clone(),
+            checksums,
+        );
+        reader.mapping = mapping;
+        reader.max_block_size = backend.max_block_size;
+        reader.block_cache = backend.block_cache.clone();
+        reader.read_pool = backend.read_pool.clone();
+        Ok(Arc::new(reader))
+    }
+
+    /// Opens `live`, a file that is still being written, so that its
+    /// committed data can be read while its writer continues.
+    fn open_live(
+        live: Arc<LiveFile>,
+        name: &StoragePath,
+        backend: &PosixBackend,
+    ) -> Result<Arc<dyn FileReader>, StorageError> {
+        backend.check_open_files()?;
+        let file = backend.open_file(OpenOptions::new().read(true), &live.path)?;
+        let file_id = FileId::new();
+        let mut reader = Self::new(
+            Arc::new(file),
+            file_id,
+            // The writer owns the file and its usage.
+            DeleteOnDrop::new(live.path.clone(), name.clone(), true, 0, false, backend),
+            RegisteredFile::new(backend, file_id, name.clone()),
+            backend.read_allocation.clone(),
+            None,
+        );
+        reader.max_block_size = backend.max_block_size;
+        reader.block_cache = backend.block_cache.clone();
+        reader.read_pool = backend.read_pool.clone();
+        reader.live = Some(live);
+        Ok(Arc::new(reader))
+    }
+
+    /// Implements [FileReader::read_scattered] for direct I/O with buffers or
+    /// an offset that direct I/O can't use, by reading the aligned range that
+    /// covers the request into an aligned buffer and copying out of it.
+    fn read_scattered_bounced(
+        &self,
+        offset: u64,
+        bufs: &mut [&mut [u8]],
+    ) -> Result<usize, StorageError> {
+        let requested = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        let total = requested.min(self.get_size()?.saturating_sub(offset));
+        if total == 0 {
+            return Ok(0);
+        }
+        let start = offset / FBuf::ALIGNMENT as u64 * FBuf::ALIGNMENT as u64;
+        let end = (offset + total).next_multiple_of(FBuf::ALIGNMENT as u64);
+        let mut bounce = FBuf::with_capacity((end - start) as usize);
+        bounce.resize((end - start) as usize, 0);
+
+        // Direct reads only come up short at end of file.
+        let skip = (offset - start) as usize;
+        let wanted = skip + total as usize;
+        let mut n = 0;
+        while n < wanted {
+            let slice = IoSliceMut::new(&mut bounce.as_mut_slice()[n..]);
+            match preadv(&self.file, &mut [slice], start + n as u64)? {
+                0 => break,
+                m => n += m,
+            }
+        }
+
+        let mut data = &bounce[skip.min(n)..wanted.min(n)];
+        let mut copied = 0;
+        for buf in bufs.iter_mut() {
+            let k = buf.len().min(data.len());
+            buf[..k].copy_from_slice(&data[..k]);
+            data = &data[k..];
+            copied += k;
+            if data.is_empty() {
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Implements [FileReader::read_scattered] without updating the file's
+    /// statistics, for reads that record their own.
+    fn read_scattered_uncounted(
+        &self,
+        mut offset: u64,
+        bufs: &mut [&mut [u8]],
+    ) -> Result<usize, StorageError> {
+        if let Some(mapping) = &self.mapping {
+            // Stop at the end of the data, before any trailer.
+            let end = (self.get_size()? as usize).min(mapping.len);
+            let mut data = mapping
+                .as_slice()
+                .get(offset as usize..end)
+                .unwrap_or_default();
+            let mut total = 0;
+            for buf in bufs.iter_mut() {
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                data = &data[n..];
+                total += n;
+            }
+            return Ok(total);
+        }
+        if self.direct && !is_aligned(offset, bufs) {
+            return self.read_scattered_bounced(offset, bufs);
+        }
+
+        // Stop at the end of the data, before any trailer.
+        let mut remaining = self.get_size()?.saturating_sub(offset);
+        let mut slices = bufs
+            .iter_mut()
+            .map_while(|buf| {
+                if remaining == 0 && !buf.is_empty() {
+                    return None;
+                }
+                let n = (buf.len() as u64).min(remaining) as usize;
+                remaining -= n as u64;
+                Some(IoSliceMut::new(&mut buf[..n]))
+            })
+            .collect::<Vec<_>>();
+        let mut cursor = slices.as_mut_slice();
+        let mut total = 0;
+        while !cursor.is_empty() {
+            let n = preadv(&self.file, cursor, offset)?;
+            if n == 0 {
+                // End of file.
+                break;
+            }
+            total += n;
+            offset += n as u64;
+            IoSliceMut::advance_slices(&mut cursor, n);
+        }
+        Ok(total)
+    }
+
+    /// Checks `block`, just read from `location`, against its checksum, if
+    /// the file has one for exactly that location.
+    fn verify(&self, location: BlockLocation, block: &[u8]) -> Result<(), StorageError> {
+        match &self.checksums {
+            Some(checksums) => checksums.verify(location, block),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PosixReader {
+    /// Reads the blocks at `locations[i]` for each `i` in `run`, which must
+    /// be in increasing order of offset without overlaps, with a single
+    /// vectored read that also reads the gaps between them.
+    fn read_run(
+        &self,
+        locations: &[BlockLocation],
+        run: &[usize],
+    ) -> Result<Vec<FBuf>, StorageError> {
+        let first = locations[run[0]];
+        let last = locations[*run.last().unwrap()];
+        let gaps = run
+            .windows(2)
+            .map(|pair| (locations[pair[1]].offset - locations[pair[0]].after()) as usize)
+            .collect::<Vec<_>>();
+        let mut blocks = run
+            .iter()
+            .map(|index| {
+                let size = locations[*index].size;
+                let mut block = FBuf::with_capacity(self.read_allocation.capacity(size));
+                block.resize(size, 0);
+                block
+            })
+            .collect::<Vec<_>>();
+        // Use an aligned buffer for the gaps, in case of direct I/O.
+        let mut scratch = FBuf::with_capacity(gaps.iter().sum());
+        scratch.resize(gaps.iter().sum(), 0);
+
+        let mut bufs = Vec::with_capacity(run.len() * 2);
+        let mut rest = scratch.as_mut_slice();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if i > 0 {
+                let (gap, tail) = std::mem::take(&mut rest).split_at_mut(gaps[i - 1]);
+                rest = tail;
+                if !gap.is_empty() {
+                    bufs.push(gap);
+                }
+            }
+            bufs.push(block.as_mut_slice());
+        }
+
+        let total = (last.after() - first.offset) as usize;
+        let request_start = Instant::now();
+        if self.read_scattered_uncounted(first.offset, &mut bufs)? < total {
+            return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+        }
+        histogram!(READ_LATENCY).record(request_start.elapsed().as_secs_f64());
+        counter!(READ_COALESCE_WASTED_BYTES).increment(gaps.iter().sum::<usize>() as u64);
+        Ok(blocks)
+    }
+
+    /// Reads `locations` into `buffer`, which must be exactly as long as
+    /// their total size, and verifies them.  See [FileReader::read_regions].
+    fn read_regions_into(
+        &self,
+        locations: &[BlockLocation],
+        buffer: &mut [u8],
+    ) -> Result<(), StorageError> {
+        let contiguous = locations
+            .windows(2)
+            .all(|pair| pair[0].after() == pair[1].offset);
+        if contiguous && !locations.is_empty() {
+            // One vectored read covers all of the regions.
+            let size = buffer.len();
+            if self.read_scattered_uncounted(locations[0].offset, &mut [&mut *buffer])? < size {
+                return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+            }
+        } else {
+            let mut rest = &mut *buffer;
+            for location in locations {
+                let (region, tail) = std::mem::take(&mut rest).split_at_mut(location.size);
+                rest = tail;
+                if self.read_scattered_uncounted(location.offset, &mut [region])? < location.size {
+                    return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+                }
+            }
+        }
+
+        let mut start = 0;
+        for location in locations {
+            self.verify(*location, &buffer[start..start + location.size])?;
+            start += location.size;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PosixReader {
+    /// Nothing can read this reader's cached blocks once it's gone, because
+    /// every reader gets a new [FileId], so drop them from the cache.  If
+    /// the reader deletes its file on drop, this also invalidates them.
+    fn drop(&mut self) {
+        if let Some(cache) = &self.block_cache {
+            cache.evict(self.file_id);
+        }
+    }
+}
+
+impl HasFileId for PosixReader {
+    fn file_id(&self) -> FileId {
+        self.file_id
+    }
+}
+
+impl FileReader for PosixReader {
+    fn mark_for_checkpoint(&self) {
+        self.drop.keep();
+    }
+
+    fn is_kept(&self) -> bool {
+        self.drop.is_kept()
+    }
+
+    /// Borrows the block from the file's mapping, if it has one.  Otherwise,
+    /// reads it like [FileReader::read_block].
+    fn read_block_ref(&self, location: BlockLocation) -> Result<BlockRef<'_>, StorageError> {
+        let Some(mapping) = &self.mapping else {
+            return self.read_block(location).map(BlockRef::Owned);
+        };
+        if let Err(error) = self.check_location(location) {
+            if !matches!(error, StorageError::BlockTooLarge { .. }) {
+                counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+            }
+            counter!(READS_FAILED).increment(1);
+            return Err(error);
+        }
+        counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+        let result = mapping
+            .get(location)
+            .and_then(|data| self.verify(location, data).map(|()| data));
+        match result {
+            Ok(data) => {
+                counter!(READS_SUCCESS).increment(1);
+                self.stats.counters.record_read(1, location.size as u64);
+                self.record_prefetch_hit(location);
+                Ok(BlockRef::Borrowed(data))
+            }
+            Err(e) => {
+                counter!(READS_FAILED).increment(1);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        // Count the full block size even if the read fails or comes up short,
+        // so that truncation shows up in the metrics, unless the block is too
+        // big to be real.
+        if let Err(error) = self.check_location(location) {
+            if !matches!(error, StorageError::BlockTooLarge { .. }) {
+                counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+            }
+            // This also keeps us from reading the trailer as if it were data.
+            counter!(READS_FAILED).increment(1);
+            return Err(error);
+        }
+        if let Some(cache) = &self.block_cache {
+            if let Some(block) = cache.get(self, location) {
+                counter!(BLOCK_CACHE_HIT).increment(1);
+                return Ok(block);
+            }
+            counter!(BLOCK_CACHE_MISS).increment(1);
+        }
+        counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+        let capacity = self.read_allocation.capacity(location.size);
+        let mut buffer = match &self.read_pool {
+            Some(pool) => pool.get(capacity),
+            None => FBuf::with_capacity(capacity),
+        };
+
+        let request_start = Instant::now();
+        let result = match &self.mapping {
+            Some(mapping) => mapping
+                .get(location)
+                .map(|data| buffer.extend_from_slice(data)),
+            None => buffer
+                .read_exact_at(&self.file, location.offset, location.size)
+                .map_err(|error| match error.kind() {
+                    ErrorKind::UnexpectedEof => self.short_read(location),
+                    _ => StorageError::from(error),
+                }),
+        };
+        histogram!(READ_LATENCY).record(request_start.elapsed().as_secs_f64());
+        match result.and_then(|()| self.verify(location, &buffer)) {
+            Ok(()) => {
+                counter!(READS_SUCCESS).increment(1);
+                self.stats.counters.record_read(1, location.size as u64);
+                self.record_prefetch_hit(location);
+                let block = Arc::new(buffer);
+                if let Some(cache) = &self.block_cache {
+                    cache.insert(self.file_id, location.offset, block.clone());
+                }
+                if let Some(pool) = &self.read_pool {
+                    pool.lend(&block);
+                }
+                Ok(block)
+            }
+            Err(e) => {
+                counter!(READS_FAILED).increment(1);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_blocks(
+        &self,
+        locations: &[BlockLocation],
+        coalesce_gap: usize,
+    ) -> Vec<Result<Arc<FBuf>, StorageError>> {
+        let mut results = locations.iter().map(|_| None).collect::<Vec<_>>();
+
+        // Fail the blocks that can't be read before coalescing the rest, so
+        // that one bad location doesn't make us allocate a huge run.
+        let mut order = Vec::with_capacity(locations.len());
+        for (index, location) in locations.iter().enumerate() {
+            match self.check_location(*location) {
+                Ok(()) => order.push(index),
+                Err(error) => {
+                    counter!(READS_FAILED).increment(1);
+                    results[index] = Some(Err(error));
+                }
+            }
+        }
+        order.sort_by_key(|index| locations[*index].offset);
+
+        let mut start = 0;
+        while start < order.len() {
+            // Extend the run while the next block doesn't overlap the run
+            // and starts within `coalesce_gap` bytes of its end.
+            let mut end = start + 1;
+            let mut after = locations[order[start]].after();
+            while let Some(next) = order.get(end).map(|index| locations[*index]) {
+                if next.offset < after || next.offset - after > coalesce_gap as u64 {
+                    break;
+                }
+                after = next.after();
+                end += 1;
+            }
+
+            let run = &order[start..end];
+            let blocks = if run.len() > 1 {
+                self.read_run(locations, run).ok()
+            } else {
+                None
+            };
+            match blocks {
+                Some(blocks) => {
+                    let mut verified = 0;
+                    let mut verified_bytes = 0;
+                    for (index, block) in run.iter().zip(blocks) {
+                        counter!(TOTAL_BYTES_READ).increment(block.len() as u64);
+                        let result = self.verify(locations[*index], &block);
+                        match &result {
+                            Ok(()) => {
+                                counter!(READS_SUCCESS).increment(1);
+                                verified += 1;
+                                verified_bytes += block.len() as u64;
+                            }
+                            Err(_) => counter!(READS_FAILED).increment(1),
+                        }
+                        results[*index] = Some(result.map(|()| Arc::new(block)));
+                    }
+                    self.stats.counters.record_read(verified, verified_bytes);
+                }
+                None => {
+                    // Read the blocks one by one, so that each gets its own
+                    // result.
+                    for index in run {
+                        results[*index] = Some(self.read_block(locations[*index]));
+                    }
+                }
+            }
+            start = end;
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn read_regions(&self, locations: &[BlockLocation]) -> Result<Arc<FBuf>, StorageError> {
+        if let Err(error) = locations
+            .iter()
+            .try_for_each(|location| self.check_location(*location))
+        {
+            counter!(READS_FAILED).increment(locations.len() as u64);
+            return Err(error);
+        }
+        let size = locations
+            .iter()
+            .map(|location| location.size)
+            .sum::<usize>();
+        counter!(TOTAL_BYTES_READ).increment(size as u64);
+        let mut buffer = FBuf::with_capacity(self.read_allocation.capacity(size));
+        buffer.resize(size, 0);
+
+        let request_start = Instant::now();
+        let result = self.read_regions_into(locations, buffer.as_mut_slice());
+        histogram!(READ_LATENCY).record(request_start.elapsed().as_secs_f64());
+        match result {
+            Ok(()) => {
+                counter!(READS_SUCCESS).increment(locations.len() as u64);
+                self.stats
+                    .counters
+                    .record_read(locations.len(), size as u64);
+                Ok(Arc::new(buffer))
+            }
+            Err(e) => {
+                counter!(READS_FAILED).increment(locations.len() as u64);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_scattered(&self, offset: u64, bufs: &mut [&mut [u8]]) -> Result<usize, StorageError> {
+        let n = self.read_scattered_uncounted(offset, bufs)?;
+        self.stats.counters.record_read(0, n as u64);
+        Ok(n)
+    }
+
+    fn stats(&self) -> FileStats {
+        self.stats.counters.get()
+    }
+
+    fn advise_dontneed(&self) {
+        fadvise(&self.file, Advice::DontNeed);
+    }
+
+    fn advise_sequential(&self) {
+        fadvise(&self.file, Advice::Sequential);
+    }
+
+    fn prefetch(&self, locations: &[BlockLocation]) {
+        // Direct I/O bypasses the page cache, so there is nowhere for the
+        // kernel to read ahead into.  Otherwise, the kernel reads ahead in
+        // the background, so we don't need threads of our own.
+        if self.direct {
+            return;
+        }
+        let mut prefetched = self.prefetched.lock().unwrap();
+        for location in locations {
+            fadvise_range(&self.file, location.offset, location.size, Advice::WillNeed);
+            counter!(PREFETCH_BYTES).increment(location.size as u64);
+            if prefetched.len() >= MAX_PREFETCHED {
+                prefetched.pop_front();
+            }
+            prefetched.push_back(*location);
+        }
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        Ok(match (&self.live, &self.checksums) {
+            (Some(live), _) => live.committed.load(Ordering::Acquire),
+            (None, Some(checksums)) => checksums.data_size,
+            (None, None) => self.drop.size,
+        })
+    }
+
+    /// Fails with [ErrorKind::Unsupported] if the file has no recorded
+    /// creation time, as described for [get_created_at].
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        get_created_at(&self.file)?.ok_or(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+}
+
+/// Name of the extended attribute in which we record a file's creation time,
+/// as a little-endian count of nanoseconds since the Unix epoch.
+///
+/// We use an extended attribute, instead of a header in the file, so that
+/// block offsets in the file are unaffected.
+#[cfg(target_os = "linux")]
+const CREATED_AT_XATTR: &std::ffi::CStr = c"user.feldera.created_at";
+
+/// Returns true if `file` is open for direct I/O.
+fn is_direct(file: &File) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: `F_GETFL` only reads the file status flags.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        flags >= 0 && flags & libc::O_DIRECT != 0
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = file;
+        false
+    }
+}
+
+/// Returns true if `offset` and all of `bufs` are aligned as direct I/O
+/// requires.
+fn is_aligned(offset: u64, bufs: &[&mut [u8]]) -> bool {
+    let alignment = FBuf::ALIGNMENT;
+    offset % alignment as u64 == 0
+        && bufs
+            .iter()
+            .all(|buf| buf.as_ptr() as usize % alignment == 0 && buf.len() % alignment == 0)
+}
+
+/// Converts `error`, from an operation on `path`, into a [StorageError],
+/// reporting `EROFS` as [StorageError::ReadOnlyFilesystem] and `ENOSPC` and
+/// `EDQUOT` as [StorageError::OutOfSpace].
+fn storage_error(error: IoError, path: &Path) -> StorageError {
+    match error.raw_os_error() {
+        Some(libc::EROFS) => StorageError::ReadOnlyFilesystem(path.to_path_buf()),
+        Some(libc::ENOSPC | libc::EDQUOT) => StorageError::OutOfSpace {
+            path: Some(path.to_path_buf()),
+            source: Arc::new(error),
+        },
+        _ => error.into(),
+    }
+}
+
+/// Returns the type of the file or directory that `entry` names, with its
+/// size if it is a file.
+fn parse_entry(entry: &DirEntry) -> Result<StorageFileType, IoError> {
+    let file_type = entry.file_type()?;
+    Ok(if file_type.is_file() {
+        let metadata = entry.metadata()?;
+        StorageFileType::File {
+            size: metadata.size(),
+            allocated: allocated_size(&metadata),
+        }
+    } else if file_type.is_dir() {
+        StorageFileType::Directory
+    } else {
+        StorageFileType::Other
+    })
+}
+
+/// Returns the permissions for directories that hold files created with
+/// `file_mode`: the same permissions, plus search permission for each class
+/// of user that may read the files.
+fn dir_mode(file_mode: u32) -> u32 {
+    file_mode | ((file_mode & 0o444) >> 2)
+}
+
+/// Returns information about the filesystem that contains `path`.
+fn statvfs(path: &Path) -> Result<libc::statvfs, StorageError> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| StorageError::InvalidPath(path.to_path_buf()))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is big enough.
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } < 0 {
+        return Err(IoError::last_os_error().into());
+    }
+    // SAFETY: `statvfs` succeeded, so it initialized `stat`.
+    Ok(unsafe { stat.assume_init() })
+}
+
+/// Access to the immutable inode flag, for [PosixBackend::freeze].
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod immutable {
+    use std::{fs::File, io::Error as IoError, os::fd::AsRawFd, path::Path};
+
+    /// `_IOR('f', 1, long)` in the generic ioctl encoding.
+    const FS_IOC_GETFLAGS: u64 = 0x8008_6601;
+
+    /// `_IOW('f', 2, long)` in the generic ioctl encoding.
+    const FS_IOC_SETFLAGS: u64 = 0x4008_6602;
+
+    const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+    fn get_flags(file: &File) -> Result<libc::c_int, IoError> {
+        let mut flags: libc::c_int = 0;
+        // SAFETY: `FS_IOC_GETFLAGS` writes an `int` through the pointer.
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(flags)
+    }
+
+    /// Sets or clears the immutable flag on `path`.
+    pub fn set(path: &Path, immutable: bool) -> Result<(), IoError> {
+        let file = File::open(path)?;
+        let old_flags = get_flags(&file)?;
+        let flags = if immutable {
+            old_flags | FS_IMMUTABLE_FL
+        } else {
+            old_flags & !FS_IMMUTABLE_FL
+        };
+        // SAFETY: `FS_IOC_SETFLAGS` reads an `int` through the pointer.
+        if flags != old_flags
+            && unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) } < 0
+        {
+            return Err(IoError::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns true if `path` has the immutable flag set.
+    pub fn get(path: &Path) -> bool {
+        File::open(path)
+            .and_then(|file| get_flags(&file))
+            .is_ok_and(|flags| flags & FS_IMMUTABLE_FL != 0)
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod immutable {
+    use std::{
+        io::{Error as IoError, ErrorKind},
+        path::Path,
+    };
+
+    pub fn set(_path: &Path, _immutable: bool) -> Result<(), IoError> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    pub fn get(_path: &Path) -> bool {
+        false
+    }
+}
+
+/// Records `time` as the creation time of `file`.  This is best-effort: some
+/// filesystems don't support extended attributes.
+fn set_created_at(file: &File, time: SystemTime) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let nanos = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64)
+            .to_le_bytes();
+        let retval = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                CREATED_AT_XATTR.as_ptr(),
+                nanos.as_ptr() as *const libc::c_void,
+                nanos.len(),
+                0,
+            )
+        };
+        if retval < 0 {
+            debug!(
+                "Unable to record file creation time: {}",
+                IoError::last_os_error()
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, time);
+}
+
+/// Allocates disk space for the first `size` bytes of `file`, extending it to
+/// at least `size` bytes.  Returns false, without error, if the filesystem or
+/// platform doesn't support preallocation.
+fn allocate(file: &File, size: u64) -> Result<bool, IoError> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        loop {
+            // SAFETY: `fallocate` doesn't access memory.
+            let retval = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+            if retval == 0 {
+                return Ok(true);
+            }
+            let error = IoError::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => (),
+                Some(libc::EOPNOTSUPP) => {
+                    debug!("Unable to preallocate {size} bytes: {error}");
+                    return Ok(false);
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, size);
+        Ok(false)
+    }
+}
+
+/// Advice about how a file will be accessed, for [fadvise].
+#[derive(Copy, Clone, Debug)]
+enum Advice {
+    /// The file will be read sequentially.
+    Sequential,
+
+    /// The file won't be accessed soon, so its cached pages can be dropped.
+    DontNeed,
+
+    /// The given range of the file will be accessed soon, so it should be
+    /// read into the cache.
+    WillNeed,
+}
+
+/// Gives the kernel `advice` about the whole of `file`.  This is best-effort,
+/// since the advice only affects performance.
+fn fadvise(file: &File, advice: Advice) {
+    fadvise_range(file, 0, 0, advice);
+}
+
+/// Gives the kernel `advice` about the `len` bytes of `file` starting at
+/// `offset`, or through the end of the file if `len` is 0.  This is
+/// best-effort, since the advice only affects performance.
+fn fadvise_range(file: &File, offset: u64, len: usize, advice: Advice) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let flag = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        };
+        // SAFETY: `posix_fadvise` doesn't access memory.  It returns an
+        // error number instead of setting `errno`.
+        let retval = unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                flag,
+            )
+        };
+        if retval != 0 {
+            debug!(
+                "Unable to advise kernel of {advice:?} access: {}",
+                IoError::from_raw_os_error(retval)
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, offset, len, advice);
+}
+
+/// Returns the creation time recorded for `file` by [set_created_at], or
+/// `None` if none was recorded, because the file predates recording it or its
+/// filesystem doesn't support extended attributes.
+///
+/// We don't fall back to the modification time, because that's usually later
+/// than the creation time and would make the file look younger than it is.
+fn get_created_at(file: &File) -> Result<Option<SystemTime>, IoError> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let mut nanos = [0u8; 8];
+        let retval = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                CREATED_AT_XATTR.as_ptr(),
+                nanos.as_mut_ptr() as *mut libc::c_void,
+                nanos.len(),
+            )
+        };
+        if retval == nanos.len() as isize {
+            return Ok(Some(
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_nanos(u64::from_le_bytes(nanos)),
+            ));
+        } else if retval < 0 {
+            let error = IoError::last_os_error();
+            if !matches!(error.raw_os_error(), Some(libc::ENODATA | libc::ENOTSUP)) {
+                return Err(error);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+    Ok(None)
+}
+
+/// Reads from `file` at `offset` into as many of `bufs` as the system allows in
+/// one call, retrying if interrupted.  Returns the number of bytes read, which
+/// is 0 only at end of file.
+#[cfg(target_os = "linux")]
+fn preadv(file: &File, bufs: &mut [IoSliceMut], offset: u64) -> Result<usize, IoError> {
+    use std::os::fd::AsRawFd;
+
+    let n_bufs = bufs.len().min(*IOV_MAX);
+    loop {
+        // SAFETY: `IoSliceMut` is guaranteed to be ABI compatible with `iovec`.
+        let retval = unsafe {
+            libc::preadv(
+                file.as_raw_fd(),
+                bufs.as_ptr() as *const libc::iovec,
+                n_bufs as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if retval >= 0 {
+            return Ok(retval as usize);
+        }
+        let error = IoError::last_os_error();
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Reads from `file` at `offset` into the first nonempty buffer in `bufs`.
+/// Returns the number of bytes read, which is 0 only at end of file.
+#[cfg(not(target_os = "linux"))]
+fn preadv(file: &File, bufs: &mut [IoSliceMut], offset: u64) -> Result<usize, IoError> {
+    match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+        Some(buf) => loop {
+            match file.read_at(buf, offset) {
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                result => return result,
+            }
+        },
+        None => Ok(0),
+    }
+}
+
+struct DeleteOnDrop {
+    path: PathBuf,
+
+    /// The file's name in storage, for log messages.
+    name: StoragePath,
+    keep: AtomicBool,
+    size: u64,
+
+    /// Number of bytes that the file contributes to `usage` when it is
+    /// counted.  This is `size`, unless the backend counts physical usage, in
+    /// which case it is the storage allocated to the file as of the last time
+    /// that we checked.
+    usage_size: u64,
+
+    /// Whether `usage_size` is included in `usage`.
+    counted: bool,
+    usage: Arc<AtomicI64>,
+    strict_usage: bool,
+
+    /// Whether `usage_size` tracks allocated storage.  See
+    /// [PosixBackend::with_physical_usage].
+    physical_usage: bool,
+
+    /// Where to send the file for deletion, if deletion happens in the
+    /// background.  See [PosixBackend::with_background_deletion].
+    deletion_queue: Option<Arc<DeletionQueue>>,
+}
+
+impl Drop for DeleteOnDrop {
+    fn drop(&mut self) {
+        if !self.keep.load(Ordering::Relaxed) {
+            let deletion = Deletion {
+                path: std::mem::take(&mut self.path),
+                name: std::mem::take(&mut self.name),
+                size: self.size,
+                usage: self
+                    .counted
+                    .then(|| (self.usage.clone(), self.usage_size, self.strict_usage)),
+            };
+            match &self.deletion_queue {
+                Some(queue) => queue.push(deletion),
+                None => deletion.run(),
+            }
+        }
+    }
+}
+
+/// Returns the number of bytes of storage allocated to the file described by
+/// `metadata`, which `st_blocks` reports in units of 512 bytes.  Some
+/// filesystems, such as some network and FUSE filesystems, don't maintain
+/// `st_blocks` and report 0 for every file, so for a nonempty file with no
+/// blocks this falls back to the file's length.
+fn allocated_size(metadata: &Metadata) -> u64 {
+    match metadata.blocks() {
+        0 => metadata.size(),
+        blocks => blocks * 512,
+    }
+}
+
+/// Adds `n` bytes to `usage`, a [PosixBackend]'s usage, and updates the
+/// [STORAGE_USAGE_BYTES] gauge to match.  The backend changes its usage
+/// through this and [subtract_usage], except where it has to update usage
+/// atomically some other way, after which it sets the gauge itself.
+fn add_usage(usage: &AtomicI64, n: u64) {
+    let new = usage.fetch_add(n as i64, Ordering::Relaxed) + n as i64;
+    gauge!(STORAGE_USAGE_BYTES).set(new as f64);
+}
+
+/// Subtracts `n` bytes from `usage`, a [PosixBackend]'s usage, with
+/// [release_usage], and updates the [STORAGE_USAGE_BYTES] gauge to match.
+fn subtract_usage(usage: &AtomicI64, n: u64, strict: bool) {
+    release_usage(usage, n, strict);
+    set_usage_gauge(usage);
+}
+
+/// Sets the [STORAGE_USAGE_BYTES] gauge to `usage`, after changing it some
+/// other way.
+fn set_usage_gauge(usage: &AtomicI64) {
+    gauge!(STORAGE_USAGE_BYTES).set(usage.load(Ordering::Relaxed) as f64);
+}
+
+/// Returns true unless the file at `path` has other hard links, such as
+/// those made by [PosixBackend::checkpoint], that keep its data alive after
+/// `path` is removed.
+fn is_last_link(path: &Path) -> bool {
+    fs::symlink_metadata(path).map_or(true, |metadata| metadata.nlink() <= 1)
+}
+
+impl DeleteOnDrop {
+    fn new(
+        path: PathBuf,
+        name: StoragePath,
+        keep: bool,
+        size: u64,
+        counted: bool,
+        backend: &PosixBackend,
+    ) -> Self {
+        Self {
+            path,
+            name,
+            keep: AtomicBool::new(keep),
+            size,
+            usage_size: size,
+            counted,
+            usage: backend.usage.clone(),
+            strict_usage: backend.strict_usage,
+            physical_usage: backend.physical_usage,
+            deletion_queue: backend.deletion_queue.clone(),
+        }
+    }
+
+    /// Returns this with `usage_size` as the number of bytes that the file
+    /// contributes to usage, for a file whose length isn't what counts.
+    fn with_usage_size(mut self, usage_size: u64) -> Self {
+        self.usage_size = usage_size;
+        self
+    }
+
+    /// Records that `n` bytes reserved with [Self::reserve] were written to
+    /// the end of the file.
+    fn wrote(&mut self, n: u64) {
+        self.size += n;
+        self.usage_size += n;
+    }
+
+    /// If we count physical usage, updates `usage_size`, and usage if the
+    /// file is counted, to the storage allocated to `file`.  Leaves them
+    /// alone if that can't be determined.
+    fn sync_allocated(&mut self, file: &File) {
+        if !self.physical_usage {
+            return;
+        }
+        let Ok(metadata) = file.metadata() else {
+            return;
+        };
+        let allocated = allocated_size(&metadata);
+        if self.counted {
+            if allocated > self.usage_size {
+                add_usage(&self.usage, allocated - self.usage_size);
+            } else {
+                subtract_usage(&self.usage, self.usage_size - allocated, self.strict_usage);
+            }
+        }
+        self.usage_size = allocated;
+    }
+
+    /// Reserves usage for `n` bytes about to be written to the file, failing
+    /// if that would take usage past `quota`.  If the file doesn't count
+    /// toward usage yet, this only checks that counting it later would stay
+    /// within `quota`.
+    fn reserve(&self, n: u64, quota: Option<u64>) -> Result<(), StorageError> {
+        let exceeded = |used: i64, quota: u64| StorageError::QuotaExceeded {
+            used: used.max(0) as u64,
+            quota,
+        };
+        if self.counted {
+            let used = self
+                .usage
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    let projected = used + n as i64;
+                    match quota {
+                        Some(quota) if projected > quota as i64 => None,
+                        _ => Some(projected),
+                    }
+                })
+                .map_err(|used| exceeded(used, quota.unwrap()))?;
+            gauge!(STORAGE_USAGE_BYTES).set((used + n as i64) as f64);
+        } else if let Some(quota) = quota {
+            let used = self.usage.load(Ordering::Relaxed);
+            if used + (self.usage_size + n) as i64 > quota as i64 {
+                return Err(exceeded(used, quota));
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases usage for `n` bytes reserved with [Self::reserve] that were
+    /// never written.
+    fn unreserve(&self, n: u64) {
+        if self.counted && n > 0 {
+            subtract_usage(&self.usage, n, self.strict_usage);
+        }
+    }
+
+    /// Records that the file was truncated to `len` bytes, releasing the
+    /// bytes cut off from usage if the file is counted.
+    fn truncate(&mut self, len: u64) {
+        let discarded = self.size - len;
+        self.size = len;
+        if self.counted {
+            subtract_usage(
+                &self.usage,
+                discarded.min(self.usage_size),
+                self.strict_usage,
+            );
+        }
+        self.usage_size = self.usage_size.saturating_sub(discarded);
+    }
+
+    /// Starts counting the file's size toward usage, if it isn't already.
+    fn count(&mut self) {
+        if !self.counted {
+            self.counted = true;
+            add_usage(&self.usage, self.usage_size);
+        }
+    }
+
+    /// Subtracts the file's size from usage, if it was counted.
+    fn release(&self) {
+        if self.counted {
+            subtract_usage(&self.usage, self.usage_size, self.strict_usage);
+        }
+    }
+    fn keep(&self) {
+        self.keep.store(true, Ordering::Relaxed);
+    }
+
+    fn is_kept(&self) -> bool {
+        self.keep.load(Ordering::Relaxed)
+    }
+
+    /// Deletes the file immediately, returning any error instead of just
+    /// logging it the way [Drop] does.
+    fn delete(self) -> Result<(), IoError> {
+        self.keep();
+        let last_link = is_last_link(&self.path);
+        fs::remove_file(&self.path)?;
+        if last_link {
+            self.release();
+            counter!(BYTES_DELETED).increment(self.size);
+        }
+        counter!(FILES_DELETED).increment(1);
+        Ok(())
+    }
+
+    fn with_path(mut self, path: PathBuf) -> Self {
+        self.path = path;
+        self
+    }
+}
+
+/// Number of bytes that a writer buffers before flushing, by default.  See
+/// [StorageConfig::flush_threshold].
+const DEFAULT_FLUSH_THRESHOLD: usize = 1024 * 1024;
+
+/// How far, in bytes, [PosixBackend::recompute_usage] can find usage to have
+/// drifted before it logs a warning.
+const USAGE_DRIFT_WARNING: u64 = 1024 * 1024;
+
+/// Returns the system's page size, which is the smallest allowed flush
+/// threshold.
+fn page_size() -> usize {
+    // SAFETY: `sysconf` has no preconditions.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+/// The number of bytes that a backend's writers buffer before flushing,
+/// optionally adapted to the latency of recent flushes.  See
+/// [AdaptiveFlushConfig].
+struct FlushThreshold {
+    /// Threshold to start from, in bytes.
+    initial: usize,
+
+    /// Configuration for adapting the threshold, or `None` to keep it fixed at
+    /// `initial`.
+    adaptive: Option<AdaptiveFlushConfig>,
+
+    /// Current threshold, in bytes.
+    bytes: AtomicUsize,
+
+    /// Moving average of the latency of flushes since the threshold last
+    /// changed.
+    average: Mutex<Option<Duration>>,
+}
+
+impl FlushThreshold {
+    fn new(initial: usize, adaptive: Option<AdaptiveFlushConfig>) -> Self {
+        let adaptive = adaptive.map(|config| AdaptiveFlushConfig {
+            max_bytes: config.max_bytes.max(config.min_bytes),
+            ..config
+        });
+        let bytes = match &adaptive {
+            Some(config) => initial.clamp(config.min_bytes, config.max_bytes),
+            None => initial,
+        };
+        Self {
+            initial,
+            adaptive,
+            bytes: AtomicUsize::new(bytes),
+            average: Mutex::new(None),
+        }
+    }
+
+    /// Returns the current threshold, in bytes.
+    fn get(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Records that a flush took `latency`.  If flushing is adaptive, halves
+    /// the threshold if flushes are averaging longer than the target latency,
+    /// or doubles it if they are averaging less than half of it.
+    fn record(&self, latency: Duration) {
+        let Some(config) = &self.adaptive else {
+            return;
+        };
+        let mut average = self.average.lock().unwrap();
+        let new_average = match *average {
+            Some(average) => (average * 3 + latency) / 4,
+            None => latency,
+        };
+        let target = Duration::from_millis(config.target_latency_ms);
+        let bytes = self.get();
+        let new_bytes = if new_average > target {
+            bytes / 2
+        } else if new_average < target / 2 {
+            bytes.saturating_mul(2)
+        } else {
+            bytes
+        }
+        .clamp(config.min_bytes, config.max_bytes);
+        if new_bytes != bytes {
+            // Start over averaging, so that flushes at the old threshold don't
+            // count against the new one.
+            debug!("adjusting flush threshold from {bytes} to {new_bytes} bytes");
+            self.bytes.store(new_bytes, Ordering::Relaxed);
+            *average = None;
+        } else {
+            *average = Some(new_average);
+        }
+    }
+}
+
+/// Calls `f` until it returns anything other than an error of kind
+/// [ErrorKind::Interrupted], which a signal can cause.
+fn retry_interrupted<T>(mut f: impl FnMut() -> Result<T, IoError>) -> Result<T, IoError> {
+    loop {
+        match f() {
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            result => return result,
+        }
+    }
+}
+
+/// Number of consecutive writes that may write nothing, without reporting an
+/// error, before [write_all_vectored] gives up.  Some FUSE filesystems
+/// occasionally do this.
+const MAX_ZERO_WRITES: usize = 8;
+
+/// Writes all of `bufs` to `writer`, passing at most `max_iov` of them to
+/// each write, re-issuing writes that are interrupted or that write only part
+/// of the data, and calls `progress` with the number of bytes that each
+/// successful write wrote.
+///
+/// Fails with [ErrorKind::WriteZero] after [MAX_ZERO_WRITES] consecutive
+/// writes that write nothing.
+fn write_all_vectored(
+    writer: &mut impl Write,
+    mut bufs: &mut [IoSlice<'_>],
+    max_iov: usize,
+    mut progress: impl FnMut(usize),
+) -> Result<(), IoError> {
+    let mut zero_writes = 0;
+    while !bufs.is_empty() {
+        let n_bufs = bufs.len().min(max_iov);
+        match retry_interrupted(|| writer.write_vectored(&bufs[..n_bufs]))? {
+            0 => {
+                zero_writes += 1;
+                if zero_writes >= MAX_ZERO_WRITES {
+                    return Err(ErrorKind::WriteZero.into());
+                }
+            }
+            n => {
+                zero_writes = 0;
+                progress(n);
+                IoSlice::advance_slices(&mut bufs, n);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the file at `from` to `to` with `rename`.  If that fails because
+/// they are on different filesystems, this instead copies the file to a
+/// temporary file beside `to`, syncs the copy as `durability` requires,
+/// renames it to `to`, and deletes `from`.  Returns true if it copied.
+fn move_file(
+    from: &Path,
+    to: &Path,
+    durability: DurabilityMode,
+    rename: impl Fn(&Path, &Path) -> Result<(), IoError>,
+) -> Result<bool, IoError> {
+    match rename(from, to) {
+        Err(error) if error.raw_os_error() == Some(libc::EXDEV) => {
+            let temp = append_to_path(to.to_path_buf(), MUTABLE_EXTENSION);
+            let copy = || {
+                fs::copy(from, &temp)?;
+                sync_file(&File::open(&temp)?, durability)?;
+                fs::rename(&temp, to)
+            };
+            if let Err(error) = copy() {
+                let _ = fs::remove_file(&temp);
+                return Err(error);
+            }
+            if let Err(error) = fs::remove_file(from) {
+                warn!(
+                    "{}: unable to delete after copying ({error})",
+                    from.display()
+                );
+            }
+            Ok(true)
+        }
+        result => result.map(|()| false),
+    }
+}
+
+/// Makes `file` durable according to `durability`.
+fn sync_file(file: &File, durability: DurabilityMode) -> Result<(), IoError> {
+    match durability {
+        DurabilityMode::SyncAll => retry_interrupted(|| file.sync_all()),
+        DurabilityMode::SyncData => retry_interrupted(|| file.sync_data()),
+        DurabilityMode::None => Ok(()),
+    }
+}
+
+/// Syncs the directory at `path`, making changes to its entries, such as
+/// renames, durable.
+///
+/// Some filesystems can't sync a directory and fail with `EINVAL` or
+/// [ErrorKind::Unsupported].  There is nothing more we can do for durability
+/// on those, so we treat that as success.
+fn sync_dir(path: &Path) -> Result<(), IoError> {
+    let dir = File::open(path)?;
+    match retry_interrupted(|| dir.sync_all()) {
+        Err(error)
+            if error.kind() == ErrorKind::Unsupported
+                || error.raw_os_error() == Some(libc::EINVAL) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Meta-data we keep per file we created.
+struct PosixWriter {
+    file_id: FileId,
+    file: File,
+    drop: DeleteOnDrop,
+    name: StoragePath,
+
+    /// This file's statistics.
+    stats: RegisteredFile,
+
+    buffers: Vec<Arc<FBuf>>,
+    len: u64,
+
+    /// Number of bytes in `buffers`.
+    buffered: usize,
+
+    /// Data passed to [FileWriter::write_all] that hasn't been added to
+    /// `buffers` yet, because it doesn't fill a block.  It follows the data
+    /// in `buffers` and isn't included in `len` or `buffered`.
+    pending: FBuf,
+
+    /// Number of times this writer has flushed.
+    flushes: usize,
+
+    /// Buffers written by the most recent flush, retained for
+    /// [FileWriter::recycle].
+    flushed: Vec<Arc<FBuf>>,
+
+    flush_limiter: Arc<FlushLimiter>,
+    flush_threshold: Arc<FlushThreshold>,
+    clock: Arc<dyn StorageClock>,
+    read_allocation: Arc<ReadAllocation>,
+    durability: DurabilityMode,
+
+    /// Maximum number of bytes to buffer, regardless of the flush threshold,
+    /// if any.  See [PosixBackend::with_max_writer_buffer].
+    max_buffer: Option<usize>,
+
+    /// This writer's share of the backend's limit on buffered bytes, which
+    /// covers the bytes in `buffers`.
+    buffer_permit: WriteBufferPermit,
+
+    /// Maximum usage, if any.  See [PosixBackend::with_quota].
+    quota: Option<u64>,
+
+    /// Maximum number of buffers to pass to one write.  See
+    /// [PosixBackend::with_max_iov].
+    max_iov: usize,
+
+    /// Passed along to the reader.  See [PosixBackend::with_max_block_size].
+    max_block_size: Option<usize>,
+
+    /// Passed along to the reader.  See [PosixBackend::with_block_cache].
+    block_cache: Option<Arc<BlockCache>>,
+
+    /// Passed along to the reader.  See
+    /// [PosixBackend::with_read_buffer_pool].
+    read_pool: Option<Arc<ReadBufferPool>>,
+
+    write_verify: bool,
+
+    /// Whether `file` was opened for direct I/O, which requires every block
+    /// to be a multiple of [FBuf::ALIGNMENT] bytes long.
+    direct: bool,
+
+    /// Checksums of the blocks written so far, as `(offset, len, crc)`, if
+    /// block checksums are enabled.
+    checksums: Option<Vec<(u64, usize, u32)>>,
+
+    /// Blocks reserved with [FileWriter::reserve_block] that haven't been
+    /// filled yet.
+    reserved: Vec<BlockLocation>,
+
+    /// Length to which [FileWriter::preallocate] extended the file, or 0.
+    preallocated: u64,
+
+    /// Whether [FileWriter::prepare_complete] has written out and synced all
+    /// of the data, after which the file can't be written any more.
+    prepared: bool,
+
+    /// The block checksums written in the file's trailer, once it has been
+    /// prepared, if block checksums are enabled.
+    trailer: Option<BlockChecksums>,
+
+    /// For a writer from [StorageBackend::open_append], the length of the
+    /// file when it was opened.
+    append_from: Option<u64>,
+
+    /// Readers that the backend shares, if any, where completing the file
+    /// invalidates any reader for its name.  See
+    /// [PosixBackend::with_shared_readers].
+    shared_readers: Option<Arc<SharedReaders>>,
+
+    /// Shares how much of the file has been written with readers that open
+    /// it before it is completed.  Files opened for appending already have
+    /// their final names, so they don't have one.
+    live: Option<LiveRegistration>,
+
+    /// Lists the file as being appended to, for a writer from
+    /// [StorageBackend::open_append], until the writer is dropped.
+    appending: Option<AppendRegistration>,
+
+    /// For a file being written in the scratch directory, the path in the
+    /// storage directory to move it to when it is completed.  See
+    /// [PosixBackend::with_scratch_base].
+    final_path: Option<PathBuf>,
+}
+
+impl HasFileId for PosixWriter {
+    fn file_id(&self) -> FileId {
+        self.file_id
+    }
+}
+
+impl FileWriter for PosixWriter {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        if self.direct && data.len() % FBuf::ALIGNMENT != 0 {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let block = Arc::new(data);
+        let request_start = self.clock.now();
+        self.stage_pending()?;
+        let offset = self.len;
+        self.write(&block)?;
+        if let Some(checksums) = &mut self.checksums {
+            checksums.push((offset, block.len(), crc32c::crc32c(block.as_slice())));
+        }
+
+        counter!(TOTAL_BYTES_WRITTEN).increment(block.len() as u64);
+        counter!(WRITES_SUCCESS).increment(1);
+        histogram!(WRITE_LATENCY).record(self.clock.elapsed_since(request_start).as_secs_f64());
+        self.stats.counters.record_write(block.len());
+
+        Ok(block)
+    }
+
+    fn write_all(&mut self, mut data: &[u8]) -> Result<(), StorageError> {
+        if self.prepared {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let request_start = self.clock.now();
+        let len = data.len();
+
+        // Make blocks as big as the flush threshold, so that flushing
+        // doesn't wait for `pending`, rounded down to keep them aligned.
+        let block_size =
+            (self.flush_threshold.get() / FBuf::ALIGNMENT * FBuf::ALIGNMENT).max(FBuf::ALIGNMENT);
+        while !data.is_empty() {
+            if self.pending.len() >= block_size {
+                self.stage_pending()?;
+            }
+            if self.pending.capacity() == 0 {
+                self.pending = FBuf::with_capacity(block_size);
+            }
+            let n = (block_size - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+
+        counter!(TOTAL_BYTES_WRITTEN).increment(len as u64);
+        counter!(WRITES_SUCCESS).increment(1);
+        histogram!(WRITE_LATENCY).record(self.clock.elapsed_since(request_start).as_secs_f64());
+        self.stats.counters.record_write(len);
+        Ok(())
+    }
+
+    fn recycle(&mut self) -> Vec<FBuf> {
+        if self
+            .flushed
+            .iter()
+            .any(|buffer| Arc::strong_count(buffer) > 1)
+        {
+            return Vec::new();
+        }
+        self.flushed
+            .drain(..)
+            .map(|buffer| {
+                let mut buffer = Arc::into_inner(buffer).unwrap();
+                buffer.clear();
+                buffer
+            })
+            .collect()
+    }
+
+    fn reserve_block(&mut self, size: usize) -> Result<BlockHandle, StorageError> {
+        self.stage_pending()?;
+        let location = BlockLocation::new(self.len, size)
+            .map_err(|_| StorageError::StdIo(ErrorKind::InvalidInput))?;
+        let mut placeholder = FBuf::with_capacity(size);
+        placeholder.resize(size, 0);
+        self.write(&Arc::new(placeholder))?;
+        self.reserved.push(location);
+        Ok(BlockHandle::new(location))
+    }
+
+    fn fill_reserved(&mut self, handle: BlockHandle, data: FBuf) -> Result<(), StorageError> {
+        let location = handle.location();
+        let index = self
+            .reserved
+            .iter()
+            .position(|reserved| reserved.offset == location.offset)
+            .filter(|_| data.len() == location.size)
+            .ok_or(StorageError::StdIo(ErrorKind::InvalidInput))?;
+        if let Some(checksums) = &mut self.checksums {
+            checksums.push((location.offset, data.len(), crc32c::crc32c(data.as_slice())));
+        }
+        let size = data.len();
+
+        if location.offset >= self.drop.size {
+            // The placeholder is still buffered, so just replace it.
+            let mut offset = self.drop.size;
+            let buffer = self
+                .buffers
+                .iter_mut()
+                .find(|buffer| {
+                    let found = offset == location.offset;
+                    offset += buffer.len() as u64;
+                    found
+                })
+                .unwrap();
+            *buffer = Arc::new(data);
+        } else {
+            // The placeholder was already flushed, so overwrite it in place.
+            self.file.write_all_at(data.as_slice(), location.offset)?;
+            if self.write_verify {
+                verify_write(&self.file, location.offset, &[Arc::new(data)])?;
+            }
+        }
+        self.reserved.swap_remove(index);
+        self.stats.counters.record_write(size);
+        self.publish();
+        Ok(())
+    }
+
+    /// Flushes everything buffered first, so that the write can go straight
+    /// to the file.
+    fn write_block_at(&mut self, offset: u64, data: FBuf) -> Result<(), StorageError> {
+        let end = offset + data.len() as u64;
+        if self.prepared
+            || self
+                .append_from
+                .is_some_and(|append_from| offset < append_from)
+            || (self.direct
+                && (offset % FBuf::ALIGNMENT as u64 != 0 || data.len() % FBuf::ALIGNMENT != 0))
+            || self
+                .reserved
+                .iter()
+                .any(|location| location.offset < end && location.after() > offset)
+        {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let request_start = self.clock.now();
+        self.stage_pending()?;
+        if !self.buffers.is_empty() {
+            self.flush()?;
+        }
+
+        let grown = end.saturating_sub(self.len);
+        self.drop.reserve(grown, self.quota)?;
+        if let Err(error) = self.file.write_all_at(data.as_slice(), offset) {
+            self.drop.unreserve(grown);
+            return Err(storage_error(error, &self.drop.path));
+        }
+        self.drop.wrote(grown);
+        self.drop.sync_allocated(&self.file);
+        self.len += grown;
+        if let Some(checksums) = &mut self.checksums {
+            // Blocks that were overwritten, even partly, no longer match
+            // their checksums.
+            checksums.retain(|(block_offset, size, _crc)| {
+                *block_offset >= end || block_offset + *size as u64 <= offset
+            });
+            checksums.push((offset, data.len(), crc32c::crc32c(data.as_slice())));
+        }
+        let size = data.len();
+        if self.write_verify {
+            verify_write(&self.file, offset, &[Arc::new(data)])?;
+        }
+        self.publish();
+
+        counter!(TOTAL_BYTES_WRITTEN).increment(size as u64);
+        counter!(WRITES_SUCCESS).increment(1);
+        histogram!(WRITE_LATENCY).record(self.clock.elapsed_since(request_start).as_secs_f64());
+        self.stats.counters.record_write(size);
+        Ok(())
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<(), StorageError> {
+        if size > self.preallocated.max(self.len) && allocate(&self.file, size)? {
+            self.preallocated = size;
+        }
+        Ok(())
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<(), StorageError> {
+        if !self.prepared {
+            self.stage_pending()?;
+        }
+        if self.prepared
+            || len > self.len
+            || self
+                .append_from
+                .is_some_and(|append_from| len < append_from)
+            || (self.direct && len % FBuf::ALIGNMENT as u64 != 0)
+            || self
+                .reserved
+                .iter()
+                .any(|location| location.offset < len && location.after() > len)
+        {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        self.reserved.retain(|location| location.offset < len);
+        if let Some(checksums) = &mut self.checksums {
+            // A block cut short loses its checksum, because it no longer
+            // matches.
+            checksums.retain(|(offset, size, _crc)| offset + *size as u64 <= len);
+        }
+
+        let flushed = self.drop.size;
+        if len >= flushed {
+            // Only buffered data is discarded, so the file stays as it is.
+            let mut keep = (len - flushed) as usize;
+            for buffer in std::mem::take(&mut self.buffers) {
+                if keep == 0 {
+                    break;
+                } else if buffer.len() <= keep {
+                    keep -= buffer.len();
+                    self.buffers.push(buffer);
+                } else {
+                    let mut prefix = FBuf::with_capacity(keep);
+                    prefix.extend_from_slice(&buffer[..keep]);
+                    self.buffers.push(Arc::new(prefix));
+                    keep = 0;
+                }
+            }
+            let buffered = (len - flushed) as usize;
+            self.buffer_permit.shrink(self.buffered - buffered);
+            self.buffered = buffered;
+        } else {
+            self.buffers.clear();
+            self.buffered = 0;
+            self.buffer_permit.release();
+            // Flushing writes at the file's position, so move that back too.
+            self.file
+                .set_len(len)
+                .and_then(|()| self.file.seek(SeekFrom::Start(len)))
+                .map_err(|error| storage_error(error, &self.drop.path))?;
+            self.drop.truncate(len);
+            self.drop.sync_allocated(&self.file);
+
+            // Truncating also freed any preallocated space.
+            self.preallocated = 0;
+            self.publish();
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// Syncs with `sync_data` whatever the backend's durability mode.  The
+    /// file keeps its temporary name until it is completed, so
+    /// [PosixBackend::recover] still deletes it after a crash, unless it was
+    /// opened with [StorageBackend::open_append].
+    fn sync(&mut self) -> Result<(), StorageError> {
+        if self.prepared {
+            // Preparing already synced everything.
+            return Ok(());
+        }
+        self.stage_pending()?;
+        if !self.buffers.is_empty() {
+            self.flush()?;
+        }
+        retry_interrupted(|| self.file.sync_data())
+            .map_err(|error| storage_error(error, &self.drop.path))
+    }
+
+    fn prepare_complete(&mut self) -> Result<(), StorageError> {
+        if self.prepared {
+            return Ok(());
+        }
+        if let Some(offset) = self.reserved.iter().map(|location| location.offset).min() {
+            return Err(StorageError::UnfilledReservation { offset });
+        }
+        self.stage_pending()?;
+        if let Some(checksums) = self.checksums.take() {
+            let checksums = BlockChecksums {
+                data_size: self.len,
+                blocks: checksums
+                    .into_iter()
+                    .map(|(offset, len, crc)| (offset, (len, crc)))
+                    .collect(),
+            };
+            // Set the trailer first, so that publishing excludes it.
+            let trailer = checksums.trailer();
+            self.trailer = Some(checksums);
+            self.write(&Arc::new(trailer))?;
+        }
+        if !self.buffers.is_empty() {
+            self.flush()?;
+        }
+        if self.preallocated > self.len {
+            // Drop the part of the preallocation that we didn't use.
+            self.file
+                .set_len(self.len)
+                .map_err(|error| storage_error(error, &self.drop.path))?;
+            self.drop.sync_allocated(&self.file);
+        }
+        let sync_start = self.clock.now();
+        sync_file(&self.file, self.durability)
+            .map_err(|error| storage_error(error, &self.drop.path))?;
+        histogram!(SYNC_LATENCY).record(self.clock.elapsed_since(sync_start).as_secs_f64());
+        self.prepared = true;
+        Ok(())
+    }
+
+    fn complete(self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let sync = self.durability != DurabilityMode::None;
+        self.finish(sync)
+    }
+
+    fn complete_prepared(
+        self: Box<Self>,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.finish(false)
+    }
+
+    /// Releases the bytes already flushed from usage along with the file.
+    /// For a writer from [StorageBackend::open_append], truncates the file
+    /// back to its length when it was opened instead of deleting it.
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        let mut this = *self;
+        match this.append_from {
+            Some(len) => {
+                this.file
+                    .set_len(len)
+                    .map_err(|error| storage_error(error, &this.drop.path))?;
+                this.drop.truncate(len);
+            }
+            None => {
+                let path = this.drop.path.clone();
+                this.drop
+                    .delete()
+                    .map_err(|error| storage_error(error, &path))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PosixWriter {
+    /// Prepares the file, if it hasn't been already, and then renames it to
+    /// remove the `.mut` extension, syncing its directory afterward if
+    /// `sync` is true.  A file opened for appending already has its final
+    /// name, so it is just prepared.  A file in the scratch directory is
+    /// moved into the storage directory, by copying it if the two are on
+    /// different filesystems.
+    fn finish(
+        mut self: Box<Self>,
+        sync: bool,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let start = self.clock.now();
+        self.prepare_complete()?;
+
+        let finalized_path = if self.append_from.is_some() {
+            self.drop.path.clone()
+        } else {
+            // Remove the .mut extension from the file.
+            let rename_start = self.clock.now();
+            let finalized_path = self
+                .final_path
+                .take()
+                .unwrap_or_else(|| self.drop.path.with_extension(""));
+            let copied = move_file(
+                &self.drop.path,
+                &finalized_path,
+                self.durability,
+                |from, to| fs::rename(from, to),
+            )
+            .map_err(|error| storage_error(error, &self.drop.path))?;
+            self.drop.path = finalized_path.clone();
+            if copied {
+                // Read the copy, since the original is gone.
+                self.file = File::open(&finalized_path)
+                    .map_err(|error| storage_error(error, &finalized_path))?;
+            }
+            self.drop.count();
+            if sync {
+                // Make the rename durable.
+                if let Some(parent) = finalized_path.parent() {
+                    sync_dir(parent).map_err(|error| storage_error(error, parent))?;
+                }
+            }
+            histogram!(RENAME_LATENCY).record(self.clock.elapsed_since(rename_start).as_secs_f64());
+            finalized_path
+        };
+        if let Some(shared_readers) = &self.shared_readers {
+            shared_readers.invalidate(&self.name);
+        }
+        if let Some(live) = self.live.take() {
+            let data_size = self
+                .trailer
+                .as_ref()
+                .map_or(self.len, |trailer| trailer.data_size);
+            live.file.committed.store(data_size, Ordering::Release);
+            live.file.finished.store(true, Ordering::Release);
+        }
+        debug!(
+            "completed {} ({} bytes) in {} flushes",
+            finalized_path.display(),
+            self.len,
+            self.flushes
+        );
+        counter!(FILES_COMPLETED).increment(1);
+        histogram!(COMPLETE_LATENCY).record(self.clock.elapsed_since(start).as_secs_f64());
+
+        let mut reader = PosixReader::new(
+            Arc::new(self.file),
+            self.file_id,
+            self.drop.with_path(finalized_path),
+            self.stats,
+            self.read_allocation,
+            self.trailer,
+        );
+        reader.max_block_size = self.max_block_size;
+        reader.block_cache = self.block_cache;
+        reader.read_pool = self.read_pool;
+        Ok((Arc::new(reader), self.name))
+    }
+
+    fn new(file: File, name: StoragePath, path: PathBuf, backend: &PosixBackend) -> Self {
+        let file_id = FileId::new();
+        Self {
+            direct: is_direct(&file),
+            file_id,
+            file,
+            stats: RegisteredFile::new(backend, file_id, name.clone()),
+            drop: DeleteOnDrop::new(
+                path,
+                name.clone(),
+                false,
+                0,
+                backend.usage_policy.counts_in_progress() && backend.scratch_base.is_none(),
+                backend,
+            ),
+            name,
+            buffers: Vec::new(),
+            len: 0,
+            buffered: 0,
+            pending: FBuf::new(),
+            flushes: 0,
+            flushed: Vec::new(),
+            flush_limiter: backend.flush_limiter.clone(),
+            flush_threshold: backend.flush_threshold.clone(),
+            max_buffer: backend.max_writer_buffer,
+            buffer_permit: WriteBufferPermit::new(backend.write_buffer_limiter.clone()),
+            clock: backend.clock.clone(),
+            read_allocation: backend.read_allocation.clone(),
+            durability: backend.durability,
+            quota: backend.quota_bytes,
+            max_iov: backend.max_iov,
+            max_block_size: backend.max_block_size,
+            block_cache: backend.block_cache.clone(),
+            read_pool: backend.read_pool.clone(),
+            write_verify: backend.write_verify,
+            checksums: backend.block_checksums.then(Vec::new),
+            reserved: Vec::new(),
+            preallocated: 0,
+            prepared: false,
+            trailer: None,
+            append_from: None,
+            shared_readers: backend.shared_readers.clone(),
+            live: None,
+            appending: None,
+            final_path: None,
+        }
+    }
+
+    /// Lets readers that opened the file before it was completed read the
+    /// data flushed so far, stopping before any block that was reserved but
+    /// not yet filled and before the trailer.
+    fn publish(&self) {
+        let Some(live) = &self.live else {
+            return;
+        };
+        let mut committed = self.drop.size;
+        if let Some(trailer) = &self.trailer {
+            committed = committed.min(trailer.data_size);
+        }
+        if let Some(offset) = self.reserved.iter().map(|location| location.offset).min() {
+            committed = committed.min(offset);
+        }
+        live.file.committed.store(committed, Ordering::Release);
+    }
+
+    /// Adds the data accumulated in `pending`, if any, to `buffers` as a
+    /// block of its own.
+    fn stage_pending(&mut self) -> Result<(), StorageError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.direct && self.pending.len() % FBuf::ALIGNMENT != 0 {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let block = Arc::new(std::mem::take(&mut self.pending));
+        let offset = self.len;
+        if let Err(error) = self.write(&block) {
+            // `write` keeps the block only if it succeeds.
+            self.pending = Arc::into_inner(block).unwrap();
+            return Err(error);
+        }
+        if let Some(checksums) = &mut self.checksums {
+            checksums.push((offset, block.len(), crc32c::crc32c(block.as_slice())));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        self.flush_with(|mut file, bufs, max_iov, progress| {
+            write_all_vectored(&mut file, bufs, max_iov, progress)
+        })
+    }
+
+    /// Flushes the buffers using `write` to write them to the file, which
+    /// lets tests make the write fail partway through.
+    fn flush_with(
+        &mut self,
+        write: impl FnOnce(
+            &File,
+            &mut [IoSlice<'_>],
+            usize,
+            &mut dyn FnMut(usize),
+        ) -> Result<(), IoError>,
+    ) -> Result<(), StorageError> {
+        let _permit = self.flush_limiter.acquire();
+        let start = self.clock.now();
+        let offset = self.drop.size;
+        let mut bufs = self
+            .buffers
+            .iter()
+            .map(|buf| IoSlice::new(buf.as_slice()))
+            .collect::<Vec<_>>();
+        let pending = self.buffered as u64;
+        self.drop.reserve(pending, self.quota)?;
+        let mut written = 0;
+        let drop = &mut self.drop;
+        let result = write(&self.file, &mut bufs, self.max_iov, &mut |n| {
+            drop.wrote(n as u64);
+            written += n as u64;
+        });
+        if let Err(error) = result {
+            self.drop.unreserve(pending - written);
+            self.undo_flush(offset);
+            if error.kind() == ErrorKind::WriteZero {
+                return Err(StorageError::WriteZero {
+                    path: self.drop.path.clone(),
+                    attempts: MAX_ZERO_WRITES,
+                });
+            }
+            return Err(storage_error(error, &self.drop.path));
+        }
+        self.drop.sync_allocated(&self.file);
+        if self.write_verify {
+            if let Err(error) = verify_write(&self.file, offset, &self.buffers) {
+                self.undo_flush(offset);
+                return Err(error);
+            }
+        }
+        self.flushed = std::mem::take(&mut self.buffers);
+        self.buffered = 0;
+        self.buffer_permit.release();
+        self.flushes += 1;
+        self.publish();
+
+        let latency = self.clock.elapsed_since(start);
+        histogram!(FLUSH_LATENCY).record(latency.as_secs_f64());
+        self.flush_threshold.record(latency);
+        Ok(())
+    }
+
+    /// Undoes a flush that failed after writing some or all of the buffers,
+    /// by cutting the file back to `offset`, where the flush started.  The
+    /// buffers stay in place, so the next flush writes all of them again at
+    /// the same offset instead of after the data already written.
+    fn undo_flush(&mut self, offset: u64) {
+        if self.drop.size == offset {
+            return;
+        }
+        let result = self
+            .file
+            .set_len(offset.max(self.preallocated))
+            .and_then(|()| self.file.seek(SeekFrom::Start(offset)));
+        if let Err(error) = result {
+            warn!(
+                "{}: failed to undo partial flush: {error}",
+                self.drop.path.display()
+            );
+        }
+        self.drop.truncate(offset);
+        self.drop.sync_allocated(&self.file);
+    }
+
+    fn write(&mut self, buffer: &Arc<FBuf>) -> Result<(), StorageError> {
+        if self.prepared {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        if let Some(quota) = self.quota {
+            // Fail as soon as the data couldn't be flushed, instead of
+            // buffering it until the next flush.
+            let used = self.drop.usage.load(Ordering::Relaxed);
+            let mut pending = (self.buffered + buffer.len()) as u64;
+            if !self.drop.counted {
+                pending += self.drop.size;
+            }
+            if used + pending as i64 > quota as i64 {
+                return Err(StorageError::QuotaExceeded {
+                    used: used.max(0) as u64,
+                    quota,
+                });
+            }
+        }
+        let over_max = self
+            .max_buffer
+            .is_some_and(|max| self.buffered + buffer.len() > max);
+        if self.buffered >= self.flush_threshold.get()
+            || self.buffers.len() >= self.max_iov
+            || (over_max && !self.buffers.is_empty())
+        {
+            self.flush()?;
+        }
+        if !self.buffer_permit.try_acquire(buffer.len()) {
+            // Flush our own buffers before waiting for other writers to flush
+            // theirs, so that we never wait while holding buffer space.
+            if !self.buffers.is_empty() {
+                self.flush()?;
+            }
+            self.buffer_permit.acquire(buffer.len());
+        }
+        self.len += buffer.len() as u64;
+        self.buffered += buffer.len();
+        self.buffers.push(buffer.clone());
+        Ok(())
+    }
+}
+
+/// Reads back `buffers`, which were just written consecutively starting at
+/// `offset` in `file`, and checks that the data matches.
+fn verify_write(
+    file: &impl FileExt,
+    mut offset: u64,
+    buffers: &[Arc<FBuf>],
+) -> Result<(), StorageError> {
+    // Use an aligned buffer, in case of direct I/O.
+    let mut data = FBuf::new();
+    for buffer in buffers {
+        data.clear();
+        data.resize(buffer.len(), 0);
+        file.read_exact_at(data.as_mut_slice(), offset)?;
+        if let Some(index) = data.iter().zip(buffer.iter()).position(|(a, b)| a != b) {
+            return Err(StorageError::WriteVerifyFailed {
+                offset: offset + index as u64,
+            });
+        }
+        offset += buffer.len() as u64;
+    }
+    Ok(())
+}
+
+/// State of the backend needed to satisfy the storage APIs.
+pub struct PosixBackend {
+    /// Directory in which we keep the files.
+    base: Arc<PathBuf>,
+
+    /// Cache configuration.
+    cache: StorageCacheConfig,
+
+    /// Additional flags for opening files.
+    open_flags: StorageOpenFlags,
+
+    /// Usage.
+    usage: Arc<AtomicI64>,
+
+    /// Limits concurrent flushes across all writers.
+    flush_limiter: Arc<FlushLimiter>,
+
+    /// How much writers buffer before flushing.
+    flush_threshold: Arc<FlushThreshold>,
+
+    /// How readers size their buffers.
+    read_allocation: Arc<ReadAllocation>,
+
+    /// How completing a file makes it durable.
+    durability: DurabilityMode,
+
+    /// Whether the filesystem rejected direct I/O, so that we fell back to
+    /// the page cache.
+    direct_io_rejected: Arc<AtomicBool>,
+
+    /// Whether usage underflow panics instead of clamping to zero.
+    strict_usage: bool,
+
+    /// Whether writers read back and compare each block they write.
+    write_verify: bool,
+
+    /// Whether writers record block checksums.
+    block_checksums: bool,
+
+    /// What counts toward usage.
+    usage_policy: UsagePolicy,
+
+    /// Whether files count toward usage by their allocated storage instead
+    /// of their lengths.
+    physical_usage: bool,
+
+    /// Maximum usage, if any.
+    quota_bytes: Option<u64>,
+
+    /// Free space below which creating files fails, if any.
+    min_free_space: Option<u64>,
+
+    /// Maximum number of bytes that one writer buffers, if any.
+    max_writer_buffer: Option<usize>,
+
+    /// Limits the number of bytes that all of our writers buffer.
+    write_buffer_limiter: Arc<WriteBufferLimiter>,
+
+    /// Maximum number of buffers that a writer passes to one write.
+    max_iov: usize,
+
+    /// Largest block that a reader will read, if any.
+    max_block_size: Option<usize>,
+
+    /// Cache of blocks shared by our readers, if any.
+    block_cache: Option<Arc<BlockCache>>,
+
+    /// Pool of buffers shared by our readers, if any.
+    read_pool: Option<Arc<ReadBufferPool>>,
+
+    /// Permissions for the files we create, if not the default.
+    file_mode: Option<u32>,
+
+    /// Statistics for the files we have open.
+    files: Arc<FileRegistry>,
+
+    /// Number of files that our readers and writers hold open.
+    open_files: Arc<AtomicUsize>,
+
+    /// Maximum number of files that our readers and writers may hold open,
+    /// if any.
+    max_open_files: Option<usize>,
+
+    /// Files that our writers are writing.
+    live_files: Arc<LiveFiles>,
+
+    /// Files that our writers are appending to.
+    appending_files: Arc<AppendingFiles>,
+
+    /// Readers that [StorageBackend::open] shares, if enabled.
+    shared_readers: Option<Arc<SharedReaders>>,
+
+    /// Whether deleting moves files into [TRASH_DIRECTORY] instead of
+    /// unlinking them.
+    trash: bool,
+
+    /// Number of bytes in [TRASH_DIRECTORY], which don't count in `usage`.
+    trash_usage: Arc<AtomicI64>,
+
+    /// How [StorageBackend::open] retries a file that doesn't exist, if at
+    /// all.
+    open_retry: Option<OpenRetryConfig>,
+
+    /// Thread that deletes dropped temporary files, if enabled.
+    deletion_queue: Option<Arc<DeletionQueue>>,
+
+    /// Directory in which writers write files until they are completed, if
+    /// not `base`.  See [PosixBackend::with_scratch_base].
+    scratch_base: Option<Arc<PathBuf>>,
+
+    /// Maximum size of a file that [StorageBackend::open] maps into memory,
+    /// if any.
+    mmap_threshold: Option<u64>,
+
+    /// Source of timestamps.
+    clock: Arc<dyn StorageClock>,
+}
+
+impl PosixBackend {
+    /// Instantiates a new backend.
+    ///
+    /// ## Parameters
+    /// - `base`: Directory in which we keep the files.
+    ///   shared among all instances of the backend.
+    pub fn new<P: AsRef<Path>>(base: P, cache: StorageCacheConfig) -> Self {
+        init();
+        Self {
+            base: Arc::new(base.as_ref().to_path_buf()),
+            cache,
+            open_flags: StorageOpenFlags::default(),
+            usage: Arc::new(AtomicI64::new(0)),
+            flush_limiter: Arc::new(FlushLimiter::new(None)),
+            flush_threshold: Arc::new(FlushThreshold::new(DEFAULT_FLUSH_THRESHOLD, None)),
+            read_allocation: Arc::new(ReadAllocation::default()),
+            durability: DurabilityMode::default(),
+            direct_io_rejected: Arc::new(AtomicBool::new(false)),
+            strict_usage: false,
+            write_verify: false,
+            block_checksums: false,
+            usage_policy: UsagePolicy::default(),
+            physical_usage: false,
+            quota_bytes: None,
+            min_free_space: None,
+            max_writer_buffer: None,
+            write_buffer_limiter: Arc::new(WriteBufferLimiter::new(None)),
+            max_iov: *IOV_MAX,
+            max_block_size: None,
+            block_cache: None,
+            read_pool: None,
+            file_mode: None,
+            files: Arc::new(FileRegistry::default()),
+            open_files: Arc::new(AtomicUsize::new(0)),
+            max_open_files: None,
+            live_files: Arc::new(LiveFiles::default()),
+            appending_files: Arc::new(AppendingFiles::default()),
+            shared_readers: None,
+            trash: false,
+            trash_usage: Arc::new(AtomicI64::new(0)),
+            open_retry: None,
+            deletion_queue: None,
+            scratch_base: None,
+            mmap_threshold: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Returns this backend, modified to take timestamps, and to time writes
+    /// and flushes, with `clock` instead of [SystemClock].
+    pub fn with_clock(mut self, clock: Arc<dyn StorageClock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns this backend, modified to adapt the amount of data that its
+    /// writers buffer before flushing to the latency of recent flushes (if
+    /// `adaptive_flush` is `Some`).  See [AdaptiveFlushConfig].
+    pub fn with_adaptive_flush(mut self, adaptive_flush: Option<AdaptiveFlushConfig>) -> Self {
+        self.flush_threshold = Arc::new(FlushThreshold::new(
+            self.flush_threshold.initial,
+            adaptive_flush,
+        ));
+        self
+    }
+
+    /// Returns this backend, modified so that its writers flush whenever they
+    /// have buffered `flush_threshold` bytes, instead of 1 MiB.  With adaptive
+    /// flushing, this is the threshold to start from.  Values smaller than a
+    /// page are rounded up to a page.  See [StorageConfig::flush_threshold].
+    pub fn with_flush_threshold(mut self, flush_threshold: usize) -> Self {
+        self.flush_threshold = Arc::new(FlushThreshold::new(
+            flush_threshold.max(page_size()),
+            self.flush_threshold.adaptive,
+        ));
+        self
+    }
+
+    /// Returns the number of bytes that this backend's writers currently
+    /// buffer before flushing.
+    pub fn flush_threshold(&self) -> usize {
+        self.flush_threshold.get()
+    }
+
+    /// Returns this backend, modified to count storage usage according to
+    /// `usage_policy`.  See [UsagePolicy].
+    pub fn with_usage_policy(mut self, usage_policy: UsagePolicy) -> Self {
+        self.usage_policy = usage_policy;
+        self
+    }
+
+    /// Returns this backend, modified to count the storage allocated to each
+    /// file toward usage, instead of the file's length (if `physical_usage`
+    /// is true).  These differ for sparse files and because storage is
+    /// allocated in blocks.  Writers update their files' usage after each
+    /// flush.  See [StorageConfig::physical_usage].
+    pub fn with_physical_usage(mut self, physical_usage: bool) -> Self {
+        self.physical_usage = physical_usage;
+        self
+    }
+
+    /// Returns the number of bytes that the regular file described by
+    /// `metadata` contributes to usage when it counts.
+    fn usage_size(&self, metadata: &Metadata) -> u64 {
+        if self.physical_usage {
+            allocated_size(metadata)
+        } else {
+            metadata.size()
+        }
+    }
+
+    /// Returns the number of bytes that the regular file at `path`, which is
+    /// `len` bytes long, contributes to usage when it counts.
+    fn usage_size_at(&self, path: &Path, len: u64) -> u64 {
+        match fs::metadata(path) {
+            Ok(metadata) if self.physical_usage => allocated_size(&metadata),
+            _ => len,
+        }
+    }
+
+    /// Returns this backend, modified so that creating or writing files fails
+    /// with [StorageError::QuotaExceeded] instead of taking usage past
+    /// `quota_bytes` (if it is `Some`).  See [StorageConfig::quota_bytes].
+    pub fn with_quota(mut self, quota_bytes: Option<u64>) -> Self {
+        self.quota_bytes = quota_bytes;
+        self
+    }
+
+    /// Returns this backend, modified so that creating files fails with
+    /// [StorageError::InsufficientSpace] when the filesystem has less than
+    /// `min_free_space` bytes free (if it is `Some`).  See
+    /// [StorageConfig::min_free_space_bytes].
+    pub fn with_min_free_space(mut self, min_free_space: Option<u64>) -> Self {
+        self.min_free_space = min_free_space;
+        self
+    }
+
+    /// Returns this backend, modified to read back every block that it writes
+    /// and compare it to the data written (if `write_verify` is true).  See
+    /// [StorageConfig::write_verify].
+    pub fn with_write_verify(mut self, write_verify: bool) -> Self {
+        self.write_verify = write_verify;
+        self
+    }
+
+    /// Returns this backend, modified to panic if its usage accounting would
+    /// go negative (if `strict_usage` is true) instead of clamping it to zero
+    /// with a warning (the default).  This is useful for catching accounting
+    /// bugs in tests.
+    pub fn with_strict_usage(mut self, strict_usage: bool) -> Self {
+        self.strict_usage = strict_usage;
+        self
+    }
+
+    /// Returns this backend, modified to sync file metadata along with data
+    /// when it completes a file (if `sync_metadata` is true, the default) or to
+    /// sync only data (otherwise).  See [StorageConfig::sync_metadata] for the
+    /// durability implications.
+    pub fn with_sync_metadata(mut self, sync_metadata: bool) -> Self {
+        self.durability = if sync_metadata {
+            DurabilityMode::SyncAll
+        } else {
+            DurabilityMode::SyncData
+        };
+        self
+    }
+
+    /// Returns this backend, modified to make files durable according to
+    /// `durability` when it completes them.  See [DurabilityMode].
+    pub fn with_durability(mut self, durability: DurabilityMode) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Returns this backend, modified to size read buffers according to
+    /// `read_allocation`.  The default, [ReadAllocation::Exact], allocates
+    /// exactly as much as each read needs.
+    pub fn with_read_allocation(mut self, read_allocation: ReadAllocation) -> Self {
+        self.read_allocation = Arc::new(read_allocation);
+        self
+    }
+
+    /// Returns this backend, modified so that its writers pass at most
+    /// `max_iov` buffers to each write and flush once they have buffered that
+    /// many blocks (if it is `Some`), instead of the system's `IOV_MAX`.
+    /// Values larger than the system's limit are clamped to it, and 0 is
+    /// treated as 1.  This is useful for testing and tuning.
+    pub fn with_max_iov(mut self, max_iov: Option<usize>) -> Self {
+        self.max_iov = max_iov.map_or(*IOV_MAX, |max_iov| max_iov.clamp(1, *IOV_MAX));
+        self
+    }
+
+    /// Returns this backend, modified so that its readers reject requests to
+    /// read a block bigger than `max_block_size` bytes (if it is `Some`) with
+    /// [StorageError::BlockTooLarge], before allocating memory for it.  This
+    /// keeps a corrupt block location from exhausting memory.
+    pub fn with_max_block_size(mut self, max_block_size: Option<usize>) -> Self {
+        self.max_block_size = max_block_size;
+        self
+    }
+
+    /// Returns this backend, modified so that its readers look up blocks in
+    /// `block_cache` (if it is `Some`) before reading them from their files,
+    /// and add the blocks that they read to it.  The cache may be shared with
+    /// other backends.  See [StorageConfig::block_cache_bytes].
+    pub fn with_block_cache(mut self, block_cache: Option<Arc<BlockCache>>) -> Self {
+        self.block_cache = block_cache;
+        self
+    }
+
+    /// Returns this backend, modified so that its readers take the buffers
+    /// for the blocks they read from `read_pool` (if it is `Some`), which
+    /// reuses the buffers of blocks that are no longer referenced.  The pool
+    /// may be shared with other backends.  See
+    /// [StorageConfig::read_buffer_pool_buffers].
+    pub fn with_read_buffer_pool(mut self, read_pool: Option<Arc<ReadBufferPool>>) -> Self {
+        self.read_pool = read_pool;
+        self
+    }
+
+    /// Returns this backend, modified to create files with permissions
+    /// `file_mode` (if it is `Some`), and directories with the same
+    /// permissions plus search permission wherever `file_mode` grants read
+    /// permission, instead of the default.  The process's umask still
+    /// applies.  Files keep their permissions when they are completed.  See
+    /// [StorageConfig::file_mode].
+    pub fn with_file_mode(mut self, file_mode: Option<u32>) -> Self {
+        self.file_mode = file_mode;
+        self
+    }
+
+    /// Returns this backend, modified to open files with `open_flags` in
+    /// addition to the flags implied by the cache configuration.
+    pub fn with_open_flags(mut self, open_flags: StorageOpenFlags) -> Self {
+        self.open_flags = open_flags;
+        self
+    }
+
+    /// Opens `path` with `options` plus the flags for this backend's cache
+    /// and open flags.
+    ///
+    /// If the filesystem rejects direct I/O, as tmpfs does on some kernels,
+    /// this logs a warning and falls back to the page cache, for this file and
+    /// all later ones.
+    fn open_file(&self, options: &OpenOptions, path: &Path) -> Result<File, IoError> {
+        let page_cache = StorageCacheConfig::PageCache;
+        let direct =
+            self.cache.uses_direct_io() && !self.direct_io_rejected.load(Ordering::Relaxed);
+        let cache = if direct { &self.cache } else { &page_cache };
+        match options
+            .clone()
+            .storage_flags(cache, &self.open_flags)
+            .open(path)
+        {
+            Err(error) if direct && error.raw_os_error() == Some(libc::EINVAL) => {
+                if !self.direct_io_rejected.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "{}: filesystem does not support direct I/O, falling back to the page cache",
+                        path.display()
+                    );
+                }
+                options
+                    .clone()
+                    .storage_flags(&page_cache, &self.open_flags)
+                    .open(path)
+            }
+            result => result,
+        }
+    }
+
+    /// Checks that the storage directory is usable.  Returns
+    /// [StorageError::ReadOnlyFilesystem] if it is on a read-only mount.
+    ///
+    /// A storage directory that doesn't exist yet passes the check, since the
+    /// backend creates directories as it needs them.
+    pub fn health_check(&self) -> Result<(), StorageError> {
+        let stat = match statvfs(&self.base) {
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        if stat.f_flag & libc::ST_RDONLY != 0 {
+            return Err(StorageError::ReadOnlyFilesystem(self.base.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    /// Returns the directory in which the backend creates files.
+    pub fn path(&self) -> &Path {
+        self.base.as_path()
+    }
+
+    /// Returns the filesystem path to `name` in this storage.
+    ///
+    /// Each [StoragePathPart] becomes one path component, in its
+    /// percent-encoded form.  Encoding should guarantee that a part is never
+    /// empty, `.`, or `..`, and never contains `/` or control characters, but
+    /// we check each part anyway, failing with [StorageError::InvalidPath] for
+    /// any part that isn't exactly one normal component, so that the result
+    /// always stays inside [Self::path].
+    fn fs_path(&self, name: &StoragePath) -> Result<PathBuf, StorageError> {
+        path_in(&self.base, name)
+    }
+
+    /// Syncs the directory `path`, making the creation, deletion, and renaming
+    /// of the files and directories in it durable.  An empty `path` syncs the
+    /// storage directory itself.
+    ///
+    /// On filesystems that can't sync directories, this does nothing.
+    pub fn sync_directory(&self, path: &StoragePath) -> Result<(), StorageError> {
+        let fs_path = self.fs_path(path)?;
+        sync_dir(&fs_path).map_err(|error| storage_error(error, &fs_path))
+    }
+
+    /// Returns an asynchronous wrapper around this backend, for use from
+    /// within a Tokio runtime.  See [AsyncStorageBackend].
+    pub fn into_async(self) -> AsyncStorageBackend {
+        AsyncStorageBackend::new(Arc::new(self))
+    }
+
+    /// Deletes every file in the storage directory, recursively, that is
+    /// still marked as being written, and logs each one at `warn` level.
+    /// Returns the number of files deleted.
+    ///
+    /// Writers delete their files when they are dropped without being
+    /// completed, so such files are only left behind by a process that
+    /// crashed or otherwise exited abruptly.  Because this also deletes the
+    /// files of writers that are still in progress, it should only be called
+    /// at startup, before creating any writers, while holding the lock on the
+    /// storage directory.  This also deletes incomplete files in the scratch
+    /// directory, if any; see [Self::with_scratch_base].
+    pub fn recover(&self) -> Result<usize, StorageError> {
+        let mut deleted = 0;
+        let counted = self.usage_policy.counts_in_progress();
+        match self.recover_recursive(&self.base, counted, &mut deleted) {
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            result => result.map_err(|error| storage_error(error, &self.base))?,
+        }
+        if let Some(scratch_base) = &self.scratch_base {
+            match self.recover_recursive(scratch_base, false, &mut deleted) {
+                Err(error) if error.kind() == ErrorKind::NotFound => (),
+                result => result.map_err(|error| storage_error(error, scratch_base))?,
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes the incomplete files under `path`, releasing their usage if
+    /// they are `counted`.
+    fn recover_recursive(
+        &self,
+        path: &Path,
+        counted: bool,
+        deleted: &mut usize,
+    ) -> Result<(), IoError> {
+        for child in fs::read_dir(path)? {
+            let child = child?;
+            let path = child.path();
+            let file_type = child.file_type()?;
+            if file_type.is_dir() {
+                self.recover_recursive(&path, counted, deleted)?;
+            } else if file_type.is_file()
+                && path.extension() == Some(OsStr::new(&MUTABLE_EXTENSION[1..]))
+            {
+                let size = child
+                    .metadata()
+                    .map_or(0, |metadata| self.usage_size(&metadata));
+                warn!(
+                    "{}: deleting incomplete file ({size} bytes)",
+                    path.display()
+                );
+                fs::remove_file(&path)?;
+                *deleted += 1;
+                if counted {
+                    // Files left behind by an earlier process were never
+                    // counted in our usage, so don't take away more than
+                    // there is.
+                    let _ =
+                        self.usage
+                            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                                Some((usage - size as i64).max(0))
+                            });
+                    set_usage_gauge(&self.usage);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Recomputes storage usage from scratch, by walking the storage
+    /// directory and adding up the sizes of the files and directories that
+    /// count under our [UsagePolicy], and replaces the backend's usage by the
+    /// result.  Returns the new usage minus the usage that it replaced.
+    ///
+    /// Usage can drift from the truth over time, e.g. when deleting the file
+    /// of a writer that was dropped fails, so this is useful as a periodic
+    /// audit.  It logs a warning if usage had drifted by more than 1 MiB.  A
+    /// file with several hard links counts only once.  Writes and deletions
+    /// that happen while this runs might not be reflected in the result.
+    pub fn recompute_usage(&self) -> Result<i64, StorageError> {
+        let mut inodes = HashSet::new();
+        let mut usage = 0;
+        match self.recompute_usage_recursive(&self.base, &mut inodes, &mut usage) {
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            result => result.map_err(|error| storage_error(error, &self.base))?,
+        }
+
+        let old_usage = self.usage.swap(usage as i64, Ordering::Relaxed);
+        gauge!(STORAGE_USAGE_BYTES).set(usage as f64);
+        let drift = usage as i64 - old_usage;
+        if drift.unsigned_abs() > USAGE_DRIFT_WARNING {
+            warn!(
+                "{}: storage usage had drifted by {drift} bytes, from {old_usage} to {usage}",
+                self.base.display()
+            );
+        }
+        Ok(drift)
+    }
+
+    fn recompute_usage_recursive(
+        &self,
+        path: &Path,
+        inodes: &mut HashSet<(u64, u64)>,
+        usage: &mut u64,
+    ) -> Result<(), IoError> {
+        for child in fs::read_dir(path)? {
+            let child = child?;
+            let path = child.path();
+            if path == self.base.join(TRASH_DIRECTORY) {
+                continue;
+            }
+            let metadata = match child.metadata() {
+                Ok(metadata) => metadata,
+                // Deleted since we read the directory.
+                Err(error) if error.kind() == ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            if metadata.is_dir() {
+                if self.usage_policy.counts_directories() {
+                    *usage += metadata.size();
+                }
+                match self.recompute_usage_recursive(&path, inodes, usage) {
+                    Err(error) if error.kind() == ErrorKind::NotFound => (),
+                    result => result?,
+                }
+            } else if metadata.is_file()
+                && self.counts_file(&path)
+                && (metadata.nlink() <= 1 || inodes.insert((metadata.dev(), metadata.ino())))
+            {
+                *usage += self.usage_size(&metadata);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the regular file at `path` counts toward usage under
+    /// our [UsagePolicy].
+    fn counts_file(&self, path: &Path) -> bool {
+        self.usage_policy.counts_in_progress()
+            || path.extension() != Some(OsStr::new(&MUTABLE_EXTENSION[1..]))
+    }
+
+    /// Creates `parent`, the directory in the storage directory for `name`,
+    /// along with any missing parent directories, and makes their entries
+    /// durable unless durability is [DurabilityMode::None].
+    fn create_parents(&self, name: &StoragePath, parent: &Path) -> Result<(), StorageError> {
+        let missing = parent
+            .ancestors()
+            .take_while(|ancestor| !ancestor.exists())
+            .count();
+        self.create_dir_all(parent)
+            .map_err(|error| storage_error(error, &self.base))?;
+
+        // Make the new directories' entries durable.  The entry for the file
+        // itself becomes durable when it is completed.
+        if self.durability != DurabilityMode::None {
+            let parts = name.parts().collect::<Vec<_>>();
+            let parent_parts = parts.len().saturating_sub(1);
+            for depth in (parent_parts.saturating_sub(missing)..parent_parts).rev() {
+                let dir = parts[..depth].iter().cloned().collect::<StoragePath>();
+                self.sync_directory(&dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates `path` and any missing parent directories, adding the space
+    /// they take to usage if our [UsagePolicy] counts directories.
+    fn create_dir_all(&self, path: &Path) -> Result<(), IoError> {
+        let create = |path: &Path| match self.file_mode {
+            Some(mode) => fs::DirBuilder::new()
+                .recursive(true)
+                .mode(dir_mode(mode))
+                .create(path),
+            None => create_dir_all(path),
+        };
+        if !self.usage_policy.counts_directories() {
+            return create(path);
+        }
+        let missing = path
+            .ancestors()
+            .take_while(|ancestor| !ancestor.exists())
+            .collect::<Vec<_>>();
+        create(path)?;
+        for dir in missing {
+            let size = fs::metadata(dir).map_or(0, |metadata| metadata.size());
+            add_usage(&self.usage, size);
+        }
+        Ok(())
+    }
+
+    /// Makes file `name` immutable at the operating system level, so that it
+    /// can't be modified or deleted, even by this backend, until it is
+    /// unfrozen with [unfreeze](Self::unfreeze).  This is useful for
+    /// protecting files that belong to a committed checkpoint.
+    ///
+    /// This is only supported on Linux, and it requires the
+    /// `CAP_LINUX_IMMUTABLE` capability and a filesystem that supports the
+    /// immutable flag.
+    pub fn freeze(&self, name: &StoragePath) -> Result<(), StorageError> {
+        Ok(immutable::set(&self.fs_path(name)?, true)?)
+    }
+
+    /// Reverses [freeze](Self::freeze) for file `name`.
+    pub fn unfreeze(&self, name: &StoragePath) -> Result<(), StorageError> {
+        Ok(immutable::set(&self.fs_path(name)?, false)?)
+    }
+
+    /// Converts `error`, from deleting `path` or something under it, into a
+    /// [StorageError], reporting frozen files as [StorageError::Immutable].
+    fn deletion_error(&self, error: IoError, path: &Path) -> StorageError {
+        fn find_immutable(path: &Path) -> Option<PathBuf> {
+            if immutable::get(path) {
+                return Some(path.to_path_buf());
+            }
+            fs::read_dir(path)
+                .ok()?
+                .flatten()
+                .find_map(|entry| find_immutable(&entry.path()))
+        }
+
+        if error.kind() == ErrorKind::PermissionDenied {
+            if let Some(path) = find_immutable(path) {
+                return StorageError::Immutable(path);
+            }
+        }
+        storage_error(error, &self.base)
+    }
+
+    /// Returns the name of the file with `file_id`, if this backend currently
+    /// has it open for reading or writing.  This maps a [FileId] in a log
+    /// message back to the file it refers to.
+    pub fn path_for(&self, file_id: FileId) -> Option<StoragePath> {
+        self.files
+            .0
+            .lock()
+            .unwrap()
+            .get(&file_id)
+            .map(|(name, _counters)| name.clone())
+    }
+
+    /// Returns this backend, modified so that, if `shared_readers` is true,
+    /// [StorageBackend::open] returns the same reader for a file that is
+    /// already open through an earlier call, instead of opening it again.
+    /// This saves system calls for files that are opened over and over, and
+    /// keeps the reader's [FileId] the same.  A reader stops being shared once
+    /// it is dropped, or when the file is deleted, renamed, or replaced.  See
+    /// [StorageConfig::shared_readers].
+    pub fn with_shared_readers(mut self, shared_readers: bool) -> Self {
+        self.shared_readers = shared_readers.then(|| Arc::new(SharedReaders::default()));
+        self
+    }
+
+    /// Returns this backend, modified so that, if `open_retry` is `Some`,
+    /// [StorageBackend::open] waits and tries again when the file doesn't
+    /// exist, up to the configured number of attempts, instead of failing
+    /// with [StorageError::NotFound] right away.
+    ///
+    /// This only helps with coordination within this process, such as a
+    /// reader racing with another thread that is completing the file: it
+    /// can't tell whether a file will ever appear, so it just gives up after
+    /// the last attempt.  See [StorageConfig::open_retry].
+    pub fn with_open_retry(mut self, open_retry: Option<OpenRetryConfig>) -> Self {
+        self.open_retry = open_retry;
+        self
+    }
+
+    /// Opens `name` for [StorageBackend::open], without retrying.
+    fn open_once(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        if let Some(reader) = self
+            .shared_readers
+            .as_ref()
+            .and_then(|shared_readers| shared_readers.get(name))
+        {
+            return Ok(reader);
+        }
+        match PosixReader::open(self.fs_path(name)?, name, self) {
+            Err(StorageError::NotFound(error)) => match self.live_files.get(name) {
+                Some(live) => PosixReader::open_live(live, name, self),
+                None => Err(StorageError::NotFound(error)),
+            },
+            Ok(reader) => {
+                if let Some(shared_readers) = &self.shared_readers {
+                    shared_readers.insert(name, &reader);
+                }
+                Ok(reader)
+            }
+            result => result,
+        }
+    }
+
+    /// Stops sharing the readers for `name` and everything under it.
+    fn invalidate_readers(&self, name: &StoragePath) {
+        if let Some(shared_readers) = &self.shared_readers {
+            shared_readers.invalidate(name);
+        }
+    }
+
+    /// Returns the names and statistics of the `n` files that have had the
+    /// most bytes read from them, most-read first, among the files that this
+    /// backend currently has open for reading or writing.
+    pub fn top_files(&self, n: usize) -> Vec<(StoragePath, FileStats)> {
+        let mut files = self
+            .files
+            .0
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(name, counters)| (name.clone(), counters.get()))
+            .collect::<Vec<_>>();
+        files.sort_by(|(_, a), (_, b)| b.bytes_read.cmp(&a.bytes_read));
+        files.truncate(n);
+        files
+    }
+
+    /// Returns this backend, modified so that, if `scratch_base` is `Some`,
+    /// writers write files in that directory instead of the storage
+    /// directory, and completing a file moves it into the storage directory.
+    /// This allows in-progress files to live on fast, ephemeral storage.  If
+    /// the two directories are on different filesystems, completing a file
+    /// copies it, which takes longer than a rename.
+    ///
+    /// Files in the scratch directory don't count toward usage until they are
+    /// completed, regardless of the [UsagePolicy].  See
+    /// [StorageConfig::scratch_path].
+    pub fn with_scratch_base(mut self, scratch_base: Option<PathBuf>) -> Self {
+        self.scratch_base = scratch_base.map(Arc::new);
+        self
+    }
+
+    /// Evicts the blocks of the open files named `name` from the block
+    /// cache, if there is one.
+    fn evict_blocks(&self, name: &StoragePath) {
+        if let Some(cache) = &self.block_cache {
+            self.files
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_file_id, (file_name, _counters))| file_name == name)
+                .for_each(|(file_id, _)| cache.evict(*file_id));
+        }
+    }
+
+    /// Lists the entries in `parent`, like [StorageBackend::list], skipping
+    /// those whose file names don't match `glob`, if it is provided.
+    fn list_filtered(
+        &self,
+        parent: &StoragePath,
+        glob: Option<&Glob>,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        let mut succeeded = 0;
+        let mut errors = Vec::new();
+        for entry in self.fs_path(parent)?.read_dir()? {
+            match entry {
+                Err(error) => errors.push((None, error.kind())),
+                Ok(entry) => {
+                    let file_name = entry.file_name();
+                    if glob.is_some_and(|glob| !glob.matches(&file_name.to_string_lossy()))
+                        || is_trash(parent, &file_name)
+                    {
+                        continue;
+                    }
+                    let name = child_path(parent, &file_name);
+                    match parse_entry(&entry) {
+                        Err(error) => errors.push((Some(name), error.kind())),
+                        Ok(file_type) => {
+                            succeeded += 1;
+                            cb(&name, file_type);
+                        }
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(StorageError::PartialList { succeeded, errors })
+        }
+    }
+
+    /// Moves `from` to `to` by copying it recursively and then deleting the
+    /// original, for when they are on different filesystems.
+    fn rename_subtree_by_copy(
+        &self,
+        from: &StoragePath,
+        to: &StoragePath,
+    ) -> Result<(), StorageError> {
+        self.copy_recursive(&self.fs_path(from)?, &self.fs_path(to)?)
+            .map_err(|error| storage_error(error, &self.base))?;
+        self.delete_recursive(from)
+    }
+
+    /// Copies the file, directory, or symlink `from` to `to`, adding the
+    /// copies to usage.
+    fn copy_recursive(&self, from: &Path, to: &Path) -> Result<(), IoError> {
+        let file_type = fs::symlink_metadata(from)?.file_type();
+        if file_type.is_dir() {
+            self.create_dir_all(to)?;
+            for entry in fs::read_dir(from)? {
+                let entry = entry?;
+                self.copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+            }
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+        } else {
+            let size = fs::copy(from, to)?;
+            if self.counts_file(to) {
+                let size = self.usage_size_at(to, size);
+                add_usage(&self.usage, size);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), IoError> {
+        let file_type = fs::symlink_metadata(path)?.file_type();
+        if file_type.is_symlink() {
+            fs::remove_file(path)
+        } else {
+            self.remove_dir_all_recursive(path)
+        }
+    }
+
+    fn remove_dir_all_recursive(&self, path: &Path) -> Result<(), IoError> {
+        fn ignore_notfound(result: Result<(), IoError>) -> Result<(), IoError> {
+            match result {
+                Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+                _ => result,
+            }
+        }
+
+        for child in fs::read_dir(path)? {
+            let child = child?;
+            let path = child.path();
+            let result = child.file_type().and_then(|file_type| {
+                if file_type.is_dir() {
+                    self.remove_dir_all_recursive(&path)
+                } else if file_type.is_file() && self.counts_file(&path) {
+                    // Files with other links still take up space.
+                    let size = child.metadata().map_or(0, |metadata| {
+                        if metadata.nlink() > 1 {
+                            0
+                        } else {
+                            self.usage_size(&metadata)
+                        }
+                    });
+                    fs::remove_file(&path).inspect(|_| {
+                        subtract_usage(&self.usage, size, self.strict_usage);
+                    })
+                } else {
+                    fs::remove_file(&path)
+                }
+            });
+            ignore_notfound(result)?;
+        }
+
+        // Measure the directory once it's empty, to match `create_dir_all`.
+        let dir_size = if self.usage_policy.counts_directories() {
+            fs::metadata(path).map_or(0, |metadata| metadata.size())
+        } else {
+            0
+        };
+        ignore_notfound(fs::remove_dir(path).inspect(|_| {
+            if dir_size > 0 {
+                subtract_usage(&self.usage, dir_size, self.strict_usage);
+            }
+        }))
+    }
+}
+
+impl Drop for PosixBackend {
+    /// Waits for the deletion thread, if any, to delete the files that it has
+    /// been sent.
+    fn drop(&mut self) {
+        if let Some(queue) = &self.deletion_queue {
+            queue.shutdown();
+        }
+    }
+}
+
+impl StorageBackend for PosixBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        fn try_create_named(this: &PosixBackend, path: &Path) -> Result<File, IoError> {
+            let mut options = OpenOptions::new();
+            options.create(true).truncate(true).write(true).read(true);
+            if let Some(mode) = this.file_mode {
+                options.mode(mode);
+            }
+            this.open_file(&options, path)
+        }
+
+        if let Some(quota) = self.quota_bytes {
+            let used = self.usage.load(Ordering::Relaxed);
+            if used >= quota as i64 {
+                return Err(StorageError::QuotaExceeded {
+                    used: used as u64,
+                    quota,
+                });
+            }
+        }
+        if let Some(reserve) = self.min_free_space {
+            let available = self.available_space()?;
+            if available < reserve {
+                return Err(StorageError::InsufficientSpace { available, reserve });
+            }
+        }
+
+        self.check_open_files()?;
+        let final_path = self.fs_path(name)?;
+        let path = match &self.scratch_base {
+            Some(scratch_base) => {
+                // Completing the file moves it into its directory in the
+                // storage directory, so that has to exist.
+                if let Some(parent) = final_path.parent().filter(|parent| !parent.exists()) {
+                    self.create_parents(name, parent)?;
+                }
+                append_to_path(path_in(scratch_base, name)?, MUTABLE_EXTENSION)
+            }
+            None => append_to_path(final_path.clone(), MUTABLE_EXTENSION),
+        };
+        let file = match try_create_named(self, &path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                if let Some(parent) = path.parent() {
+                    if self.scratch_base.is_some() {
+                        // The scratch directory is ephemeral, so its
+                        // directories neither count toward usage nor need to
+                        // be durable.
+                        create_dir_all(parent).map_err(|error| storage_error(error, parent))?;
+                    } else {
+                        self.create_parents(name, parent)?;
+                    }
+                }
+                try_create_named(self, &path)
+            }
+            other => other,
+        }
+        .map_err(|error| storage_error(error, &self.base))?;
+        set_created_at(&file, self.clock.now());
+        counter!(FILES_CREATED).increment(1);
+        let mut writer = PosixWriter::new(file, name.clone(), path.clone(), self);
+        writer.live = Some(LiveRegistration::new(&self.live_files, name.clone(), path));
+        writer.final_path = self.scratch_base.is_some().then_some(final_path);
+        Ok(Box::new(writer))
+    }
+
+    /// Returns the space available to unprivileged users on the filesystem
+    /// that holds the storage directory, or the nearest of its ancestors
+    /// that exists if the storage directory doesn't exist yet.
+    fn available_space(&self) -> Result<u64, StorageError> {
+        let path = self
+            .base
+            .ancestors()
+            .find(|path| path.exists())
+            .unwrap_or(&self.base);
+        let stat = statvfs(path)?;
+        // The types of these fields vary from one platform to another.
+        #[allow(clippy::unnecessary_cast)]
+        let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+        Ok(available)
+    }
+
+    /// If `name` doesn't exist but one of our writers is writing it, this
+    /// returns a reader that can read the data that the writer has flushed so
+    /// far, and fails reads beyond that with [StorageError::NotYetWritten].
+    /// Once the writer completes the file, the reader can read all of it.
+    ///
+    /// With [PosixBackend::with_shared_readers], opening a file that is
+    /// already open returns the same reader.  With
+    /// [PosixBackend::with_open_retry], opening a file that doesn't exist
+    /// waits and tries again.
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        let Some(open_retry) = self.open_retry else {
+            return self.open_once(name);
+        };
+        let mut attempt = 1;
+        loop {
+            match self.open_once(name) {
+                Err(StorageError::NotFound(_)) if attempt < open_retry.attempts => {
+                    attempt += 1;
+                    std::thread::sleep(Duration::from_millis(open_retry.delay_ms));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The file stays in storage even if the writer is dropped without being
+    /// completed, with whatever blocks it had flushed by then.  With
+    /// [PosixBackend::with_block_checksums], files can't be appended to,
+    /// because their checksums are in a trailer at the end of the file.
+    fn open_append(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        self.check_open_files()?;
+        let path = self.fs_path(name)?;
+        // Register before checking the file's size, so that any reader that
+        // maps the file maps no more than that.
+        let appending = AppendRegistration::new(&self.appending_files, name.clone());
+        let mut file = self
+            .open_file(OpenOptions::new().read(true).write(true), &path)
+            .map_err(|error| storage_error(error, &path))?;
+        let metadata = file.metadata()?;
+        let size = metadata.size();
+        if self.block_checksums {
+            return Err(StorageError::StdIo(ErrorKind::Unsupported));
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let mut writer = PosixWriter::new(file, name.clone(), path.clone(), self);
+        writer.len = size;
+        // Keep the file when the placeholder that we're replacing drops.
+        writer.drop.keep();
+        writer.drop = DeleteOnDrop::new(path, name.clone(), true, size, true, self)
+            .with_usage_size(self.usage_size(&metadata));
+        writer.append_from = Some(size);
+        writer.appending = Some(appending);
+        Ok(Box::new(writer))
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        self.list_filtered(parent, None, cb)
+    }
+
+    /// Reads the metadata only of the entries in the page that it returns.
+    fn list_paginated(
+        &self,
+        parent: &StoragePath,
+        after: Option<StoragePath>,
+        limit: usize,
+    ) -> Result<(Vec<(StoragePath, StorageFileType)>, Option<StoragePath>), StorageError> {
+        if limit == 0 {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let mut errors = Vec::new();
+        let mut candidates = Vec::new();
+        for entry in self.fs_path(parent)?.read_dir()? {
+            match entry {
+                Err(error) => errors.push((None, error.kind())),
+                Ok(entry) if is_trash(parent, &entry.file_name()) => (),
+                Ok(entry) => {
+                    let name = child_path(parent, &entry.file_name());
+                    if after.as_ref().is_none_or(|after| &name > after) {
+                        candidates.push((name, entry));
+                    }
+                }
+            }
+        }
+        candidates.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Take one extra entry, so that `paginate` can tell whether there are
+        // more.
+        let mut entries = Vec::new();
+        for (name, entry) in candidates.into_iter().take(limit + 1) {
+            match parse_entry(&entry) {
+                Err(error) => errors.push((Some(name), error.kind())),
+                Ok(file_type) => entries.push((name, file_type)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(paginate(entries, limit))
+        } else {
+            Err(StorageError::PartialList {
+                succeeded: entries.len(),
+                errors,
+            })
+        }
+    }
+
+    /// Matches `pattern` against each directory entry's file name before
+    /// constructing its [StoragePath] or reading its metadata, so that
+    /// entries that don't match cost little.
+    fn list_matching(
+        &self,
+        parent: &StoragePath,
+        pattern: &str,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        self.list_filtered(parent, Some(&Glob::new(pattern)?), cb)
+    }
+
+    /// Renames `from` to `to` with a single `rename` system call, which is
+    /// atomic.  If they are on different filesystems, falls back to copying
+    /// and then deleting `from`, which is not atomic.  Usage only changes in
+    /// the latter case.
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let from_path = self.fs_path(from)?;
+        let to_path = self.fs_path(to)?;
+        self.invalidate_readers(from);
+        self.invalidate_readers(to);
+        if let Some(parent) = to_path.parent() {
+            self.create_dir_all(parent)
+                .map_err(|error| storage_error(error, &self.base))?;
+        }
+        match fs::rename(&from_path, &to_path) {
+            Err(error) if error.raw_os_error() == Some(libc::EXDEV) => {
+                self.rename_subtree_by_copy(from, to)
+            }
+            Err(error) => Err(storage_error(error, &self.base)),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Renames `from` to `to` with a single `rename` system call, which
+    /// replaces `to` atomically.  If they are on different filesystems, falls
+    /// back to copying and then deleting `from`.
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let from_path = self.fs_path(from)?;
+        let to_path = self.fs_path(to)?;
+        self.invalidate_readers(from);
+        self.invalidate_readers(to);
+        if let Some(parent) = to_path.parent() {
+            self.create_dir_all(parent)
+                .map_err(|error| storage_error(error, &self.base))?;
+        }
+
+        // Space taken by a file that `to` replaces, which renaming frees.
+        let replaced = fs::metadata(&to_path)
+            .ok()
+            .filter(|metadata| {
+                from_path != to_path
+                    && metadata.is_file()
+                    && metadata.nlink() == 1
+                    && self.counts_file(&to_path)
+            })
+            .map(|metadata| self.usage_size(&metadata));
+        match fs::rename(&from_path, &to_path) {
+            Err(error) if error.raw_os_error() == Some(libc::EXDEV) => {
+                self.copy_recursive(&from_path, &to_path)
+                    .map_err(|error| storage_error(error, &self.base))?;
+                self.delete(from)?;
+            }
+            Err(error) => return Err(storage_error(error, &self.base)),
+            Ok(()) => (),
+        }
+        if let Some(size) = replaced {
+            subtract_usage(&self.usage, size, self.strict_usage);
+        }
+        Ok(())
+    }
+
+    /// Copies `from` to `to` within the kernel where possible, without
+    /// passing the data through any [FileReader] or [FileWriter].
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let from_path = self.fs_path(from)?;
+        let to_path = self.fs_path(to)?;
+        let mut source = File::open(&from_path)?;
+        if let Some(quota) = self.quota_bytes {
+            let used = self.usage.load(Ordering::Relaxed);
+            if used + self.usage_size(&source.metadata()?) as i64 > quota as i64 {
+                return Err(StorageError::QuotaExceeded {
+                    used: used.max(0) as u64,
+                    quota,
+                });
+            }
+        }
+        if let Some(parent) = to_path.parent() {
+            self.create_dir_all(parent)
+                .map_err(|error| storage_error(error, &self.base))?;
+        }
+
+        // `create_new` fails with `AlreadyExists` if `to` exists.
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        if let Some(mode) = self.file_mode {
+            options.mode(mode);
+        }
+        let mut destination = options
+            .open(&to_path)
+            .map_err(|error| storage_error(error, &self.base))?;
+        let result = std::io::copy(&mut source, &mut destination).and_then(|size| {
+            sync_file(&destination, self.durability)?;
+            Ok(size)
+        });
+        match result {
+            Ok(size) => {
+                if self.counts_file(&to_path) {
+                    let size = self.usage_size_at(&to_path, size);
+                    add_usage(&self.usage, size);
+                }
+                Ok(())
+            }
+            Err(error) => {
+                let _ = fs::remove_file(&to_path);
+                Err(storage_error(error, &self.base))
+            }
+        }
+    }
+
+    /// Writes out and syncs all of the files, then renames them all, and then
+    /// syncs each of the directories they are in once.
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        let completed = complete_in_two_phases(writers)?;
+        if self.durability != DurabilityMode::None {
+            let mut dirs = HashSet::new();
+            for (_reader, name) in &completed {
+                if let Some(parent) = self.fs_path(name)?.parent() {
+                    if dirs.insert(parent.to_path_buf()) {
+                        sync_dir(parent).map_err(|error| storage_error(error, &self.base))?;
+                    }
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// With [PosixBackend::with_trash], moves the file into the trash.
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let path = self.fs_path(name)?;
+        let metadata = fs::metadata(&path)?;
+        if self.trash && !metadata.is_dir() {
+            return self.move_to_trash(name);
+        }
+        fs::remove_file(&path).map_err(|error| self.deletion_error(error, &path))?;
+        self.invalidate_readers(name);
+        self.evict_blocks(name);
+        if metadata.file_type().is_file() && metadata.nlink() == 1 && self.counts_file(&path) {
+            subtract_usage(&self.usage, self.usage_size(&metadata), self.strict_usage);
+        }
+        Ok(())
+    }
+
+    /// With [PosixBackend::with_trash], moves `name` into the trash, or, for
+    /// the storage directory itself, everything in it.
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let path = self.fs_path(name)?;
+        if self.trash {
+            if name.parts().next().is_none() {
+                let mut children = Vec::new();
+                match self.list(name, &mut |child, _file_type| children.push(child.clone())) {
+                    Err(StorageError::NotFound(_)) => (),
+                    result => result?,
+                }
+                for child in children {
+                    self.delete_recursive(&child)?;
+                }
+                return Ok(());
+            }
+            return match self.move_to_trash(name) {
+                Err(StorageError::NotFound(_)) => Ok(()),
+                result => result,
+            };
+        }
+        self.invalidate_readers(name);
+        match self.remove_dir_all(&path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            Err(error) if error.kind() == ErrorKind::NotADirectory => self.delete(name)?,
+            Err(error) => return Err(self.deletion_error(error, &path)),
+            Ok(()) => (),
+        }
+        Ok(())
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.usage.clone()
+    }
+
+    /// Returns the filesystem's preferred I/O size (`st_blksize`) for the
+    /// storage directory, rounded up to a multiple of
+    /// [min_block_size](Self::min_block_size).
+    fn preferred_block_size(&self) -> usize {
+        let min = self.min_block_size();
+        match fs::metadata(&self.base) {
+            Ok(metadata) => (metadata.blksize() as usize).max(min).next_multiple_of(min),
+            Err(_) => 4096,
+        }
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            supports_direct_io: cfg!(target_os = "linux")
+                && self.cache.uses_direct_io()
+                && !self.direct_io_rejected.load(Ordering::Relaxed),
+            max_block_size: None,
+            atomic_rename: true,
+            supports_checksums: true,
+            is_remote: false,
+        }
+    }
+}
+
+/// Returns the [StoragePath] for the file named `file_name` in `parent`.
+///
+/// This is the inverse of [PosixBackend::fs_path].  A file name that is
+/// already a valid percent-encoded part, which is true of every name that
+/// [PosixBackend] creates, is used as-is.  Anything else, such as a non-UTF8
+/// name created by some other program, is percent-encoded, so that the
+/// result still names the same file.
+fn child_path(parent: &StoragePath, file_name: &OsStr) -> StoragePath {
+    let encoded = file_name
+        .to_str()
+        .and_then(|name| StoragePath::parse(name).ok())
+        .filter(|name| name.parts().count() == 1);
+    match encoded {
+        Some(name) => parent.parts().chain(name.parts()).collect(),
+        None => parent.child(StoragePathPart::from(file_name.as_encoded_bytes())),
+    }
+}
+
+pub(crate) struct PosixBackendFactory;
+impl StorageBackendFactory for PosixBackendFactory {
+    fn backend(&self) -> &'static str {
+        "default"
+    }
+
+    fn uri_schemes(&self) -> &'static [&'static str] {
+        &["file"]
+    }
+
+    /// Accepts `file:///<path>` and `file://localhost/<path>`, where `path` is
+    /// an absolute path, without percent-decoding.
+    fn create_from_uri(
+        &self,
+        scheme: &str,
+        location: &str,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let path = location.strip_prefix("localhost").unwrap_or(location);
+        if !path.starts_with('/') {
+            return Err(StorageError::InvalidURL(format!("{scheme}://{location}")));
+        }
+        let storage_config = StorageConfig {
+            path: path.into(),
+            ..StorageConfig::default()
+        };
+        self.create(&storage_config, &StorageBackendConfig::Default)
+    }
+
+    fn create(
+        &self,
+        storage_config: &StorageConfig,
+        _backend_config: &StorageBackendConfig,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let mut backend = PosixBackend::new(storage_config.path(), storage_config.cache)
+            .with_open_flags(storage_config.extra_open_flags)
+            .with_durability(storage_config.durability())
+            .with_write_verify(storage_config.write_verify)
+            .with_block_checksums(storage_config.block_checksums)
+            .with_usage_policy(storage_config.usage_policy)
+            .with_physical_usage(storage_config.physical_usage)
+            .with_quota(storage_config.quota_bytes)
+            .with_min_free_space(storage_config.min_free_space_bytes)
+            .with_mmap_threshold(storage_config.mmap_threshold_bytes)
+            .with_file_mode(storage_config.file_mode)
+            .with_max_open_files(storage_config.max_open_files)
+            .with_shared_readers(storage_config.shared_readers)
+            .with_trash(storage_config.trash)
+            .with_open_retry(storage_config.open_retry)
+            .with_background_deletion(storage_config.background_deletion)?
+            .with_scratch_base(storage_config.scratch_path.as_ref().map(PathBuf::from))
+            .with_read_buffer_pool(
+                storage_config
+                    .read_buffer_pool_buffers
+                    .map(|max_buffers| Arc::new(ReadBufferPool::new(max_buffers))),
+            )
+            .with_block_cache(
+                storage_config
+                    .block_cache_bytes
+                    .map(|capacity| Arc::new(BlockCache::new(capacity))),
+            )
+            .with_max_writer_buffer(storage_config.max_writer_buffer_bytes)
+            .with_max_total_write_buffer(storage_config.max_total_write_buffer_bytes)
+            .with_adaptive_flush(storage_config.adaptive_flush);
+        if let Some(flush_threshold) = storage_config.flush_threshold {
+            let minimum = page_size();
+            if flush_threshold < minimum {
+                return Err(StorageError::InvalidFlushThreshold {
+                    flush_threshold,
+                    minimum,
+                });
+            }
+            backend = backend.with_flush_threshold(flush_threshold);
+        }
+        backend.health_check()?;
+        let backend = Arc::new(backend);
+        Ok(match storage_config.block_compression {
+            Some(compression) => {
+                let mut compressed = CompressedBackend::new(backend, compression.algorithm);
+                if let Some(level) = compression.level {
+                    compressed = compressed.with_level(level);
+                }
+                Arc::new(compressed)
+            }
+            None => backend,
+        })
+    }
+}
+
+inventory::submit! {
+    &PosixBackendFactory as &dyn StorageBackendFactory
+}
+
+#[cfg(test)]
+mod tests {
+    use feldera_storage::{
+        clock::{ManualClock, StorageClock},
+        commit::CommitGroup,
+        copy_file,
+        lazy::LazyFile,
+        rotating::RotatingWriter,
+        FileStats, FileWriter, StorageBackend, StorageBackendFactory, StorageFileType, StoragePath,
+        StoragePathPart,
+    };
+    use feldera_types::config::{
+        AdaptiveFlushConfig, DurabilityMode, OpenRetryConfig, StorageCacheConfig, StorageConfig,
+        UsagePolicy,
+    };
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::{
+        ffi::OsStr,
+        fs::File,
+        os::unix::fs::FileExt,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use crate::storage::{
+        backend::{
+            is_normal_component,
+            tests::{random_sizes, test_backend, test_read, test_reserve},
+            BeforeStart, BlockLocation, BlockRef, ReadAllocation,
+        },
+        buffer_cache::FBuf,
+    };
+
+    use crate::circuit::metrics::{
+        BLOCK_CACHE_HIT, BLOCK_CACHE_MISS, BYTES_DELETED, COMPLETE_LATENCY, FILES_COMPLETED,
+        FILES_DELETE_FAILED, FILES_DELETE_FAILED_BYTES, PREFETCH_BYTES, PREFETCH_HIT_BYTES,
+        READS_FAILED, READS_SUCCESS, READ_LATENCY, RENAME_LATENCY, STORAGE_USAGE_BYTES,
+        SYNC_LATENCY, TOTAL_BYTES_READ, WRITE_LATENCY,
+    };
+
+    use super::{
+        move_file, retry_interrupted, storage_error, verify_write, write_all_vectored, BlockCache,
+        HasFileId, IoError, PosixBackend, PosixWriter, ReadBufferPool, StorageError,
+        MAX_ZERO_WRITES,
+    };
+
+    fn create_posix_backend(path: &Path) -> Arc<dyn StorageBackend> {
+        Arc::new(PosixBackend::new(path, StorageCacheConfig::default()))
+    }
+
+    /// Write 10 MiB total in 1 KiB chunks.  `VectoredWrite` flushes its buffer when it
+    /// reaches 1 MiB of sequential data, and we limit the amount of queued work
+    /// to 4 MiB, so this has a chance to trigger both limits.
+    #[test]
+    fn sequential_1024() {
+        test_backend(Box::new(create_posix_backend), &[1024; 1024 * 10], true)
+    }
+
+    /// Verify that files get deleted if not marked for a checkpoint.
+    #[test]
+    fn delete_1024() {
+        test_backend(Box::new(create_posix_backend), &[1024; 1024 * 10], false)
+    }
+
+    #[test]
+    fn sequential_random() {
+        test_backend(Box::new(create_posix_backend), &random_sizes(), true);
+    }
+
+    #[test]
+    fn empty() {
+        test_backend(Box::new(create_posix_backend), &[], true);
+    }
+
+    /// Verify that scattered reads fill consecutive buffers and stop at end
+    /// of file.
+    #[test]
+    fn read_scattered() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..1024).map(|i| i as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        let mut writer = backend.create().unwrap();
+        writer.write_block(block).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+
+        let mut a = vec![0; 300];
+        let mut b = vec![0; 400];
+        let mut c = vec![0; 1000];
+        let n = reader
+            .read_scattered(100, &mut [&mut a[..], &mut b[..], &mut c[..]])
+            .unwrap();
+        assert_eq!(n, 924);
+        assert_eq!(a.as_slice(), &data[100..400]);
+        assert_eq!(b.as_slice(), &data[400..800]);
+        assert_eq!(&c[..224], &data[800..]);
+    }
+
+    /// Verify that [feldera_storage::files_equal] detects differences in size
+    /// and content.
+    #[test]
+    fn files_equal() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let write = |name: &str, data: &[u8]| {
+            let mut block = FBuf::with_capacity(data.len());
+            block.extend_from_slice(data);
+            backend.write(&name.into(), block).unwrap();
+            backend.open(&name.into()).unwrap()
+        };
+
+        let data = vec![1; 4096];
+        let mut modified = data.clone();
+        modified[4000] = 2;
+        let a = write("a", &data);
+        let b = write("b", &data);
+        let c = write("c", &modified);
+        let d = write("d", &data[..2048]);
+        assert!(feldera_storage::files_equal(a.as_ref(), b.as_ref()).unwrap());
+        assert!(!feldera_storage::files_equal(a.as_ref(), c.as_ref()).unwrap());
+        assert!(!feldera_storage::files_equal(a.as_ref(), d.as_ref()).unwrap());
+    }
+
+    /// Verify that [FileWriter::recycle] only returns buffers that have been
+    /// written and are no longer shared.
+    #[test]
+    fn recycle() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let block = |size| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, 0);
+            block
+        };
+
+        let mut writer = backend.create().unwrap();
+        let first = writer.write_block(block(1024 * 1024)).unwrap();
+
+        // This write flushes the first block.
+        writer.write_block(block(512)).unwrap();
+        assert!(writer.recycle().is_empty());
+
+        drop(first);
+        let recycled = writer.recycle();
+        assert_eq!(recycled.len(), 1);
+        assert!(recycled[0].is_empty());
+        assert_eq!(recycled[0].capacity(), 1024 * 1024);
+    }
+
+    /// Verify that reads relative to the end of the file resolve and clamp
+    /// their offsets correctly, or fail if asked not to clamp.
+    #[test]
+    fn read_block_from_end() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..2048).map(|i| (i / 512) as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        let mut writer = backend.create().unwrap();
+        writer.write_block(block).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+
+        for before_start in [BeforeStart::Clamp, BeforeStart::Fail] {
+            let tail = reader.read_block_from_end(512, 512, before_start).unwrap();
+            assert_eq!(tail.as_slice(), &data[1536..]);
+        }
+
+        let clamped = reader
+            .read_block_from_end(2560, 1024, BeforeStart::Clamp)
+            .unwrap();
+        assert_eq!(clamped.as_slice(), &data[..512]);
+        assert!(matches!(
+            reader.read_block_from_end(2560, 1024, BeforeStart::Fail),
+            Err(StorageError::BlockBeforeStart {
+                distance: 2560,
+                file_size: 2048
+            })
+        ));
+
+        // Clamping would leave nothing of the block.
+        for before_start in [BeforeStart::Clamp, BeforeStart::Fail] {
+            assert!(matches!(
+                reader.read_block_from_end(4096, 1024, before_start),
+                Err(StorageError::BlockBeforeStart { .. })
+            ));
+        }
+    }
+
+    /// Clamping a block that starts before the beginning of a file whose
+    /// size isn't a multiple of 512 yields a size that isn't either, which
+    /// isn't a valid block.
+    #[test]
+    fn from_end_unaligned_clamp() {
+        assert!(matches!(
+            BlockLocation::from_end(1000, 1024, 1024, BeforeStart::Clamp),
+            Err(StorageError::InvalidBlockLocation {
+                offset: 0,
+                size: 1000
+            })
+        ));
+        assert!(matches!(
+            BlockLocation::from_end(1000, 1024, 1024, BeforeStart::Fail),
+            Err(StorageError::BlockBeforeStart { .. })
+        ));
+        assert_eq!(
+            BlockLocation::from_end(1536, 1536, 1024, BeforeStart::Fail)
+                .unwrap()
+                .offset,
+            0
+        );
+    }
+
+    /// Verify that reads round their allocations up to the configured size
+    /// classes without changing the amount of data returned.
+    #[test]
+    fn read_allocation() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_read_allocation(ReadAllocation::PowerOfTwo);
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+        let mut writer = backend.create().unwrap();
+        writer.write_block(block).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+
+        let block = reader
+            .read_block(BlockLocation::new(0, 1536).unwrap())
+            .unwrap();
+        assert_eq!(block.len(), 1536);
+        assert_eq!(block.capacity(), 2048);
+    }
+
+    /// Returns `backend`'s creation time for `name`, or `None` if the
+    /// filesystem can't record it.
+    fn created_at_if_supported(backend: &dyn StorageBackend, name: &str) -> Option<SystemTime> {
+        match backend.open(&name.into()).unwrap().created_at() {
+            Ok(created_at) => Some(created_at),
+            Err(error) => {
+                assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+                None
+            }
+        }
+    }
+
+    /// Verify that a file's creation time is recorded and survives reopening
+    /// it.
+    #[test]
+    fn created_at() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let before = SystemTime::now();
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 0);
+        backend.write(&"file".into(), block).unwrap();
+        let after = SystemTime::now();
+
+        if let Some(created_at) = created_at_if_supported(&*backend, "file") {
+            assert!(before <= created_at && created_at <= after);
+        }
+    }
+
+    /// A file without a recorded creation time, such as one written outside
+    /// the backend, reports [std::io::ErrorKind::Unsupported] rather than its
+    /// modification time.
+    #[test]
+    fn created_at_unrecorded() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        std::fs::write(tmpdir.path().join("file"), [0; 512]).unwrap();
+        let error = backend
+            .open(&"file".into())
+            .unwrap()
+            .created_at()
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    /// Verify that writers pick `fsync` or `fdatasync` according to the
+    /// configuration.
+    #[test]
+    fn sync_metadata() {
+        for (sync_metadata, expected) in [
+            (true, DurabilityMode::SyncAll),
+            (false, DurabilityMode::SyncData),
+        ] {
+            let tmpdir = tempfile::tempdir().unwrap();
+            let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+                .with_sync_metadata(sync_metadata);
+            let path = tmpdir.path().join("file");
+            let file = File::create(&path).unwrap();
+            let writer = PosixWriter::new(file, "file".into(), path, &backend);
+            assert_eq!(writer.durability, expected);
+            Box::new(writer).complete().unwrap();
+        }
+    }
+
+    /// Verify that files complete under every durability mode, and that the
+    /// configured mode takes precedence over `sync_metadata`.
+    #[test]
+    fn durability() {
+        for durability in [
+            DurabilityMode::SyncAll,
+            DurabilityMode::SyncData,
+            DurabilityMode::None,
+        ] {
+            let config = StorageConfig {
+                sync_metadata: false,
+                durability_mode: Some(durability),
+                ..StorageConfig::default()
+            };
+            assert_eq!(config.durability(), durability);
+
+            let tmpdir = tempfile::tempdir().unwrap();
+            let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+                .with_durability(durability);
+            let mut block = FBuf::with_capacity(512);
+            block.resize(512, 1);
+            backend.write(&"file".into(), block).unwrap();
+            assert_eq!(backend.read(&"file".into()).unwrap().len(), 512);
+        }
+        assert_eq!(
+            StorageConfig {
+                sync_metadata: false,
+                ..StorageConfig::default()
+            }
+            .durability(),
+            DurabilityMode::SyncData
+        );
+    }
+
+    /// Verify that a file can be streamed to a [std::io::Write] sink,
+    /// including a final block shorter than the block size.
+    #[test]
+    fn read_to() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..2560).map(|i| i as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        backend.write(&"file".into(), block).unwrap();
+
+        let reader = backend.open(&"file".into()).unwrap();
+        let mut output = Vec::new();
+        assert_eq!(reader.read_to(&mut output, 1024).unwrap(), 2560);
+        assert_eq!(output, data);
+    }
+
+    /// Names that need escaping must map to a single file directly inside
+    /// the storage directory and come back unchanged from `list`.
+    #[test]
+    fn name_encoding() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let parts = [
+            StoragePathPart::from("a/b"),
+            StoragePathPart::from(".."),
+            StoragePathPart::from("."),
+            StoragePathPart::from("x\0y"),
+            StoragePathPart::from("\u{1}\u{7f}"),
+            StoragePathPart::from("100%"),
+            StoragePathPart::from(&b"\xff\xfeabc"[..]),
+        ];
+        let names = parts
+            .into_iter()
+            .map(|part| StoragePath::default().child(part))
+            .collect::<Vec<_>>();
+        for (i, name) in names.iter().enumerate() {
+            let mut block = FBuf::with_capacity(512);
+            block.resize(512, i as u8);
+            backend.write(name, block).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_dir(tmpdir.path()).unwrap().count(),
+            names.len()
+        );
+
+        let mut listed = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |name, _| {
+                listed.push(name.clone())
+            })
+            .unwrap();
+        listed.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        let mut expected = names.clone();
+        expected.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        assert_eq!(listed, expected);
+
+        for (i, name) in names.iter().enumerate() {
+            let block = backend.read(name).unwrap();
+            assert!(block.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    /// A file created by some other program with a non-UTF8 name still gets
+    /// listed, under a valid percent-encoded name.
+    #[test]
+    fn foreign_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        std::fs::write(tmpdir.path().join(OsStr::from_bytes(b"\xffz")), [0u8; 512]).unwrap();
+
+        let mut listed = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |name, _| {
+                listed.push(name.clone())
+            })
+            .unwrap();
+        assert_eq!(listed, vec![StoragePath::parse("%FFz").unwrap()]);
+    }
+
+    #[test]
+    fn block_sizes() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let min = backend.min_block_size();
+        let preferred = backend.preferred_block_size();
+        assert_eq!(min % 512, 0);
+        assert!(preferred >= min);
+        assert_eq!(preferred % min, 0);
+    }
+
+    /// Deleting a file that the backend didn't account for must not drive
+    /// usage negative.
+    #[test]
+    fn usage_underflow() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        std::fs::write(tmpdir.path().join("foreign"), [0u8; 512]).unwrap();
+        backend.delete(&"foreign".into()).unwrap();
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "storage usage underflow")]
+    fn strict_usage_underflow() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_strict_usage(true);
+        std::fs::write(tmpdir.path().join("foreign"), [0u8; 512]).unwrap();
+        let _ = backend.delete(&"foreign".into());
+    }
+
+    /// A file that corrupts one byte of everything read from it.
+    struct CorruptingFile {
+        file: File,
+        offset: u64,
+    }
+
+    impl FileExt for CorruptingFile {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            let n = self.file.read_at(buf, offset)?;
+            if let Some(index) = self.offset.checked_sub(offset) {
+                if let Some(byte) = buf[..n].get_mut(index as usize) {
+                    *byte ^= 1;
+                }
+            }
+            Ok(n)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+            self.file.write_at(buf, offset)
+        }
+    }
+
+    #[test]
+    fn write_verify() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let path = tmpdir.path().join("file");
+        let buffers = (0..4)
+            .map(|i| {
+                let mut block = FBuf::with_capacity(1024);
+                block.resize(1024, i as u8);
+                Arc::new(block)
+            })
+            .collect::<Vec<_>>();
+        let mut data = FBuf::with_capacity(4096);
+        for buffer in &buffers {
+            data.extend_from_slice(buffer);
+        }
+        backend.write(&"file".into(), data).unwrap();
+
+        let file = CorruptingFile {
+            file: File::open(&path).unwrap(),
+            offset: u64::MAX,
+        };
+        verify_write(&file, 0, &buffers).unwrap();
+
+        let file = CorruptingFile {
+            file: File::open(&path).unwrap(),
+            offset: 2500,
+        };
+        assert!(matches!(
+            verify_write(&file, 0, &buffers),
+            Err(StorageError::WriteVerifyFailed { offset: 2500 })
+        ));
+    }
+
+    /// With write verification enabled, uncorrupted writes still succeed.
+    #[test]
+    fn write_verify_backend() {
+        test_backend(
+            Box::new(|path| {
+                Arc::new(
+                    PosixBackend::new(path, StorageCacheConfig::default()).with_write_verify(true),
+                )
+            }),
+            &random_sizes(),
+            true,
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut block = FBuf::with_capacity(1024);
+        block.resize(1024, 7);
+        backend.write(&"dir/file".into(), block.clone()).unwrap();
+        backend.write(&"file".into(), block.clone()).unwrap();
+        let mut writer = backend.create_named(&"partial".into()).unwrap();
+        writer.write_block(block.clone()).unwrap();
+
+        let snapshot = backend.snapshot().unwrap();
+        let summary = snapshot
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.file_type, entry.mutable))
+            .collect::<Vec<_>>();
+        let file = |name: &str, size| StorageFileType::File {
+            size,
+            allocated: super::allocated_size(&std::fs::metadata(tmpdir.path().join(name)).unwrap()),
+        };
+        assert_eq!(
+            summary,
+            [
+                ("dir", StorageFileType::Directory, false),
+                ("dir/file", file("dir/file", 1024), false),
+                ("file", file("file", 1024), false),
+                ("partial.mut", file("partial.mut", 0), true),
+            ]
+        );
+        let file = &snapshot.entries[2];
+        assert_eq!(file.crc32c, Some(crc32c::crc32c(block.as_slice())));
+        assert_eq!(file.created_at, created_at_if_supported(&*backend, "file"));
+        assert!(file.error.is_none());
+        assert_eq!(snapshot.entries[0].crc32c, None);
+    }
+
+    /// Snapshots taken in parallel have the same entries in the same order.
+    #[test]
+    fn snapshot_parallel() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        for i in 0..20 {
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, i);
+            backend
+                .write(&format!("dir{}/file{i}", i % 3).into(), block)
+                .unwrap();
+        }
+
+        let summarize = |parallelism| {
+            backend
+                .snapshot_with_parallelism(parallelism)
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|entry| (entry.path, entry.crc32c))
+                .collect::<Vec<_>>()
+        };
+        let expected = summarize(1);
+        assert_eq!(expected.len(), 23);
+        for parallelism in [0, 2, 4, 100] {
+            assert_eq!(summarize(parallelism), expected);
+        }
+    }
+
+    fn usage_of(backend: &PosixBackend) -> i64 {
+        backend.usage().load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Exercises usage accounting under each [UsagePolicy].  Writers buffer
+    /// up to 1 MiB before flushing, so writing two 1 MiB blocks leaves 1 MiB
+    /// on disk in a file that is still in progress.
+    #[test]
+    fn usage_policy() {
+        const MIB: i64 = 1024 * 1024;
+        let mut block = FBuf::with_capacity(MIB as usize);
+        block.resize(MIB as usize, 1);
+
+        for policy in [
+            UsagePolicy::DataOnly,
+            UsagePolicy::IncludeInProgress,
+            UsagePolicy::IncludeAll,
+        ] {
+            let tmpdir = tempfile::tempdir().unwrap();
+            let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+                .with_usage_policy(policy)
+                .with_strict_usage(true);
+
+            // Write a file into a new directory but don't complete it yet.
+            let mut writer = backend.create_named(&"dir/file".into()).unwrap();
+            let dir_size = usage_of(&backend);
+            assert_eq!(dir_size > 0, policy.counts_directories());
+            writer.write_block(block.clone()).unwrap();
+            writer.write_block(block.clone()).unwrap();
+            let in_progress = if policy.counts_in_progress() { MIB } else { 0 };
+            assert_eq!(usage_of(&backend), dir_size + in_progress);
+
+            // Completing the file always counts it.
+            let (reader, _name) = writer.complete().unwrap();
+            reader.mark_for_checkpoint();
+            drop(reader);
+            assert_eq!(usage_of(&backend), dir_size + 2 * MIB);
+
+            // An abandoned file releases whatever it counted.
+            let mut writer = backend.create_named(&"dir/abandoned".into()).unwrap();
+            writer.write_block(block.clone()).unwrap();
+            writer.write_block(block.clone()).unwrap();
+            assert_eq!(usage_of(&backend), dir_size + 2 * MIB + in_progress);
+            drop(writer);
+            assert_eq!(usage_of(&backend), dir_size + 2 * MIB);
+
+            // Deleting everything brings usage back to zero.
+            backend.delete_recursive(&"dir".into()).unwrap();
+            assert_eq!(usage_of(&backend), 0);
+        }
+    }
+
+    #[test]
+    fn reserve() {
+        test_reserve(Box::new(create_posix_backend));
+    }
+
+    #[test]
+    fn read_only_filesystem() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        backend.health_check().unwrap();
+        let missing =
+            PosixBackend::new(tmpdir.path().join("missing"), StorageCacheConfig::default());
+        missing.health_check().unwrap();
+
+        let error = storage_error(
+            std::io::Error::from_raw_os_error(libc::EROFS),
+            tmpdir.path(),
+        );
+        assert!(matches!(error, StorageError::ReadOnlyFilesystem(path) if path == tmpdir.path()));
+        let error = storage_error(
+            std::io::Error::from_raw_os_error(libc::ENOSPC),
+            tmpdir.path(),
+        );
+        assert!(
+            matches!(error, StorageError::OutOfSpace { path: Some(path), .. } if path == tmpdir.path())
+        );
+        let error = storage_error(std::io::Error::from_raw_os_error(libc::EIO), tmpdir.path());
+        assert!(matches!(error, StorageError::Other(_)));
+    }
+
+    #[test]
+    fn rotating_writer() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut writer = RotatingWriter::new(backend.clone(), "log".into(), 2048);
+        let mut data = Vec::new();
+        for i in 0..9 {
+            let mut block = FBuf::with_capacity(1024);
+            block.resize(1024, i);
+            data.extend_from_slice(&block);
+            writer.append(block).unwrap();
+        }
+        assert_eq!(
+            writer.segments(),
+            ["log.000001", "log.000002", "log.000003", "log.000004"]
+                .map(StoragePath::from)
+                .to_vec()
+        );
+
+        let segments = writer.finish().unwrap();
+        assert_eq!(segments.len(), 5);
+        assert_eq!(segments[4], StoragePath::from("log.000005"));
+        let mut replayed = Vec::new();
+        for segment in &segments {
+            replayed.extend_from_slice(&backend.read(segment).unwrap());
+        }
+        assert_eq!(replayed, data);
+    }
+
+    /// Files take their creation time from the backend's clock, and a clock
+    /// that goes backward doesn't make ages negative.
+    #[test]
+    fn clock() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let clock = Arc::new(ManualClock::new(start));
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_clock(clock.clone());
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 0);
+        backend.write(&"file".into(), block).unwrap();
+
+        if let Some(created_at) = created_at_if_supported(&backend, "file") {
+            assert_eq!(created_at, start);
+        }
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(clock.elapsed_since(start), Duration::from_secs(10));
+        clock.set(start - Duration::from_secs(10));
+        assert_eq!(clock.elapsed_since(start), Duration::ZERO);
+    }
+
+    #[test]
+    fn lazy_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..16 * 1024).map(|i| (i / 7) as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        backend.write(&"file".into(), block).unwrap();
+
+        let file = LazyFile::new(backend.open(&"file".into()).unwrap(), 4096, 2).unwrap();
+        assert_eq!(file.len(), data.len() as u64);
+        assert_eq!(file.materialized(), 0);
+
+        // Touching one block reads only that block, once.
+        assert_eq!(file.byte(5000).unwrap(), data[5000]);
+        assert_eq!(file.byte(5001).unwrap(), data[5001]);
+        assert_eq!(file.materialized(), 1);
+
+        // A read that spans blocks reads both.
+        let mut buf = vec![0; 2000];
+        file.read_exact_at(7000, &mut buf).unwrap();
+        assert_eq!(buf, data[7000..9000]);
+        assert_eq!(file.materialized(), 2);
+
+        // The cache holds two blocks, so touching a third evicts the least
+        // recently used one.
+        assert_eq!(file.byte(0).unwrap(), data[0]);
+        assert_eq!(file.materialized(), 3);
+        assert_eq!(file.byte(8192).unwrap(), data[8192]);
+        assert_eq!(file.materialized(), 3);
+        assert_eq!(file.byte(4096).unwrap(), data[4096]);
+        assert_eq!(file.materialized(), 4);
+
+        assert!(file.read_exact_at(16 * 1024 - 1, &mut buf).is_err());
+    }
+
+    /// Frozen files can't be deleted until they're unfrozen.  This needs the
+    /// `CAP_LINUX_IMMUTABLE` capability and filesystem support, so it passes
+    /// trivially if freezing fails.
+    #[test]
+    fn freeze() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let name = StoragePath::from("dir/file");
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 0);
+        backend.write(&name, block).unwrap();
+        if backend.freeze(&name).is_err() {
+            return;
+        }
+
+        let path = tmpdir.path().join("dir/file");
+        assert!(matches!(backend.delete(&name), Err(StorageError::Immutable(p)) if p == path));
+        assert!(matches!(
+            backend.delete_recursive(&"dir".into()),
+            Err(StorageError::Immutable(p)) if p == path
+        ));
+
+        backend.unfreeze(&name).unwrap();
+        backend.delete_recursive(&"dir".into()).unwrap();
+        assert!(!backend.exists(&name).unwrap());
+    }
+
+    #[test]
+    fn open_many() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut names = Vec::new();
+        for i in 0..100 {
+            let name = StoragePath::from(format!("file{i}"));
+            if i % 10 != 3 {
+                let mut block = FBuf::with_capacity(512 * (i + 1));
+                block.resize(512 * (i + 1), 0);
+                backend.write(&name, block).unwrap();
+            }
+            names.push(name);
+        }
+
+        let readers = backend.open_many(&names);
+        assert_eq!(readers.len(), names.len());
+        for (i, reader) in readers.into_iter().enumerate() {
+            match reader {
+                Ok(reader) => {
+                    assert_ne!(i % 10, 3);
+                    assert_eq!(reader.get_size().unwrap(), 512 * (i as u64 + 1));
+                }
+                Err(error) => {
+                    assert_eq!(i % 10, 3);
+                    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_blocks() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..8192).map(|i| (i / 512) as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        // Unsorted, with gaps of various sizes, an overlap, and a block past
+        // the end of the file.
+        let locations = [
+            (2048, 512),
+            (0, 512),
+            (512, 1024),
+            (4096, 1024),
+            (4608, 512),
+            (7680, 512),
+            (7680, 1024),
+        ]
+        .map(|(offset, size)| BlockLocation::new(offset, size).unwrap());
+        for coalesce_gap in [0, 512, 4096] {
+            let results = reader.read_blocks(&locations, coalesce_gap);
+            assert_eq!(results.len(), locations.len());
+            for (location, result) in locations.iter().zip(results) {
+                if location.after() > data.len() as u64 {
+                    assert!(result.is_err());
+                } else {
+                    let range = location.offset as usize..location.after() as usize;
+                    assert_eq!(result.unwrap().as_slice(), &data[range]);
+                }
+            }
+        }
+
+        // A batch read fails as a whole, naming the block that failed.
+        let batch = reader.read_batch(&locations[..5]).unwrap();
+        for (location, block) in locations.iter().zip(batch) {
+            let range = location.offset as usize..location.after() as usize;
+            assert_eq!(block.as_slice(), &data[range]);
+        }
+        let Err(StorageError::BlockReadFailed { offset, .. }) = reader.read_batch(&locations)
+        else {
+            unreachable!()
+        };
+        assert_eq!(offset, 7680);
+    }
+
+    /// Regions are concatenated in the order given, whether they are
+    /// contiguous, out of order, or overlapping.
+    #[test]
+    fn read_regions() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..8192).map(|i| (i / 512) as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        let cases: [&[(u64, usize)]; 4] = [
+            &[],
+            &[(0, 512), (512, 1024), (1536, 512)],
+            &[(4096, 512), (0, 1024), (512, 1024)],
+            &[(7680, 512), (7680, 512), (1024, 2048)],
+        ];
+        for regions in cases {
+            let locations = regions
+                .iter()
+                .map(|(offset, size)| BlockLocation::new(*offset, *size).unwrap())
+                .collect::<Vec<_>>();
+            let expected = locations
+                .iter()
+                .flat_map(|location| &data[location.offset as usize..location.after() as usize])
+                .copied()
+                .collect::<Vec<_>>();
+            assert_eq!(
+                reader.read_regions(&locations).unwrap().as_slice(),
+                expected.as_slice()
+            );
+        }
+
+        // Reading past the end fails, whether or not the regions are
+        // contiguous.
+        for regions in [[(7168, 512), (7680, 1024)], [(0, 512), (7680, 1024)]] {
+            let locations = regions.map(|(offset, size)| BlockLocation::new(offset, size).unwrap());
+            assert!(reader.read_regions(&locations).is_err());
+        }
+    }
+
+    /// Renames a tree, both with `rename` and with the copying fallback that
+    /// is used across filesystems.
+    #[test]
+    fn rename_subtree() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_strict_usage(true);
+        let names = ["a/file", "a/b/file", "a/b/c/file"];
+        for (i, name) in names.iter().enumerate() {
+            let mut block = FBuf::with_capacity(512);
+            block.resize(512, i as u8);
+            backend.write(&(*name).into(), block).unwrap();
+        }
+        let usage = backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(usage, 512 * names.len() as i64);
+
+        backend
+            .rename_subtree(&"a".into(), &"staging/x".into())
+            .unwrap();
+        backend
+            .rename_subtree_by_copy(&"staging/x".into(), &"final/y".into())
+            .unwrap();
+        assert!(!tmpdir.path().join("a").exists());
+        assert!(!tmpdir.path().join("staging/x").exists());
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            usage
+        );
+        for (i, name) in names.iter().enumerate() {
+            let name = StoragePath::from(name.replacen('a', "final/y", 1));
+            let block = backend.read(&name).unwrap();
+            assert!(block.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    /// A clock that advances by `step` every time it is read, so that every
+    /// flush appears to take `step`.
+    #[derive(Debug)]
+    struct SteppingClock {
+        clock: ManualClock,
+        step: Mutex<Duration>,
+    }
+
+    impl StorageClock for SteppingClock {
+        fn now(&self) -> SystemTime {
+            self.clock.advance(*self.step.lock().unwrap());
+            self.clock.now()
+        }
+    }
+
+    /// Write latency is measured with the backend's clock.
+    #[test]
+    fn write_latency() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(SteppingClock {
+            clock: ManualClock::new(SystemTime::UNIX_EPOCH),
+            step: Mutex::new(Duration::from_millis(5)),
+        });
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_clock(clock.clone());
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let mut writer = backend.create().unwrap();
+            for _ in 0..3 {
+                let mut block = FBuf::with_capacity(4096);
+                block.resize(4096, 0);
+                writer.write_block(block).unwrap();
+            }
+        });
+
+        // The clock advances once when each write starts and once when it
+        // ends.
+        let snapshot = snapshotter.snapshot().into_vec();
+        let samples = snapshot
+            .iter()
+            .find_map(|(key, _, _, value)| match value {
+                DebugValue::Histogram(samples) if key.key().name() == WRITE_LATENCY => {
+                    Some(samples.iter().map(|sample| sample.0).collect::<Vec<_>>())
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(samples, [Duration::from_millis(5).as_secs_f64(); 3]);
+    }
+
+    #[test]
+    fn adaptive_flush() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(SteppingClock {
+            clock: ManualClock::new(SystemTime::UNIX_EPOCH),
+            step: Mutex::new(Duration::from_millis(50)),
+        });
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_clock(clock.clone())
+            .with_adaptive_flush(Some(AdaptiveFlushConfig {
+                min_bytes: 64 * 1024,
+                max_bytes: 4 * 1024 * 1024,
+                target_latency_ms: 10,
+            }));
+        assert_eq!(backend.flush_threshold(), 1024 * 1024);
+
+        let mut writer = backend.create().unwrap();
+        let mut write = |mib| {
+            for _ in 0..mib * 64 {
+                let mut block = FBuf::with_capacity(16 * 1024);
+                block.resize(16 * 1024, 0);
+                writer.write_block(block).unwrap();
+            }
+        };
+
+        // Slow flushes shrink the threshold to its minimum.
+        write(4);
+        assert_eq!(backend.flush_threshold(), 64 * 1024);
+
+        // Fast flushes grow it to its maximum.
+        *clock.step.lock().unwrap() = Duration::from_millis(1);
+        write(8);
+        assert_eq!(backend.flush_threshold(), 4 * 1024 * 1024);
+
+        // Flushes close to the target leave it alone.
+        *clock.step.lock().unwrap() = Duration::from_millis(7);
+        write(8);
+        assert_eq!(backend.flush_threshold(), 4 * 1024 * 1024);
+    }
+
+    /// A writer with a small flush threshold flushes each time it has
+    /// buffered that much.
+    #[test]
+    fn flush_threshold() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_flush_threshold(1);
+        let threshold = backend.flush_threshold();
+        assert_eq!(threshold, super::page_size());
+
+        let path = tmpdir.path().join("file.mut");
+        let file = File::create(&path).unwrap();
+        let mut writer = PosixWriter::new(file, "file".into(), path, &backend);
+        for _ in 0..16 {
+            let mut block = FBuf::with_capacity(threshold / 4);
+            block.resize(threshold / 4, 0);
+            writer.write_block(block).unwrap();
+        }
+
+        // Every fourth block fills the buffer, and the next write flushes it.
+        assert_eq!(writer.flushes, 3);
+        let (reader, _name) = Box::new(writer).complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), 4 * threshold as u64);
+    }
+
+    /// Reads, including failed and coalesced ones, are counted in the read
+    /// metrics.
+    #[test]
+    fn read_metrics() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let location = |offset, size| BlockLocation::new(offset, size).unwrap();
+            reader.read_block(location(0, 1024)).unwrap();
+            reader.read_block(location(3584, 1024)).unwrap_err();
+            let results = reader.read_blocks(&[location(1024, 512), location(2048, 512)], 4096);
+            assert!(results.iter().all(|result| result.is_ok()));
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let counter = |name: &str| {
+            snapshot
+                .iter()
+                .find_map(|(key, _, _, value)| match value {
+                    DebugValue::Counter(n) if key.key().name() == name => Some(*n),
+                    _ => None,
+                })
+                .unwrap_or(0)
+        };
+        assert_eq!(counter(READS_SUCCESS), 3);
+        assert_eq!(counter(READS_FAILED), 1);
+        assert_eq!(counter(TOTAL_BYTES_READ), 1024 + 1024 + 512 + 512);
+        assert!(snapshot.iter().any(|(key, _, _, value)| {
+            key.key().name() == READ_LATENCY
+                && matches!(value, DebugValue::Histogram(samples) if samples.len() == 3)
+        }));
+    }
+
+    /// Direct I/O works, or falls back to the page cache where the filesystem
+    /// doesn't support it, and unaligned reads still work either way.
+    #[test]
+    fn direct_io() {
+        test_backend(
+            Box::new(|path| Arc::new(PosixBackend::new(path, StorageCacheConfig::Direct))),
+            &random_sizes(),
+            true,
+        );
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::Direct);
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        writer.write_block(block).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+
+        let mut a = vec![0; 100];
+        let mut b = vec![0; 5000];
+        let n = reader.read_scattered(1000, &mut [&mut a, &mut b]).unwrap();
+        assert_eq!(n, 3096);
+        assert_eq!(&a, &data[1000..1100]);
+        assert_eq!(&b[..2996], &data[1100..]);
+
+        let results = reader.read_blocks(
+            &[
+                BlockLocation::new(0, 512).unwrap(),
+                BlockLocation::new(1536, 512).unwrap(),
+            ],
+            4096,
+        );
+        assert_eq!(results[0].as_ref().unwrap().as_slice(), &data[..512]);
+        assert_eq!(results[1].as_ref().unwrap().as_slice(), &data[1536..2048]);
+    }
+
+    /// Recovery deletes files abandoned in the middle of writing, and only
+    /// those, releasing their usage.
+    #[test]
+    fn recover() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let block = |size| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, 0);
+            block
+        };
+        backend.write(&"keep".into(), block(512)).unwrap();
+        assert_eq!(backend.recover().unwrap(), 0);
+
+        // Simulate a crash mid-write by leaking the writers.
+        for name in ["abandoned", "a/b/abandoned"] {
+            let mut writer = backend.create_named(&name.into()).unwrap();
+            writer.write_block(block(1024 * 1024)).unwrap();
+            writer.write_block(block(512)).unwrap();
+            std::mem::forget(writer);
+        }
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(usage(), 512 + 2 * 1024 * 1024);
+        assert!(tmpdir.path().join("a/b/abandoned.mut").exists());
+
+        assert_eq!(backend.recover().unwrap(), 2);
+        assert!(!tmpdir.path().join("abandoned.mut").exists());
+        assert!(!tmpdir.path().join("a/b/abandoned.mut").exists());
+        assert_eq!(backend.read(&"keep".into()).unwrap().len(), 512);
+        assert_eq!(usage(), 512);
+
+        // A new backend, as after a restart, hasn't counted abandoned files.
+        let mut writer = backend.create_named(&"abandoned".into()).unwrap();
+        writer.write_block(block(1024 * 1024)).unwrap();
+        writer.write_block(block(512)).unwrap();
+        std::mem::forget(writer);
+        let restarted = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        assert_eq!(restarted.recover().unwrap(), 1);
+        assert_eq!(
+            restarted.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    /// The asynchronous wrapper writes and reads files like the backend it
+    /// wraps, including usage and deleting files that aren't kept.
+    #[test]
+    fn into_async() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).into_async();
+        feldera_storage::tokio::TOKIO.block_on(async {
+            let mut writer = backend.create_named(&"file".into()).await.unwrap();
+            for i in 0..4 {
+                let mut block = FBuf::with_capacity(512);
+                block.resize(512, i);
+                writer.write_block(block).await.unwrap();
+            }
+            let (reader, name) = writer.complete().await.unwrap();
+            assert_eq!(name, StoragePath::from("file"));
+            assert_eq!(reader.get_size().unwrap(), 2048);
+            let block = reader
+                .read_block(BlockLocation::new(1024, 512).unwrap())
+                .await
+                .unwrap();
+            assert!(block.iter().all(|&b| b == 2));
+            assert_eq!(
+                backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+                2048
+            );
+
+            // Dropping the reader without marking it for a checkpoint deletes
+            // the file.
+            drop(reader);
+            assert!(backend.open(&"file".into()).await.is_err());
+            assert_eq!(
+                backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+                0
+            );
+        });
+    }
+
+    /// Writes fail with [StorageError::QuotaExceeded] as soon as they would
+    /// take usage past the quota, and a flush rejected because of another
+    /// writer leaves usage unchanged.
+    #[test]
+    fn quota() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_flush_threshold(1);
+        let threshold = backend.flush_threshold();
+        let with_quota = |quota| {
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+                .with_flush_threshold(threshold)
+                .with_quota(Some(quota))
+        };
+        let block = || {
+            let mut block = FBuf::with_capacity(threshold);
+            block.resize(threshold, 0);
+            block
+        };
+        let usage = |backend: &PosixBackend| {
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed) as u64
+        };
+        let backend = with_quota(4 * threshold as u64);
+
+        // The fifth block doesn't fit, although only three have been flushed.
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        for _ in 0..4 {
+            writer.write_block(block()).unwrap();
+        }
+        assert!(matches!(
+            writer.write_block(block()),
+            Err(StorageError::QuotaExceeded { used, quota })
+                if used == 3 * threshold as u64 && quota == 4 * threshold as u64
+        ));
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(usage(&backend), 4 * threshold as u64);
+        assert!(matches!(
+            backend.create_named(&"full".into()),
+            Err(StorageError::QuotaExceeded { .. })
+        ));
+        drop(reader);
+
+        // Each writer's buffered data fits on its own, but not together.
+        let backend = with_quota(threshold as u64 * 3 / 2);
+        let mut a = backend.create_named(&"a".into()).unwrap();
+        let mut b = backend.create_named(&"b".into()).unwrap();
+        a.write_block(block()).unwrap();
+        b.write_block(block()).unwrap();
+        let (_a, _name) = a.complete().unwrap();
+        assert_eq!(usage(&backend), threshold as u64);
+        assert!(matches!(
+            b.complete(),
+            Err(StorageError::QuotaExceeded { used, .. }) if used == threshold as u64
+        ));
+        assert_eq!(usage(&backend), threshold as u64);
+        assert!(!tmpdir.path().join("b.mut").exists());
+    }
+
+    /// Access advice doesn't change what reads return.
+    #[test]
+    fn advise() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let data = (0..8192).map(|i| i as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        let mut writer = backend.create().unwrap();
+        writer.write_block(block).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+        reader.advise_dontneed();
+
+        let mut read = Vec::new();
+        for block in reader.blocks(4096) {
+            read.extend_from_slice(&block.unwrap());
+        }
+        assert_eq!(read, data);
+        reader.advise_dontneed();
+        test_read(reader.as_ref(), &data);
+    }
+
+    /// Preallocating doesn't count toward usage, and a file that turns out
+    /// smaller than its preallocation is truncated to the data written.
+    #[test]
+    fn preallocate() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        writer.preallocate(1024 * 1024).unwrap();
+        writer.write_block(block.clone()).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+        assert_eq!(usage(), 4096);
+        assert_eq!(reader.get_size().unwrap(), 4096);
+        assert_eq!(
+            std::fs::metadata(tmpdir.path().join("file")).unwrap().len(),
+            4096
+        );
+        test_read(reader.as_ref(), &block);
+
+        // Writing more than the preallocation is fine too.
+        let mut writer = backend.create_named(&"bigger".into()).unwrap();
+        writer.preallocate(1024).unwrap();
+        writer.write_block(block.clone()).unwrap();
+        writer.write_block(block.clone()).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), 8192);
+        assert_eq!(usage(), 4096 + 8192);
+    }
+
+    /// The backend reports direct I/O only when it is configured and the
+    /// filesystem accepts it.
+    #[test]
+    fn capabilities() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let capabilities = backend.capabilities();
+        assert!(capabilities.atomic_rename);
+        assert!(capabilities.supports_checksums);
+        assert!(!capabilities.is_remote);
+        assert!(!capabilities.supports_direct_io);
+
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::Direct);
+        backend.write(&"file".into(), FBuf::new()).unwrap();
+        assert_eq!(
+            backend.capabilities().supports_direct_io,
+            cfg!(target_os = "linux")
+                && !backend
+                    .direct_io_rejected
+                    .load(std::sync::atomic::Ordering::Relaxed)
+        );
+    }
+
+    /// Recursive listing honors the depth limit and doesn't follow symbolic
+    /// links to directories.
+    #[test]
+    fn list_recursive() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        for name in ["top", "a/file", "a/b/file", "a/b/c/file"] {
+            backend.write(&name.into(), FBuf::new()).unwrap();
+        }
+        std::os::unix::fs::symlink(tmpdir.path().join("a"), tmpdir.path().join("link")).unwrap();
+
+        let list = |max_depth| {
+            let mut entries = Vec::new();
+            backend
+                .list_recursive(
+                    &StoragePath::default(),
+                    max_depth,
+                    &mut |path, file_type| entries.push((path.to_string(), file_type)),
+                )
+                .unwrap();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+        let file = StorageFileType::File {
+            size: 0,
+            allocated: 0,
+        };
+        let dir = StorageFileType::Directory;
+        let expected = [
+            ("a", dir),
+            ("a/b", dir),
+            ("a/b/c", dir),
+            ("a/b/c/file", file),
+            ("a/b/file", file),
+            ("a/file", file),
+            ("link", StorageFileType::Other),
+            ("top", file),
+        ]
+        .map(|(name, file_type)| (name.to_string(), file_type));
+        assert_eq!(list(None), expected);
+        let depth = |name: &str| name.matches('/').count();
+        for max_depth in 0..3 {
+            let expected = expected
+                .iter()
+                .filter(|(name, _)| depth(name) <= max_depth)
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(list(Some(max_depth)), expected);
+        }
+    }
+
+    /// Tests [PosixBackend::list_matching] with some glob patterns.
+    #[test]
+    fn list_matching() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        for name in [
+            "a.feldera",
+            "b.feldera",
+            "c.txt",
+            "a1",
+            "a2",
+            "ax",
+            "dir/d.feldera",
+        ] {
+            backend.write(&name.into(), FBuf::new()).unwrap();
+        }
+
+        let list = |pattern| {
+            let mut names = Vec::new();
+            backend.list_matching(&StoragePath::default(), pattern, &mut |path, _file_type| {
+                names.push(path.to_string())
+            })?;
+            names.sort();
+            Ok::<_, StorageError>(names)
+        };
+        for (pattern, expected) in [
+            (
+                "*",
+                &["a.feldera", "a1", "a2", "ax", "b.feldera", "c.txt", "dir"][..],
+            ),
+            ("*.feldera", &["a.feldera", "b.feldera"]),
+            ("a*", &["a.feldera", "a1", "a2", "ax"]),
+            ("c.txt", &["c.txt"]),
+            ("c.mu", &[]),
+            ("a?", &["a1", "a2", "ax"]),
+            ("a[0-9]", &["a1", "a2"]),
+            ("a[!0-9]", &["ax"]),
+            ("[bc].*", &["b.feldera", "c.txt"]),
+            ("\\*", &[]),
+        ] {
+            assert_eq!(list(pattern).unwrap(), expected, "pattern {pattern:?}");
+        }
+
+        for pattern in ["a[0-9", "a\\", "[z-a]"] {
+            assert!(matches!(
+                list(pattern),
+                Err(StorageError::InvalidPattern { .. })
+            ));
+        }
+    }
+
+    /// Tests that a [CommitGroup] completes all of its files.
+    #[test]
+    fn commit_group() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let names = ["a", "b/c", "b/d"];
+        let mut group = CommitGroup::new(backend.as_ref());
+        for (i, name) in names.into_iter().enumerate() {
+            let mut writer = backend.create_named(&name.into()).unwrap();
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, i as u8);
+            writer.write_block(block).unwrap();
+            group.add(writer);
+        }
+        assert_eq!(group.len(), names.len());
+
+        let completed = group.complete_all().unwrap();
+        for (i, ((reader, name), expected_name)) in completed.iter().zip(names).enumerate() {
+            assert_eq!(name.to_string(), expected_name);
+            test_read(reader.as_ref(), &[i as u8; 4096]);
+            reader.mark_for_checkpoint();
+        }
+        drop(completed);
+        for name in names {
+            assert!(tmpdir.path().join(name).is_file());
+            assert!(!tmpdir.path().join(format!("{name}.mut")).exists());
+        }
+    }
+
+    /// Tests that a [CommitGroup] renames none of its files, and deletes all
+    /// of them, if any of them fails to complete.
+    #[test]
+    fn commit_group_failure() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut group = CommitGroup::new(backend.as_ref());
+        let mut good = backend.create_named(&"good".into()).unwrap();
+        good.write_block(FBuf::with_capacity(512)).unwrap();
+        group.add(good);
+
+        // A reservation that is never filled makes preparing fail.
+        let mut bad = backend.create_named(&"bad".into()).unwrap();
+        bad.reserve_block(512).unwrap();
+        group.add(bad);
+
+        assert!(matches!(
+            group.complete_all(),
+            Err(StorageError::UnfilledReservation { offset: 0 })
+        ));
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    /// Tests [PosixBackend::rename] and [PosixBackend::copy], including their
+    /// effect on usage.
+    #[test]
+    fn rename_and_copy() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        let write = |name: &str, size: usize| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, size as u8);
+            backend.write(&name.into(), block).unwrap();
+        };
+        write("a", 4096);
+        write("b", 1024);
+        assert_eq!(usage(), 5120);
+
+        // Renaming into a new directory doesn't change usage.
+        backend.rename(&"a".into(), &"dir/a".into()).unwrap();
+        assert!(!backend.exists(&"a".into()).unwrap());
+        assert_eq!(backend.read(&"dir/a".into()).unwrap().len(), 4096);
+        assert_eq!(usage(), 5120);
+
+        // Renaming over an existing file releases its space.
+        backend.rename(&"b".into(), &"dir/a".into()).unwrap();
+        assert!(!backend.exists(&"b".into()).unwrap());
+        assert_eq!(
+            backend.read(&"dir/a".into()).unwrap().as_slice(),
+            &[0u8; 1024]
+        );
+        assert_eq!(usage(), 1024);
+
+        // Copying adds the copy's space.
+        backend.copy(&"dir/a".into(), &"other/c".into()).unwrap();
+        assert_eq!(
+            backend.read(&"other/c".into()).unwrap().as_slice(),
+            backend.read(&"dir/a".into()).unwrap().as_slice()
+        );
+        assert_eq!(usage(), 2048);
+
+        // Copying over an existing file fails without changing it.
+        write("d", 512);
+        assert_eq!(usage(), 2560);
+        assert_eq!(
+            backend
+                .copy(&"dir/a".into(), &"d".into())
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(backend.read(&"d".into()).unwrap().len(), 512);
+        assert_eq!(usage(), 2560);
+    }
+
+    /// Tests [FileReader::stats] and [PosixBackend::top_files] after a known
+    /// sequence of writes and reads.
+    #[test]
+    fn file_stats() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let mut writer = backend.create_named(&"hot".into()).unwrap();
+        for _ in 0..2 {
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, 3);
+            writer.write_block(block).unwrap();
+        }
+        let (hot, _name) = writer.complete().unwrap();
+        hot.mark_for_checkpoint();
+        assert_eq!(
+            hot.stats(),
+            FileStats {
+                bytes_written: 8192,
+                write_count: 2,
+                ..FileStats::default()
+            }
+        );
+
+        let location = |offset, size| BlockLocation::new(offset, size).unwrap();
+        hot.read_block(location(0, 4096)).unwrap();
+        let results = hot.read_blocks(&[location(0, 512), location(1024, 512)], 4096);
+        assert!(results.iter().all(|result| result.is_ok()));
+        hot.read_regions(&[location(0, 512), location(4096, 1024)])
+            .unwrap();
+        let mut buf = [0; 512];
+        hot.read_scattered(8000, &mut [&mut buf[..]]).unwrap();
+        assert_eq!(
+            hot.stats(),
+            FileStats {
+                bytes_read: 4096 + 1024 + 1536 + 192,
+                bytes_written: 8192,
+                read_count: 4,
+                write_count: 2,
+                blocks_read: 5,
+            }
+        );
+
+        backend.write(&"cold".into(), FBuf::new()).unwrap();
+        let cold = backend.open(&"cold".into()).unwrap();
+        let top = backend.top_files(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0.to_string(), "hot");
+        assert_eq!(top[0].1, hot.stats());
+        assert_eq!(backend.top_files(10).len(), 2);
+
+        drop((hot, cold));
+        assert!(backend.top_files(10).is_empty());
+    }
+
+    /// A [Write] implementation that fails every other write with
+    /// [ErrorKind::Interrupted](std::io::ErrorKind::Interrupted) and otherwise
+    /// writes at most 100 bytes at a time.
+    #[derive(Default)]
+    struct InterruptingWriter {
+        data: Vec<u8>,
+        calls: usize,
+    }
+
+    impl std::io::Write for InterruptingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 2 == 1 {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(100);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Tests that writing retries interrupted and partial writes until all of
+    /// the data is written, reporting each byte's progress exactly once, so
+    /// that flushing adds it to usage exactly once.
+    #[test]
+    fn write_interrupted() {
+        let a = [1; 250];
+        let b = [2; 130];
+        let mut bufs = [std::io::IoSlice::new(&a), std::io::IoSlice::new(&b)];
+        let mut writer = InterruptingWriter::default();
+        let mut progress = 0;
+        write_all_vectored(&mut writer, &mut bufs, usize::MAX, |n| progress += n).unwrap();
+        assert_eq!(writer.data, [&a[..], &b[..]].concat());
+        assert_eq!(progress, a.len() + b.len());
+    }
+
+    /// Tests that [retry_interrupted] retries only interrupted calls.
+    #[test]
+    fn retry_interrupted_calls() {
+        let mut calls = 0;
+        let result = retry_interrupted(|| {
+            calls += 1;
+            match calls {
+                1 | 2 => Err(std::io::ErrorKind::Interrupted.into()),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry_interrupted(|| {
+            calls += 1;
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        });
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(calls, 1);
+    }
+
+    /// Recomputing usage corrects drift in either direction, reports the
+    /// drift, and follows the [UsagePolicy].
+    #[test]
+    fn recompute_usage() {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+        backend.write(&"a".into(), block.clone()).unwrap();
+        backend.write(&"dir/b".into(), block).unwrap();
+        let usage = backend.usage();
+        assert_eq!(usage.load(Relaxed), 8192);
+
+        usage.store(100, Relaxed);
+        assert_eq!(backend.recompute_usage().unwrap(), 8092);
+        assert_eq!(usage.load(Relaxed), 8192);
+
+        usage.store(1 << 30, Relaxed);
+        assert_eq!(backend.recompute_usage().unwrap(), 8192 - (1 << 30));
+        assert_eq!(usage.load(Relaxed), 8192);
+
+        // A hard link doesn't count again, but a file that the backend didn't
+        // write, including one still being written, does.
+        std::fs::hard_link(tmpdir.path().join("a"), tmpdir.path().join("c")).unwrap();
+        std::fs::write(tmpdir.path().join("d.mut"), [0; 1000]).unwrap();
+        assert_eq!(backend.recompute_usage().unwrap(), 1000);
+        assert_eq!(usage.load(Relaxed), 9192);
+
+        // Under `DataOnly`, files still being written don't count.
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_usage_policy(UsagePolicy::DataOnly);
+        assert_eq!(backend.recompute_usage().unwrap(), 8192);
+    }
+
+    /// Reading a file with `blocks` prefetches the blocks ahead of the one
+    /// being read, and the later reads count as hits.
+    #[test]
+    fn prefetch() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut data = FBuf::with_capacity(8 * 4096);
+        data.resize(8 * 4096, 1);
+        backend.write(&"file".into(), data).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            for block in reader.blocks(4096) {
+                assert_eq!(block.unwrap().len(), 4096);
+            }
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let counter = |name: &str| {
+            snapshot.iter().find_map(|(key, _, _, value)| match value {
+                DebugValue::Counter(n) if key.key().name() == name => Some(*n),
+                _ => None,
+            })
+        };
+
+        // Every block but the first is prefetched before it is read.
+        assert_eq!(counter(PREFETCH_BYTES), Some(7 * 4096));
+        assert_eq!(counter(PREFETCH_HIT_BYTES), Some(7 * 4096));
+    }
+
+    /// A [Write] implementation that records how many buffers each call to
+    /// [Write::write_vectored] passes.
+    #[derive(Default)]
+    struct CountingWriter {
+        data: Vec<u8>,
+        calls: Vec<usize>,
+    }
+
+    impl std::io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.write_vectored(&[std::io::IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+            self.calls.push(bufs.len());
+            for buf in bufs {
+                self.data.extend_from_slice(buf);
+            }
+            Ok(bufs.iter().map(|buf| buf.len()).sum())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// With `max_iov` set to 2, writing 10 blocks passes at most 2 of them to
+    /// each write, and a writer flushes every time it has buffered 2 blocks.
+    #[test]
+    fn max_iov() {
+        let blocks = (0..10).map(|i| [i; 512]).collect::<Vec<_>>();
+        let mut bufs = blocks
+            .iter()
+            .map(|block| std::io::IoSlice::new(block))
+            .collect::<Vec<_>>();
+        let mut writer = CountingWriter::default();
+        write_all_vectored(&mut writer, &mut bufs, 2, |_| ()).unwrap();
+        assert_eq!(writer.calls, [2; 5]);
+        assert_eq!(writer.data, blocks.concat());
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_max_iov(Some(2));
+        let path = tmpdir.path().join("file.mut");
+        let file = File::create(&path).unwrap();
+        let mut writer = PosixWriter::new(file, "file".into(), path, &backend);
+        for i in 0..10 {
+            let mut block = FBuf::with_capacity(512);
+            block.resize(512, i);
+            writer.write_block(block).unwrap();
+        }
+
+        // Every third write flushes the two blocks before it, and completing
+        // the file flushes the last two.
+        assert_eq!(writer.flushes, 4);
+        let (reader, _name) = Box::new(writer).complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), 10 * 512);
+
+        // Limits beyond the system's are clamped to it.
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_max_iov(Some(usize::MAX));
+        assert_eq!(backend.max_iov, *super::IOV_MAX);
+    }
+
+    /// `list` reports both the length of a sparse file and the storage
+    /// allocated to it, and counting physical usage counts the latter.
+    #[test]
+    fn sparse_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sparse = File::create(tmpdir.path().join("sparse")).unwrap();
+        sparse.write_all_at(&[1; 4096], 0).unwrap();
+        sparse.set_len(1024 * 1024).unwrap();
+        sparse.sync_all().unwrap();
+        let allocated = super::allocated_size(&sparse.metadata().unwrap());
+        assert!(allocated < 1024 * 1024);
+
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_physical_usage(true);
+        let mut entries = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |_path, file_type| {
+                entries.push(file_type)
+            })
+            .unwrap();
+        assert_eq!(
+            entries,
+            [StorageFileType::File {
+                size: 1024 * 1024,
+                allocated,
+            }]
+        );
+        backend.recompute_usage().unwrap();
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(usage(), allocated as i64);
+
+        // A file written through the backend counts by its allocation.
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 2);
+        backend.write(&"file".into(), block).unwrap();
+        let metadata = std::fs::metadata(tmpdir.path().join("file")).unwrap();
+        let file_allocated = super::allocated_size(&metadata);
+        assert_eq!(usage(), (allocated + file_allocated) as i64);
+
+        // Deleting files releases what they counted.
+        backend.delete(&"file".into()).unwrap();
+        backend.delete(&"sparse".into()).unwrap();
+        assert_eq!(usage(), 0);
+    }
+
+    /// Truncating a writer discards what was written after the new length,
+    /// whether it was still buffered or already flushed, and releases its
+    /// usage.
+    #[test]
+    fn truncate_to() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_usage_policy(UsagePolicy::IncludeInProgress)
+            .with_max_iov(Some(2));
+        let usage = || backend.usage.load(std::sync::atomic::Ordering::Relaxed);
+        let write = |writer: &mut Box<dyn FileWriter>, n, byte| {
+            for _ in 0..n {
+                let mut block = FBuf::with_capacity(4096);
+                block.resize(4096, byte);
+                writer.write_block(block).unwrap();
+            }
+        };
+        let contents = |parts: &[(usize, u8)]| {
+            let mut data = FBuf::new();
+            for &(len, byte) in parts {
+                data.extend_from_slice(&vec![byte; len]);
+            }
+            data
+        };
+
+        // Within the buffered range, including part of a block.
+        let mut writer = backend.create_named(&"buffered".into()).unwrap();
+        write(&mut writer, 1, 1);
+        writer.truncate_to(2048).unwrap();
+        write(&mut writer, 1, 2);
+        let (reader, _name) = writer.complete().unwrap();
+        test_read(reader.as_ref(), &contents(&[(2048, 1), (4096, 2)]));
+        assert_eq!(usage(), 2048 + 4096);
+        drop(reader);
+        assert_eq!(usage(), 0);
+
+        // Back across a flush.  With at most 2 buffers per write, the third
+        // block flushes the first two.
+        let mut writer = backend.create_named(&"flushed".into()).unwrap();
+        write(&mut writer, 3, 1);
+        assert_eq!(usage(), 8192);
+        writer.truncate_to(4096).unwrap();
+        assert_eq!(usage(), 4096);
+        assert_eq!(
+            std::fs::metadata(tmpdir.path().join("flushed.mut"))
+                .unwrap()
+                .len(),
+            4096
+        );
+        write(&mut writer, 1, 2);
+        let (reader, _name) = writer.complete().unwrap();
+        test_read(reader.as_ref(), &contents(&[(4096, 1), (4096, 2)]));
+        assert_eq!(usage(), 8192);
+        drop(reader);
+
+        // To zero, and not beyond the end.
+        let mut writer = backend.create_named(&"empty".into()).unwrap();
+        write(&mut writer, 3, 1);
+        assert!(writer.truncate_to(3 * 4096 + 1).is_err());
+        writer.truncate_to(0).unwrap();
+        assert_eq!(usage(), 0);
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), 0);
+        drop(reader);
+        assert_eq!(usage(), 0);
+    }
+
+    /// The backend reports the filesystem's free space, and refuses to
+    /// create files when that is below the configured reserve.
+    #[test]
+    fn min_free_space() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let available = backend.available_space().unwrap();
+        assert!(available > 0);
+        let missing = PosixBackend::new(tmpdir.path().join("a/b"), StorageCacheConfig::default());
+        assert!(missing.available_space().is_ok());
+
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_min_free_space(Some(u64::MAX));
+        let Err(StorageError::InsufficientSpace { reserve, .. }) =
+            backend.create_named(&"file".into())
+        else {
+            unreachable!()
+        };
+        assert_eq!(reserve, u64::MAX);
+        assert!(!tmpdir.path().join("file.mut").exists());
+
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_min_free_space(Some(512));
+        backend.write(&"file".into(), FBuf::new()).unwrap();
+    }
+
+    /// A file's ID maps back to its name for as long as the backend has the
+    /// file open, and no longer.
+    #[test]
+    fn path_for() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let name = StoragePath::from("dir/file");
+
+        let writer = backend.create_named(&name).unwrap();
+        let file_id = writer.file_id();
+        assert_eq!(backend.path_for(file_id), Some(name.clone()));
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(reader.file_id(), file_id);
+        assert_eq!(backend.path_for(file_id), Some(name.clone()));
+
+        // Files that are kept rather than deleted are forgotten, too.
+        reader.mark_for_checkpoint();
+        drop(reader);
+        assert_eq!(backend.path_for(file_id), None);
+
+        let reader = backend.open(&name).unwrap();
+        assert_eq!(backend.path_for(reader.file_id()), Some(name));
+        let file_id = reader.file_id();
+        drop(reader);
+        assert_eq!(backend.path_for(file_id), None);
+    }
+
+    /// Streaming writes with `write_all` of any length interleave with
+    /// `write_block`, with the data ending up in the file in order.
+    #[test]
+    fn write_all() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_flush_threshold(1)
+            .with_block_checksums(true);
+        let block_size = super::page_size();
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        let mut expected = Vec::new();
+        let write_all = |writer: &mut Box<dyn FileWriter>, len, byte| {
+            let data = vec![byte; len];
+            writer.write_all(&data).unwrap();
+            data
+        };
+
+        expected.extend(write_all(&mut writer, 700, 1));
+        expected.extend(write_all(&mut writer, 324, 2));
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 3);
+        expected.extend_from_slice(&block);
+        writer.write_block(block).unwrap();
+
+        // Bigger than a block, so that part of it gets flushed.
+        expected.extend(write_all(&mut writer, block_size + 904, 4));
+        expected.extend(write_all(&mut writer, 120, 5));
+        let (reader, _name) = writer.complete().unwrap();
+        test_read(reader.as_ref(), &expected);
+
+        let stats = reader.stats();
+        assert_eq!(stats.bytes_written, expected.len() as u64);
+        assert_eq!(stats.write_count, 5);
+    }
+
+    /// With a maximum block size, reads of bigger blocks fail before reading
+    /// anything, singly and in batches, while smaller blocks still read.
+    #[test]
+    fn max_block_size() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_max_block_size(Some(4096));
+        let mut block = FBuf::with_capacity(16384);
+        block.resize(16384, 1);
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        writer.write_block(block).unwrap();
+        let (completed, _name) = writer.complete().unwrap();
+        completed.mark_for_checkpoint();
+
+        let location = |offset, size| BlockLocation::new(offset, size).unwrap();
+        for reader in [completed, backend.open(&"file".into()).unwrap()] {
+            assert_eq!(reader.read_block(location(0, 4096)).unwrap().len(), 4096);
+            let Err(StorageError::BlockTooLarge { requested, max }) =
+                reader.read_block(location(0, 8192))
+            else {
+                panic!("oversized read should fail");
+            };
+            assert_eq!((requested, max), (8192, 4096));
+
+            let results = reader.read_blocks(&[location(0, 4096), location(4096, 8192)], 4096);
+            assert!(results[0].is_ok());
+            assert!(matches!(
+                results[1],
+                Err(StorageError::BlockTooLarge { .. })
+            ));
+            assert!(matches!(
+                reader.read_regions(&[location(0, 512), location(4096, 8192)]),
+                Err(StorageError::BlockTooLarge { .. })
+            ));
+        }
+    }
+
+    /// Reads that extend past the end of the file fail cleanly, even if they
+    /// ask for far more data than the file holds.
+    #[test]
+    fn read_past_end() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 1);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        let location = |offset, size| BlockLocation::new(offset, size).unwrap();
+        for past_end in [
+            location(4096, 512),
+            location(3584, 1024),
+            location(0, (1 << 31) - 512),
+            location(1 << 40, 512),
+        ] {
+            assert_eq!(
+                reader.read_block(past_end).unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        let results = reader.read_blocks(&[location(0, 512), location(0, 1 << 30)], 4096);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            results[1].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    /// Operating system errors are classified into structured variants that
+    /// keep the original error as their source.
+    #[test]
+    fn structured_errors() {
+        use std::error::Error;
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+
+        let error = backend.open(&"missing".into()).err().unwrap();
+        assert!(matches!(error, StorageError::NotFound(_)));
+        let source = error.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(matches!(
+            backend.delete(&"missing".into()),
+            Err(StorageError::NotFound(_))
+        ));
+
+        for name in ["a", "b"] {
+            let mut block = FBuf::with_capacity(512);
+            block.resize(512, 1);
+            backend.write(&name.into(), block).unwrap();
+        }
+        assert!(matches!(
+            backend.copy(&"a".into(), &"b".into()),
+            Err(StorageError::AlreadyExists(_))
+        ));
+
+        let error = StorageError::from(std::io::Error::from(std::io::ErrorKind::StorageFull));
+        assert!(matches!(error, StorageError::OutOfSpace { path: None, .. }));
+        assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+        let error = StorageError::from(std::io::Error::from_raw_os_error(libc::EIO));
+        assert!(matches!(error, StorageError::Other(_)));
+        assert_eq!(
+            error.kind(),
+            std::io::Error::from_raw_os_error(libc::EIO).kind()
+        );
+    }
+
+    /// With a block cache, reading a block a second time is served from the
+    /// cache instead of the file, and dropping the reader evicts its blocks.
+    #[test]
+    fn block_cache() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_block_cache(Some(cache.clone()));
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let location = BlockLocation::new(0, 1024).unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            assert_eq!(&reader.read_block(location).unwrap()[..], &data[..1024]);
+
+            // Empty the file behind the reader's back, so that a read that
+            // reaches the file fails.
+            File::options()
+                .write(true)
+                .open(tmpdir.path().join("file"))
+                .unwrap()
+                .set_len(0)
+                .unwrap();
+            assert_eq!(&reader.read_block(location).unwrap()[..], &data[..1024]);
+        });
+        let snapshot = snapshotter.snapshot().into_vec();
+        let counter = |name: &str| {
+            snapshot
+                .iter()
+                .find_map(|(key, _, _, value)| match value {
+                    DebugValue::Counter(n) if key.key().name() == name => Some(*n),
+                    _ => None,
+                })
+                .unwrap_or(0)
+        };
+        assert_eq!(counter(BLOCK_CACHE_MISS), 1);
+        assert_eq!(counter(BLOCK_CACHE_HIT), 1);
+        assert_eq!(counter(READS_SUCCESS), 1);
+        assert_eq!(cache.occupancy().0, 1024);
+
+        drop(reader);
+        assert_eq!(cache.occupancy().0, 0);
+    }
+
+    /// Appending to a completed file adds blocks after its existing ones,
+    /// and aborting the append puts the file back the way it was.
+    #[test]
+    fn open_append() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let block = |size: usize, value: u8| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, value);
+            block
+        };
+        backend.write(&"file".into(), block(1024, 1)).unwrap();
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(usage(), 1024);
+
+        let mut writer = backend.open_append(&"file".into()).unwrap();
+        writer.write_block(block(512, 2)).unwrap();
+        writer.write_block(block(512, 3)).unwrap();
+        let (reader, name) = writer.complete().unwrap();
+        assert_eq!(name, "file".into());
+        drop(reader);
+        assert_eq!(usage(), 2048);
+
+        let mut expected = vec![1; 1024];
+        expected.extend([2; 512]);
+        expected.extend([3; 512]);
+        test_read(backend.open(&"file".into()).unwrap().as_ref(), &expected);
+        let mut names = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |path, _file_type| {
+                names.push(path.to_string())
+            })
+            .unwrap();
+        assert_eq!(names, ["file"]);
+
+        let mut writer = backend.open_append(&"file".into()).unwrap();
+        writer.write_block(block(512, 4)).unwrap();
+        // The data that was already there can't be overwritten.
+        assert!(matches!(
+            writer.write_block_at(1536, block(1024, 5)),
+            Err(StorageError::StdIo(std::io::ErrorKind::InvalidInput))
+        ));
+        writer.write_block_at(2048, block(512, 5)).unwrap();
+        writer.abort().unwrap();
+        assert_eq!(usage(), 2048);
+        test_read(backend.open(&"file".into()).unwrap().as_ref(), &expected);
+
+        assert!(matches!(
+            backend.open_append(&"missing".into()),
+            Err(StorageError::NotFound(_))
+        ));
+    }
+
+    /// A writer from [StorageBackend::open_append] can't truncate the file
+    /// below its length when it was opened, and readers don't map the file
+    /// into memory while it is being appended to.
+    #[test]
+    fn open_append_truncate() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_mmap_threshold(Some(8192));
+        let block = |size: usize, value: u8| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, value);
+            block
+        };
+        backend.write(&"file".into(), block(1024, 1)).unwrap();
+        #[cfg(target_os = "linux")]
+        let mapped = || {
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+            let path = tmpdir.path().canonicalize().unwrap().join("file");
+            maps.contains(path.to_str().unwrap())
+        };
+
+        let mut writer = backend.open_append(&"file".into()).unwrap();
+        writer.write_block(block(512, 2)).unwrap();
+        writer.sync().unwrap();
+        assert_eq!(
+            writer.truncate_to(512).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        let reader = backend.open(&"file".into()).unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(!mapped());
+        writer.truncate_to(1024).unwrap();
+        writer.abort().unwrap();
+        let location = BlockLocation::new(0, 1024).unwrap();
+        assert_eq!(reader.read_block(location).unwrap().as_slice(), &[1; 1024]);
+        drop(reader);
+
+        // Once nothing is appending to the file, readers map it again.
+        let reader = backend.open(&"file".into()).unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(mapped());
+        test_read(reader.as_ref(), &[1; 1024]);
+    }
+
+    /// Files and directories are created with the configured permissions,
+    /// which files keep when they are completed.
+    #[test]
+    fn file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Choose a mode that common umasks (022, 002, 077) leave alone.
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_file_mode(Some(0o600));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let mut writer = backend.create_named(&"dir/file".into()).unwrap();
+        assert_eq!(mode(&tmpdir.path().join("dir/file.mut")), 0o600);
+        assert_eq!(mode(&tmpdir.path().join("dir")), 0o700);
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 1);
+        writer.write_block(block).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        reader.mark_for_checkpoint();
+        assert_eq!(mode(&tmpdir.path().join("dir/file")), 0o600);
+
+        backend
+            .copy(&"dir/file".into(), &"copy/file".into())
+            .unwrap();
+        assert_eq!(mode(&tmpdir.path().join("copy/file")), 0o600);
+        assert_eq!(mode(&tmpdir.path().join("copy")), 0o700);
+
+        assert_eq!(super::dir_mode(0o640), 0o750);
+        assert_eq!(super::dir_mode(0o644), 0o755);
+    }
+
+    /// Block slices share the cached block and outlive the reader, and
+    /// ranges outside the block are rejected.
+    #[test]
+    fn read_block_slice() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_block_cache(Some(cache.clone()));
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut block = FBuf::with_capacity(data.len());
+        block.extend_from_slice(&data);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        let location = BlockLocation::new(0, 4096).unwrap();
+        let first = reader.read_block_slice(location, 100..300).unwrap();
+        let second = reader.read_block_slice(location, 3000..4096).unwrap();
+        assert_eq!(&first[..], &data[100..300]);
+        assert_eq!(&second[..], &data[3000..]);
+        assert!(Arc::ptr_eq(first.block(), second.block()));
+        assert_eq!(cache.occupancy().0, 4096);
+
+        for sub in [4000..4097, 200..100] {
+            assert!(matches!(
+                reader.read_block_slice(location, sub),
+                Err(StorageError::StdIo(std::io::ErrorKind::InvalidInput))
+            ));
+        }
+
+        drop(reader);
+        assert_eq!(cache.occupancy().0, 0);
+        assert_eq!(&first[..], &data[100..300]);
+    }
+
+    /// A [Write] implementation that writes `limit` bytes and then makes no
+    /// more progress, returning `Ok(0)` without an error.
+    struct StallingWriter {
+        data: Vec<u8>,
+        limit: usize,
+        calls: usize,
+    }
+
+    impl std::io::Write for StallingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            let n = buf.len().min(self.limit - self.data.len());
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes that stop making progress without an error fail with
+    /// [WriteZero](std::io::ErrorKind::WriteZero) after a bounded number of
+    /// retries, with progress reported only for the bytes actually written.
+    #[test]
+    fn write_zero() {
+        let a = [1; 250];
+        let b = [2; 130];
+        let mut bufs = [std::io::IoSlice::new(&a), std::io::IoSlice::new(&b)];
+        let mut writer = StallingWriter {
+            data: Vec::new(),
+            limit: 300,
+            calls: 0,
+        };
+        let mut progress = 0;
+        let error =
+            write_all_vectored(&mut writer, &mut bufs, usize::MAX, |n| progress += n).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(writer.data.len(), 300);
+        assert_eq!(progress, 300);
+        assert_eq!(writer.calls, 2 + MAX_ZERO_WRITES);
+    }
+
+    /// Only single, normal components are accepted as parts of a path.
+    #[test]
+    fn normal_components() {
+        for part in ["file", "%2E%2E", "a.b", "..."] {
+            assert!(is_normal_component(part), "{part}");
+        }
+        for part in ["", ".", "..", "/abs", "a/b", "../escape", "a/", "/"] {
+            assert!(!is_normal_component(part), "{part}");
+        }
+    }
+
+    /// Names with traversal or absolute components resolve inside the
+    /// storage directory, and valid nested names resolve as expected.
+    #[test]
+    fn fs_path_stays_inside() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        assert_eq!(
+            backend.fs_path(&"a/b/c".into()).unwrap(),
+            tmpdir.path().join("a/b/c")
+        );
+        for name in ["../escape", "/abs", "a/../../b"] {
+            let name = StoragePath::from(name);
+            let path = backend.fs_path(&name).unwrap();
+            assert!(path.starts_with(tmpdir.path()), "{}", path.display());
+            assert!(
+                path.components()
+                    .all(|component| component != std::path::Component::ParentDir),
+                "{}",
+                path.display()
+            );
+            backend.write(&name, FBuf::new()).unwrap();
+            assert!(path.is_file());
+        }
+        assert!(!tmpdir.path().parent().unwrap().join("escape").exists());
+    }
+
+    /// Reads reuse the buffers of blocks that have been dropped, and the pool
+    /// never holds more buffers than its limit.
+    #[test]
+    fn read_buffer_pool() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let pool = Arc::new(ReadBufferPool::new(2));
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_read_buffer_pool(Some(pool.clone()));
+        let mut block = FBuf::with_capacity(8192);
+        block.resize(8192, 1);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+        let location = BlockLocation::new(0, 4096).unwrap();
+
+        let first = reader.read_block(location).unwrap();
+        let ptr = first.as_ptr();
+        assert_eq!(pool.idle(), 0);
+        drop(first);
+        assert_eq!(pool.idle(), 1);
+        let second = reader.read_block(location).unwrap();
+        assert_eq!(second.as_ptr(), ptr);
+        assert_eq!(&second[..], &[1; 4096][..]);
+
+        // A block too big for any idle buffer gets a new one, and a third
+        // block doesn't fit in the pool.
+        let big = reader
+            .read_block(BlockLocation::new(0, 8192).unwrap())
+            .unwrap();
+        let third = reader.read_block(location).unwrap();
+        drop((second, big, third));
+        assert_eq!(pool.idle(), 2);
+    }
+
+    /// Syncing writes out the buffered data without finishing the file, so
+    /// that more can be written afterward.
+    #[test]
+    fn sync() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_strict_usage(true);
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+        let block = |value| {
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, value);
+            block
+        };
+
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        writer.write_block(block(1)).unwrap();
+        writer.write_all(&[3; 512]).unwrap();
+        writer.sync().unwrap();
+        let path = tmpdir.path().join("file.mut");
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4608);
+        assert_eq!(usage(), 4608);
+
+        // Syncing again with nothing new is harmless.
+        writer.sync().unwrap();
+        assert_eq!(usage(), 4608);
+
+        writer.write_block(block(2)).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), 9216);
+        assert_eq!(usage(), 9216);
+        let mut expected = vec![1; 4096];
+        expected.extend([3; 512]);
+        expected.extend([2; 4096]);
+        test_read(reader.as_ref(), &expected);
+    }
+
+    /// Reading a block from a file that was truncated behind the reader's back
+    /// reports how much of the block is there.
+    #[test]
+    fn short_read() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut block = FBuf::with_capacity(8192);
+        block.resize(8192, 1);
+        backend.write(&"file".into(), block).unwrap();
+        let reader = backend.open(&"file".into()).unwrap();
+
+        // As if a crash lost the end of the file.
+        File::options()
+            .write(true)
+            .open(tmpdir.path().join("file"))
+            .unwrap()
+            .set_len(5000)
+            .unwrap();
+        for (offset, available) in [(4096, 904), (6144, 0)] {
+            assert!(matches!(
+                reader.read_block(BlockLocation::new(offset, 2048).unwrap()),
+                Err(StorageError::ShortRead { requested: 2048, available: a }) if a == available
+            ));
+        }
+        assert!(reader
+            .read_block(BlockLocation::new(0, 4096).unwrap())
+            .is_ok());
+    }
+
+    /// Opening a file that is already open returns the same reader, until it
+    /// is dropped or the file is deleted or replaced.
+    #[test]
+    fn shared_readers() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_shared_readers(true);
+        let name = StoragePath::from("file");
+        backend.write(&name, FBuf::new()).unwrap();
+
+        let first = backend.open(&name).unwrap();
+        let second = backend.open(&name).unwrap();
+        assert_eq!(first.file_id(), second.file_id());
+        assert_eq!(backend.open_file_count(), 1);
+
+        // Replacing the file stops sharing the old reader.
+        backend.write(&name, FBuf::new()).unwrap();
+        let third = backend.open(&name).unwrap();
+        assert_ne!(third.file_id(), first.file_id());
+
+        // So does dropping every reference to it.
+        let file_id = third.file_id();
+        drop(third);
+        assert_ne!(backend.open(&name).unwrap().file_id(), file_id);
+
+        let fourth = backend.open(&name).unwrap();
+        backend.delete(&name).unwrap();
+        assert!(matches!(
+            backend.open(&name),
+            Err(StorageError::NotFound(_))
+        ));
+        drop(fourth);
+
+        // Without sharing, each open gets its own reader.
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        backend.write(&name, FBuf::new()).unwrap();
+        assert_ne!(
+            backend.open(&name).unwrap().file_id(),
+            backend.open(&name).unwrap().file_id()
+        );
+    }
+
+    /// Syncing directories, directly and through creating files in new
+    /// directories, succeeds on an ordinary filesystem.
+    #[test]
+    fn sync_directory() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        backend.sync_directory(&StoragePath::default()).unwrap();
+
+        let name = StoragePath::from("a/b/c/file");
+        let mut writer = backend.create_named(&name).unwrap();
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 1);
+        writer.write_block(block).unwrap();
+        writer.complete().unwrap();
+        assert!(tmpdir.path().join("a/b/c/file").is_file());
+        for dir in ["a", "a/b", "a/b/c"] {
+            backend.sync_directory(&StoragePath::from(dir)).unwrap();
+        }
+
+        assert!(matches!(
+            backend.sync_directory(&StoragePath::from("missing")),
+            Err(StorageError::NotFound(_))
+        ));
+    }
+
+    /// A header written at offset 0 after the body reads back along with the
+    /// body, and writing past the end extends the file.
+    #[test]
+    fn write_block_at() {
+        let block = |size: usize, value: u8| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, value);
+            block
+        };
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = create_posix_backend(tmpdir.path());
+        let mut writer = backend.create().unwrap();
+        writer.write_block(block(512, 0)).unwrap();
+        writer.write_block(block(1024, 2)).unwrap();
+        writer.write_block_at(0, block(512, 1)).unwrap();
+        writer.write_block(block(512, 3)).unwrap();
+        writer.write_block_at(2560, block(512, 5)).unwrap();
+        writer.write_block(block(512, 6)).unwrap();
+
+        // Overlapping an unfilled reservation isn't allowed.
+        let handle = writer.reserve_block(512).unwrap();
+        assert!(matches!(
+            writer.write_block_at(handle.location().offset, block(512, 7)),
+            Err(StorageError::StdIo(std::io::ErrorKind::InvalidInput))
+        ));
+        writer.fill_reserved(handle, block(512, 7)).unwrap();
+
+        let (reader, _name) = writer.complete().unwrap();
+        let mut expected = Vec::new();
+        for (size, value) in [
+            (512, 1),
+            (1024, 2),
+            (512, 3),
+            (512, 0),
+            (512, 5),
+            (512, 6),
+            (512, 7),
+        ] {
+            expected.resize(expected.len() + size, value);
+        }
+        test_read(reader.as_ref(), &expected);
+    }
+
+    /// Paging through a directory returns each entry once, in order, and
+    /// starts each page just after the continuation token.
+    #[test]
+    fn list_paginated() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let mut expected = (0..10)
+            .map(|i| StoragePath::from(format!("dir/file{i}")))
+            .collect::<Vec<_>>();
+        for name in &expected {
+            backend.write(name, FBuf::new()).unwrap();
+        }
+        backend
+            .write(&"dir/sub/nested".into(), FBuf::new())
+            .unwrap();
+        expected.push(StoragePath::from("dir/sub"));
+
+        let parent = StoragePath::from("dir");
+        let mut listed = Vec::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let (entries, token) = backend.list_paginated(&parent, after, 4).unwrap();
+            assert!(entries.len() <= 4);
+            pages += 1;
+            listed.extend(entries.into_iter().map(|(name, _file_type)| name));
+            match token {
+                Some(token) => {
+                    assert_eq!(Some(&token), listed.last());
+                    after = Some(token);
+                }
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(listed, expected);
+
+        // Starting after an entry skips it and everything before it, whether
+        // or not the entry exists.
+        let (entries, token) = backend
+            .list_paginated(&parent, Some("dir/file4".into()), 5)
+            .unwrap();
+        let names = entries
+            .into_iter()
+            .map(|(name, _file_type)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, &expected[5..10]);
+        assert_eq!(token, Some("dir/file9".into()));
+        let (entries, token) = backend
+            .list_paginated(&parent, Some("dir/file45".into()), 100)
+            .unwrap();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(token, None);
+        let (entries, token) = backend
+            .list_paginated(&parent, Some("dir/sub".into()), 1)
+            .unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(token, None);
+
+        assert!(matches!(
+            backend.list_paginated(&parent, None, 0),
+            Err(StorageError::StdIo(std::io::ErrorKind::InvalidInput))
+        ));
+    }
+
+    /// Completing a file counts it once and records how long completing,
+    /// syncing, and renaming took.
+    #[test]
+    fn complete_metrics() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            for _ in 0..2 {
+                let mut writer = backend.create().unwrap();
+                let mut block = FBuf::with_capacity(4096);
+                block.resize(4096, 0);
+                writer.write_block(block).unwrap();
+                writer.complete().unwrap();
+            }
+
+            // Writing without completing isn't counted.
+            let mut writer = backend.create().unwrap();
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, 0);
+            writer.write_block(block).unwrap();
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let counter = snapshot
+            .iter()
+            .find_map(|(key, _, _, value)| match value {
+                DebugValue::Counter(n) if key.key().name() == FILES_COMPLETED => Some(*n),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(counter, 2);
+        for name in [COMPLETE_LATENCY, SYNC_LATENCY, RENAME_LATENCY] {
+            assert!(
+                snapshot.iter().any(|(key, _, _, value)| {
+                    key.key().name() == name
+                        && matches!(value, DebugValue::Histogram(samples) if samples.len() == 2)
+                }),
+                "{name}"
+            );
+        }
+    }
+
+    /// A `file://` URI creates a backend rooted at the URI's path, and other
+    /// URIs fail clearly.
+    #[test]
+    fn from_uri() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path().join("root");
+        for uri in [
+            format!("file://{}", root.display()),
+            format!("FILE://localhost{}", root.display()),
+        ] {
+            let backend = <dyn StorageBackendFactory>::from_uri(&uri).unwrap();
+            backend.write(&"dir/file".into(), FBuf::new()).unwrap();
+            assert!(root.join("dir/file").is_file());
+            backend.delete_recursive(&"dir".into()).unwrap();
+        }
+
+        assert!(matches!(
+            <dyn StorageBackendFactory>::from_uri("nosuchscheme://somewhere"),
+            Err(StorageError::UnsupportedUriScheme(scheme)) if scheme == "nosuchscheme"
+        ));
+        for uri in ["/no/scheme", "file://host/path", "file://relative"] {
+            assert!(
+                matches!(
+                    <dyn StorageBackendFactory>::from_uri(uri),
+                    Err(StorageError::InvalidURL(_))
+                ),
+                "{uri}"
+            );
+        }
+    }
+
+    /// Aborting a writer deletes its file, including the part already
+    /// flushed, and releases everything it wrote from usage.
+    #[test]
+    fn abort() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_flush_threshold(4096)
+            .with_strict_usage(true);
+        let mut writer = backend.create_named(&"file".into()).unwrap();
+        for _ in 0..5 {
+            let mut block = FBuf::with_capacity(65536);
+            block.resize(65536, 1);
+            writer.write_block(block).unwrap();
+        }
+        let mut written = tmpdir
+            .path()
+            .read_dir()
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(written.len(), 1);
+        let written = written.pop().unwrap();
+        assert!(written.metadata().unwrap().len() > 0);
+        assert!(backend.usage().load(std::sync::atomic::Ordering::Relaxed) > 0);
+
+        writer.abort().unwrap();
+        assert!(!written.exists());
+        assert!(tmpdir.path().read_dir().unwrap().next().is_none());
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+        assert!(!backend.exists(&"file".into()).unwrap());
+    }
+
+    /// A mapped file lends out its blocks, and an unmapped one reads them
+    /// into buffers of their own.
+    #[test]
+    fn read_block_ref() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_mmap_threshold(Some(8192));
+        let data = (0..16384).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for (name, size) in [("small", 4096), ("large", 16384)] {
+            let mut block = FBuf::with_capacity(size);
+            block.extend_from_slice(&data[..size]);
+            backend.write(&name.into(), block).unwrap();
+        }
+
+        let location = BlockLocation::new(1024, 2048).unwrap();
+        let small = backend.open(&"small".into()).unwrap();
+        let block = small.read_block_ref(location).unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(matches!(block, BlockRef::Borrowed(_)));
+        assert_eq!(block.as_slice(), &data[1024..3072]);
+        assert!(small
+            .read_block_ref(BlockLocation::new(4096, 512).unwrap())
+            .is_err());
+
+        let large = backend.open(&"large".into()).unwrap();
+        let block = large.read_block_ref(location).unwrap();
+        assert!(matches!(block, BlockRef::Owned(_)));
+        assert_eq!(block.as_slice(), &data[1024..3072]);
+    }
+
+    /// Opening a file that another thread is about to complete waits for it
+    /// with [PosixBackend::with_open_retry], and fails right away without.
+    #[test]
+    fn open_retry() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        assert!(matches!(
+            backend.open(&"missing".into()),
+            Err(StorageError::NotFound(_))
+        ));
+
+        let backend = backend.with_open_retry(Some(OpenRetryConfig {
+            attempts: 1000,
+            delay_ms: 1,
+        }));
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 7);
+        let name = StoragePath::from("racy");
+        let reader = std::thread::scope(|scope| {
+            let opener = scope.spawn(|| backend.open(&name).unwrap());
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                backend.write(&name, block.clone()).unwrap();
+            });
+            opener.join().unwrap()
+        });
+        assert_eq!(reader.get_size().unwrap(), 4096);
+        assert_eq!(
+            reader
+                .read_block(BlockLocation::new(0, 4096).unwrap())
+                .unwrap()[..],
+            block[..]
+        );
+
+        let backend = backend.with_open_retry(Some(OpenRetryConfig {
+            attempts: 3,
+            delay_ms: 1,
+        }));
+        assert!(matches!(
+            backend.open(&"missing".into()),
+            Err(StorageError::NotFound(_))
+        ));
+    }
+
+    /// A completed file's reader doesn't keep the file until it is marked for
+    /// a checkpoint, but a reader for an opened file always keeps it.
+    #[test]
+    fn is_kept() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 3);
+
+        let mut writer = backend.create_named(&"marked".into()).unwrap();
+        writer.write_block(block.clone()).unwrap();
+        let (reader, _path) = writer.complete().unwrap();
+        assert!(!reader.is_kept());
+        assert!(reader.will_delete_on_drop());
+        reader.mark_for_checkpoint();
+        assert!(reader.is_kept());
+        assert!(!reader.will_delete_on_drop());
+        drop(reader);
+        assert!(backend.exists(&"marked".into()).unwrap());
+
+        let mut writer = backend.create_named(&"unmarked".into()).unwrap();
+        writer.write_block(block).unwrap();
+        let (reader, _path) = writer.complete().unwrap();
+        assert!(reader.will_delete_on_drop());
+        drop(reader);
+        assert!(!backend.exists(&"unmarked".into()).unwrap());
+
+        let reader = backend.open(&"marked".into()).unwrap();
+        assert!(reader.is_kept());
+        assert!(!reader.will_delete_on_drop());
+    }
+
+    /// [copy_file] copies a file from one backend to another, including a
+    /// final partial block, and leaves nothing behind when it fails.
+    #[test]
+    fn copy_file_between_backends() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = PosixBackend::new(src_dir.path(), StorageCacheConfig::default());
+        let dst = PosixBackend::new(dst_dir.path(), StorageCacheConfig::default());
+
+        let mut content = FBuf::with_capacity(5632);
+        content.extend_from_slice(&(0..5632).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+        src.write(&"src".into(), content.clone()).unwrap();
+        assert_eq!(
+            copy_file(&src, &"src".into(), &dst, &"dir/dst".into(), 2048).unwrap(),
+            5632
+        );
+        let copy = dst.open(&"dir/dst".into()).unwrap();
+        assert_eq!(copy.get_size().unwrap(), 5632);
+        assert_eq!(
+            copy.read_block(BlockLocation::new(0, 5632).unwrap())
+                .unwrap()
+                .as_slice(),
+            content.as_slice()
+        );
+        drop(copy);
+
+        assert!(matches!(
+            copy_file(&src, &"missing".into(), &dst, &"missing".into(), 2048),
+            Err(StorageError::NotFound(_))
+        ));
+        assert!(matches!(
+            copy_file(&src, &"src".into(), &dst, &"odd".into(), 1000),
+            Err(StorageError::StdIo(std::io::ErrorKind::InvalidInput))
+        ));
+
+        let full = PosixBackend::new(dst_dir.path(), StorageCacheConfig::default())
+            .with_strict_usage(true)
+            .with_quota(Some(4096));
+        assert!(copy_file(&src, &"src".into(), &full, &"full".into(), 2048).is_err());
+        assert!(!full.exists(&"full".into()).unwrap());
+        assert!(!dst.exists(&"missing".into()).unwrap());
+        assert!(!dst.exists(&"odd".into()).unwrap());
+    }
+
+    /// Dropping a temporary file counts the bytes it frees, or, if it can't be
+    /// deleted, the file and the bytes left behind.
+    #[test]
+    fn delete_metrics() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default());
+        let mut block = FBuf::with_capacity(4096);
+        block.resize(4096, 5);
+        let complete = |name: &str| {
+            let mut writer = backend.create_named(&name.into()).unwrap();
+            writer.write_block(block.clone()).unwrap();
+            writer.complete().unwrap().0
+        };
+        let deleted = complete("deleted");
+        let leaked = complete("leaked");
+
+        // Make "leaked" undeletable by putting a read-only directory where it
+        // was.  Making the storage directory read-only instead wouldn't stop
+        // root from deleting the file, but nobody can `remove_file` a
+        // directory.
+        let leaked_path = tmpdir.path().join("leaked");
+        std::fs::remove_file(&leaked_path).unwrap();
+        std::fs::create_dir(&leaked_path).unwrap();
+        let mut permissions = leaked_path.metadata().unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&leaked_path, permissions).unwrap();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            drop(deleted);
+            drop(leaked);
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let counter = |name: &str| {
+            snapshot
+                .iter()
+                .find_map(|(key, _, _, value)| match value {
+                    DebugValue::Counter(n) if key.key().name() == name => Some(*n),
+                    _ => None,
+                })
+                .unwrap_or(0)
+        };
+        assert_eq!(counter(BYTES_DELETED), 4096);
+        assert_eq!(counter(FILES_DELETE_FAILED), 1);
+        assert_eq!(counter(FILES_DELETE_FAILED_BYTES), 4096);
+        assert!(!tmpdir.path().join("deleted").exists());
+        assert!(leaked_path.is_dir());
+
+        // Let the temporary directory clean up after itself.
+        let mut permissions = leaked_path.metadata().unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&leaked_path, permissions).unwrap();
+    }
+
+    /// With a scratch directory, a file stays there, without counting toward
+    /// usage, until completing it moves it into the storage directory.
+    #[test]
+    fn scratch_base() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let scratch = tempfile::tempdir().unwrap();
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_flush_threshold(4096)
+            .with_strict_usage(true)
+            .with_scratch_base(Some(scratch.path().to_path_buf()));
+        let mut writer = backend.create_named(&"dir/file".into()).unwrap();
+        let mut block = FBuf::with_capacity(65536);
+        block.resize(65536, 4);
+        writer.write_block(block.clone()).unwrap();
+        writer.write_block(block.clone()).unwrap();
+        assert!(scratch.path().join("dir/file.mut").exists());
+        assert!(tmpdir.path().join("dir").is_dir());
+        assert!(!tmpdir.path().join("dir/file.mut").exists());
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+
+        let (reader, _path) = writer.complete().unwrap();
+        assert!(!scratch.path().join("dir/file.mut").exists());
+        assert!(tmpdir.path().join("dir/file").exists());
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            131072
+        );
+        assert_eq!(
+            reader
+                .read_block(BlockLocation::new(65536, 65536).unwrap())
+                .unwrap()[..],
+            block[..]
+        );
+
+        // Dropping the reader deletes the completed file from its new home.
+        drop(reader);
+        assert!(!tmpdir.path().join("dir/file").exists());
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+
+        // Incomplete files in the scratch directory are recovered, too.
+        std::fs::write(scratch.path().join("dir/leftover.mut"), b"x").unwrap();
+        assert_eq!(backend.recover().unwrap(), 1);
+        assert!(!scratch.path().join("dir/leftover.mut").exists());
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    /// [move_file] renames when it can and otherwise copies.
+    #[test]
+    fn move_file_across_filesystems() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let from = tmpdir.path().join("from");
+        let to = tmpdir.path().join("to");
+
+        std::fs::write(&from, b"renamed").unwrap();
+        assert!(!move_file(&from, &to, DurabilityMode::SyncAll, |from, to| {
+            std::fs::rename(from, to)
+        })
+        .unwrap());
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"renamed");
+
+        // Pretend that `from` and `to` are on different filesystems.
+        std::fs::write(&from, b"copied").unwrap();
+        let cross_device = |_: &Path, _: &Path| Err(std::io::Error::from_raw_os_error(libc::EXDEV));
+        assert!(move_file(&from, &to, DurabilityMode::SyncAll, cross_device).unwrap());
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"copied");
+        assert_eq!(tmpdir.path().read_dir().unwrap().count(), 1);
+
+        // Other errors aren't retried by copying.
+        std::fs::write(&from, b"failed").unwrap();
+        let fails =
+            |_: &Path, _: &Path| Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(move_file(&from, &to, DurabilityMode::SyncAll, fails).is_err());
+        assert_eq!(std::fs::read(&from).unwrap(), b"failed");
+        assert_eq!(std::fs::read(&to).unwrap(), b"copied");
+    }
+
+    /// [STORAGE_USAGE_BYTES] follows usage through writes and deletions.
+    #[test]
+    fn usage_gauge() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_strict_usage(true);
+        let block = |size| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, 6);
+            block
+        };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let gauge = || {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .find_map(|(key, _, _, value)| match value {
+                    DebugValue::Gauge(value) if key.key().name() == STORAGE_USAGE_BYTES => {
+                        Some(value.0)
+                    }
+                    _ => None,
+                })
+        };
+        metrics::with_local_recorder(&recorder, || {
+            backend.write(&"a".into(), block(8192)).unwrap();
+            assert_eq!(gauge(), Some(8192.0));
+            backend.write(&"dir/b".into(), block(4096)).unwrap();
+            backend.write(&"dir/c".into(), block(4096)).unwrap();
+            assert_eq!(gauge(), Some(16384.0));
+
+            backend.delete(&"a".into()).unwrap();
+            assert_eq!(gauge(), Some(8192.0));
+            backend.delete_recursive(&"dir".into()).unwrap();
+            assert_eq!(gauge(), Some(0.0));
+        });
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    /// A flush that fails, either partway through writing or when verifying
+    /// the data, leaves the file and usage as they were before it, so that
+    /// the next flush doesn't write the data twice.
+    #[test]
+    fn failed_flush() {
+        use std::io::Write;
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend =
+            PosixBackend::new(tmpdir.path(), StorageCacheConfig::default()).with_write_verify(true);
+        let usage = backend.usage();
+        let before = usage.load(std::sync::atomic::Ordering::Relaxed);
+
+        let path = tmpdir.path().join("file.mut");
+        let file = File::create(&path).unwrap();
+        let mut writer = PosixWriter::new(file, "file".into(), path.clone(), &backend);
+        let mut expected = Vec::new();
+        for i in 0..3 {
+            let mut block = FBuf::with_capacity(4096);
+            block.resize(4096, i + 1);
+            expected.extend_from_slice(block.as_slice());
+            writer.write_block(block).unwrap();
+        }
+        assert_eq!(writer.flushes, 0);
+
+        // Write the first block and part of the second, then fail.
+        writer
+            .flush_with(|mut file, bufs, _max_iov, progress| {
+                let n = file.write(&bufs[0])?;
+                progress(n);
+                let n = file.write(&bufs[1][..100])?;
+                progress(n);
+                Err(IoError::from_raw_os_error(libc::EIO))
+            })
+            .unwrap_err();
+        assert_eq!(writer.drop.size, 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(usage.load(std::sync::atomic::Ordering::Relaxed), before);
+
+        // Write all of the data, but wrongly, so that verifying it fails.
+        let error = writer
+            .flush_with(|mut file, bufs, _max_iov, progress| {
+                for buf in bufs.iter() {
+                    let n = file.write(&vec![0; buf.len()])?;
+                    progress(n);
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StorageError::WriteVerifyFailed { offset: 0 }
+        ));
+        assert_eq!(writer.drop.size, 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(usage.load(std::sync::atomic::Ordering::Relaxed), before);
+
+        // Now the data gets written once, in the right place.
+        let (reader, _name) = Box::new(writer).complete().unwrap();
+        assert_eq!(reader.get_size().unwrap(), expected.len() as u64);
+        assert_eq!(std::fs::read(tmpdir.path().join("file")).unwrap(), expected);
+        assert_eq!(
+            usage.load(std::sync::atomic::Ordering::Relaxed),
+            before + expected.len() as i64
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Trash for [PosixBackend].
+//!
+//! With [PosixBackend::with_trash], deleting a file or directory moves it into
+//! a new subdirectory of [TRASH_DIRECTORY] instead of unlinking it, from which
+//! [PosixBackend::restore] can bring it back until
+//! [PosixBackend::empty_trash] purges it.
+
+use super::{add_usage, storage_error, subtract_usage, PosixBackend};
+use crate::storage::backend::{release_usage, StorageError};
+use feldera_storage::StoragePath;
+use std::{
+    ffi::OsStr,
+    fs::{self, Metadata},
+    io::{Error as IoError, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+/// Name of the directory, directly within the storage directory, that holds
+/// deleted files when the trash is enabled.  See [PosixBackend::with_trash].
+/// Listings leave it out, and it doesn't count toward usage.
+pub const TRASH_DIRECTORY: &str = ".trash";
+
+/// Returns true if `file_name`, in `parent`, is [TRASH_DIRECTORY].
+pub(super) fn is_trash(parent: &StoragePath, file_name: &OsStr) -> bool {
+    parent.parts().next().is_none() && file_name == TRASH_DIRECTORY
+}
+
+impl PosixBackend {
+    /// Returns this backend, modified so that, if `trash` is true,
+    /// [StorageBackend::delete] and [StorageBackend::delete_recursive] move
+    /// what they delete into a new subdirectory of [TRASH_DIRECTORY] named
+    /// for the time of deletion, instead of unlinking it.  Deleted files can
+    /// then be brought back with [Self::restore] until [Self::empty_trash]
+    /// purges them.  See [StorageConfig::trash].
+    ///
+    /// Bytes in the trash count toward [Self::trash_usage] instead of
+    /// [StorageBackend::usage].  This measures what is already in the trash.
+    ///
+    /// [StorageBackend::delete]: feldera_storage::StorageBackend::delete
+    /// [StorageBackend::delete_recursive]: feldera_storage::StorageBackend::delete_recursive
+    /// [StorageBackend::usage]: feldera_storage::StorageBackend::usage
+    /// [StorageConfig::trash]: feldera_types::config::StorageConfig::trash
+    pub fn with_trash(mut self, trash: bool) -> Self {
+        self.trash = trash;
+        let trash_path = self.base.join(TRASH_DIRECTORY);
+        let trash_usage = fs::symlink_metadata(&trash_path)
+            .and_then(|metadata| self.subtree_usage(&trash_path, &metadata))
+            .unwrap_or(0);
+        self.trash_usage
+            .store(trash_usage as i64, Ordering::Relaxed);
+        self
+    }
+
+    /// Returns the number of bytes in the trash.  See [Self::with_trash].
+    pub fn trash_usage(&self) -> i64 {
+        self.trash_usage.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes that deleting the file or directory at
+    /// `path`, with `metadata`, would release from usage: the files in it
+    /// that count toward usage and have no other links, plus its
+    /// directories if our [UsagePolicy](feldera_types::config::UsagePolicy)
+    /// counts them.
+    fn subtree_usage(&self, path: &Path, metadata: &Metadata) -> Result<u64, IoError> {
+        if metadata.is_dir() {
+            let mut usage = if self.usage_policy.counts_directories() {
+                metadata.size()
+            } else {
+                0
+            };
+            for child in fs::read_dir(path)? {
+                let child = child?;
+                usage += self.subtree_usage(&child.path(), &child.metadata()?)?;
+            }
+            Ok(usage)
+        } else if metadata.is_file() && metadata.nlink() == 1 && self.counts_file(path) {
+            Ok(self.usage_size(metadata))
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Moves `name` into a new subdirectory of [TRASH_DIRECTORY], moving
+    /// the bytes it takes from usage to trash usage.
+    pub(super) fn move_to_trash(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let path = self.fs_path(name)?;
+        let metadata = fs::symlink_metadata(&path)?;
+        let size = self
+            .subtree_usage(&path, &metadata)
+            .map_err(|error| storage_error(error, &self.base))?;
+
+        // Give each deletion a directory of its own, so that deleting
+        // something with the same name again doesn't collide.
+        let trash_path = self.base.join(TRASH_DIRECTORY);
+        fs::create_dir_all(&trash_path).map_err(|error| storage_error(error, &self.base))?;
+        let now = self.clock.now();
+        let mut timestamp = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let deletion_dir = loop {
+            let deletion_dir = trash_path.join(format!("{timestamp:020}"));
+            match fs::create_dir(&deletion_dir) {
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => timestamp += 1,
+                Err(error) => return Err(storage_error(error, &self.base)),
+                Ok(()) => break deletion_dir,
+            }
+        };
+
+        let mut destination = deletion_dir;
+        destination.extend(name.parts().map(|part| part.as_ref().to_string()));
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).map_err(|error| storage_error(error, &self.base))?;
+        }
+        fs::rename(&path, &destination).map_err(|error| self.deletion_error(error, &path))?;
+        self.invalidate_readers(name);
+        self.evict_blocks(name);
+        subtract_usage(&self.usage, size, self.strict_usage);
+        self.trash_usage.fetch_add(size as i64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Moves `name` back out of the trash, from the latest deletion that
+    /// includes it, to where it was before it was deleted.  Fails with
+    /// [StorageError::NotFound] if the trash doesn't have `name`, and with
+    /// [StorageError::AlreadyExists] if `name` exists again.
+    pub fn restore(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let path = self.fs_path(name)?;
+        let trash_path = self.base.join(TRASH_DIRECTORY);
+        let mut deletions = match fs::read_dir(&trash_path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+            result => result?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        deletions.sort();
+        let (deletion_dir, source, metadata) = deletions
+            .into_iter()
+            .rev()
+            .find_map(|deletion_dir| {
+                let mut source = deletion_dir.clone();
+                source.extend(name.parts().map(|part| part.as_ref().to_string()));
+                let metadata = fs::symlink_metadata(&source).ok()?;
+                Some((deletion_dir, source, metadata))
+            })
+            .ok_or_else(|| {
+                StorageError::NotFound(Arc::new(IoError::new(
+                    ErrorKind::NotFound,
+                    format!("{name} is not in the trash"),
+                )))
+            })?;
+        if fs::symlink_metadata(&path).is_ok() {
+            return Err(StorageError::AlreadyExists(Arc::new(IoError::new(
+                ErrorKind::AlreadyExists,
+                format!("{name} already exists"),
+            ))));
+        }
+
+        let size = self
+            .subtree_usage(&source, &metadata)
+            .map_err(|error| storage_error(error, &self.base))?;
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)
+                .map_err(|error| storage_error(error, &self.base))?;
+        }
+        fs::rename(&source, &path).map_err(|error| storage_error(error, &self.base))?;
+        release_usage(&self.trash_usage, size, self.strict_usage);
+        add_usage(&self.usage, size);
+
+        // Clean up the directories that held `name` in the trash, stopping
+        // at the first one that still holds something else.
+        for dir in source.ancestors().skip(1) {
+            if !dir.starts_with(&deletion_dir) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Permanently deletes everything that was moved into the trash at least
+    /// `older_than` ago, and returns the number of bytes that this reclaimed
+    /// from [Self::trash_usage].
+    pub fn empty_trash(&self, older_than: Duration) -> Result<u64, StorageError> {
+        let trash_path = self.base.join(TRASH_DIRECTORY);
+        let entries = match fs::read_dir(&trash_path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(0),
+            result => result?,
+        };
+        let cutoff = self
+            .clock
+            .now()
+            .checked_sub(older_than)
+            .and_then(|cutoff| cutoff.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |cutoff| cutoff.as_nanos());
+        let mut reclaimed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let deleted_at = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u128>().ok());
+            if deleted_at.is_none_or(|deleted_at| deleted_at > cutoff) {
+                continue;
+            }
+            let path = entry.path();
+            let size = self
+                .subtree_usage(&path, &entry.metadata()?)
+                .map_err(|error| storage_error(error, &self.base))?;
+            fs::remove_dir_all(&path).map_err(|error| self.deletion_error(error, &path))?;
+            release_usage(&self.trash_usage, size, self.strict_usage);
+            reclaimed += size;
+        }
+        Ok(reclaimed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        backend::{posix::PosixBackend, StorageError},
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::{clock::ManualClock, StorageBackend, StoragePath};
+    use feldera_types::config::StorageCacheConfig;
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    /// With the trash enabled, deleting moves files into the trash, where
+    /// they count separately from usage, until they are restored or the
+    /// trash is emptied.
+    #[test]
+    fn trash() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+        ));
+        let backend = PosixBackend::new(tmpdir.path(), StorageCacheConfig::default())
+            .with_clock(clock.clone())
+            .with_strict_usage(true)
+            .with_trash(true);
+        let block = |size: usize, value: u8| {
+            let mut block = FBuf::with_capacity(size);
+            block.resize(size, value);
+            block
+        };
+        let usage = || backend.usage().load(std::sync::atomic::Ordering::Relaxed);
+
+        // Delete a file and then restore it.
+        let name = StoragePath::from("dir/file");
+        backend.write(&name, block(4096, 1)).unwrap();
+        assert_eq!(usage(), 4096);
+        backend.delete(&name).unwrap();
+        assert!(!backend.exists(&name).unwrap());
+        assert_eq!(usage(), 0);
+        assert_eq!(backend.trash_usage(), 4096);
+        let mut listed = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |path, _file_type| {
+                listed.push(path.clone())
+            })
+            .unwrap();
+        assert_eq!(listed, [StoragePath::from("dir")]);
+
+        backend.restore(&name).unwrap();
+        assert_eq!(backend.read(&name).unwrap().as_slice(), &[1; 4096]);
+        assert_eq!(usage(), 4096);
+        assert_eq!(backend.trash_usage(), 0);
+        assert!(matches!(
+            backend.restore(&name),
+            Err(StorageError::NotFound(_))
+        ));
+
+        // Deleting the same name twice within the same instant keeps both,
+        // and restoring brings back the later one.
+        backend.delete(&name).unwrap();
+        backend.write(&name, block(512, 2)).unwrap();
+        backend.delete(&name).unwrap();
+        assert_eq!(backend.trash_usage(), 4096 + 512);
+        backend.restore(&name).unwrap();
+        assert_eq!(backend.read(&name).unwrap().as_slice(), &[2; 512]);
+        assert!(matches!(
+            backend.restore(&name),
+            Err(StorageError::AlreadyExists(_))
+        ));
+        backend.delete(&name).unwrap();
+
+        // Delete a whole directory, then purge the trash.
+        backend.write(&"ckpt/a".into(), block(1024, 3)).unwrap();
+        backend.write(&"ckpt/b".into(), block(2048, 4)).unwrap();
+        clock.advance(Duration::from_secs(60));
+        backend.delete_recursive(&"ckpt".into()).unwrap();
+        assert!(!backend.exists(&"ckpt".into()).unwrap());
+        assert_eq!(usage(), 0);
+        assert_eq!(backend.trash_usage(), 4096 + 512 + 1024 + 2048);
+
+        // Only the deletions from before the clock advanced are old enough.
+        assert_eq!(
+            backend.empty_trash(Duration::from_secs(30)).unwrap(),
+            4096 + 512
+        );
+        assert_eq!(backend.trash_usage(), 1024 + 2048);
+        assert!(matches!(
+            backend.restore(&name),
+            Err(StorageError::NotFound(_))
+        ));
+        assert_eq!(backend.empty_trash(Duration::ZERO).unwrap(), 1024 + 2048);
+        assert_eq!(backend.trash_usage(), 0);
+        assert!(matches!(
+            backend.restore(&"ckpt/a".into()),
+            Err(StorageError::NotFound(_))
+        ));
+        assert_eq!(backend.recompute_usage().unwrap(), 0);
+    }
+}
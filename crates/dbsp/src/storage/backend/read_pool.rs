@@ -0,0 +1,187 @@
+//! Pool of buffers for reads, so that reading a block can reuse a buffer from
+//! an earlier read instead of allocating a new one.
+//!
+//! [FileReader::read_block](super::FileReader::read_block) returns its block
+//! as an `Arc<FBuf>`, so the pool can't tell when the last reference to a
+//! block is dropped.  Instead, it keeps a reference to each block that it
+//! lends out and, when it needs a buffer, reclaims the blocks that nothing
+//! else references any longer.  The pool holds at most a fixed number of
+//! buffers, counting both those it has lent and those that are idle, so that
+//! idle capacity stays bounded.
+
+use crate::circuit::metrics::{READ_POOL_HIT, READ_POOL_MISS};
+use crate::storage::buffer_cache::FBuf;
+use metrics::counter;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+
+/// A bounded pool of read buffers.  See the [module documentation](self).
+///
+/// A pool may be shared among several backends by passing the same
+/// `Arc<ReadBufferPool>` to each of them.
+pub struct ReadBufferPool {
+    max_buffers: usize,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// Buffers ready for reuse.
+    idle: Vec<FBuf>,
+
+    /// Blocks lent out, which may still be referenced elsewhere.
+    lent: Vec<Arc<FBuf>>,
+}
+
+impl PoolState {
+    /// Moves the blocks in `lent` that nothing else references to `idle`.
+    fn reclaim(&mut self) {
+        for block in std::mem::take(&mut self.lent) {
+            match Arc::try_unwrap(block) {
+                Ok(buffer) => self.idle.push(buffer),
+                Err(block) => self.lent.push(block),
+            }
+        }
+    }
+}
+
+impl Debug for ReadBufferPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ReadBufferPool")
+            .field("max_buffers", &self.max_buffers)
+            .finish()
+    }
+}
+
+impl ReadBufferPool {
+    /// Creates a new pool that holds up to `max_buffers` buffers.
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            max_buffers,
+            state: Mutex::new(PoolState::default()),
+        }
+    }
+
+    /// Returns an empty buffer with at least `capacity` bytes of capacity,
+    /// the smallest idle one that is big enough if there is one, otherwise a
+    /// newly allocated one.
+    pub fn get(&self, capacity: usize) -> FBuf {
+        let mut state = self.state.lock().unwrap();
+        state.reclaim();
+        let best = state
+            .idle
+            .iter()
+            .enumerate()
+            .filter(|(_, buffer)| buffer.capacity() >= capacity)
+            .min_by_key(|(_, buffer)| buffer.capacity())
+            .map(|(index, _)| index);
+        match best {
+            Some(index) => {
+                counter!(READ_POOL_HIT).increment(1);
+                let mut buffer = state.idle.swap_remove(index);
+                buffer.clear();
+                buffer
+            }
+            None => {
+                drop(state);
+                counter!(READ_POOL_MISS).increment(1);
+                FBuf::with_capacity(capacity)
+            }
+        }
+    }
+
+    /// Records that `block`, which holds a buffer from [Self::get], has been
+    /// handed out, so that its buffer can be reused once nothing else
+    /// references it.  If the pool is full, the buffer is freed normally
+    /// instead.
+    pub fn lend(&self, block: &Arc<FBuf>) {
+        let mut state = self.state.lock().unwrap();
+        if state.lent.len() + state.idle.len() < self.max_buffers {
+            state.lent.push(block.clone());
+        }
+    }
+
+    /// Returns the number of buffers that are ready for reuse.
+    pub fn idle(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.reclaim();
+        state.idle.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadBufferPool;
+    use crate::storage::buffer_cache::FBuf;
+    use std::sync::Arc;
+
+    /// Gets a buffer of `size` bytes from `pool`, fills it, and lends it.
+    fn lend(pool: &ReadBufferPool, size: usize) -> Arc<FBuf> {
+        let mut buffer = pool.get(size);
+        buffer.resize(size, 1);
+        let block = Arc::new(buffer);
+        pool.lend(&block);
+        block
+    }
+
+    /// A lent buffer becomes idle once nothing else references it, and
+    /// comes back empty from the next [ReadBufferPool::get] that fits.
+    #[test]
+    fn reuses_buffers() {
+        let pool = ReadBufferPool::new(4);
+        let block = lend(&pool, 1024);
+        let ptr = block.as_ptr();
+        assert_eq!(pool.idle(), 0);
+
+        drop(block);
+        assert_eq!(pool.idle(), 1);
+
+        let buffer = pool.get(512);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 1024);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    /// A buffer that's still referenced isn't reused, and a request that no
+    /// idle buffer fits gets a new one.
+    #[test]
+    fn skips_referenced_and_small_buffers() {
+        let pool = ReadBufferPool::new(4);
+        let held = lend(&pool, 1024);
+        assert_ne!(pool.get(512).as_ptr(), held.as_ptr());
+
+        drop(lend(&pool, 512));
+        assert_eq!(pool.idle(), 1);
+        assert!(pool.get(4096).capacity() >= 4096);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    /// [ReadBufferPool::get] picks the smallest idle buffer that fits.
+    #[test]
+    fn picks_smallest_fit() {
+        let pool = ReadBufferPool::new(4);
+        let big = lend(&pool, 8192);
+        let small = lend(&pool, 1024);
+        let small_ptr = small.as_ptr();
+        drop((big, small));
+        assert_eq!(pool.idle(), 2);
+        assert_eq!(pool.get(1000).as_ptr(), small_ptr);
+    }
+
+    /// The pool holds no more than its limit of buffers, counting both lent
+    /// and idle ones.
+    #[test]
+    fn limits_buffers() {
+        let pool = ReadBufferPool::new(2);
+        let blocks = (0..3).map(|_| lend(&pool, 512)).collect::<Vec<_>>();
+        drop(blocks);
+        assert_eq!(pool.idle(), 2);
+
+        // With the pool full of idle buffers, lending more doesn't keep them.
+        let extra = Arc::new(FBuf::with_capacity(512));
+        pool.lend(&extra);
+        drop(extra);
+        assert_eq!(pool.idle(), 2);
+    }
+}
@@ -0,0 +1,609 @@
+//! [StorageBackend] decorator that retries operations that fail with
+//! transient errors.
+//!
+//! Network-attached storage occasionally fails an operation with an error,
+//! such as `EIO` or `ETIMEDOUT`, that goes away if the operation is simply
+//! tried again.  [RetryBackend] retries such operations, with exponential
+//! backoff, where that is safe:
+//!
+//! - Creating and opening files, and reading from them, is always safe to
+//!   retry.
+//!
+//! - A [FileWriter::write_block] that fails may have written part of the
+//!   block, so it is only retried if the wrapped backend's writers are
+//!   declared idempotent with [RetryBackend::with_idempotent_writes].
+//!
+//! - [FileWriter::complete] consumes the writer, so it can't be retried.
+//!
+//! Errors other than those in the retryable list, such as
+//! [ErrorKind::NotFound] and [ErrorKind::AlreadyExists], are returned
+//! immediately.
+
+use super::{
+    BlockHandle, BlockLocation, BlockRef, FileId, FileReader, FileWriter, HasFileId,
+    StorageBackend, StorageError,
+};
+use crate::circuit::metrics::RETRIES;
+use crate::storage::buffer_cache::FBuf;
+use feldera_storage::{FileStats, StorageCapabilities, StorageFileType, StoragePath};
+use metrics::counter;
+use std::io::ErrorKind;
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+/// Returns true if an operation that failed with `error` might succeed if
+/// retried.
+///
+/// [StorageError::NotYetWritten] isn't retried: the data that it is waiting
+/// for appears only once the writer flushes it, which retrying doesn't hasten.
+fn is_retryable(error: &StorageError) -> bool {
+    let transient = |kind: ErrorKind| {
+        matches!(
+            kind,
+            ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+        )
+    };
+    match error {
+        // `EIO` doesn't have a kind of its own; its kind is shared with many
+        // errors that aren't transient, so check for it by number.
+        StorageError::Interrupted(error) | StorageError::Other(error) => {
+            error.raw_os_error() == Some(libc::EIO) || transient(error.kind())
+        }
+        StorageError::StdIo(kind) | StorageError::ObjectStore { kind, .. } => transient(*kind),
+        _ => false,
+    }
+}
+
+/// How many times to try an operation, and how long to wait in between.
+#[derive(Clone, Debug)]
+struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Runs `op` until it succeeds, fails with an error that isn't
+    /// retryable, or has been tried `max_attempts` times.
+    fn run<T>(
+        &self,
+        what: &str,
+        mut op: impl FnMut() -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(error) if attempt < self.max_attempts && is_retryable(&error) => {
+                    debug!(
+                        "retrying {what} in {backoff:?} after attempt {attempt} failed: {error}"
+                    );
+                    counter!(RETRIES).increment(1);
+                    sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A [StorageBackend] that retries operations on another backend that fail
+/// with transient errors.  See the [module documentation](self).
+pub struct RetryBackend {
+    inner: Arc<dyn StorageBackend>,
+    policy: Arc<RetryPolicy>,
+
+    /// Whether the inner backend's [FileWriter::write_block] can safely be
+    /// retried.
+    idempotent_writes: bool,
+}
+
+impl RetryBackend {
+    /// Returns a new backend that retries failed operations on `inner` up to
+    /// 3 times in total, waiting 10 ms after the first failure and twice as
+    /// long after each later one, up to 1 s.
+    pub fn new(inner: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            inner,
+            policy: Arc::new(RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_secs(1),
+            }),
+            idempotent_writes: false,
+        }
+    }
+
+    /// Returns this backend, modified to try each operation up to
+    /// `max_attempts` times in total.  A value of 0 is treated as 1.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        Arc::make_mut(&mut self.policy).max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Returns this backend, modified to wait `initial` after the first
+    /// failure of an operation, doubling the wait after each later failure
+    /// up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        let policy = Arc::make_mut(&mut self.policy);
+        policy.initial_backoff = initial;
+        policy.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Returns this backend, modified to also retry
+    /// [FileWriter::write_block] (if `idempotent_writes` is true).  This is
+    /// only safe if a failed write leaves the writer as if it had never been
+    /// attempted.
+    pub fn with_idempotent_writes(mut self, idempotent_writes: bool) -> Self {
+        self.idempotent_writes = idempotent_writes;
+        self
+    }
+
+    /// Returns the wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn StorageBackend> {
+        &self.inner
+    }
+
+    fn wrap_reader(&self, reader: Arc<dyn FileReader>) -> Arc<dyn FileReader> {
+        Arc::new(RetryReader {
+            inner: reader,
+            policy: self.policy.clone(),
+        })
+    }
+}
+
+impl StorageBackend for RetryBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let writer = self
+            .policy
+            .run("create", || self.inner.create_named(name))?;
+        Ok(Box::new(RetryWriter {
+            inner: writer,
+            policy: self.policy.clone(),
+            idempotent: self.idempotent_writes,
+        }))
+    }
+
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        let reader = self.policy.run("open", || self.inner.open(name))?;
+        Ok(self.wrap_reader(reader))
+    }
+
+    fn open_append(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let writer = self
+            .policy
+            .run("open for append", || self.inner.open_append(name))?;
+        Ok(Box::new(RetryWriter {
+            inner: writer,
+            policy: self.policy.clone(),
+            idempotent: self.idempotent_writes,
+        }))
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        self.inner.list(parent, cb)
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.inner.delete(name)
+    }
+
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.inner.delete_recursive(name)
+    }
+
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.rename_subtree(from, to)
+    }
+
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.rename(from, to)
+    }
+
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.inner.copy(from, to)
+    }
+
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        self.inner.complete_group(writers)
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.inner.usage()
+    }
+
+    fn available_space(&self) -> Result<u64, StorageError> {
+        self.inner.available_space()
+    }
+
+    fn preferred_block_size(&self) -> usize {
+        self.inner.preferred_block_size()
+    }
+
+    fn min_block_size(&self) -> usize {
+        self.inner.min_block_size()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+struct RetryWriter {
+    inner: Box<dyn FileWriter>,
+    policy: Arc<RetryPolicy>,
+    idempotent: bool,
+}
+
+impl HasFileId for RetryWriter {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileWriter for RetryWriter {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        if !self.idempotent {
+            return self.inner.write_block(data);
+        }
+        let inner = &mut self.inner;
+        self.policy.run("write", || inner.write_block(data.clone()))
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), StorageError> {
+        self.inner.write_all(data)
+    }
+
+    fn recycle(&mut self) -> Vec<FBuf> {
+        self.inner.recycle()
+    }
+
+    fn reserve_block(&mut self, size: usize) -> Result<BlockHandle, StorageError> {
+        self.inner.reserve_block(size)
+    }
+
+    fn fill_reserved(&mut self, handle: BlockHandle, data: FBuf) -> Result<(), StorageError> {
+        self.inner.fill_reserved(handle, data)
+    }
+
+    fn write_block_at(&mut self, offset: u64, data: FBuf) -> Result<(), StorageError> {
+        self.inner.write_block_at(offset, data)
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<(), StorageError> {
+        self.inner.preallocate(size)
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<(), StorageError> {
+        self.inner.truncate_to(len)
+    }
+
+    fn sync(&mut self) -> Result<(), StorageError> {
+        self.inner.sync()
+    }
+
+    fn complete(self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let (reader, name) = self.inner.complete()?;
+        let reader = Arc::new(RetryReader {
+            inner: reader,
+            policy: self.policy,
+        });
+        Ok((reader, name))
+    }
+
+    fn prepare_complete(&mut self) -> Result<(), StorageError> {
+        self.inner.prepare_complete()
+    }
+
+    fn complete_prepared(
+        self: Box<Self>,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let (reader, name) = self.inner.complete_prepared()?;
+        let reader = Arc::new(RetryReader {
+            inner: reader,
+            policy: self.policy,
+        });
+        Ok((reader, name))
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        self.inner.abort()
+    }
+}
+
+struct RetryReader {
+    inner: Arc<dyn FileReader>,
+    policy: Arc<RetryPolicy>,
+}
+
+impl HasFileId for RetryReader {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileReader for RetryReader {
+    fn mark_for_checkpoint(&self) {
+        self.inner.mark_for_checkpoint();
+    }
+
+    fn is_kept(&self) -> bool {
+        self.inner.is_kept()
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        self.policy.run("read", || self.inner.read_block(location))
+    }
+
+    fn read_block_ref(&self, location: BlockLocation) -> Result<BlockRef<'_>, StorageError> {
+        self.policy
+            .run("read", || self.inner.read_block_ref(location))
+    }
+
+    fn read_blocks(
+        &self,
+        locations: &[BlockLocation],
+        coalesce_gap: usize,
+    ) -> Vec<Result<Arc<FBuf>, StorageError>> {
+        // Retry just the blocks that failed, one by one.
+        self.inner
+            .read_blocks(locations, coalesce_gap)
+            .into_iter()
+            .zip(locations)
+            .map(|(result, location)| match result {
+                Err(error) if is_retryable(&error) => self.read_block(*location),
+                result => result,
+            })
+            .collect()
+    }
+
+    fn read_regions(&self, locations: &[BlockLocation]) -> Result<Arc<FBuf>, StorageError> {
+        self.policy
+            .run("read", || self.inner.read_regions(locations))
+    }
+
+    fn read_scattered(&self, offset: u64, bufs: &mut [&mut [u8]]) -> Result<usize, StorageError> {
+        self.policy
+            .run("read", || self.inner.read_scattered(offset, bufs))
+    }
+
+    fn advise_dontneed(&self) {
+        self.inner.advise_dontneed();
+    }
+
+    fn advise_sequential(&self) {
+        self.inner.advise_sequential();
+    }
+
+    fn prefetch(&self, locations: &[BlockLocation]) {
+        self.inner.prefetch(locations);
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        self.inner.get_size()
+    }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        self.inner.created_at()
+    }
+
+    fn stats(&self) -> FileStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_retryable, RetryBackend};
+    use crate::circuit::metrics::RETRIES;
+    use crate::storage::backend::{
+        memory_impl::MemoryBackend, BlockLocation, FileId, FileReader, FileWriter, HasFileId,
+        StorageBackend, StorageError,
+    };
+    use crate::storage::buffer_cache::FBuf;
+    use feldera_storage::{StorageFileType, StoragePath};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::io::ErrorKind;
+    use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Fails the next `failures` operations with `kind`.
+    #[derive(Clone)]
+    struct Flaky {
+        failures: Arc<AtomicUsize>,
+        kind: ErrorKind,
+    }
+
+    impl Flaky {
+        fn fail(&self) -> Result<(), StorageError> {
+            match self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err(StorageError::StdIo(self.kind)),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    /// A backend whose opens, and reads from the files it opens, are
+    /// [Flaky].
+    struct FlakyBackend {
+        inner: MemoryBackend,
+        flaky: Flaky,
+    }
+
+    struct FlakyReader {
+        inner: Arc<dyn FileReader>,
+        flaky: Flaky,
+    }
+
+    impl HasFileId for FlakyReader {
+        fn file_id(&self) -> FileId {
+            self.inner.file_id()
+        }
+    }
+
+    impl FileReader for FlakyReader {
+        fn mark_for_checkpoint(&self) {
+            self.inner.mark_for_checkpoint();
+        }
+
+        fn is_kept(&self) -> bool {
+            self.inner.is_kept()
+        }
+
+        fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+            self.flaky.fail()?;
+            self.inner.read_block(location)
+        }
+
+        fn get_size(&self) -> Result<u64, StorageError> {
+            self.inner.get_size()
+        }
+    }
+
+    impl StorageBackend for FlakyBackend {
+        fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+            self.inner.create_named(name)
+        }
+
+        fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+            self.flaky.fail()?;
+            Ok(Arc::new(FlakyReader {
+                inner: self.inner.open(name)?,
+                flaky: self.flaky.clone(),
+            }))
+        }
+
+        fn list(
+            &self,
+            parent: &StoragePath,
+            cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+        ) -> Result<(), StorageError> {
+            self.inner.list(parent, cb)
+        }
+
+        fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+            self.inner.delete(name)
+        }
+
+        fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+            self.inner.delete_recursive(name)
+        }
+
+        fn usage(&self) -> Arc<AtomicI64> {
+            self.inner.usage()
+        }
+    }
+
+    /// Returns a retrying backend over a [FlakyBackend] that contains a file
+    /// named "file", and the flaky backend's failure count.
+    fn flaky(kind: ErrorKind, max_attempts: u32) -> (RetryBackend, Arc<AtomicUsize>) {
+        let inner = MemoryBackend::new();
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 1);
+        inner.write(&"file".into(), block).unwrap();
+        let failures = Arc::new(AtomicUsize::new(0));
+        let flaky = FlakyBackend {
+            inner,
+            flaky: Flaky {
+                failures: failures.clone(),
+                kind,
+            },
+        };
+        let backend = RetryBackend::new(Arc::new(flaky))
+            .with_max_attempts(max_attempts)
+            .with_backoff(Duration::ZERO, Duration::ZERO);
+        (backend, failures)
+    }
+
+    /// Transient failures are retried until they succeed, and each retry is
+    /// counted.
+    #[test]
+    fn retries_transient_errors() {
+        let (backend, failures) = flaky(ErrorKind::TimedOut, 3);
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            failures.store(2, Ordering::Relaxed);
+            let reader = backend.open(&"file".into()).unwrap();
+            failures.store(2, Ordering::Relaxed);
+            let block = reader
+                .read_block(BlockLocation::new(0, 512).unwrap())
+                .unwrap();
+            assert_eq!(block.as_slice(), &[1; 512]);
+        });
+
+        let retries = snapshotter.snapshot().into_vec().into_iter().find_map(
+            |(key, _, _, value)| match value {
+                DebugValue::Counter(n) if key.key().name() == RETRIES => Some(n),
+                _ => None,
+            },
+        );
+        assert_eq!(retries, Some(4));
+    }
+
+    /// An operation that keeps failing gives up after the maximum number of
+    /// attempts.
+    #[test]
+    fn gives_up() {
+        let (backend, failures) = flaky(ErrorKind::TimedOut, 3);
+        failures.store(3, Ordering::Relaxed);
+        let Err(error) = backend.open(&"file".into()) else {
+            unreachable!()
+        };
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(failures.load(Ordering::Relaxed), 0);
+        backend.open(&"file".into()).unwrap();
+    }
+
+    /// Errors that aren't transient are never retried.
+    #[test]
+    fn does_not_retry_other_errors() {
+        for kind in [ErrorKind::NotFound, ErrorKind::AlreadyExists] {
+            let (backend, failures) = flaky(kind, 3);
+            failures.store(1, Ordering::Relaxed);
+            let Err(error) = backend.open(&"file".into()) else {
+                unreachable!()
+            };
+            assert_eq!(error.kind(), kind);
+            assert_eq!(failures.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    /// `EIO` is retried, but other operating system errors without an
+    /// [ErrorKind] of their own, and data not yet written, are not.
+    #[test]
+    fn retryable_errors() {
+        let os_error = |errno| StorageError::from(std::io::Error::from_raw_os_error(errno));
+        assert!(is_retryable(&os_error(libc::EIO)));
+        assert!(is_retryable(&os_error(libc::ETIMEDOUT)));
+        assert!(!is_retryable(&os_error(libc::EBADF)));
+        assert!(!is_retryable(&os_error(libc::ENOTRECOVERABLE)));
+        assert!(is_retryable(&StorageError::StdIo(ErrorKind::TimedOut)));
+        assert!(!is_retryable(&StorageError::NotYetWritten {
+            end: 4096,
+            committed: 0
+        }));
+    }
+}
@@ -0,0 +1,516 @@
+//! [StorageBackend] implementation for S3-compatible object storage.
+//!
+//! Objects can't be appended to or modified in place, so a writer buffers its
+//! file's blocks in memory and uploads them all at once when the file is
+//! completed.  Reads fetch byte ranges of the uploaded object.  A
+//! [StoragePath] maps to the key formed by appending it to the prefix given in
+//! the bucket URL.
+//!
+//! The [ObjectStore] API is asynchronous.  This backend waits for it on the
+//! calling thread using [TOKIO], so it must not be called from within an
+//! asynchronous task.  Callers in asynchronous code can use
+//! [AsyncStorageBackend](feldera_storage::asynchronous::AsyncStorageBackend),
+//! which moves each call to a blocking thread.
+
+use super::{
+    release_usage, BlockLocation, FileId, FileReader, FileWriter, HasFileId, StorageBackend,
+    StorageError,
+};
+use crate::circuit::metrics::{
+    FILES_CREATED, READS_FAILED, READS_SUCCESS, TOTAL_BYTES_READ, TOTAL_BYTES_WRITTEN,
+    WRITES_SUCCESS,
+};
+use crate::storage::buffer_cache::FBuf;
+use feldera_storage::tokio::TOKIO;
+use feldera_storage::{StorageBackendFactory, StorageCapabilities, StorageFileType, StoragePath};
+use feldera_types::config::{ObjectStorageConfig, StorageBackendConfig, StorageConfig};
+use metrics::counter;
+use object_store::{
+    parse_url_opts, Error as ObjectStoreError, MultipartUpload, ObjectStore, ObjectStoreScheme,
+    PutPayload,
+};
+use std::io::{Error as IoError, ErrorKind};
+use std::mem::take;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use url::Url;
+
+/// Size of the parts of a multipart upload.  Files no bigger than this are
+/// uploaded with a single request.
+///
+/// S3 requires every part except the last to be at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+struct S3BackendInner {
+    /// The bucket.
+    store: Arc<dyn ObjectStore>,
+
+    /// Prefix for the keys of all the objects in this backend.
+    prefix: StoragePath,
+
+    /// Tracks the total size of all the uploaded objects.
+    usage: Arc<AtomicI64>,
+}
+
+/// Storage backend for S3-compatible object storage.
+#[derive(Clone)]
+pub struct S3Backend(Arc<S3BackendInner>);
+
+impl S3Backend {
+    /// Creates and returns a new backend for the bucket and prefix in
+    /// `config.url`, which must be an S3 URL such as `s3://<bucket>/<path>`.
+    pub fn new(config: &ObjectStorageConfig) -> Result<Self, StorageError> {
+        let invalid_url = || StorageError::InvalidURL(config.url.clone());
+        let url = Url::parse(&config.url).map_err(|_| invalid_url())?;
+        match ObjectStoreScheme::parse(&url) {
+            Ok((ObjectStoreScheme::AmazonS3, _)) => (),
+            _ => return Err(invalid_url()),
+        }
+        let (store, prefix) = parse_url_opts(&url, config.other_options.iter())?;
+        Ok(Self::with_store(Arc::from(store), prefix))
+    }
+
+    /// Creates and returns a new backend that stores objects in `store` with
+    /// keys under `prefix`.
+    pub fn with_store(store: Arc<dyn ObjectStore>, prefix: StoragePath) -> Self {
+        Self(Arc::new(S3BackendInner {
+            store,
+            prefix,
+            usage: Arc::new(AtomicI64::new(0)),
+        }))
+    }
+
+    /// Returns the object key for `name`.
+    fn key(&self, name: &StoragePath) -> StoragePath {
+        self.0.prefix.parts().chain(name.parts()).collect()
+    }
+
+    /// Returns the name for object key `key`, which must be under our prefix.
+    fn name(&self, key: &StoragePath) -> StoragePath {
+        key.parts().skip(self.0.prefix.parts().count()).collect()
+    }
+
+    /// Calls `cb` for each object under `parent`, recursively, with its key
+    /// and size.
+    fn list_recursive(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, u64),
+    ) -> Result<(), StorageError> {
+        let result = TOKIO.block_on(self.0.store.list_with_delimiter(Some(parent)))?;
+        for object in &result.objects {
+            cb(&object.location, object.size as u64);
+        }
+        for prefix in &result.common_prefixes {
+            self.list_recursive(prefix, cb)?;
+        }
+        Ok(())
+    }
+}
+
+/// Uploads `blocks` to `key` in `store`, using a multipart upload if they add
+/// up to more than [PART_SIZE] bytes.
+async fn upload(
+    store: &dyn ObjectStore,
+    key: &StoragePath,
+    blocks: &[Arc<FBuf>],
+    size: u64,
+) -> Result<(), ObjectStoreError> {
+    if size <= PART_SIZE as u64 {
+        let mut data = Vec::with_capacity(size as usize);
+        for block in blocks {
+            data.extend_from_slice(block);
+        }
+        store.put(key, PutPayload::from(data)).await?;
+        return Ok(());
+    }
+
+    async fn put_parts(
+        upload: &mut dyn MultipartUpload,
+        blocks: &[Arc<FBuf>],
+    ) -> Result<(), ObjectStoreError> {
+        let mut part = Vec::with_capacity(PART_SIZE);
+        for block in blocks {
+            part.extend_from_slice(block);
+            if part.len() >= PART_SIZE {
+                upload.put_part(PutPayload::from(take(&mut part))).await?;
+            }
+        }
+        if !part.is_empty() {
+            upload.put_part(PutPayload::from(part)).await?;
+        }
+        Ok(())
+    }
+
+    let mut upload = store.put_multipart(key).await?;
+    match put_parts(upload.as_mut(), blocks).await {
+        Ok(()) => {
+            upload.complete().await?;
+            Ok(())
+        }
+        Err(error) => {
+            // Don't leave the parts we did upload behind to be billed.
+            let _ = upload.abort().await;
+            Err(error)
+        }
+    }
+}
+
+struct S3Writer {
+    backend: S3Backend,
+    file_id: FileId,
+    name: StoragePath,
+
+    /// Blocks written so far.  Nothing is uploaded until
+    /// [FileWriter::complete], so dropping the writer leaves nothing behind.
+    blocks: Vec<Arc<FBuf>>,
+
+    /// Total size of `blocks`.
+    size: u64,
+}
+
+impl HasFileId for S3Writer {
+    fn file_id(&self) -> FileId {
+        self.file_id
+    }
+}
+
+impl FileWriter for S3Writer {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        let data = Arc::new(data);
+        self.blocks.push(data.clone());
+        self.size += data.len() as u64;
+
+        counter!(TOTAL_BYTES_WRITTEN).increment(data.len() as u64);
+        counter!(WRITES_SUCCESS).increment(1);
+
+        Ok(data)
+    }
+
+    fn complete(self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let key = self.backend.key(&self.name);
+        TOKIO.block_on(upload(
+            self.backend.0.store.as_ref(),
+            &key,
+            &self.blocks,
+            self.size,
+        ))?;
+        self.backend
+            .0
+            .usage
+            .fetch_add(self.size as i64, Ordering::Relaxed);
+
+        let reader = Arc::new(S3Reader {
+            backend: self.backend,
+            file_id: self.file_id,
+            name: self.name.clone(),
+            key,
+            size: self.size,
+            created_at: SystemTime::now(),
+            keep: AtomicBool::new(false),
+        });
+        Ok((reader, self.name))
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        // Nothing has been uploaded yet.
+        Ok(())
+    }
+}
+
+struct S3Reader {
+    backend: S3Backend,
+    file_id: FileId,
+    name: StoragePath,
+    key: StoragePath,
+    size: u64,
+    created_at: SystemTime,
+    keep: AtomicBool,
+}
+
+impl HasFileId for S3Reader {
+    fn file_id(&self) -> FileId {
+        self.file_id
+    }
+}
+
+impl FileReader for S3Reader {
+    fn mark_for_checkpoint(&self) {
+        self.keep.store(true, Ordering::Relaxed);
+    }
+
+    fn is_kept(&self) -> bool {
+        self.keep.load(Ordering::Relaxed)
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        if location.after() > self.size {
+            counter!(READS_FAILED).increment(1);
+            return Err(IoError::from(ErrorKind::UnexpectedEof).into());
+        }
+
+        counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+        let range = location.offset as usize..location.after() as usize;
+        let data = match TOKIO.block_on(self.backend.0.store.get_range(&self.key, range)) {
+            Ok(data) if data.len() == location.size => data,
+            Ok(_) => {
+                counter!(READS_FAILED).increment(1);
+                return Err(IoError::from(ErrorKind::UnexpectedEof).into());
+            }
+            Err(error) => {
+                counter!(READS_FAILED).increment(1);
+                return Err(error.into());
+            }
+        };
+        counter!(READS_SUCCESS).increment(1);
+
+        let mut block = FBuf::with_capacity(location.size);
+        block.extend_from_slice(&data);
+        Ok(Arc::new(block))
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        Ok(self.size)
+    }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        Ok(self.created_at)
+    }
+}
+
+impl Drop for S3Reader {
+    fn drop(&mut self) {
+        if !self.keep.load(Ordering::Relaxed) {
+            let _ = self.backend.delete(&self.name);
+        }
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        counter!(FILES_CREATED).increment(1);
+        Ok(Box::new(S3Writer {
+            backend: self.clone(),
+            file_id: FileId::new(),
+            name: name.clone(),
+            blocks: Vec::new(),
+            size: 0,
+        }))
+    }
+
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        let key = self.key(name);
+        let meta = TOKIO.block_on(self.0.store.head(&key))?;
+        Ok(Arc::new(S3Reader {
+            backend: self.clone(),
+            file_id: FileId::new(),
+            name: name.clone(),
+            key,
+            size: meta.size as u64,
+            created_at: SystemTime::from(meta.last_modified),
+            keep: AtomicBool::new(true),
+        }))
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        let result = TOKIO.block_on(self.0.store.list_with_delimiter(Some(&self.key(parent))))?;
+        for prefix in &result.common_prefixes {
+            cb(&self.name(prefix), StorageFileType::Directory);
+        }
+        for object in &result.objects {
+            cb(
+                &self.name(&object.location),
+                StorageFileType::File {
+                    size: object.size as u64,
+                    allocated: object.size as u64,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let key = self.key(name);
+        let meta = TOKIO.block_on(self.0.store.head(&key))?;
+        TOKIO.block_on(self.0.store.delete(&key))?;
+        release_usage(&self.0.usage, meta.size as u64, false);
+        Ok(())
+    }
+
+    fn delete_recursive(&self, parent: &StoragePath) -> Result<(), StorageError> {
+        let mut objects = Vec::new();
+        self.list_recursive(&self.key(parent), &mut |key, size| {
+            objects.push((key.clone(), size))
+        })?;
+        for (key, size) in objects {
+            TOKIO.block_on(self.0.store.delete(&key))?;
+            release_usage(&self.0.usage, size, false);
+        }
+        Ok(())
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.0.usage.clone()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            is_remote: true,
+            ..StorageCapabilities::default()
+        }
+    }
+}
+
+pub(crate) struct S3BackendFactory;
+impl StorageBackendFactory for S3BackendFactory {
+    fn backend(&self) -> &'static str {
+        "s3"
+    }
+
+    fn uri_schemes(&self) -> &'static [&'static str] {
+        &["s3", "s3a"]
+    }
+
+    fn create_from_uri(
+        &self,
+        scheme: &str,
+        location: &str,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let config = ObjectStorageConfig {
+            url: format!("{scheme}://{location}"),
+            ..ObjectStorageConfig::default()
+        };
+        Ok(Arc::new(S3Backend::new(&config)?))
+    }
+
+    fn create(
+        &self,
+        _storage_config: &StorageConfig,
+        backend_config: &StorageBackendConfig,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let StorageBackendConfig::S3(config) = backend_config else {
+            return Err(StorageError::BackendNotSupported(backend_config.clone()));
+        };
+        Ok(Arc::new(S3Backend::new(config)?))
+    }
+}
+
+inventory::submit! {
+    &S3BackendFactory as &dyn StorageBackendFactory
+}
+
+#[cfg(test)]
+mod tests {
+    use feldera_storage::{StorageBackend, StorageFileType, StoragePath};
+    use feldera_types::config::{
+        ObjectStorageConfig, StorageBackendConfig, StorageConfig, StorageOptions,
+    };
+    use object_store::{memory::InMemory, ObjectStore};
+    use std::{path::Path, sync::Arc};
+
+    use crate::storage::{
+        backend::{
+            s3_impl::S3Backend,
+            tests::{random_sizes, test_backend},
+        },
+        buffer_cache::FBuf,
+    };
+    use feldera_storage::tokio::TOKIO;
+
+    fn create_s3_backend(_path: &Path) -> Arc<dyn StorageBackend> {
+        Arc::new(S3Backend::with_store(
+            Arc::new(InMemory::new()),
+            "pipeline".into(),
+        ))
+    }
+
+    #[test]
+    fn sequential_1024() {
+        test_backend(Box::new(create_s3_backend), &[1024; 1024 * 10], true)
+    }
+
+    /// Verify that files get deleted if not marked for a checkpoint.
+    #[test]
+    fn delete_1024() {
+        test_backend(Box::new(create_s3_backend), &[1024; 1024 * 10], false)
+    }
+
+    #[test]
+    fn sequential_random() {
+        test_backend(Box::new(create_s3_backend), &random_sizes(), true);
+    }
+
+    #[test]
+    fn empty() {
+        test_backend(Box::new(create_s3_backend), &[], true);
+    }
+
+    /// Files are stored under the prefix and listed relative to it, with
+    /// subdirectories reported as directories.
+    #[test]
+    fn keys() {
+        let store = Arc::new(InMemory::new());
+        let backend = S3Backend::with_store(store.clone(), "pipeline".into());
+        let mut block = FBuf::with_capacity(512);
+        block.resize(512, 1);
+        backend.write(&"a/b".into(), block.clone()).unwrap();
+        backend.write(&"c".into(), block).unwrap();
+        TOKIO
+            .block_on(store.head(&StoragePath::from("pipeline/a/b")))
+            .unwrap();
+
+        let mut entries = Vec::new();
+        backend
+            .list(&StoragePath::default(), &mut |path, file_type| {
+                entries.push((path.to_string(), file_type))
+            })
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("a".into(), StorageFileType::Directory),
+                (
+                    "c".into(),
+                    StorageFileType::File {
+                        size: 512,
+                        allocated: 512
+                    }
+                )
+            ]
+        );
+
+        backend.delete_recursive(&"a".into()).unwrap();
+        assert_eq!(
+            backend.usage().load(std::sync::atomic::Ordering::Relaxed),
+            512
+        );
+        backend.open(&"a/b".into()).unwrap_err();
+    }
+
+    #[test]
+    fn capabilities() {
+        let capabilities = create_s3_backend(Path::new("")).capabilities();
+        assert!(capabilities.is_remote);
+        assert!(!capabilities.atomic_rename);
+    }
+
+    /// The factory rejects URLs for object stores other than S3.
+    #[test]
+    fn factory() {
+        let options = |url: &str| StorageOptions {
+            backend: StorageBackendConfig::S3(ObjectStorageConfig {
+                url: url.into(),
+                ..ObjectStorageConfig::default()
+            }),
+            ..StorageOptions::default()
+        };
+        for url in ["gs://bucket/path", "not a url"] {
+            assert!(<dyn StorageBackend>::new(&StorageConfig::default(), &options(url)).is_err());
+        }
+    }
+}
@@ -13,7 +13,7 @@ use rand::{thread_rng, Fill, Rng};
 
 use crate::storage::{backend::BlockLocation, buffer_cache::FBuf, test::init_test_logger};
 
-use super::{FileReader, StorageBackend};
+use super::{FileReader, StorageBackend, StorageError, StorageFileType, StoragePath};
 
 fn test_read_block(reader: &dyn FileReader, data: &[u8], offset: usize) -> usize {
     let remaining = data.len() - offset;
@@ -30,7 +30,7 @@ fn test_read_block(reader: &dyn FileReader, data: &[u8], offset: usize) -> usize
     length
 }
 
-fn test_read(reader: &dyn FileReader, data: &[u8]) {
+pub(super) fn test_read(reader: &dyn FileReader, data: &[u8]) {
     let mut rng = thread_rng();
     assert_eq!(reader.get_size().unwrap(), data.len() as u64);
     let mut offset = 0;
@@ -45,6 +45,46 @@ fn test_read(reader: &dyn FileReader, data: &[u8]) {
         .unwrap_err();
 }
 
+/// Asserts that `backend` contains exactly the files named in `kept`, that is,
+/// that every file other than those was deleted, and that the backend's usage
+/// equals their total size.
+pub(super) fn assert_clean(backend: &dyn StorageBackend, kept: &[StoragePath]) {
+    fn list_files(
+        backend: &dyn StorageBackend,
+        parent: &StoragePath,
+        files: &mut Vec<(String, u64)>,
+    ) {
+        let mut directories = Vec::new();
+        backend
+            .list(
+                parent,
+                &mut |path: &StoragePath, file_type: StorageFileType| match file_type {
+                    StorageFileType::File { size, .. } => files.push((path.to_string(), size)),
+                    StorageFileType::Directory => directories.push(path.clone()),
+                    StorageFileType::Other => panic!("unexpected file type for {path}"),
+                },
+            )
+            .unwrap();
+        for directory in directories {
+            list_files(backend, &directory, files);
+        }
+    }
+
+    let mut files = Vec::new();
+    list_files(backend, &StoragePath::default(), &mut files);
+    let mut names = files
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut kept = kept.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+    kept.sort();
+    assert_eq!(names, kept, "files were leaked");
+
+    let total = files.iter().map(|(_, size)| *size as i64).sum::<i64>();
+    assert_eq!(backend.usage().load(Ordering::Relaxed), total);
+}
+
 pub(super) fn test_backend(
     create_backend: Box<dyn FnOnce(&Path) -> Arc<dyn StorageBackend>>,
     writes: &[usize],
@@ -85,6 +125,68 @@ pub(super) fn test_backend(
             unreachable!()
         };
     }
+    assert_clean(
+        backend.as_ref(),
+        if mark_for_checkpoint {
+            std::slice::from_ref(&name)
+        } else {
+            &[]
+        },
+    );
+}
+
+/// Tests [FileWriter::reserve_block](super::FileWriter::reserve_block) with a
+/// reservation filled right away, while it is still buffered, and one filled
+/// after more than 1 MiB of later writes has flushed it.
+pub(super) fn test_reserve(create_backend: Box<dyn FnOnce(&Path) -> Arc<dyn StorageBackend>>) {
+    init_test_logger();
+    let tmpdir = tempfile::tempdir().unwrap();
+    let backend = create_backend(tmpdir.path());
+    let block = |size: usize, value: u8| {
+        let mut block = FBuf::with_capacity(size);
+        block.resize(size, value);
+        block
+    };
+
+    let mut writer = backend.create().unwrap();
+    let header = writer.reserve_block(512).unwrap();
+    writer.write_block(block(1024 * 1024, 1)).unwrap();
+    writer.write_block(block(1024 * 1024, 2)).unwrap();
+    let trailer = writer.reserve_block(1024).unwrap();
+    writer.write_block(block(512, 3)).unwrap();
+
+    // Filling with the wrong size fails, and so does completing with an
+    // unfilled reservation.
+    assert!(writer.fill_reserved(trailer, block(512, 4)).is_err());
+    writer.fill_reserved(header, block(512, 5)).unwrap();
+    let Err(StorageError::UnfilledReservation { offset }) = writer.complete() else {
+        unreachable!()
+    };
+    assert_eq!(offset, 512 + 2 * 1024 * 1024);
+
+    let mut writer = backend.create().unwrap();
+    let header = writer.reserve_block(512).unwrap();
+    writer.fill_reserved(header, block(512, 5)).unwrap();
+    writer.write_block(block(1024 * 1024, 1)).unwrap();
+    writer.write_block(block(1024 * 1024, 2)).unwrap();
+    let trailer = writer.reserve_block(1024).unwrap();
+    writer.write_block(block(512, 3)).unwrap();
+    writer.write_block(block(512, 6)).unwrap();
+    writer.fill_reserved(trailer, block(1024, 4)).unwrap();
+    let (reader, _name) = writer.complete().unwrap();
+
+    let mut expected = Vec::new();
+    for (size, value) in [
+        (512, 5),
+        (1024 * 1024, 1),
+        (1024 * 1024, 2),
+        (1024, 4),
+        (512, 3),
+        (512, 6),
+    ] {
+        expected.extend_from_slice(&block(size, value));
+    }
+    test_read(reader.as_ref(), &expected);
 }
 
 pub(super) fn random_sizes() -> Vec<usize> {
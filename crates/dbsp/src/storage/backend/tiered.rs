@@ -0,0 +1,761 @@
+//! [StorageBackend] that spills from a fast backend to a slow one.
+//!
+//! A [TieredBackend] combines a small, fast backend, such as one on NVMe,
+//! with a large, slow one, such as one on spinning disks.  It creates new
+//! files on the fast tier as long as the fast tier's usage is below a
+//! high-watermark, and on the slow tier otherwise.  It remembers which tier
+//! each file that stays in storage is on, that is, each file that it opened
+//! or that was marked for a checkpoint, so that it can open and delete the
+//! file later without searching for it.  It falls back to trying the fast
+//! tier and then the slow one for files that it doesn't know about, e.g.
+//! temporary files and ones written before a restart.
+//!
+//! [TieredBackend::migrate] moves a file from the fast tier to the slow one,
+//! to make room on the fast tier for files that are hotter.
+
+use super::{
+    BlockHandle, BlockLocation, BlockRef, FileId, FileReader, FileWriter, HasFileId,
+    StorageBackend, StorageError,
+};
+use crate::circuit::metrics::{FAST_TIER_USAGE_BYTES, SLOW_TIER_USAGE_BYTES};
+use crate::storage::buffer_cache::FBuf;
+use feldera_storage::commit::complete_in_two_phases;
+use feldera_storage::{copy_file, FileStats, StorageFileType, StoragePath};
+use metrics::gauge;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// How often a [TieredBackend] brings its usage up to date with its tiers'
+/// usage, when nothing else has done so in the meantime.
+const USAGE_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Size of the blocks that [TieredBackend::migrate] copies.
+const MIGRATE_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// One of the two tiers of a [TieredBackend].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tier {
+    /// The fast tier, which is preferred for new files.
+    Fast,
+
+    /// The slow tier, which new files spill to when the fast tier is full.
+    Slow,
+}
+
+impl Tier {
+    fn other(self) -> Self {
+        match self {
+            Tier::Fast => Tier::Slow,
+            Tier::Slow => Tier::Fast,
+        }
+    }
+}
+
+/// The tier that each file we know about is on.
+type Owners = Arc<RwLock<HashMap<StoragePath, Tier>>>;
+
+/// Maintains the usage that a [TieredBackend] reports, by adding to it the
+/// changes in its tiers' usage.  Adding changes, instead of storing the sum,
+/// keeps a value stored into the counter by the backend's user, such as the
+/// initial value that a checkpointer measures.
+struct TieredUsage {
+    fast: Arc<AtomicI64>,
+    slow: Arc<AtomicI64>,
+
+    /// The usage that [TieredBackend::usage] returns.
+    total: Arc<AtomicI64>,
+
+    /// The sum of the tiers' usage as of the last update.
+    last: Mutex<i64>,
+}
+
+impl TieredUsage {
+    fn new(fast: &dyn StorageBackend, slow: &dyn StorageBackend) -> Self {
+        let fast = fast.usage();
+        let slow = slow.usage();
+        let last = fast.load(Ordering::Relaxed) + slow.load(Ordering::Relaxed);
+        Self {
+            fast,
+            slow,
+            total: Arc::new(AtomicI64::new(last)),
+            last: Mutex::new(last),
+        }
+    }
+
+    /// Brings `total` up to date and reports the tiers' usage as metrics.
+    /// Returns the usage of each tier, fast then slow.
+    fn update(&self) -> (i64, i64) {
+        let mut last = self.last.lock().unwrap();
+        let fast = self.fast.load(Ordering::Relaxed);
+        let slow = self.slow.load(Ordering::Relaxed);
+        self.total.fetch_add(fast + slow - *last, Ordering::Relaxed);
+        *last = fast + slow;
+        gauge!(FAST_TIER_USAGE_BYTES).set(fast as f64);
+        gauge!(SLOW_TIER_USAGE_BYTES).set(slow as f64);
+        (fast, slow)
+    }
+}
+
+/// A [StorageBackend] that spills from a fast backend to a slow one.  See the
+/// [module documentation](self).
+pub struct TieredBackend {
+    fast: Arc<dyn StorageBackend>,
+    slow: Arc<dyn StorageBackend>,
+
+    /// Fast tier usage, in bytes, at or above which new files go to the slow
+    /// tier.
+    high_watermark: u64,
+
+    /// The tier that each file we opened, migrated, or saw marked for a
+    /// checkpoint is on.
+    owners: Owners,
+
+    usage: Arc<TieredUsage>,
+
+    /// Keeps the thread that refreshes `usage` running until we're dropped.
+    _refresh: Sender<()>,
+}
+
+impl TieredBackend {
+    /// Returns a new backend that creates files on `fast` while its usage is
+    /// below `high_watermark` bytes, and on `slow` otherwise.
+    ///
+    /// The tiers' [usage](StorageBackend::usage) counters must be the same
+    /// from one call to the next, as they are for the backends in this
+    /// crate.  A thread keeps the usage that this backend reports up to date
+    /// with them, because files on the tiers grow and go away without this
+    /// backend being involved.
+    pub fn new(
+        fast: Arc<dyn StorageBackend>,
+        slow: Arc<dyn StorageBackend>,
+        high_watermark: u64,
+    ) -> Self {
+        let usage = Arc::new(TieredUsage::new(fast.as_ref(), slow.as_ref()));
+        let (sender, receiver) = channel::<()>();
+        let result = std::thread::Builder::new()
+            .name(String::from("dbsp-storage-tiers"))
+            .spawn({
+                let usage = usage.clone();
+                move || {
+                    while let Err(RecvTimeoutError::Timeout) =
+                        receiver.recv_timeout(USAGE_REFRESH_INTERVAL)
+                    {
+                        usage.update();
+                    }
+                }
+            });
+        if let Err(error) = result {
+            // Usage still gets updated by our own operations.
+            warn!("unable to start tiered storage usage thread: {error}");
+        }
+        Self {
+            fast,
+            slow,
+            high_watermark,
+            owners: Arc::new(RwLock::new(HashMap::new())),
+            usage,
+            _refresh: sender,
+        }
+    }
+
+    /// Returns the backend for `tier`.
+    pub fn tier(&self, tier: Tier) -> &Arc<dyn StorageBackend> {
+        match tier {
+            Tier::Fast => &self.fast,
+            Tier::Slow => &self.slow,
+        }
+    }
+
+    /// Returns the tier that `name` is on, if it is one that we know about.
+    pub fn tier_of(&self, name: &StoragePath) -> Option<Tier> {
+        self.owners.read().unwrap().get(name).copied()
+    }
+
+    /// Returns the tiers to try for `name`, in the order to try them.
+    fn candidates(&self, name: &StoragePath) -> [Tier; 2] {
+        let first = self.tier_of(name).unwrap_or(Tier::Fast);
+        [first, first.other()]
+    }
+
+    /// Runs `op` on the tier that owns `name`, trying the other tier if the
+    /// file isn't found there.  Returns the result along with the tier that
+    /// produced it.  If `name` isn't on either tier, forgets it.
+    fn try_tiers<T>(
+        &self,
+        name: &StoragePath,
+        op: impl Fn(&dyn StorageBackend) -> Result<T, StorageError>,
+    ) -> Result<(T, Tier), StorageError> {
+        let [first, second] = self.candidates(name);
+        match op(self.tier(first).as_ref()) {
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                let result = op(self.tier(second).as_ref());
+                if result
+                    .as_ref()
+                    .is_err_and(|error| error.kind() == ErrorKind::NotFound)
+                {
+                    self.owners.write().unwrap().remove(name);
+                }
+                result.map(|result| (result, second))
+            }
+            result => result.map(|result| (result, first)),
+        }
+    }
+
+    /// Wraps `writer`, which writes `name` on `tier`, so that the file's tier
+    /// is recorded if the file is kept.
+    fn wrap_writer(
+        &self,
+        writer: Box<dyn FileWriter>,
+        name: &StoragePath,
+        tier: Tier,
+    ) -> Box<dyn FileWriter> {
+        Box::new(TieredWriter {
+            inner: writer,
+            file: TieredFile {
+                name: name.clone(),
+                tier,
+                owners: self.owners.clone(),
+            },
+        })
+    }
+
+    /// Returns the number of bytes in use on each tier, fast then slow, and
+    /// reports them as metrics.
+    pub fn tier_usage(&self) -> (i64, i64) {
+        self.usage.update()
+    }
+
+    /// Moves `name` from the fast tier to the slow tier, by copying it block
+    /// by block and then deleting the original.  Does nothing if `name` is
+    /// already on the slow tier.
+    ///
+    /// Readers that already have the file open keep reading it from the fast
+    /// tier, for as long as the fast tier allows.
+    pub fn migrate(&self, name: &StoragePath) -> Result<(), StorageError> {
+        if self.tier_of(name) == Some(Tier::Slow) {
+            return Ok(());
+        }
+        let result = copy_file(
+            self.fast.as_ref(),
+            name,
+            self.slow.as_ref(),
+            name,
+            MIGRATE_BLOCK_SIZE,
+        );
+        let copied = match result {
+            Ok(_size) => true,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                if !self.slow.exists(name)? {
+                    self.owners.write().unwrap().remove(name);
+                    return Err(error);
+                }
+                false
+            }
+            Err(error) => return Err(error),
+        };
+        self.owners
+            .write()
+            .unwrap()
+            .insert(name.clone(), Tier::Slow);
+        if copied {
+            self.fast.delete(name)?;
+        }
+        self.tier_usage();
+        Ok(())
+    }
+}
+
+impl StorageBackend for TieredBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let (fast_usage, _slow_usage) = self.tier_usage();
+        let tier = if fast_usage < self.high_watermark as i64 {
+            Tier::Fast
+        } else {
+            Tier::Slow
+        };
+        let writer = self.tier(tier).create_named(name)?;
+        Ok(self.wrap_writer(writer, name, tier))
+    }
+
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        let (reader, tier) = self.try_tiers(name, |backend| backend.open(name))?;
+        self.owners.write().unwrap().insert(name.clone(), tier);
+        Ok(reader)
+    }
+
+    /// Appends to `name` on the tier that has it, even if that's the fast
+    /// tier and it's above the watermark.
+    fn open_append(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let (writer, tier) = self.try_tiers(name, |backend| backend.open_append(name))?;
+        self.owners.write().unwrap().insert(name.clone(), tier);
+        Ok(self.wrap_writer(writer, name, tier))
+    }
+
+    /// Lists `parent` on both tiers, reporting directories that exist on
+    /// both only once.
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        let mut directories = HashSet::new();
+        let mut found = false;
+        for backend in [&self.fast, &self.slow] {
+            let result = backend.list(parent, &mut |path, file_type| {
+                if file_type != StorageFileType::Directory || directories.insert(path.clone()) {
+                    cb(path, file_type);
+                }
+            });
+            match result {
+                Err(error) if error.kind() == ErrorKind::NotFound => (),
+                Err(error) => return Err(error),
+                Ok(()) => found = true,
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(StorageError::StdIo(ErrorKind::NotFound))
+        }
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.try_tiers(name, |backend| backend.delete(name))?;
+        self.owners.write().unwrap().remove(name);
+        self.tier_usage();
+        Ok(())
+    }
+
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        self.fast.delete_recursive(name)?;
+        self.slow.delete_recursive(name)?;
+        self.owners
+            .write()
+            .unwrap()
+            .retain(|path, _tier| !path.prefix_matches(name));
+        self.tier_usage();
+        Ok(())
+    }
+
+    /// Renames `from` to `to` on each tier that has it.
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let mut found = false;
+        for backend in [&self.fast, &self.slow] {
+            match backend.rename_subtree(from, to) {
+                Err(error) if error.kind() == ErrorKind::NotFound => (),
+                Err(error) => return Err(error),
+                Ok(()) => found = true,
+            }
+        }
+        if !found {
+            return Err(StorageError::StdIo(ErrorKind::NotFound));
+        }
+
+        let mut owners = self.owners.write().unwrap();
+        let moved = owners
+            .keys()
+            .filter(|path| path.prefix_matches(from))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in moved {
+            let tier = owners.remove(&path).unwrap();
+            let suffix = path.parts().skip(from.parts().count());
+            owners.insert(to.parts().chain(suffix).collect(), tier);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let ((), tier) = self.try_tiers(from, |backend| backend.rename(from, to))?;
+        let mut owners = self.owners.write().unwrap();
+        owners.remove(from);
+        owners.insert(to.clone(), tier);
+        Ok(())
+    }
+
+    /// Copies `from` within the tier that has it.
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let ((), tier) = self.try_tiers(from, |backend| backend.copy(from, to))?;
+        self.owners.write().unwrap().insert(to.clone(), tier);
+        self.tier_usage();
+        Ok(())
+    }
+
+    /// The writers in a group may belong to different tiers, so this can't
+    /// delegate to either tier's implementation.  It completes the writers in
+    /// two phases, but it doesn't sync any directories.
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        complete_in_two_phases(writers)
+    }
+
+    /// Returns the total usage of both tiers, brought up to date as of the
+    /// call.  It keeps tracking changes to the tiers' usage afterward, with a
+    /// delay of up to [USAGE_REFRESH_INTERVAL].
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.tier_usage();
+        self.usage.total.clone()
+    }
+
+    fn available_space(&self) -> Result<u64, StorageError> {
+        Ok(self.fast.available_space()? + self.slow.available_space()?)
+    }
+
+    fn preferred_block_size(&self) -> usize {
+        self.fast
+            .preferred_block_size()
+            .max(self.slow.preferred_block_size())
+    }
+
+    fn min_block_size(&self) -> usize {
+        self.fast.min_block_size().max(self.slow.min_block_size())
+    }
+}
+
+/// The file that a [TieredWriter], or the [TieredReader] that it completes
+/// to, is for.
+struct TieredFile {
+    name: StoragePath,
+    tier: Tier,
+    owners: Owners,
+}
+
+impl TieredFile {
+    /// Records the file's tier, because the file is staying in storage.
+    fn keep(&self) {
+        self.owners
+            .write()
+            .unwrap()
+            .insert(self.name.clone(), self.tier);
+    }
+
+    /// Wraps `reader`, the reader that completing the file produced.
+    fn wrap_reader(self, reader: Arc<dyn FileReader>) -> Arc<dyn FileReader> {
+        if reader.is_kept() {
+            self.keep();
+        }
+        Arc::new(TieredReader {
+            inner: reader,
+            file: self,
+        })
+    }
+}
+
+/// A writer for a [TieredBackend], which records the file's tier only once
+/// the file is kept, because a temporary file is deleted on drop by its tier
+/// without the [TieredBackend] finding out.
+struct TieredWriter {
+    inner: Box<dyn FileWriter>,
+    file: TieredFile,
+}
+
+impl HasFileId for TieredWriter {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileWriter for TieredWriter {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        self.inner.write_block(data)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), StorageError> {
+        self.inner.write_all(data)
+    }
+
+    fn recycle(&mut self) -> Vec<FBuf> {
+        self.inner.recycle()
+    }
+
+    fn reserve_block(&mut self, size: usize) -> Result<BlockHandle, StorageError> {
+        self.inner.reserve_block(size)
+    }
+
+    fn fill_reserved(&mut self, handle: BlockHandle, data: FBuf) -> Result<(), StorageError> {
+        self.inner.fill_reserved(handle, data)
+    }
+
+    fn write_block_at(&mut self, offset: u64, data: FBuf) -> Result<(), StorageError> {
+        self.inner.write_block_at(offset, data)
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<(), StorageError> {
+        self.inner.preallocate(size)
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<(), StorageError> {
+        self.inner.truncate_to(len)
+    }
+
+    fn sync(&mut self) -> Result<(), StorageError> {
+        self.inner.sync()
+    }
+
+    fn complete(self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let (reader, name) = self.inner.complete()?;
+        Ok((self.file.wrap_reader(reader), name))
+    }
+
+    fn prepare_complete(&mut self) -> Result<(), StorageError> {
+        self.inner.prepare_complete()
+    }
+
+    fn complete_prepared(
+        self: Box<Self>,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        let (reader, name) = self.inner.complete_prepared()?;
+        Ok((self.file.wrap_reader(reader), name))
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        self.inner.abort()
+    }
+}
+
+/// A reader for a file completed by a [TieredWriter], which records the
+/// file's tier when the file is marked for a checkpoint.
+struct TieredReader {
+    inner: Arc<dyn FileReader>,
+    file: TieredFile,
+}
+
+impl HasFileId for TieredReader {
+    fn file_id(&self) -> FileId {
+        self.inner.file_id()
+    }
+}
+
+impl FileReader for TieredReader {
+    fn mark_for_checkpoint(&self) {
+        self.inner.mark_for_checkpoint();
+        self.file.keep();
+    }
+
+    fn is_kept(&self) -> bool {
+        self.inner.is_kept()
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        self.inner.read_block(location)
+    }
+
+    fn read_block_ref(&self, location: BlockLocation) -> Result<BlockRef<'_>, StorageError> {
+        self.inner.read_block_ref(location)
+    }
+
+    fn read_blocks(
+        &self,
+        locations: &[BlockLocation],
+        coalesce_gap: usize,
+    ) -> Vec<Result<Arc<FBuf>, StorageError>> {
+        self.inner.read_blocks(locations, coalesce_gap)
+    }
+
+    fn read_regions(&self, locations: &[BlockLocation]) -> Result<Arc<FBuf>, StorageError> {
+        self.inner.read_regions(locations)
+    }
+
+    fn read_scattered(&self, offset: u64, bufs: &mut [&mut [u8]]) -> Result<usize, StorageError> {
+        self.inner.read_scattered(offset, bufs)
+    }
+
+    fn advise_dontneed(&self) {
+        self.inner.advise_dontneed();
+    }
+
+    fn advise_sequential(&self) {
+        self.inner.advise_sequential();
+    }
+
+    fn prefetch(&self, locations: &[BlockLocation]) {
+        self.inner.prefetch(locations);
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        self.inner.get_size()
+    }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        self.inner.created_at()
+    }
+
+    fn stats(&self) -> FileStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tier, TieredBackend};
+    use crate::circuit::metrics::{FAST_TIER_USAGE_BYTES, SLOW_TIER_USAGE_BYTES};
+    use crate::storage::backend::{
+        memory_impl::MemoryBackend,
+        tests::{random_sizes, test_backend},
+        StorageBackend,
+    };
+    use crate::storage::buffer_cache::FBuf;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn block(size: usize, value: u8) -> FBuf {
+        let mut block = FBuf::with_capacity(size);
+        block.resize(size, value);
+        block
+    }
+
+    #[test]
+    fn tiered_backend() {
+        test_backend(
+            Box::new(|_path| {
+                Arc::new(TieredBackend::new(
+                    Arc::new(MemoryBackend::new()),
+                    Arc::new(MemoryBackend::new()),
+                    64 * 1024,
+                ))
+            }),
+            &random_sizes(),
+            true,
+        );
+    }
+
+    /// Files go to the fast tier until it reaches the watermark, then to the
+    /// slow tier, and can be migrated from fast to slow.
+    #[test]
+    fn spill_and_migrate() {
+        let fast = Arc::new(MemoryBackend::new());
+        let slow = Arc::new(MemoryBackend::new());
+        let backend = TieredBackend::new(fast.clone(), slow.clone(), 1024);
+
+        backend.write(&"a".into(), block(2048, 1)).unwrap();
+        backend.write(&"b".into(), block(512, 2)).unwrap();
+        assert_eq!(backend.tier_of(&"a".into()), Some(Tier::Fast));
+        assert_eq!(backend.tier_of(&"b".into()), Some(Tier::Slow));
+        assert_eq!(fast.usage().load(Ordering::Relaxed), 2048);
+        assert_eq!(slow.usage().load(Ordering::Relaxed), 512);
+        assert_eq!(backend.usage().load(Ordering::Relaxed), 2560);
+        assert_eq!(&backend.read(&"b".into()).unwrap()[..], &[2; 512][..]);
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            backend.migrate(&"a".into()).unwrap();
+        });
+        let gauge = |name: &str| {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .find_map(|(key, _, _, value)| match value {
+                    DebugValue::Gauge(n) if key.key().name() == name => Some(n.0),
+                    _ => None,
+                })
+        };
+        assert_eq!(backend.tier_of(&"a".into()), Some(Tier::Slow));
+        assert_eq!(gauge(FAST_TIER_USAGE_BYTES), Some(0.0));
+        assert_eq!(gauge(SLOW_TIER_USAGE_BYTES), Some(2560.0));
+        assert!(!fast.exists(&"a".into()).unwrap());
+        assert_eq!(&backend.read(&"a".into()).unwrap()[..], &[1; 2048][..]);
+
+        // With room on the fast tier again, new files go there.
+        backend.write(&"c".into(), block(512, 3)).unwrap();
+        assert_eq!(backend.tier_of(&"c".into()), Some(Tier::Fast));
+
+        backend.delete(&"a".into()).unwrap();
+        assert_eq!(backend.tier_of(&"a".into()), None);
+        assert_eq!(slow.usage().load(Ordering::Relaxed), 512);
+    }
+
+    /// Files that the backend didn't create are found on either tier.
+    #[test]
+    fn unknown_files() {
+        let fast = Arc::new(MemoryBackend::new());
+        let slow = Arc::new(MemoryBackend::new());
+        slow.write(&"old".into(), block(512, 1)).unwrap();
+        let backend = TieredBackend::new(fast, slow, 1024);
+
+        assert_eq!(backend.tier_of(&"old".into()), None);
+        assert_eq!(
+            backend.open(&"old".into()).unwrap().get_size().unwrap(),
+            512
+        );
+        assert_eq!(backend.tier_of(&"old".into()), Some(Tier::Slow));
+        backend.open(&"missing".into()).unwrap_err();
+    }
+
+    /// The usage that the backend returns keeps up with writes and deletions
+    /// made afterward, on either tier, and keeps a value stored into it.
+    #[test]
+    fn live_usage() {
+        let fast = Arc::new(MemoryBackend::new());
+        let slow = Arc::new(MemoryBackend::new());
+        let backend = TieredBackend::new(fast, slow.clone(), 1024);
+        let usage = backend.usage();
+        assert_eq!(usage.load(Ordering::Relaxed), 0);
+
+        // Wait for the refresh thread to notice changes that bypass us.
+        let wait_for = |expected: i64| {
+            let start = Instant::now();
+            while usage.load(Ordering::Relaxed) != expected {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        backend.write(&"a".into(), block(2048, 1)).unwrap();
+        wait_for(2048);
+        slow.write(&"b".into(), block(512, 2)).unwrap();
+        wait_for(2560);
+
+        // A temporary file's usage goes away when it is dropped.
+        let mut writer = backend.create().unwrap();
+        writer.write_block(block(512, 3)).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        wait_for(3072);
+        drop(reader);
+        wait_for(2560);
+
+        usage.store(10_000, Ordering::Relaxed);
+        backend.delete(&"a".into()).unwrap();
+        assert_eq!(usage.load(Ordering::Relaxed), 10_000 - 2048);
+        assert!(Arc::ptr_eq(&usage, &backend.usage()));
+    }
+
+    /// Only files that stay in storage are remembered, so temporary files
+    /// deleted on drop don't accumulate, and files that turn out to be
+    /// missing are forgotten.
+    #[test]
+    fn owners() {
+        let fast = Arc::new(MemoryBackend::new());
+        let slow = Arc::new(MemoryBackend::new());
+        let backend = TieredBackend::new(fast.clone(), slow, 1 << 20);
+
+        let mut writer = backend.create_named(&"temp".into()).unwrap();
+        writer.write_block(block(512, 1)).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(backend.tier_of(&"temp".into()), None);
+        drop(reader);
+        assert_eq!(backend.tier_of(&"temp".into()), None);
+
+        let mut writer = backend.create_named(&"kept".into()).unwrap();
+        writer.write_block(block(512, 2)).unwrap();
+        let (reader, _name) = writer.complete().unwrap();
+        assert_eq!(backend.tier_of(&"kept".into()), None);
+        reader.mark_for_checkpoint();
+        drop(reader);
+        assert_eq!(backend.tier_of(&"kept".into()), Some(Tier::Fast));
+
+        // Deleted behind our back.
+        fast.delete(&"kept".into()).unwrap();
+        backend.open(&"kept".into()).unwrap_err();
+        assert_eq!(backend.tier_of(&"kept".into()), None);
+    }
+}
@@ -0,0 +1,489 @@
+//! [StorageBackend] implementation for Windows.
+//!
+//! This is a simpler backend than the POSIX one: it writes each block as soon
+//! as it gets it, with positioned writes, and reads with positioned reads.
+//! It doesn't support direct I/O, write buffering, or the other tuning knobs
+//! that [PosixBackend](super::posix::PosixBackend) offers.
+//!
+//! Windows differs from POSIX in two ways that matter here.  First, a file
+//! that is open can only be renamed or deleted if every handle to it was
+//! opened with `FILE_SHARE_DELETE`, so this backend opens all of its files
+//! that way.  Second, renaming a file over an existing one is not the
+//! default; [std::fs::rename] uses `MoveFileExW` with
+//! `MOVEFILE_REPLACE_EXISTING`, which is what completing a file needs.
+
+use super::{
+    path_in, release_usage, BlockLocation, FileId, FileReader, FileWriter, HasFileId, StorageError,
+    MUTABLE_EXTENSION,
+};
+use crate::circuit::metrics::{
+    FILES_CREATED, FILES_DELETED, READS_FAILED, READS_SUCCESS, TOTAL_BYTES_READ,
+    TOTAL_BYTES_WRITTEN, WRITES_SUCCESS, WRITE_LATENCY,
+};
+use crate::storage::buffer_cache::FBuf;
+use feldera_storage::{
+    append_to_path, StorageBackend, StorageBackendFactory, StorageFileType, StoragePath,
+};
+use feldera_types::config::{StorageBackendConfig, StorageConfig};
+use metrics::{counter, histogram};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error as IoError, ErrorKind},
+    os::windows::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime},
+};
+use tracing::warn;
+
+/// `FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE`, so that other
+/// handles may read, write, rename, and delete the file while we have it
+/// open.
+const SHARE_ALL: u32 = 0x1 | 0x2 | 0x4;
+
+/// Opens `path` with the sharing mode that this backend needs.
+fn open_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.share_mode(SHARE_ALL);
+    options
+}
+
+/// Writes all of `data` to `file` at `offset`.
+fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> Result<(), IoError> {
+    while !data.is_empty() {
+        match file.seek_write(data, offset) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Reads from `file` at `offset` into `buf` until it's full or we reach the
+/// end of the file, and returns the number of bytes read.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, IoError> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.seek_read(&mut buf[total..], offset + total as u64) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(error) if error.kind() == ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(total)
+}
+
+/// Deletes a file and releases its usage when dropped, unless it's kept.
+struct DeleteOnDrop {
+    path: PathBuf,
+    keep: AtomicBool,
+    size: u64,
+    usage: Arc<AtomicI64>,
+}
+
+impl DeleteOnDrop {
+    fn new(path: PathBuf, keep: bool, size: u64, usage: Arc<AtomicI64>) -> Self {
+        Self {
+            path,
+            keep: AtomicBool::new(keep),
+            size,
+            usage,
+        }
+    }
+
+    fn keep(&self) {
+        self.keep.store(true, Ordering::Relaxed);
+    }
+
+    fn is_kept(&self) -> bool {
+        self.keep.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for DeleteOnDrop {
+    fn drop(&mut self) {
+        if !self.is_kept() {
+            if let Err(error) = fs::remove_file(&self.path) {
+                warn!(
+                    "unable to delete dropped file {}: {error}",
+                    self.path.display()
+                );
+            } else {
+                counter!(FILES_DELETED).increment(1);
+            }
+            release_usage(&self.usage, self.size, false);
+        }
+    }
+}
+
+/// Meta-data we keep per file we created.
+struct WindowsReader {
+    file: File,
+    file_id: FileId,
+    drop: DeleteOnDrop,
+    size: u64,
+}
+
+impl WindowsReader {
+    fn new(file: File, file_id: FileId, drop: DeleteOnDrop) -> Result<Self, IoError> {
+        let size = file.metadata()?.len();
+        Ok(Self {
+            file,
+            file_id,
+            drop,
+            size,
+        })
+    }
+}
+
+impl HasFileId for WindowsReader {
+    fn file_id(&self) -> FileId {
+        self.file_id
+    }
+}
+
+impl FileReader for WindowsReader {
+    fn mark_for_checkpoint(&self) {
+        self.drop.keep();
+    }
+
+    fn is_kept(&self) -> bool {
+        self.drop.is_kept()
+    }
+
+    fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        let mut buffer = FBuf::with_capacity(location.size);
+        buffer.resize(location.size, 0);
+        match read_at(&self.file, buffer.as_mut_slice(), location.offset) {
+            Ok(n) if n == location.size => {
+                counter!(TOTAL_BYTES_READ).increment(location.size as u64);
+                counter!(READS_SUCCESS).increment(1);
+                Ok(Arc::new(buffer))
+            }
+            Ok(_) => {
+                counter!(READS_FAILED).increment(1);
+                Err(StorageError::StdIo(ErrorKind::UnexpectedEof))
+            }
+            Err(error) => {
+                counter!(READS_FAILED).increment(1);
+                Err(error.into())
+            }
+        }
+    }
+
+    fn read_scattered(
+        &self,
+        mut offset: u64,
+        bufs: &mut [&mut [u8]],
+    ) -> Result<usize, StorageError> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let n = read_at(&self.file, buf, offset)?;
+            total += n;
+            offset += n as u64;
+            if n < buf.len() {
+                break;
+            }
+        }
+        counter!(TOTAL_BYTES_READ).increment(total as u64);
+        Ok(total)
+    }
+
+    fn get_size(&self) -> Result<u64, StorageError> {
+        Ok(self.size)
+    }
+
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        Ok(self.file.metadata()?.created()?)
+    }
+}
+
+struct WindowsWriter {
+    file: File,
+    file_id: FileId,
+    drop: DeleteOnDrop,
+    name: StoragePath,
+    final_path: PathBuf,
+    len: u64,
+}
+
+impl HasFileId for WindowsWriter {
+    fn file_id(&self) -> FileId {
+        self.file_id
+    }
+}
+
+impl FileWriter for WindowsWriter {
+    fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        let start = Instant::now();
+        write_all_at(&self.file, data.as_slice(), self.len)?;
+        histogram!(WRITE_LATENCY).record(start.elapsed().as_secs_f64());
+        counter!(TOTAL_BYTES_WRITTEN).increment(data.len() as u64);
+        counter!(WRITES_SUCCESS).increment(1);
+
+        self.len += data.len() as u64;
+        self.drop.size += data.len() as u64;
+        self.drop
+            .usage
+            .fetch_add(data.len() as i64, Ordering::Relaxed);
+        Ok(Arc::new(data))
+    }
+
+    /// Blocks are written as soon as they arrive, so this only has to sync.
+    fn sync(&mut self) -> Result<(), StorageError> {
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Renames the file from its temporary name to its final name, replacing
+    /// any existing file by that name.
+    fn complete(self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.file.sync_all()?;
+        fs::rename(&self.drop.path, &self.final_path)?;
+
+        // From here on, the reader is responsible for the file.
+        let drop = DeleteOnDrop::new(
+            self.final_path.clone(),
+            false,
+            self.drop.size,
+            self.drop.usage.clone(),
+        );
+        self.drop.keep();
+        let file = self.file.try_clone()?;
+        let reader = WindowsReader::new(file, self.file_id, drop)?;
+        Ok((Arc::new(reader), self.name.clone()))
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        // Dropping the writer deletes the file through `DeleteOnDrop`.
+        Ok(())
+    }
+}
+
+/// State of the backend needed to satisfy the storage APIs.
+pub struct WindowsBackend {
+    /// Directory in which we keep the files.
+    base: Arc<PathBuf>,
+
+    /// Tracks the total size of all the files.
+    usage: Arc<AtomicI64>,
+}
+
+impl WindowsBackend {
+    /// Instantiates a new backend that stores its files in `base`.
+    pub fn new<P: AsRef<Path>>(base: P) -> Self {
+        let base = Arc::new(base.as_ref().to_path_buf());
+        let usage = Arc::new(AtomicI64::new(Self::measure(&base)));
+        Self { base, usage }
+    }
+
+    /// Returns the total size of the files under `path`, recursively.
+    fn measure(path: &Path) -> i64 {
+        let Ok(entries) = path.read_dir() else {
+            return 0;
+        };
+        entries
+            .flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => Self::measure(&entry.path()),
+                Ok(metadata) => metadata.len() as i64,
+                Err(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Returns the filesystem path to `name` in this storage, failing with
+    /// [StorageError::InvalidPath] if that would be outside of it.
+    fn fs_path(&self, name: &StoragePath) -> Result<PathBuf, StorageError> {
+        path_in(&self.base, name)
+    }
+}
+
+impl StorageBackend for WindowsBackend {
+    fn create_named(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let final_path = self.fs_path(name)?;
+        let path = append_to_path(final_path.clone(), MUTABLE_EXTENSION);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = open_options()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        counter!(FILES_CREATED).increment(1);
+        Ok(Box::new(WindowsWriter {
+            file,
+            file_id: FileId::new(),
+            drop: DeleteOnDrop::new(path, false, 0, self.usage.clone()),
+            name: name.clone(),
+            final_path,
+            len: 0,
+        }))
+    }
+
+    /// Files opened this way were completed earlier, so dropping the reader
+    /// doesn't delete them.
+    fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError> {
+        let path = self.fs_path(name)?;
+        let file = open_options().read(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let drop = DeleteOnDrop::new(path, true, size, self.usage.clone());
+        Ok(Arc::new(WindowsReader::new(file, FileId::new(), drop)?))
+    }
+
+    fn list(
+        &self,
+        parent: &StoragePath,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        for entry in self.fs_path(parent)?.read_dir()? {
+            let entry = entry?;
+            let name = parent.child(entry.file_name().to_string_lossy().as_ref());
+            let metadata = entry.metadata()?;
+            let file_type = if metadata.is_file() {
+                StorageFileType::File {
+                    size: metadata.len(),
+                    allocated: metadata.len(),
+                }
+            } else if metadata.is_dir() {
+                StorageFileType::Directory
+            } else {
+                StorageFileType::Other
+            };
+            cb(&name, file_type);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let path = self.fs_path(name)?;
+        let metadata = fs::metadata(&path)?;
+        fs::remove_file(&path)?;
+        counter!(FILES_DELETED).increment(1);
+        release_usage(&self.usage, metadata.len(), false);
+        Ok(())
+    }
+
+    fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let path = self.fs_path(name)?;
+        let size = Self::measure(&path);
+        match fs::remove_dir_all(&path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            Err(error) if error.kind() == ErrorKind::NotADirectory => self.delete(name)?,
+            Err(error) => return Err(error.into()),
+            Ok(()) => release_usage(&self.usage, size as u64, false),
+        }
+        Ok(())
+    }
+
+    fn usage(&self) -> Arc<AtomicI64> {
+        self.usage.clone()
+    }
+}
+
+/// Backend factory for [WindowsBackend].
+pub(crate) struct WindowsBackendFactory;
+
+impl StorageBackendFactory for WindowsBackendFactory {
+    fn backend(&self) -> &'static str {
+        "default"
+    }
+
+    fn create(
+        &self,
+        storage_config: &StorageConfig,
+        _backend_config: &StorageBackendConfig,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        Ok(Arc::new(WindowsBackend::new(storage_config.path())))
+    }
+}
+
+inventory::submit! {
+    &WindowsBackendFactory as &dyn StorageBackendFactory
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use feldera_storage::{StorageBackend, StoragePath};
+
+    use super::WindowsBackend;
+    use crate::storage::{
+        backend::{
+            is_normal_component,
+            tests::{random_sizes, test_backend},
+        },
+        buffer_cache::FBuf,
+    };
+
+    fn create_windows_backend(path: &Path) -> Arc<dyn StorageBackend> {
+        Arc::new(WindowsBackend::new(path))
+    }
+
+    /// Write 10 MiB total in 1 KiB chunks.
+    #[test]
+    fn sequential_1024() {
+        test_backend(Box::new(create_windows_backend), &[1024; 1024 * 10], true)
+    }
+
+    /// Verify that files get deleted if not marked for a checkpoint.
+    #[test]
+    fn delete_1024() {
+        test_backend(Box::new(create_windows_backend), &[1024; 1024 * 10], false)
+    }
+
+    #[test]
+    fn sequential_random() {
+        test_backend(Box::new(create_windows_backend), &random_sizes(), true);
+    }
+
+    #[test]
+    fn empty() {
+        test_backend(Box::new(create_windows_backend), &[], true);
+    }
+
+    /// Names with traversal or absolute components resolve inside the
+    /// storage directory.
+    #[test]
+    fn fs_path_stays_inside() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = WindowsBackend::new(tmpdir.path());
+        for name in ["../escape", "/abs", "a/../../b"] {
+            let name = StoragePath::from(name);
+            let path = backend.fs_path(&name).unwrap();
+            assert!(path.starts_with(tmpdir.path()), "{}", path.display());
+            backend.write(&name, FBuf::new()).unwrap();
+            assert!(path.is_file());
+        }
+        assert!(!tmpdir.path().parent().unwrap().join("escape").exists());
+    }
+
+    /// Drive prefixes and backslashes aren't normal components on Windows.
+    #[test]
+    fn windows_components() {
+        for part in ["C:", "C:file", "a\\b", "a\\", "\\\\server\\share"] {
+            assert!(!is_normal_component(part), "{part}");
+        }
+    }
+
+    /// Dropping a reader from [WindowsBackend::open] doesn't delete the file.
+    #[test]
+    fn open_keeps_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let backend = WindowsBackend::new(tmpdir.path());
+        let name = StoragePath::from("file");
+        backend.write(&name, FBuf::new()).unwrap();
+        drop(backend.open(&name).unwrap());
+        assert!(backend.fs_path(&name).unwrap().is_file());
+    }
+}
@@ -19,7 +19,7 @@ use std::time::{Duration, Instant};
 
 use clap::Parser;
 
-use feldera_storage::backend::posixio_impl::PosixBackend;
+use feldera_storage::backend::posix::PosixBackend;
 use feldera_storage::backend::{AtomicIncrementOnlyI64, Storage};
 use feldera_storage::buffer_cache::FBuf;
 
@@ -215,7 +215,12 @@ impl BufferCache {
     }
 
     pub fn evict(&self, file: &dyn FileReader) {
-        self.inner.lock().unwrap().delete_file(file.file_id());
+        self.evict_file(file.file_id());
+    }
+
+    /// Removes all of the entries for the file with `file_id`.
+    pub fn evict_file(&self, file_id: FileId) {
+        self.inner.lock().unwrap().delete_file(file_id);
     }
 
     /// Returns `(cur_cost, max_cost)`, reporting the amount of the cache that
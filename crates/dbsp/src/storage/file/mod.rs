@@ -602,7 +602,7 @@ mod test {
         let storage_backend = <dyn StorageBackend>::new(
             &StorageConfig {
                 path: tempdir.path().to_string_lossy().to_string(),
-                cache: Default::default(),
+                ..Default::default()
             },
             &StorageOptions::default(),
         )
@@ -720,7 +720,7 @@ mod test {
                 let storage_backend = <dyn StorageBackend>::new(
                     &StorageConfig {
                         path: tempdir.path().to_string_lossy().to_string(),
-                        cache: Default::default(),
+                        ..Default::default()
                     },
                     &StorageOptions::default(),
                 )
@@ -78,7 +78,7 @@ impl From<BinError> for Error {
 
 impl From<io::Error> for Error {
     fn from(source: io::Error) -> Self {
-        Error::Storage(StorageError::StdIo(source.kind()))
+        Error::Storage(StorageError::from(source))
     }
 }
 
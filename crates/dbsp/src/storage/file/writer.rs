@@ -1217,7 +1217,7 @@ impl Writer {
 /// let tempdir = tempfile::tempdir().unwrap();
 /// let storage_backend = <dyn StorageBackend>::new(&StorageConfig {
 ///     path: tempdir.path().to_string_lossy().to_string(),
-///    cache: Default::default(),
+///     ..Default::default()
 /// }, &StorageOptions::default()).unwrap();
 /// let cache = Arc::new(BufferCache::new(1024 * 1024));
 /// let parameters = Parameters::default();
@@ -1353,7 +1353,7 @@ where
 /// let tempdir = tempfile::tempdir().unwrap();
 /// let storage_backend = <dyn StorageBackend>::new(&StorageConfig {
 ///     path: tempdir.path().to_string_lossy().to_string(),
-///    cache: Default::default(),
+///     ..Default::default()
 /// }, &StorageOptions::default()).unwrap();
 /// let cache = Arc::new(BufferCache::new(1024 * 1024));
 /// let parameters = Parameters::default();
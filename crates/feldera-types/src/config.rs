@@ -118,15 +118,284 @@ pub struct StorageConfig {
     /// the log.
     pub path: String,
 
+    /// A directory in which to write storage files until they are complete,
+    /// instead of `path`, such as a directory on fast ephemeral storage.
+    ///
+    /// Completing a file moves it into `path`, which requires copying it if
+    /// the two directories are on different filesystems.  Files in this
+    /// directory don't count toward storage usage.  When this is unset, the
+    /// default, files are written in `path` throughout.
+    #[serde(default)]
+    pub scratch_path: Option<String>,
+
     /// How to cache access to storage in this pipeline.
     #[serde(default)]
     pub cache: StorageCacheConfig,
+
+    /// Additional flags for opening files in storage.
+    #[serde(default)]
+    pub extra_open_flags: StorageOpenFlags,
+
+    /// Whether completing a file in storage should make its metadata durable,
+    /// along with its data.
+    ///
+    /// When this is true, the default, completing a file uses `fsync`.  When
+    /// it is false, completing a file uses `fdatasync`, which is faster but
+    /// only guarantees that the metadata needed to read the data back is
+    /// durable.  On some filesystems, this might not include the file's size,
+    /// so that a crash could truncate a file that was completed.
+    ///
+    /// This is ignored if `durability_mode` is set.
+    #[serde(default = "default_sync_metadata")]
+    pub sync_metadata: bool,
+
+    /// How completing a file in storage makes it durable.
+    ///
+    /// When this is set, it overrides `sync_metadata`.  With any mode other
+    /// than `none`, completing a file also syncs the directory that contains
+    /// it, so that the file's final name survives a crash too.
+    #[serde(default)]
+    pub durability_mode: Option<DurabilityMode>,
+
+    /// Whether to read back every block written to storage and compare it
+    /// against the data that was written.
+    ///
+    /// This is a diagnostic mode for catching silent write corruption on
+    /// suspect hardware.  It roughly doubles storage I/O, so it is off by
+    /// default.
+    #[serde(default)]
+    pub write_verify: bool,
+
+    /// Whether to record a CRC32C checksum for every block written to
+    /// storage, and verify it whenever the block is read back.
+    ///
+    /// The checksums are stored in a trailer at the end of each file.  Files
+    /// written without checksums can still be read with this enabled, but
+    /// files written with checksums must be read with this enabled, because
+    /// otherwise storage doesn't look for the trailer and reads it as part of
+    /// the file.  This is off by default.
+    #[serde(default)]
+    pub block_checksums: bool,
+
+    /// What counts toward the amount of storage reported as in use.
+    #[serde(default)]
+    pub usage_policy: UsagePolicy,
+
+    /// Whether to count the storage actually allocated to files toward the
+    /// amount of storage in use, instead of their lengths.
+    ///
+    /// These differ for sparse files, which take up less space than their
+    /// length, and slightly for other files, because storage is allocated in
+    /// blocks.  Counting allocated storage makes `quota_bytes` track actual
+    /// disk consumption.  Where the allocated size is unavailable, a file's
+    /// length is counted instead.  This is off by default.
+    #[serde(default)]
+    pub physical_usage: bool,
+
+    /// Maximum number of bytes of storage to use, as counted according to
+    /// `usage_policy`.
+    ///
+    /// Writes that would take usage past the quota fail, which makes the
+    /// pipeline stop rather than fill the disk.  By default, there is no
+    /// quota.
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+
+    /// Number of bytes of free space to keep in reserve on the filesystem
+    /// that holds storage.
+    ///
+    /// Creating a storage file fails when less space than this is free,
+    /// which reports running low on disk before writes start to fail partway
+    /// through.  Unlike `quota_bytes`, this accounts for everything else on
+    /// the filesystem.  By default, there is no reserve.
+    #[serde(default)]
+    pub min_free_space_bytes: Option<u64>,
+
+    /// Maximum size, in bytes, of a file in storage that is read through a
+    /// memory mapping instead of with system calls.
+    ///
+    /// Mapping small files that are read over and over, such as indexes,
+    /// avoids the cost of a system call for each block read.  By default, no
+    /// files are mapped.
+    #[serde(default)]
+    pub mmap_threshold_bytes: Option<u64>,
+
+    /// Capacity, in bytes, of a cache of blocks read from storage, shared by
+    /// all of the pipeline's storage readers.
+    ///
+    /// Reading a cached block again doesn't need a system call.  The cache is
+    /// in addition to the operating system's page cache and to the buffer
+    /// cache of parsed blocks.  By default, there is no block cache.
+    #[serde(default)]
+    pub block_cache_bytes: Option<usize>,
+
+    /// Maximum number of buffers in a pool of read buffers shared by all of
+    /// the pipeline's storage readers.
+    ///
+    /// Reads take their buffers from the pool, which reclaims the buffers of
+    /// blocks that are no longer in use, instead of allocating a new buffer
+    /// for every read.  By default, there is no pool.
+    #[serde(default)]
+    pub read_buffer_pool_buffers: Option<usize>,
+
+    /// Permissions, such as `0o640`, for the files that storage creates, as
+    /// passed to `open`, so that the process's umask still applies.
+    /// Directories get the same permissions, plus search permission wherever
+    /// the file permissions grant read permission.
+    ///
+    /// By default, files and directories get the permissions that the umask
+    /// allows.  This is ignored on platforms other than Unix.
+    #[serde(default)]
+    pub file_mode: Option<u32>,
+
+    /// Maximum number of files that storage readers and writers may hold open
+    /// at once.  Opening or creating a file beyond the limit fails, so that
+    /// the pipeline can back off before it runs into the operating system's
+    /// limit on open files.
+    ///
+    /// By default, there is no limit.
+    #[serde(default)]
+    pub max_open_files: Option<usize>,
+
+    /// Whether opening a storage file that is already open returns the
+    /// reader that already has it open, instead of opening it again.
+    ///
+    /// This saves system calls for files that are opened over and over.  The
+    /// default is false.
+    #[serde(default)]
+    pub shared_readers: bool,
+
+    /// Whether deleting storage files moves them into a `.trash` directory
+    /// within the storage directory, from which they can be restored,
+    /// instead of deleting them immediately.
+    ///
+    /// Files in the trash don't count toward storage usage, but they still
+    /// take up disk space until the trash is emptied.  The default is false.
+    #[serde(default)]
+    pub trash: bool,
+
+    /// Whether temporary storage files that are no longer needed are deleted
+    /// by a dedicated background thread, instead of by the thread that
+    /// happened to release them.
+    ///
+    /// Deleting a large file can block for a while on some filesystems, which
+    /// would otherwise stall the pipeline.  The default is false.
+    #[serde(default)]
+    pub background_deletion: bool,
+
+    /// The number of bytes that a storage writer buffers before flushing it
+    /// to disk.  This is provided for fine-tuning and should ordinarily be left
+    /// unset.
+    ///
+    /// Larger values, such as 4 MiB to 8 MiB, can help on devices with deep
+    /// queues, and smaller values reduce memory use.  The value must be at
+    /// least one page.  Writers never buffer more than `IOV_MAX` blocks,
+    /// regardless of this setting.
+    ///
+    /// The default is 1,048,576 (1 MiB).
+    #[serde(default)]
+    pub flush_threshold: Option<usize>,
+
+    /// The maximum number of bytes that a single storage writer buffers
+    /// before flushing, regardless of `flush_threshold` and
+    /// `adaptive_flush`.
+    ///
+    /// By default, there is no limit other than the flush threshold.
+    #[serde(default)]
+    pub max_writer_buffer_bytes: Option<usize>,
+
+    /// The maximum number of bytes that all of a pipeline's storage writers
+    /// together buffer before flushing.
+    ///
+    /// A writer that would exceed this limit flushes its own buffer and then
+    /// waits for writers on other threads to flush theirs, which bounds the
+    /// memory that many concurrent writers can pin.  The limit is soft: a
+    /// writer exceeds it instead of waiting if only writers on its own thread
+    /// hold the space, or if waiting for a second doesn't free enough.  By
+    /// default, there is no limit.
+    #[serde(default)]
+    pub max_total_write_buffer_bytes: Option<usize>,
+
+    /// Whether to adapt how much data a storage writer buffers before flushing
+    /// it to the latency that flushes are observed to take.
+    ///
+    /// When this is unset, the default, writers always flush at
+    /// `flush_threshold`.
+    #[serde(default)]
+    pub adaptive_flush: Option<AdaptiveFlushConfig>,
+
+    /// Whether opening a storage file that doesn't exist waits briefly and
+    /// tries again, in case the file is about to appear.
+    ///
+    /// This covers the brief window in which another thread in the pipeline
+    /// is renaming a completed file into place.  When this is unset, the
+    /// default, opening a file that doesn't exist fails immediately.
+    #[serde(default)]
+    pub open_retry: Option<OpenRetryConfig>,
+
+    /// Whether to compress each block written to storage.
+    ///
+    /// Compression trades CPU time for less disk space and I/O.  Files written
+    /// without compression can still be read with it enabled, but files
+    /// written with compression can only be read with it enabled.  This is
+    /// independent of the `compression` storage option, which compresses the
+    /// data inside batches, so that there is usually little to gain from
+    /// enabling both.  By default, blocks are not compressed.
+    #[serde(default)]
+    pub block_compression: Option<BlockCompressionConfig>,
+}
+
+fn default_sync_metadata() -> bool {
+    true
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            scratch_path: None,
+            cache: StorageCacheConfig::default(),
+            extra_open_flags: StorageOpenFlags::default(),
+            sync_metadata: default_sync_metadata(),
+            durability_mode: None,
+            write_verify: false,
+            block_checksums: false,
+            usage_policy: UsagePolicy::default(),
+            physical_usage: false,
+            quota_bytes: None,
+            min_free_space_bytes: None,
+            mmap_threshold_bytes: None,
+            block_cache_bytes: None,
+            read_buffer_pool_buffers: None,
+            file_mode: None,
+            max_open_files: None,
+            shared_readers: false,
+            trash: false,
+            background_deletion: false,
+            flush_threshold: None,
+            max_writer_buffer_bytes: None,
+            max_total_write_buffer_bytes: None,
+            adaptive_flush: None,
+            open_retry: None,
+            block_compression: None,
+        }
+    }
 }
 
 impl StorageConfig {
     pub fn path(&self) -> &Path {
         Path::new(&self.path)
     }
+
+    /// Returns how completing a file makes it durable, according to
+    /// `durability_mode` or, if that is unset, `sync_metadata`.
+    pub fn durability(&self) -> DurabilityMode {
+        self.durability_mode.unwrap_or(if self.sync_metadata {
+            DurabilityMode::SyncAll
+        } else {
+            DurabilityMode::SyncData
+        })
+    }
 }
 
 /// How to cache access to storage within a Feldera pipeline.
@@ -145,6 +414,15 @@ pub enum StorageCacheConfig {
     /// This is under development. It will become the default when its
     /// performance exceeds that of `PageCache`.
     FelderaCache,
+
+    /// Bypass the operating system's page cache with direct I/O (`O_DIRECT`
+    /// on Linux), without otherwise caching.
+    ///
+    /// This keeps large sequential scans from evicting other data from the
+    /// page cache.  If the filesystem doesn't support direct I/O, as with
+    /// tmpfs on some kernels, storage falls back to the page cache with a
+    /// warning.
+    Direct,
 }
 
 impl StorageCacheConfig {
@@ -152,13 +430,201 @@ impl StorageCacheConfig {
     pub fn to_custom_open_flags(&self) -> i32 {
         match self {
             StorageCacheConfig::PageCache => (),
-            StorageCacheConfig::FelderaCache => {
+            StorageCacheConfig::FelderaCache | StorageCacheConfig::Direct => {
                 #[cfg(target_os = "linux")]
                 return libc::O_DIRECT;
             }
         }
         0
     }
+
+    /// Returns true if this configuration asks for direct I/O.
+    pub fn uses_direct_io(&self) -> bool {
+        !matches!(self, StorageCacheConfig::PageCache)
+    }
+}
+
+/// How completing a file in storage makes it durable.
+#[derive(Copy, Clone, Default, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DurabilityMode {
+    /// Sync the file's data and metadata (`fsync`).
+    #[default]
+    SyncAll,
+
+    /// Sync the file's data and only the metadata needed to read it back
+    /// (`fdatasync`).
+    SyncData,
+
+    /// Don't sync at all.  A crash can lose or truncate recently completed
+    /// files, so this only suits storage that doesn't need to survive a crash,
+    /// such as tmpfs, or callers that make data durable some other way.
+    None,
+}
+
+/// What counts toward the amount of storage that a Feldera pipeline reports as
+/// in use, e.g. for quotas.
+#[derive(Copy, Clone, Default, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UsagePolicy {
+    /// Count only the bytes in completed files.  Bytes in files that are still
+    /// being written count only once the file is completed.
+    DataOnly,
+
+    /// Count the bytes in all regular files, including those still being
+    /// written.
+    #[default]
+    IncludeInProgress,
+
+    /// Like `IncludeInProgress`, but also count the space taken by the
+    /// directories that storage creates.
+    IncludeAll,
+}
+
+impl UsagePolicy {
+    /// Returns true if bytes written to files that are not yet complete count
+    /// toward usage.
+    pub fn counts_in_progress(&self) -> bool {
+        !matches!(self, UsagePolicy::DataOnly)
+    }
+
+    /// Returns true if directories count toward usage.
+    pub fn counts_directories(&self) -> bool {
+        matches!(self, UsagePolicy::IncludeAll)
+    }
+}
+
+/// Configuration for adapting the amount of data that storage writers buffer
+/// before flushing to the observed latency of flushes.
+///
+/// Writers start out flushing at the configured flush threshold.  When
+/// flushes take longer than `target_latency_ms`, writers flush smaller
+/// amounts; when they are much faster, writers flush larger amounts, within
+/// the given bounds.
+#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct AdaptiveFlushConfig {
+    /// The fewest bytes to buffer before flushing.
+    ///
+    /// The default is 65,536 (64 KiB).
+    pub min_bytes: usize,
+
+    /// The most bytes to buffer before flushing.
+    ///
+    /// The default is 16,777,216 (16 MiB).
+    pub max_bytes: usize,
+
+    /// The latency to aim for in each flush, in milliseconds.
+    ///
+    /// The default is 10 ms.
+    pub target_latency_ms: u64,
+}
+
+impl Default for AdaptiveFlushConfig {
+    fn default() -> Self {
+        Self {
+            min_bytes: 64 * 1024,
+            max_bytes: 16 * 1024 * 1024,
+            target_latency_ms: 10,
+        }
+    }
+}
+
+/// Configuration for retrying opening a storage file that doesn't exist.
+#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct OpenRetryConfig {
+    /// The most times to try opening the file, including the first.
+    ///
+    /// The default is 5.
+    pub attempts: u32,
+
+    /// How long to wait before each retry, in milliseconds.
+    ///
+    /// The default is 1 ms.
+    pub delay_ms: u64,
+}
+
+impl Default for OpenRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            delay_ms: 1,
+        }
+    }
+}
+
+/// Algorithm for compressing blocks in storage.
+#[derive(Copy, Clone, Default, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockCompression {
+    /// [Snappy](https://github.com/google/snappy), which is fast but doesn't
+    /// compress as well as zstd.
+    #[default]
+    Snappy,
+
+    /// [Zstandard](https://facebook.github.io/zstd/), which compresses better
+    /// than Snappy at a higher CPU cost.
+    Zstd,
+}
+
+/// Configuration for compressing blocks in storage.
+#[derive(Copy, Clone, Default, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct BlockCompressionConfig {
+    /// The compression algorithm.
+    ///
+    /// The default is Snappy.
+    pub algorithm: BlockCompression,
+
+    /// The compression level, for algorithms that have levels.
+    ///
+    /// For zstd, higher levels compress better but more slowly, and the
+    /// default is 3.  Snappy ignores the level.
+    pub level: Option<i32>,
+}
+
+/// Flags for opening files in storage, in addition to those implied by
+/// [StorageCacheConfig].
+#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct StorageOpenFlags {
+    /// Don't update files' access times when they are read (`O_NOATIME`).
+    ///
+    /// This avoids metadata writes in read-heavy workloads.  It only has an
+    /// effect on Linux, which only allows it for files owned by the pipeline's
+    /// user.
+    pub noatime: bool,
+
+    /// Close files in child processes (`O_CLOEXEC`), so that file descriptors
+    /// don't leak into them.
+    ///
+    /// This is enabled by default.
+    pub cloexec: bool,
+}
+
+impl Default for StorageOpenFlags {
+    fn default() -> Self {
+        Self {
+            noatime: false,
+            cloexec: true,
+        }
+    }
+}
+
+impl StorageOpenFlags {
+    #[cfg(unix)]
+    pub fn to_custom_open_flags(&self) -> i32 {
+        let mut flags = 0;
+        #[cfg(target_os = "linux")]
+        if self.noatime {
+            flags |= libc::O_NOATIME;
+        }
+        if self.cloexec {
+            flags |= libc::O_CLOEXEC;
+        }
+        flags
+    }
 }
 
 /// Storage configuration for a pipeline.
@@ -204,15 +670,30 @@ pub enum StorageBackendConfig {
     #[default]
     Default,
 
+    /// Keep all data in memory.
+    ///
+    /// Everything in storage is lost when the pipeline exits, so this is only
+    /// suitable for tests and ephemeral workloads.
+    Memory,
+
     /// Object storage.
     Object(ObjectStorageConfig),
+
+    /// S3-compatible object storage.
+    ///
+    /// Files are buffered in memory while they are written and uploaded
+    /// when they are completed.  Only `s3://`, `s3a://`, and S3-style
+    /// `https://` URLs are useful here.
+    S3(ObjectStorageConfig),
 }
 
 impl Display for StorageBackendConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             StorageBackendConfig::Default => write!(f, "default"),
+            StorageBackendConfig::Memory => write!(f, "memory"),
             StorageBackendConfig::Object(_) => write!(f, "object"),
+            StorageBackendConfig::S3(_) => write!(f, "s3"),
         }
     }
 }
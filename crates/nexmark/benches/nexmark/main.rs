@@ -258,6 +258,7 @@ fn run_query(config: &NexmarkConfig, snapshotter: &Snapshotter, query: Query) ->
                         } else {
                             StorageCacheConfig::PageCache
                         },
+                        ..StorageConfig::default()
                     },
                     StorageOptions::default(),
                 )
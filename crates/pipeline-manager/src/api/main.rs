@@ -150,6 +150,13 @@ only the program-related core fields, and is used by the compiler to discern whe
         feldera_types::config::PipelineConfig,
         feldera_types::config::StorageConfig,
         feldera_types::config::StorageCacheConfig,
+        feldera_types::config::StorageOpenFlags,
+        feldera_types::config::UsagePolicy,
+        feldera_types::config::DurabilityMode,
+        feldera_types::config::AdaptiveFlushConfig,
+        feldera_types::config::OpenRetryConfig,
+        feldera_types::config::BlockCompression,
+        feldera_types::config::BlockCompressionConfig,
         feldera_types::config::StorageOptions,
         feldera_types::config::StorageBackendConfig,
         feldera_types::config::StorageCompression,
@@ -10,7 +10,7 @@ use crate::runner::error::RunnerError;
 use crate::runner::logs_buffer::LogsBuffer;
 use crate::runner::pipeline_executor::{LogMessage, PipelineExecutor, LOGS_END_MESSAGE};
 use async_trait::async_trait;
-use feldera_types::config::{PipelineConfig, StorageCacheConfig, StorageConfig};
+use feldera_types::config::{PipelineConfig, StorageConfig};
 use log::{debug, error, Level};
 use reqwest::StatusCode;
 use std::path::Path;
@@ -429,7 +429,7 @@ impl PipelineExecutor for LocalRunner {
         let pipeline_storage_dir = pipeline_dir.join("storage");
         StorageConfig {
             path: pipeline_storage_dir.to_string_lossy().into(),
-            cache: StorageCacheConfig::default(),
+            ..StorageConfig::default()
         }
     }
 
@@ -1257,7 +1257,7 @@ mod test {
         async fn generate_storage_config(&self) -> StorageConfig {
             StorageConfig {
                 path: "".to_string(),
-                cache: Default::default(),
+                ..Default::default()
             }
         }
 
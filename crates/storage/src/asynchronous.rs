@@ -0,0 +1,163 @@
+//! Asynchronous access to a synchronous [StorageBackend], for callers that run
+//! inside a Tokio runtime.
+//!
+//! Each operation runs on Tokio's blocking thread pool with
+//! [spawn_blocking](tokio::task::spawn_blocking), so that it doesn't block the
+//! executor.  Usage accounting and deletion of files that aren't completed or
+//! marked for a checkpoint work the same as for the wrapped backend, because
+//! the wrapped backend still does all of the work.
+
+use std::io::ErrorKind;
+use std::panic::resume_unwind;
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+
+use crate::block::BlockLocation;
+use crate::error::StorageError;
+use crate::fbuf::FBuf;
+use crate::{FileReader, FileWriter, StorageBackend, StoragePath};
+
+/// Runs `f` on Tokio's blocking thread pool and returns its result.
+async fn blocking<T, F>(f: F) -> Result<T, StorageError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => resume_unwind(error.into_panic()),
+        Err(_) => Err(StorageError::StdIo(ErrorKind::Interrupted)),
+    }
+}
+
+/// Asynchronous wrapper around a [StorageBackend].
+#[derive(Clone)]
+pub struct AsyncStorageBackend {
+    backend: Arc<dyn StorageBackend>,
+}
+
+impl AsyncStorageBackend {
+    /// Returns a new wrapper around `backend`.
+    pub fn new(backend: Arc<dyn StorageBackend>) -> Self {
+        Self { backend }
+    }
+
+    /// Returns the wrapped backend.
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    /// Asynchronous version of [StorageBackend::create_named].
+    pub async fn create_named(&self, name: &StoragePath) -> Result<AsyncFileWriter, StorageError> {
+        let backend = self.backend.clone();
+        let name = name.clone();
+        let writer = blocking(move || backend.create_named(&name)).await?;
+        Ok(AsyncFileWriter {
+            writer: Some(writer),
+        })
+    }
+
+    /// Asynchronous version of [StorageBackend::open].
+    pub async fn open(&self, name: &StoragePath) -> Result<AsyncFileReader, StorageError> {
+        let backend = self.backend.clone();
+        let name = name.clone();
+        let reader = blocking(move || backend.open(&name)).await?;
+        Ok(AsyncFileReader::new(reader))
+    }
+
+    /// Asynchronous version of [StorageBackend::delete].
+    pub async fn delete(&self, name: &StoragePath) -> Result<(), StorageError> {
+        let backend = self.backend.clone();
+        let name = name.clone();
+        blocking(move || backend.delete(&name)).await
+    }
+
+    /// Returns the wrapped backend's usage counter.  See
+    /// [StorageBackend::usage].
+    pub fn usage(&self) -> Arc<AtomicI64> {
+        self.backend.usage()
+    }
+}
+
+/// Asynchronous wrapper around a [FileWriter].
+///
+/// If a future returned by one of this writer's methods is dropped before it
+/// completes, the operation still runs to completion in the background, but
+/// the writer is then dropped, which deletes the file as usual for a writer
+/// dropped without completing it.  Later calls then fail.
+pub struct AsyncFileWriter {
+    /// The writer, or `None` if an earlier operation was cancelled.
+    writer: Option<Box<dyn FileWriter>>,
+}
+
+impl AsyncFileWriter {
+    fn take(&mut self) -> Result<Box<dyn FileWriter>, StorageError> {
+        self.writer
+            .take()
+            .ok_or(StorageError::StdIo(ErrorKind::BrokenPipe))
+    }
+
+    /// Asynchronous version of [FileWriter::write_block].
+    pub async fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError> {
+        let mut writer = self.take()?;
+        let (writer, result) = blocking(move || {
+            let result = writer.write_block(data);
+            Ok((writer, result))
+        })
+        .await?;
+        self.writer = Some(writer);
+        result
+    }
+
+    /// Asynchronous version of [FileWriter::complete].
+    ///
+    /// Dropping the returned future before it completes never leaves the file
+    /// half-renamed: completion still finishes in the background, and then
+    /// the completed file is deleted, because nothing can mark it for a
+    /// checkpoint.
+    pub async fn complete(mut self) -> Result<(AsyncFileReader, StoragePath), StorageError> {
+        let writer = self.take()?;
+        let (reader, name) = blocking(move || writer.complete()).await?;
+        Ok((AsyncFileReader::new(reader), name))
+    }
+
+    /// Asynchronous version of [FileWriter::abort].
+    pub async fn abort(mut self) -> Result<(), StorageError> {
+        let writer = self.take()?;
+        blocking(move || writer.abort()).await
+    }
+}
+
+/// Asynchronous wrapper around a [FileReader].
+#[derive(Clone)]
+pub struct AsyncFileReader {
+    reader: Arc<dyn FileReader>,
+}
+
+impl AsyncFileReader {
+    /// Returns a new wrapper around `reader`.
+    pub fn new(reader: Arc<dyn FileReader>) -> Self {
+        Self { reader }
+    }
+
+    /// Returns the wrapped reader.
+    pub fn reader(&self) -> &Arc<dyn FileReader> {
+        &self.reader
+    }
+
+    /// See [FileReader::mark_for_checkpoint].
+    pub fn mark_for_checkpoint(&self) {
+        self.reader.mark_for_checkpoint();
+    }
+
+    /// Asynchronous version of [FileReader::read_block].
+    pub async fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError> {
+        let reader = self.reader.clone();
+        blocking(move || reader.read_block(location)).await
+    }
+
+    /// See [FileReader::get_size].
+    pub fn get_size(&self) -> Result<u64, StorageError> {
+        self.reader.get_size()
+    }
+}
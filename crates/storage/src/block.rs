@@ -1,4 +1,10 @@
 use std::fmt::Display;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use crate::error::StorageError;
+use crate::fbuf::FBuf;
+use crate::FileReader;
 
 /// A block that can be read or written in a [crate::FileReader] or [crate::FileWriter].
 #[derive(Copy, Clone, Debug)]
@@ -23,18 +29,188 @@ impl BlockLocation {
         }
     }
 
+    /// Constructs a new [BlockLocation] for the `size` bytes that start
+    /// `distance` bytes before the end of a file that is `file_size` bytes
+    /// long.
+    ///
+    /// If `distance` exceeds `file_size`, then `before_start` says what to
+    /// do.  With [BeforeStart::Clamp], the block is clamped to start at
+    /// offset 0 and includes only the part of the requested range that lies
+    /// within the file.  This fails with [StorageError::BlockBeforeStart] if
+    /// none of it does, and with [StorageError::InvalidBlockLocation] if the
+    /// part that does isn't a multiple of 512 bytes, which happens when
+    /// `file_size` isn't.  With [BeforeStart::Fail], it always fails with
+    /// [StorageError::BlockBeforeStart].
+    ///
+    /// Otherwise, the result must satisfy the constraints in
+    /// [BlockLocation::new].
+    pub fn from_end(
+        file_size: u64,
+        distance: u64,
+        size: usize,
+        before_start: BeforeStart,
+    ) -> Result<Self, StorageError> {
+        let Some(offset) = file_size.checked_sub(distance) else {
+            let missing = distance - file_size;
+            return match before_start {
+                BeforeStart::Clamp if missing < size as u64 => {
+                    Ok(Self::new(0, size - missing as usize)?)
+                }
+                BeforeStart::Clamp | BeforeStart::Fail => Err(StorageError::BlockBeforeStart {
+                    distance,
+                    file_size,
+                }),
+            };
+        };
+        Ok(Self::new(offset, size)?)
+    }
+
     /// File offset just after this block.
     pub fn after(&self) -> u64 {
         self.offset + self.size as u64
     }
 }
 
+/// What [BlockLocation::from_end] does with a block that would start before
+/// the beginning of the file.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BeforeStart {
+    /// Read only the part of the block that lies within the file.
+    #[default]
+    Clamp,
+
+    /// Fail with [StorageError::BlockBeforeStart].
+    Fail,
+}
+
 impl Display for BlockLocation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{} bytes at offset {}", self.size, self.offset)
     }
 }
 
+/// A block reserved in a [crate::FileWriter] by
+/// [FileWriter::reserve_block](crate::FileWriter::reserve_block), to be filled
+/// in later with
+/// [FileWriter::fill_reserved](crate::FileWriter::fill_reserved).
+///
+/// A handle can't be copied, so each reserved block can be filled only once.
+#[derive(Debug)]
+pub struct BlockHandle(BlockLocation);
+
+impl BlockHandle {
+    /// Constructs a handle for `location`.  This is for use by storage
+    /// backends.
+    pub fn new(location: BlockLocation) -> Self {
+        Self(location)
+    }
+
+    /// Returns the location of the reserved block.
+    pub fn location(&self) -> BlockLocation {
+        self.0
+    }
+}
+
+/// A range of bytes within a block, returned by
+/// [FileReader::read_block_slice].
+///
+/// A slice shares the block's buffer instead of copying the bytes out of it,
+/// and keeps the buffer alive for as long as the slice exists.
+#[derive(Clone, Debug)]
+pub struct BlockSlice {
+    block: Arc<FBuf>,
+    range: Range<usize>,
+}
+
+impl BlockSlice {
+    /// Constructs a slice of the bytes in `range` within `block`, or returns
+    /// `None` if `range` isn't within `block`.
+    pub fn new(block: Arc<FBuf>, range: Range<usize>) -> Option<Self> {
+        (range.start <= range.end && range.end <= block.len()).then_some(Self { block, range })
+    }
+
+    /// Returns the block that this slice is part of.
+    pub fn block(&self) -> &Arc<FBuf> {
+        &self.block
+    }
+
+    /// Returns the range of bytes within [Self::block] that this slice
+    /// covers.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// Returns the bytes in the slice.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.block[self.range.clone()]
+    }
+}
+
+impl Deref for BlockSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for BlockSlice {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+/// A block returned by [FileReader::read_block_ref], either in a buffer of
+/// its own or borrowed from the reader.
+///
+/// Callers that don't care which can use [BlockRef::as_slice].
+#[derive(Clone, Debug)]
+pub enum BlockRef<'a> {
+    /// The block was read into a buffer of its own.
+    Owned(Arc<FBuf>),
+
+    /// The block is borrowed from data that the reader already holds, such
+    /// as a memory mapping of the file.
+    Borrowed(&'a [u8]),
+}
+
+impl BlockRef<'_> {
+    /// Returns the bytes in the block.
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            BlockRef::Owned(block) => block.as_slice(),
+            BlockRef::Borrowed(data) => data,
+        }
+    }
+
+    /// Returns the block in a buffer of its own, copying it if it is
+    /// borrowed.
+    pub fn into_owned(self) -> Arc<FBuf> {
+        match self {
+            BlockRef::Owned(block) => block,
+            BlockRef::Borrowed(data) => {
+                let mut block = FBuf::with_capacity(data.len());
+                block.extend_from_slice(data);
+                Arc::new(block)
+            }
+        }
+    }
+}
+
+impl Deref for BlockRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for BlockRef<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
 /// A range of bytes in a file that doesn't satisfy the constraints for
 /// [BlockLocation].
 #[derive(Copy, Clone, Debug)]
@@ -51,3 +227,92 @@ impl Display for InvalidBlockLocation {
         write!(f, "{} bytes at offset {}", self.size, self.offset)
     }
 }
+
+/// Iterator over the blocks in a [FileReader], returned by
+/// `<dyn FileReader>::blocks`.
+///
+/// Each block is `block_size` bytes long, except that the final block may be
+/// shorter.  The iterator asks the reader to
+/// [prefetch](FileReader::prefetch) the next [PREFETCH_BLOCKS] blocks ahead
+/// of the one it reads.
+pub struct Blocks<'a> {
+    reader: &'a dyn FileReader,
+    block_size: usize,
+    offset: u64,
+    size: Option<u64>,
+
+    /// Offset up to which blocks have been prefetched.
+    prefetched: u64,
+}
+
+/// Number of blocks that [Blocks] prefetches ahead of the one it reads.
+pub const PREFETCH_BLOCKS: usize = 4;
+
+impl<'a> Blocks<'a> {
+    pub(crate) fn new(reader: &'a dyn FileReader, block_size: usize) -> Self {
+        assert!(
+            block_size > 0 && block_size % 512 == 0,
+            "block size {block_size} is not a positive multiple of 512"
+        );
+        reader.advise_sequential();
+        Self {
+            reader,
+            block_size,
+            offset: 0,
+            size: None,
+            prefetched: 0,
+        }
+    }
+
+    /// Prefetches the blocks that start within [PREFETCH_BLOCKS] blocks after
+    /// the current offset and haven't been prefetched yet.
+    fn prefetch(&mut self, size: u64) {
+        let block_size = self.block_size as u64;
+        let end = size.min(self.offset + PREFETCH_BLOCKS as u64 * block_size);
+        let start = self.prefetched.max(self.offset);
+        if start >= end {
+            return;
+        }
+        let locations = (start..end)
+            .step_by(self.block_size)
+            .map(|offset| BlockLocation {
+                offset,
+                size: (size - offset).min(block_size) as usize,
+            })
+            .collect::<Vec<_>>();
+        self.reader.prefetch(&locations);
+        self.prefetched = end;
+    }
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<Arc<FBuf>, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let size = match self.size {
+            Some(size) => size,
+            None => match self.reader.get_size() {
+                Ok(size) => *self.size.insert(size),
+                Err(error) => {
+                    self.size = Some(0);
+                    return Some(Err(error));
+                }
+            },
+        };
+        if self.offset >= size {
+            return None;
+        }
+        let location = BlockLocation {
+            offset: self.offset,
+            size: (size - self.offset).min(self.block_size as u64) as usize,
+        };
+        self.offset = location.after();
+        self.prefetch(size);
+        let result = self.reader.read_block(location);
+        if result.is_err() {
+            // Don't keep reading after an error.
+            self.offset = size;
+        }
+        Some(result)
+    }
+}
@@ -0,0 +1,95 @@
+//! Sources of wall-clock time for storage.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of wall-clock time, for timestamps that storage records (such as
+/// [FileReader::created_at](crate::FileReader::created_at)) and for features
+/// that compare against them.
+///
+/// Wall-clock time can jump backward, e.g. on NTP corrections, and it can
+/// differ between hosts, so a stored timestamp can appear to be in the
+/// future.  Use [elapsed_since](Self::elapsed_since) rather than subtracting
+/// times directly.
+pub trait StorageClock: Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns the time elapsed since `time`, or zero if `time` is in the
+    /// future.
+    fn elapsed_since(&self, time: SystemTime) -> Duration {
+        self.now().duration_since(time).unwrap_or(Duration::ZERO)
+    }
+}
+
+/// The system's wall clock, [SystemTime::now].  This is the default.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl StorageClock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A wall clock that never goes backward, because it reads the system's
+/// wall clock once, at construction, and then advances it by a monotonic
+/// clock.
+///
+/// This clock doesn't follow corrections to the system clock after it is
+/// constructed, so it drifts from the system clock over time.
+#[derive(Copy, Clone, Debug)]
+pub struct MonotonicClock {
+    wall: SystemTime,
+    start: Instant,
+}
+
+impl MonotonicClock {
+    /// Creates a clock that starts at the current system time.
+    pub fn new() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StorageClock for MonotonicClock {
+    fn now(&self) -> SystemTime {
+        self.wall + self.start.elapsed()
+    }
+}
+
+/// A clock that only changes when told to, for tests.
+#[derive(Debug)]
+pub struct ManualClock(Mutex<SystemTime>);
+
+impl ManualClock {
+    /// Creates a clock that reads `time` until it is changed.
+    pub fn new(time: SystemTime) -> Self {
+        Self(Mutex::new(time))
+    }
+
+    /// Sets the clock to `time`, which may be earlier than its current time.
+    pub fn set(&self, time: SystemTime) {
+        *self.0.lock().unwrap() = time;
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl StorageClock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,105 @@
+//! Completing several files together.
+//!
+//! A checkpoint often consists of several files.  Completing their writers
+//! one at a time with [FileWriter::complete] means that a crash between two
+//! of the calls leaves some of the files complete and others not, after
+//! each of them had already spent time syncing its data.  A [CommitGroup]
+//! narrows that window: it first writes out and syncs the data for every
+//! file, and only once all of that has succeeded does it give the files
+//! their final names, one right after another.  If writing any of the files
+//! fails, none of them get their final names, and all of them are deleted.
+//!
+//! This is not fully atomic.  A crash while the files are being renamed can
+//! still leave some of them under their final names and others under their
+//! temporary names, which the POSIX backend's `recover` deletes on restart.
+//! Backends that sync directories, such as the POSIX backend, do so once for
+//! each directory after all of the renames, so that until the group
+//! completes, any subset of the renames might survive a crash, and after it
+//! completes, all of them do.  A caller that needs all-or-nothing behavior
+//! should therefore record the group as committed, e.g. by writing a
+//! checkpoint's metadata, only after [CommitGroup::complete_all] returns, and
+//! treat any files without such a record as garbage.
+
+use std::sync::Arc;
+
+use tracing::warn;
+
+use crate::error::StorageError;
+use crate::{FileReader, FileWriter, StorageBackend, StoragePath};
+
+/// A group of files to be completed together.
+///
+/// Add writers to the group with [add](Self::add), then complete all of them
+/// with [complete_all](Self::complete_all).  Dropping the group without
+/// completing it drops the writers, which deletes their files.
+pub struct CommitGroup<'a> {
+    backend: &'a dyn StorageBackend,
+    writers: Vec<Box<dyn FileWriter>>,
+}
+
+impl<'a> CommitGroup<'a> {
+    /// Returns a new, empty group for files created by `backend`.
+    pub fn new(backend: &'a dyn StorageBackend) -> Self {
+        Self {
+            backend,
+            writers: Vec::new(),
+        }
+    }
+
+    /// Adds `writer` to the group.  It must have been created by this group's
+    /// backend.
+    pub fn add(&mut self, writer: Box<dyn FileWriter>) {
+        self.writers.push(writer);
+    }
+
+    /// Returns the number of writers in the group.
+    pub fn len(&self) -> usize {
+        self.writers.len()
+    }
+
+    /// Returns true if the group has no writers.
+    pub fn is_empty(&self) -> bool {
+        self.writers.is_empty()
+    }
+
+    /// Completes all of the files in the group, returning a reader and a name
+    /// for each one, in the order that they were added.
+    ///
+    /// On failure, none of the files are kept: if preparing any of them
+    /// failed, none of them were renamed, and otherwise the ones that were
+    /// already renamed are deleted along with the rest.
+    pub fn complete_all(self) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        self.backend.complete_group(self.writers)
+    }
+}
+
+/// Completes `writers` by calling [FileWriter::prepare_complete] on all of
+/// them and then, only if all of those succeed, calling
+/// [FileWriter::complete_prepared] on each of them.  If any of the former
+/// fail, aborts all of the writers and returns the error.
+///
+/// This doesn't make the files' names durable, so backends that need to sync
+/// directories for that should do so in their own
+/// [StorageBackend::complete_group] after calling this.
+pub fn complete_in_two_phases(
+    mut writers: Vec<Box<dyn FileWriter>>,
+) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+    if let Err(error) = writers
+        .iter_mut()
+        .try_for_each(|writer| writer.prepare_complete())
+    {
+        for writer in writers {
+            if let Err(abort_error) = writer.abort() {
+                warn!("failed to delete file after aborting commit group: {abort_error}");
+            }
+        }
+        return Err(error);
+    }
+
+    // If renaming one of the files fails, dropping the readers and writers
+    // that we have at that point deletes all of the files.
+    writers
+        .into_iter()
+        .map(|writer| writer.complete_prepared())
+        .collect()
+}
@@ -2,18 +2,43 @@ use feldera_types::config::StorageBackendConfig;
 use object_store::Error as ObjectStoreError;
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
-use std::io::ErrorKind;
+use std::io::{Error as IoError, ErrorKind};
 use std::path::PathBuf;
+use std::sync::Arc;
 use thiserror::Error;
 use uuid::Uuid;
 
+use crate::block::InvalidBlockLocation;
+use crate::StoragePath;
+
 /// An error that can occur when using the storage backend.
 #[derive(Clone, Error, Debug)]
 pub enum StorageError {
-    /// I/O error.
+    /// I/O error that the storage layer detected itself, rather than one
+    /// that an operating system call returned.
     #[error("{0}")]
     StdIo(ErrorKind),
 
+    /// A file or directory does not exist.
+    #[error("{0}")]
+    NotFound(#[source] Arc<IoError>),
+
+    /// The operating system denied access to a file or directory.
+    #[error("{0}")]
+    PermissionDenied(#[source] Arc<IoError>),
+
+    /// A file or directory already exists.
+    #[error("{0}")]
+    AlreadyExists(#[source] Arc<IoError>),
+
+    /// An operation was interrupted and may be retried.
+    #[error("{0}")]
+    Interrupted(#[source] Arc<IoError>),
+
+    /// Any other error from an operating system call.
+    #[error("{0}")]
+    Other(#[source] Arc<IoError>),
+
     /// A process already locked the provided storage directory.
     ///
     /// If this is not expected, please remove the lock file manually, after verifying
@@ -43,6 +68,17 @@ pub enum StorageError {
     #[error("Path is not valid in storage: {}", .0.display())]
     InvalidPath(PathBuf),
 
+    /// Storage is on a filesystem that is mounted read-only.
+    ///
+    /// Kernels commonly remount a filesystem read-only after it encounters
+    /// disk errors.
+    #[error("Storage at {} is on a read-only filesystem; it might have been remounted read-only after disk errors", .0.display())]
+    ReadOnlyFilesystem(PathBuf),
+
+    /// A file in storage is frozen, so it can't be modified or deleted.
+    #[error("Storage file {} is frozen (immutable) and must be unfrozen before it can be modified or deleted", .0.display())]
+    Immutable(PathBuf),
+
     /// Unable to parse URL.
     #[error("Unable to parse URL {0:?}")]
     InvalidURL(String),
@@ -51,15 +87,151 @@ pub enum StorageError {
     #[error("Error accessing object store: {message}")]
     ObjectStore { kind: ErrorKind, message: String },
 
+    /// Listing a directory failed for some of its entries.
+    ///
+    /// The listing callback was invoked for the `succeeded` entries that could
+    /// be read.  `errors` describes the entries that could not, including their
+    /// names if those were available.
+    #[error("Listing was incomplete: {succeeded} entries succeeded but {} failed", .errors.len())]
+    PartialList {
+        succeeded: usize,
+        errors: Vec<(Option<StoragePath>, ErrorKind)>,
+    },
+
+    /// Error writing data read from storage to its destination.
+    #[error("Error writing data read from storage: {0}")]
+    SinkWrite(ErrorKind),
+
+    /// Reading back data just written to storage returned something
+    /// different.
+    #[error("Verifying data written at offset {offset} failed: read back different data")]
+    WriteVerifyFailed { offset: u64 },
+
+    /// A block read from storage doesn't match the checksum recorded when it
+    /// was written.
+    #[error("Block at offset {offset} is corrupt: expected checksum {expected:#010x} but read data with checksum {actual:#010x}")]
+    ChecksumMismatch {
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+
+    /// The configured flush threshold is too small.
+    #[error("Flush threshold of {flush_threshold} bytes is less than the minimum of one page ({minimum} bytes)")]
+    InvalidFlushThreshold {
+        flush_threshold: usize,
+        minimum: usize,
+    },
+
+    /// Writing would take storage usage past the configured quota.
+    #[error("Storage quota exceeded: {used} bytes are in use and the quota is {quota} bytes")]
+    QuotaExceeded { used: u64, quota: u64 },
+
+    /// The filesystem that holds storage ran out of space while writing.
+    ///
+    /// `path` is the file or directory being written, if known.
+    #[error("Storage{} is out of space", .path.as_ref().map(|path| format!(" at {}", path.display())).unwrap_or_default())]
+    OutOfSpace {
+        path: Option<PathBuf>,
+        #[source]
+        source: Arc<IoError>,
+    },
+
+    /// Writing to a file repeatedly made no progress, without the operating
+    /// system reporting an error.
+    #[error("Writing {} made no progress in {attempts} attempts", .path.display())]
+    WriteZero { path: PathBuf, attempts: usize },
+
+    /// Opening or creating a file would exceed the backend's limit on open
+    /// files.
+    #[error("Storage already has {open} files open, the most allowed")]
+    TooManyOpenFiles { open: usize },
+
+    /// Creating a file would eat into the configured reserve of free space.
+    #[error("Storage is low on space: {available} bytes are free and at least {reserve} bytes must stay free")]
+    InsufficientSpace { available: u64, reserve: u64 },
+
+    /// A read of a file that is still being written requested data beyond
+    /// what the writer has flushed so far.
+    #[error("Block ending at offset {end} is beyond the {committed} bytes written so far")]
+    NotYetWritten { end: u64, committed: u64 },
+
+    /// Reading a block came up short because the file ended partway through
+    /// it, with only `available` of the `requested` bytes present.
+    #[error("Read of {requested} bytes found only {available} bytes before the end of the file")]
+    ShortRead { requested: usize, available: usize },
+
+    /// A block to read relative to the end of a file, with
+    /// [BlockLocation::from_end](crate::block::BlockLocation::from_end),
+    /// starts before the beginning of the file.
+    #[error("Block starting {distance} bytes before the end of a {file_size}-byte file starts before its beginning")]
+    BlockBeforeStart { distance: u64, file_size: u64 },
+
+    /// A block's offset or size isn't a multiple of 512, or its size is zero
+    /// or at least 2 GiB.
+    #[error("Block of {size} bytes at offset {offset} is invalid: offset and size must be multiples of 512 and size must be nonzero and less than 2 GiB")]
+    InvalidBlockLocation { offset: u64, size: usize },
+
+    /// A read requested a block bigger than the backend's maximum block size.
+    #[error("Block of {requested} bytes exceeds the maximum block size of {max} bytes")]
+    BlockTooLarge { requested: usize, max: usize },
+
+    /// Reading one of a batch of blocks failed.
+    #[error("Reading block at offset {offset} failed: {kind}")]
+    BlockReadFailed { offset: u64, kind: ErrorKind },
+
+    /// A file was completed without filling in a block reserved with
+    /// [FileWriter::reserve_block](crate::FileWriter::reserve_block).
+    #[error("Block reserved at offset {offset} was never filled")]
+    UnfilledReservation { offset: u64 },
+
+    /// A glob pattern passed to
+    /// [StorageBackend::list_matching](crate::StorageBackend::list_matching)
+    /// could not be compiled.
+    #[error("Invalid pattern {pattern:?}: {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+
+    /// A block read from encrypted storage failed authentication, because it
+    /// was modified or the wrong key was used to decrypt it.
+    #[error("Decrypting data at offset {offset} failed: it was tampered with or the key is wrong")]
+    DecryptionFailed { offset: u64 },
+
+    /// No storage backend handles the scheme of a storage URI.
+    #[error("No storage backend handles URIs with scheme {0:?}")]
+    UnsupportedUriScheme(String),
+
     /// The requested storage backend is not available.
     #[error("The requested storage backend ({0:?}) is not available in the open-source version of feldera"
     )]
     BackendNotSupported(StorageBackendConfig),
 }
 
-impl From<std::io::Error> for StorageError {
-    fn from(value: std::io::Error) -> Self {
-        Self::StdIo(value.kind())
+/// Classifies `value` by its [ErrorKind], keeping it as the new error's
+/// [source](std::error::Error::source).
+impl From<IoError> for StorageError {
+    fn from(value: IoError) -> Self {
+        let kind = value.kind();
+        let error = Arc::new(value);
+        match kind {
+            ErrorKind::NotFound => Self::NotFound(error),
+            ErrorKind::PermissionDenied => Self::PermissionDenied(error),
+            ErrorKind::AlreadyExists => Self::AlreadyExists(error),
+            ErrorKind::Interrupted => Self::Interrupted(error),
+            ErrorKind::StorageFull => Self::OutOfSpace {
+                path: None,
+                source: error,
+            },
+            _ => Self::Other(error),
+        }
+    }
+}
+
+impl From<InvalidBlockLocation> for StorageError {
+    fn from(value: InvalidBlockLocation) -> Self {
+        Self::InvalidBlockLocation {
+            offset: value.offset,
+            size: value.size,
+        }
     }
 }
 
@@ -101,6 +273,16 @@ impl Serialize for StorageError {
                 ser.serialize_field("kind", &error.to_string())?;
                 ser.end()
             }
+            Self::NotFound(error)
+            | Self::PermissionDenied(error)
+            | Self::AlreadyExists(error)
+            | Self::Interrupted(error)
+            | Self::Other(error) => {
+                let mut ser = serializer.serialize_struct("IOError", 2)?;
+                ser.serialize_field("kind", &error.kind().to_string())?;
+                ser.serialize_field("message", &error.to_string())?;
+                ser.end()
+            }
             error => error.serialize(serializer),
         }
     }
@@ -110,6 +292,11 @@ impl StorageError {
     pub fn kind(&self) -> ErrorKind {
         match self {
             StorageError::StdIo(kind) => *kind,
+            StorageError::NotFound(_) => ErrorKind::NotFound,
+            StorageError::PermissionDenied(_) => ErrorKind::PermissionDenied,
+            StorageError::AlreadyExists(_) => ErrorKind::AlreadyExists,
+            StorageError::Interrupted(_) => ErrorKind::Interrupted,
+            StorageError::Other(error) => error.kind(),
             StorageError::StorageLocked(..) => ErrorKind::ResourceBusy,
             StorageError::NoPersistentId(_) => ErrorKind::Other,
             StorageError::CheckpointNotFound(_) => ErrorKind::NotFound,
@@ -117,8 +304,32 @@ impl StorageError {
             StorageError::BloomFilter => ErrorKind::Other,
             StorageError::InvalidPath(_) => ErrorKind::Other,
             StorageError::InvalidURL(_) => ErrorKind::Other,
+            StorageError::ReadOnlyFilesystem(_) => ErrorKind::Other,
+            StorageError::Immutable(_) => ErrorKind::PermissionDenied,
             StorageError::ObjectStore { kind, .. } => *kind,
+            StorageError::UnsupportedUriScheme(_) => ErrorKind::Unsupported,
             StorageError::BackendNotSupported(_) => ErrorKind::Other,
+            StorageError::SinkWrite(kind) => *kind,
+            StorageError::WriteVerifyFailed { .. } => ErrorKind::InvalidData,
+            StorageError::ChecksumMismatch { .. } => ErrorKind::InvalidData,
+            StorageError::InvalidFlushThreshold { .. } => ErrorKind::InvalidInput,
+            StorageError::QuotaExceeded { .. } => ErrorKind::StorageFull,
+            StorageError::OutOfSpace { .. } => ErrorKind::StorageFull,
+            StorageError::TooManyOpenFiles { .. } => ErrorKind::ResourceBusy,
+            StorageError::WriteZero { .. } => ErrorKind::WriteZero,
+            StorageError::InsufficientSpace { .. } => ErrorKind::StorageFull,
+            StorageError::BlockTooLarge { .. } => ErrorKind::InvalidInput,
+            StorageError::BlockBeforeStart { .. } => ErrorKind::InvalidInput,
+            StorageError::InvalidBlockLocation { .. } => ErrorKind::InvalidInput,
+            StorageError::ShortRead { .. } => ErrorKind::UnexpectedEof,
+            StorageError::NotYetWritten { .. } => ErrorKind::WouldBlock,
+            StorageError::BlockReadFailed { kind, .. } => *kind,
+            StorageError::UnfilledReservation { .. } => ErrorKind::InvalidInput,
+            StorageError::InvalidPattern { .. } => ErrorKind::InvalidInput,
+            StorageError::DecryptionFailed { .. } => ErrorKind::InvalidData,
+            StorageError::PartialList { errors, .. } => errors
+                .first()
+                .map_or(ErrorKind::Other, |(_name, kind)| *kind),
         }
     }
 
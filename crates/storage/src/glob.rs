@@ -0,0 +1,156 @@
+//! Simple glob patterns for matching file names in
+//! [StorageBackend::list_matching](crate::StorageBackend::list_matching).
+
+use crate::error::StorageError;
+
+/// One element of a compiled [Glob].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    /// Matches exactly this character.
+    Literal(char),
+
+    /// `?`: matches any single character.
+    Any,
+
+    /// `*`: matches any sequence of characters, including none.
+    Star,
+
+    /// `[...]`: matches any single character in one of the inclusive
+    /// `ranges`, or any character not in them if `negated`.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Literal(literal) => *literal == c,
+            Token::Any | Token::Star => true,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+/// A compiled glob pattern that matches a single file name (not a path).
+///
+/// The pattern syntax is:
+///
+/// * `*` matches any sequence of characters, including none.
+///
+/// * `?` matches any single character.
+///
+/// * `[...]` matches any one of the characters inside the brackets, which may
+///   include ranges such as `a-z`.  `[!...]` or `[^...]` matches any character
+///   not inside the brackets.  A `]` immediately after the opening bracket (or
+///   the negation) is taken literally.
+///
+/// * `\` makes the following character literal.
+///
+/// * Every other character matches itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    /// Compiles `pattern`, returning [StorageError::InvalidPattern] if it has
+    /// an unterminated `[...]` class or a trailing `\`.
+    pub fn new(pattern: &str) -> Result<Self, StorageError> {
+        let invalid = |reason: &str| StorageError::InvalidPattern {
+            pattern: pattern.into(),
+            reason: reason.into(),
+        };
+
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' => {
+                    // Consecutive stars are equivalent to one.
+                    if tokens.last() == Some(&Token::Star) {
+                        continue;
+                    }
+                    Token::Star
+                }
+                '?' => Token::Any,
+                '\\' => Token::Literal(chars.next().ok_or_else(|| invalid("trailing `\\`"))?),
+                '[' => {
+                    let negated = chars.next_if(|&c| c == '!' || c == '^').is_some();
+                    let mut ranges = Vec::new();
+                    let mut first = true;
+                    loop {
+                        let lo = match chars.next() {
+                            None => return Err(invalid("unterminated `[`")),
+                            Some(']') if !first => break,
+                            Some('\\') => chars.next().ok_or_else(|| invalid("trailing `\\`"))?,
+                            Some(c) => c,
+                        };
+                        first = false;
+                        let hi = if chars.next_if_eq(&'-').is_some() {
+                            match chars.next() {
+                                None => return Err(invalid("unterminated `[`")),
+                                Some(']') => {
+                                    // A trailing `-` is literal, as in `[a-]`.
+                                    ranges.push((lo, lo));
+                                    ranges.push(('-', '-'));
+                                    break;
+                                }
+                                Some('\\') => {
+                                    chars.next().ok_or_else(|| invalid("trailing `\\`"))?
+                                }
+                                Some(c) => c,
+                            }
+                        } else {
+                            lo
+                        };
+                        if hi < lo {
+                            return Err(invalid("character range is out of order"));
+                        }
+                        ranges.push((lo, hi));
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Literal(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Self { tokens })
+    }
+
+    /// Returns true if `name` matches this pattern in its entirety.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.chars().collect::<Vec<_>>();
+
+        // Iterative matching with backtracking to the most recent `*`, which
+        // takes time proportional to `tokens.len() * name.len()` at worst.
+        let mut t = 0;
+        let mut n = 0;
+        let mut backtrack = None;
+        while n < name.len() {
+            match self.tokens.get(t) {
+                Some(Token::Star) => {
+                    backtrack = Some((t, n));
+                    t += 1;
+                }
+                Some(token) if token.matches(name[n]) => {
+                    t += 1;
+                    n += 1;
+                }
+                _ => match backtrack {
+                    Some((star_t, star_n)) => {
+                        // Let the `*` absorb one more character and retry.
+                        backtrack = Some((star_t, star_n + 1));
+                        t = star_t + 1;
+                        n = star_n + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.tokens[t..].iter().all(|token| *token == Token::Star)
+    }
+}
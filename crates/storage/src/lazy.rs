@@ -0,0 +1,118 @@
+//! Random access to a file that reads only the blocks that are touched.
+
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::block::BlockLocation;
+use crate::error::StorageError;
+use crate::fbuf::FBuf;
+use crate::FileReader;
+
+/// Byte-addressable access to a [FileReader] that reads fixed-size blocks on
+/// demand, keeping the most recently used ones in a small cache.
+///
+/// This suits consumers that touch only a few scattered parts of a large
+/// file, since they only pay for the blocks that they access.
+pub struct LazyFile {
+    reader: Arc<dyn FileReader>,
+    block_size: usize,
+    size: u64,
+
+    /// Cached blocks, indexed by block number, most recently used last.
+    cache: Mutex<VecDeque<(u64, Arc<FBuf>)>>,
+    capacity: usize,
+
+    /// Number of blocks read from `reader` so far.
+    materialized: AtomicU64,
+}
+
+impl LazyFile {
+    /// Creates a new [LazyFile] that reads `reader` in blocks of `block_size`
+    /// bytes, which must be a positive multiple of 512, and caches up to
+    /// `capacity` of them.
+    pub fn new(
+        reader: Arc<dyn FileReader>,
+        block_size: usize,
+        capacity: usize,
+    ) -> Result<Self, StorageError> {
+        assert!(
+            block_size > 0 && block_size % 512 == 0,
+            "block size {block_size} is not a positive multiple of 512"
+        );
+        Ok(Self {
+            size: reader.get_size()?,
+            reader,
+            block_size,
+            cache: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            materialized: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the file's size in bytes.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns true if the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns the number of blocks read from the underlying file so far,
+    /// including blocks that were read again after being evicted.
+    pub fn materialized(&self) -> u64 {
+        self.materialized.load(Ordering::Relaxed)
+    }
+
+    /// Returns the byte at `offset`.
+    pub fn byte(&self, offset: u64) -> Result<u8, StorageError> {
+        let mut byte = [0];
+        self.read_exact_at(offset, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Fills `buf` from the file starting at `offset`.  Fails with
+    /// [ErrorKind::UnexpectedEof] if that extends past the end of the file.
+    pub fn read_exact_at(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), StorageError> {
+        let end = offset.checked_add(buf.len() as u64);
+        if !end.is_some_and(|end| end <= self.size) {
+            return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+        }
+        while !buf.is_empty() {
+            let block_size = self.block_size as u64;
+            let block = self.block(offset / block_size)?;
+            let start = (offset % block_size) as usize;
+            let n = buf.len().min(block.len() - start);
+            buf[..n].copy_from_slice(&block[start..start + n]);
+            buf = &mut buf[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns block number `index`, reading it if it isn't cached.
+    fn block(&self, index: u64) -> Result<Arc<FBuf>, StorageError> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(position) = cache.iter().position(|(i, _block)| *i == index) {
+            let entry = cache.remove(position).unwrap();
+            let block = entry.1.clone();
+            cache.push_back(entry);
+            return Ok(block);
+        }
+
+        let offset = index * self.block_size as u64;
+        let block = self.reader.read_block(BlockLocation {
+            offset,
+            size: (self.size - offset).min(self.block_size as u64) as usize,
+        })?;
+        self.materialized.fetch_add(1, Ordering::Relaxed);
+        if cache.len() >= self.capacity {
+            cache.pop_front();
+        }
+        cache.push_back((index, block.clone()));
+        Ok(block)
+    }
+}
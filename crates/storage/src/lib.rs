@@ -1,30 +1,50 @@
 //! Common Types and Trait Definition for Storage in Feldera.
 
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicI64;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use feldera_types::config::{StorageBackendConfig, StorageConfig, StorageOptions};
+use serde::Serialize;
 use tracing::warn;
 use uuid::Uuid;
 
-use crate::block::BlockLocation;
+use crate::block::{BeforeStart, BlockHandle, BlockLocation, BlockRef, BlockSlice, Blocks};
+use crate::commit::complete_in_two_phases;
 use crate::error::StorageError;
 use crate::fbuf::FBuf;
 use crate::file::HasFileId;
+use crate::glob::Glob;
+use crate::snapshot::StorageSnapshot;
 
 pub use object_store::path::{Path as StoragePath, PathPart as StoragePathPart};
 
+pub mod asynchronous;
 pub mod block;
+pub mod clock;
+pub mod commit;
 pub mod error;
 pub mod fbuf;
 pub mod file;
+pub mod glob;
+pub mod lazy;
+pub mod rotating;
+pub mod snapshot;
 pub mod tokio;
 
 /// Extension for batch files used by the engine.
 const CREATE_FILE_EXTENSION: &str = ".feldera";
 
+/// Extension that backends add to files that are still being written.
+const MUTABLE_EXTENSION: &str = ".mut";
+
+/// Default maximum gap, in bytes, between blocks that `read_batch` on a
+/// [FileReader] reads in a single operation.  See [FileReader::read_blocks].
+pub const DEFAULT_COALESCE_GAP: usize = 64 * 1024;
+
 /// Helper function that appends to a [`PathBuf`].
 pub fn append_to_path(p: PathBuf, s: &str) -> PathBuf {
     let mut p = p.into_os_string();
@@ -39,10 +59,48 @@ pub trait StorageBackendFactory: Sync {
         storage_config: &StorageConfig,
         backend_config: &StorageBackendConfig,
     ) -> Result<Arc<dyn StorageBackend>, StorageError>;
+
+    /// Returns the URI schemes, such as `file`, for which
+    /// [create_from_uri](Self::create_from_uri) creates a backend.  The
+    /// default is none.
+    fn uri_schemes(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Creates a backend for the URI `<scheme>://<location>`, where `scheme`
+    /// is one of [uri_schemes](Self::uri_schemes).  The backend has the
+    /// default configuration apart from what `location` specifies.
+    fn create_from_uri(
+        &self,
+        scheme: &str,
+        location: &str,
+    ) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let _ = location;
+        Err(StorageError::UnsupportedUriScheme(scheme.into()))
+    }
 }
 
 inventory::collect!(&'static dyn StorageBackendFactory);
 
+impl dyn StorageBackendFactory {
+    /// Creates and returns a new backend for `uri`, such as `file:///data` or
+    /// `s3://bucket/prefix`, using the registered factory whose
+    /// [uri_schemes](StorageBackendFactory::uri_schemes) include the URI's
+    /// scheme.  Schemes are case-insensitive.
+    pub fn from_uri(uri: &str) -> Result<Arc<dyn StorageBackend>, StorageError> {
+        let Some((scheme, location)) = uri.split_once("://") else {
+            return Err(StorageError::InvalidURL(uri.into()));
+        };
+        let scheme = scheme.to_ascii_lowercase();
+        for factory in inventory::iter::<&dyn StorageBackendFactory> {
+            if factory.uri_schemes().contains(&scheme.as_str()) {
+                return factory.create_from_uri(&scheme, location);
+            }
+        }
+        Err(StorageError::UnsupportedUriScheme(scheme))
+    }
+}
+
 /// A storage backend.
 pub trait StorageBackend: Send + Sync {
     /// Create a new file with the given `name`, automatically creating any
@@ -69,6 +127,50 @@ pub trait StorageBackend: Send + Sync {
     /// Opens `name` for reading.
     fn open(&self, name: &StoragePath) -> Result<Arc<dyn FileReader>, StorageError>;
 
+    /// Opens the existing, completed file `name` to append blocks to it.
+    /// Blocks written to the returned writer go after the file's current
+    /// contents.  Completing the writer syncs the file but doesn't rename
+    /// it, because it already has its final name.  Fails with
+    /// [ErrorKind::NotFound] if `name` doesn't exist.
+    ///
+    /// The default implementation fails with [ErrorKind::Unsupported].
+    fn open_append(&self, name: &StoragePath) -> Result<Box<dyn FileWriter>, StorageError> {
+        let _ = name;
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Opens each of `names` for reading, returning their results in the same
+    /// order.  A failure to open one file doesn't prevent opening the others.
+    ///
+    /// The default implementation spreads the opens across a few threads, so
+    /// that opening many files, e.g. all of the files in a checkpoint,
+    /// overlaps their latency.
+    fn open_many(&self, names: &[StoragePath]) -> Vec<Result<Arc<dyn FileReader>, StorageError>> {
+        /// Maximum number of threads for opening files.
+        const MAX_THREADS: usize = 8;
+
+        /// Minimum number of files before it's worth starting a thread.
+        const FILES_PER_THREAD: usize = 16;
+
+        let n_threads = names.len().div_ceil(FILES_PER_THREAD).min(MAX_THREADS);
+        if n_threads <= 1 {
+            return names.iter().map(|name| self.open(name)).collect();
+        }
+        let chunk_size = names.len().div_ceil(n_threads);
+        std::thread::scope(|scope| {
+            let threads = names
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(|| chunk.iter().map(|name| self.open(name)).collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect()
+        })
+    }
+
     /// Calls `cb` with the name of each of the files under `parent`. This is a
     /// non-recursive list: it does not include files under sub-directories of
     /// `parent`.
@@ -78,10 +180,140 @@ pub trait StorageBackend: Send + Sync {
         cb: &mut dyn FnMut(&StoragePath, StorageFileType),
     ) -> Result<(), StorageError>;
 
+    /// Returns one page of the files and directories under `parent`, like
+    /// [list](Self::list), in lexical order of their names.  The page holds
+    /// at most `limit` entries, which must be positive, starting just after
+    /// `after` if it is given and otherwise from the beginning.
+    ///
+    /// Also returns a continuation token, which is the name of the last entry
+    /// in the page if there might be more entries after it, and `None` if the
+    /// listing is finished.  Passing the token as `after` returns the next
+    /// page.  Entries created or deleted between calls may or may not be
+    /// listed.
+    ///
+    /// The default implementation lists all of `parent` to return each page.
+    #[allow(clippy::type_complexity)]
+    fn list_paginated(
+        &self,
+        parent: &StoragePath,
+        after: Option<StoragePath>,
+        limit: usize,
+    ) -> Result<(Vec<(StoragePath, StorageFileType)>, Option<StoragePath>), StorageError> {
+        if limit == 0 {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        let mut entries = Vec::new();
+        self.list(parent, &mut |path, file_type| {
+            if after.as_ref().is_none_or(|after| path > after) {
+                entries.push((path.clone(), file_type))
+            }
+        })?;
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(paginate(entries, limit))
+    }
+
+    /// Calls `cb` with the name of each of the files and directories under
+    /// `parent`, descending into subdirectories depth-first.  Each directory
+    /// is reported before its contents.
+    ///
+    /// With `max_depth` of `Some(0)`, this reports only the immediate
+    /// children of `parent`, like [list](Self::list); each increment lets it
+    /// descend one level further.  With `None`, there is no limit.  Entries
+    /// reported as [StorageFileType::Other], such as symbolic links to
+    /// directories, are never descended into, which avoids cycles.
+    fn list_recursive(
+        &self,
+        parent: &StoragePath,
+        max_depth: Option<usize>,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        let mut entries = Vec::new();
+        self.list(parent, &mut |path, file_type| {
+            entries.push((path.clone(), file_type))
+        })?;
+        for (path, file_type) in entries {
+            cb(&path, file_type);
+            if file_type == StorageFileType::Directory && max_depth != Some(0) {
+                self.list_recursive(&path, max_depth.map(|depth| depth - 1), cb)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `cb` with the name of each of the files and directories under
+    /// `parent` whose final name component matches the glob `pattern` (see
+    /// [Glob](crate::glob::Glob) for the syntax).  Like [list](Self::list),
+    /// this is not recursive.
+    ///
+    /// Returns [StorageError::InvalidPattern] if `pattern` does not compile.
+    fn list_matching(
+        &self,
+        parent: &StoragePath,
+        pattern: &str,
+        cb: &mut dyn FnMut(&StoragePath, StorageFileType),
+    ) -> Result<(), StorageError> {
+        let glob = Glob::new(pattern)?;
+        self.list(parent, &mut |path, file_type| {
+            if path.filename().is_some_and(|name| glob.matches(name)) {
+                cb(path, file_type)
+            }
+        })
+    }
+
+    /// Completes all of `writers`, which must have been created by this
+    /// backend, as a group, returning a reader and a name for each one in
+    /// the same order.  See [CommitGroup](crate::commit::CommitGroup) for
+    /// details.
+    ///
+    /// The default implementation calls [complete_in_two_phases], which is
+    /// enough for backends whose [FileWriter::complete_prepared] makes names
+    /// durable by itself.  A decorator whose writers forward both phases to
+    /// the wrapped backend's writers must instead forward this to the wrapped
+    /// backend, which knows what it has to sync.
+    fn complete_group(
+        &self,
+        writers: Vec<Box<dyn FileWriter>>,
+    ) -> Result<Vec<(Arc<dyn FileReader>, StoragePath)>, StorageError> {
+        complete_in_two_phases(writers)
+    }
+
     fn delete(&self, name: &StoragePath) -> Result<(), StorageError>;
 
     fn delete_recursive(&self, name: &StoragePath) -> Result<(), StorageError>;
 
+    /// Moves the file or directory tree `from` to `to`, creating any parent
+    /// directories within `to` that don't already exist.
+    ///
+    /// Backends do this atomically where they can.  The default implementation
+    /// doesn't support renaming.
+    fn rename_subtree(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        let _ = (from, to);
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Moves file `from` to `to`, replacing any file already named `to` and
+    /// creating any parent directories within `to` that don't already exist.
+    /// Replacing a file releases its space from usage.
+    ///
+    /// The default implementation uses [rename_subtree](Self::rename_subtree).
+    fn rename(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        self.rename_subtree(from, to)
+    }
+
+    /// Copies file `from` to a new file `to`, creating any parent directories
+    /// within `to` that don't already exist, and adds the copy to usage.
+    /// Fails with [ErrorKind::AlreadyExists] if `to` already exists.
+    ///
+    /// The default implementation reads all of `from` into memory and then
+    /// writes it to `to`.
+    fn copy(&self, from: &StoragePath, to: &StoragePath) -> Result<(), StorageError> {
+        if self.exists(to)? {
+            return Err(StorageError::StdIo(ErrorKind::AlreadyExists));
+        }
+        let content = self.read(from)?;
+        self.write(to, Arc::unwrap_or_clone(content))
+    }
+
     fn delete_if_exists(&self, name: &StoragePath) -> Result<(), StorageError> {
         match self.delete(name) {
             Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
@@ -136,6 +368,53 @@ pub trait StorageBackend: Send + Sync {
     /// The value is signed because the problems above can cause it to become
     /// negative.
     fn usage(&self) -> Arc<AtomicI64>;
+
+    /// Returns the number of bytes of free space available for this
+    /// backend's files, as the underlying storage reports it.  Unlike
+    /// [usage](Self::usage), this reflects files outside the backend, too.
+    ///
+    /// The default implementation returns an error of kind
+    /// [ErrorKind::Unsupported], for backends without a meaningful limit.
+    fn available_space(&self) -> Result<u64, StorageError> {
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Returns the block size, in bytes, that this backend handles most
+    /// efficiently.  Higher layers should make their block sizes a multiple of
+    /// this value where they can.  This is always a multiple of
+    /// [min_block_size](Self::min_block_size).
+    fn preferred_block_size(&self) -> usize {
+        4096
+    }
+
+    /// Returns the smallest block size, in bytes, that this backend accepts.
+    /// This is always a multiple of 512, the granularity of [BlockLocation].
+    fn min_block_size(&self) -> usize {
+        512
+    }
+
+    /// Returns what this backend supports, so that higher layers can adapt
+    /// to it instead of assuming.  The default implementation reports a
+    /// local backend with none of the optional capabilities.
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::default()
+    }
+
+    /// Walks the whole backend and returns an inventory of every file and
+    /// directory in it, with sizes and checksums, for debugging.
+    fn snapshot(&self) -> Result<StorageSnapshot, StorageError> {
+        self.snapshot_with_parallelism(1)
+    }
+
+    /// Like [snapshot](Self::snapshot), but reads up to `parallelism` files
+    /// concurrently to make better use of disk bandwidth and CPU.  Entries are
+    /// in the same order regardless of `parallelism`.
+    fn snapshot_with_parallelism(
+        &self,
+        parallelism: usize,
+    ) -> Result<StorageSnapshot, StorageError> {
+        snapshot::snapshot(self, parallelism)
+    }
 }
 
 impl dyn StorageBackend {
@@ -184,11 +463,156 @@ pub trait FileWriter: Send + Sync + HasFileId {
     /// Returns the data that was written encapsulated in an `Arc`.
     fn write_block(&mut self, data: FBuf) -> Result<Arc<FBuf>, StorageError>;
 
+    /// Appends `data`, which may be any length, to the file.  This suits
+    /// callers that produce a stream of bytes rather than blocks: the writer
+    /// accumulates the data into blocks of its own, instead of the caller
+    /// allocating a buffer for each piece.  Calls may be interleaved with
+    /// [write_block](Self::write_block), and the data ends up in the file in
+    /// the order written.
+    ///
+    /// Blocks in the file are read at multiples of 512 bytes, so a caller
+    /// that goes on to call [write_block](Self::write_block) should first
+    /// make the data passed to this add up to a multiple of 512 bytes.
+    ///
+    /// The default implementation doesn't support streaming writes.
+    fn write_all(&mut self, data: &[u8]) -> Result<(), StorageError> {
+        let _ = data;
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Returns buffers passed to [write_block](Self::write_block) that have
+    /// already been written to storage, cleared, for the caller to reuse.
+    ///
+    /// This only yields buffers whose `Arc` reference count has dropped to
+    /// one, that is, after the caller has dropped all the `Arc`s returned by
+    /// [write_block](Self::write_block) for them.  If any of them are still
+    /// shared, it returns nothing, and a later call might succeed.  The
+    /// default implementation never returns any buffers.
+    fn recycle(&mut self) -> Vec<FBuf> {
+        Vec::new()
+    }
+
+    /// Reserves the next `size` bytes of the file, which must be a multiple of
+    /// 512, as a block whose contents will be supplied later with
+    /// [fill_reserved](Self::fill_reserved).  This allows writing a file
+    /// whose early blocks refer to data that comes later, in a single pass.
+    ///
+    /// The default implementation doesn't support reservations.
+    fn reserve_block(&mut self, size: usize) -> Result<BlockHandle, StorageError> {
+        let _ = size;
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Writes `data` into the block reserved as `handle`.  `data` must be
+    /// exactly the size of the reserved block.
+    ///
+    /// [complete](Self::complete) fails if any reserved block hasn't been
+    /// filled.
+    fn fill_reserved(&mut self, handle: BlockHandle, data: FBuf) -> Result<(), StorageError> {
+        let _ = (handle, data);
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Writes `data` at `offset` in the file, overwriting whatever was written
+    /// there before, for example to fill in a header at offset 0 after
+    /// writing the data it describes.  Writing past the end of the data
+    /// written so far extends the file, so that the next block written starts
+    /// after `data`, and leaves zeros in any gap.  `data` must not overlap a
+    /// block reserved with [reserve_block](Self::reserve_block) that hasn't
+    /// been filled.  For a writer from [StorageBackend::open_append], `offset`
+    /// must not be before the end of the file as it was when it was opened.
+    ///
+    /// This writes out everything buffered so far before writing `data`, so
+    /// it defeats the batching of writes that backends otherwise do.  Use it
+    /// sparingly, and prefer [reserve_block](Self::reserve_block) when the
+    /// size of the block to fill in later is known.
+    ///
+    /// The default implementation doesn't support writing at an offset.
+    fn write_block_at(&mut self, offset: u64, data: FBuf) -> Result<(), StorageError> {
+        let _ = (offset, data);
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Advises the writer that the file will be about `size` bytes long, so
+    /// that the backend can allocate space for it up front instead of
+    /// extending the file piecemeal.  Writing fewer bytes than `size` is
+    /// fine, and so is writing more.
+    ///
+    /// Preallocated space doesn't count toward storage usage.  The default
+    /// implementation does nothing.
+    fn preallocate(&mut self, size: u64) -> Result<(), StorageError> {
+        let _ = size;
+        Ok(())
+    }
+
+    /// Discards everything written to the file after its first `len` bytes,
+    /// so that the next block written starts at offset `len`.  This lets a
+    /// caller that wrote a block speculatively roll back to an earlier
+    /// offset.  `len` must not be more than the number of bytes written so
+    /// far, and it must not fall inside a block reserved with
+    /// [reserve_block](Self::reserve_block) that hasn't been filled;
+    /// reservations after `len` are discarded.  For a writer from
+    /// [StorageBackend::open_append], `len` must also not be less than the
+    /// file's length when it was opened.
+    ///
+    /// The default implementation doesn't support truncation.
+    fn truncate_to(&mut self, len: u64) -> Result<(), StorageError> {
+        let _ = len;
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Writes out everything written so far and makes it durable, leaving the
+    /// writer open for more writes, so that a caller can reach a
+    /// crash-consistent intermediate state without completing the file.
+    /// Syncing again without writing anything more is harmless.
+    ///
+    /// The default implementation doesn't support syncing.
+    fn sync(&mut self) -> Result<(), StorageError> {
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
     /// Completes writing of a file and returns a reader for the file and the
     /// file's path. The file is treated as temporary and will be deleted if the
     /// reader is dropped without first calling
     /// [FileReader::mark_for_checkpoint].
     fn complete(self: Box<Self>) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError>;
+
+    /// Writes out all of the file's data and makes it durable, without giving
+    /// the file its final name.  This is the first phase of completing a
+    /// file as part of a [CommitGroup](crate::commit::CommitGroup).  Writing
+    /// to the file afterward fails.
+    ///
+    /// The default implementation does nothing, leaving all of the work to
+    /// [complete_prepared](Self::complete_prepared).
+    fn prepare_complete(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Completes a file like [complete](Self::complete), after preparing it
+    /// with [prepare_complete](Self::prepare_complete) if that hasn't already
+    /// been done.  Unlike [complete](Self::complete), this need not make the
+    /// file's final name durable: that is up to the caller, which is
+    /// [StorageBackend::complete_group].
+    ///
+    /// The default implementation calls [complete](Self::complete).
+    fn complete_prepared(
+        self: Box<Self>,
+    ) -> Result<(Arc<dyn FileReader>, StoragePath), StorageError> {
+        self.complete()
+    }
+
+    /// Abandons writing the file and deletes it.
+    ///
+    /// Dropping a [FileWriter] without completing it also deletes the file,
+    /// but it can only log a failure to do so. This reports the failure to the
+    /// caller instead, and it makes intentional abandonment explicit.
+    ///
+    /// The default implementation just drops the writer, relying on it to
+    /// delete the file, and so it always succeeds.
+    fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        drop(self);
+        Ok(())
+    }
 }
 
 /// A readable file.
@@ -201,21 +625,367 @@ pub trait FileReader: Send + Sync + HasFileId {
     /// deleted on drop.
     fn mark_for_checkpoint(&self);
 
+    /// Returns whether the file will stay in storage when this reader is
+    /// dropped.  This is false for a reader obtained via
+    /// [FileWriter::complete] until [FileReader::mark_for_checkpoint] is
+    /// called, and true for readers obtained via [StorageBackend::open].
+    ///
+    /// The default implementation, for backends that never delete files on
+    /// drop, returns true.
+    fn is_kept(&self) -> bool {
+        true
+    }
+
+    /// Returns whether the file will be deleted when this reader is dropped.
+    /// This is the inverse of [FileReader::is_kept].
+    fn will_delete_on_drop(&self) -> bool {
+        !self.is_kept()
+    }
+
     /// Reads data at `location` from the file.  If successful, the result will
     /// be exactly the requested length; that is, this API treats read past EOF
     /// as an error.
     fn read_block(&self, location: BlockLocation) -> Result<Arc<FBuf>, StorageError>;
 
+    /// Reads the block at `location`, like [read_block](Self::read_block),
+    /// but borrows it from the reader instead of copying it into a buffer if
+    /// the reader already holds the bytes contiguously, for example in a
+    /// memory mapping.  The block can't outlive the reader.
+    ///
+    /// The default implementation returns [BlockRef::Owned] from
+    /// [read_block](Self::read_block).
+    fn read_block_ref(&self, location: BlockLocation) -> Result<BlockRef<'_>, StorageError> {
+        self.read_block(location).map(BlockRef::Owned)
+    }
+
+    /// Reads the block at `location`, like [read_block](Self::read_block),
+    /// and passes its bytes to `f`, for a caller that only needs to look at
+    /// them once, e.g. to decompress them.  A reader that has to transform
+    /// the bytes that it reads, e.g. by decrypting them, can then transform
+    /// them into scratch space that it reuses, instead of into a newly
+    /// allocated buffer.  Returns the error from reading or from `f`.
+    ///
+    /// The default implementation passes `f` the block from
+    /// [read_block](Self::read_block).
+    fn read_block_with(
+        &self,
+        location: BlockLocation,
+        f: &mut dyn FnMut(&[u8]) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        f(&self.read_block(location)?)
+    }
+
+    /// Reads the block at `location`, like [FileReader::read_block], and
+    /// returns the bytes in `sub`, relative to the start of the block, without
+    /// copying them.  Fails with [ErrorKind::InvalidInput], without reading
+    /// anything, if `sub` isn't within the block.
+    ///
+    /// For a backend that caches blocks, reading several small ranges of the
+    /// same block this way reads the block only once.
+    fn read_block_slice(
+        &self,
+        location: BlockLocation,
+        sub: Range<usize>,
+    ) -> Result<BlockSlice, StorageError> {
+        if sub.start > sub.end || sub.end > location.size {
+            return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+        }
+        BlockSlice::new(self.read_block(location)?, sub)
+            .ok_or(StorageError::StdIo(ErrorKind::InvalidInput))
+    }
+
+    /// Reads each of `locations`, returning the results in the same order.
+    ///
+    /// Backends may combine reads of locations that are no more than
+    /// `coalesce_gap` bytes apart into a single operation, reading and
+    /// discarding the bytes between them.  This trades some wasted I/O for
+    /// fewer system calls on access patterns that are nearly sequential.  The
+    /// default implementation reads each location separately.
+    fn read_blocks(
+        &self,
+        locations: &[BlockLocation],
+        coalesce_gap: usize,
+    ) -> Vec<Result<Arc<FBuf>, StorageError>> {
+        let _ = coalesce_gap;
+        locations
+            .iter()
+            .map(|location| self.read_block(*location))
+            .collect()
+    }
+
+    /// Reads each of `locations` and returns their concatenation, in the
+    /// order given, as a single buffer.  Locations may overlap and need not
+    /// be in order of offset.
+    ///
+    /// Backends may read locations that are contiguous in the file, in the
+    /// order given, with a single operation.  The default implementation
+    /// reads each location separately and copies it into place.
+    fn read_regions(&self, locations: &[BlockLocation]) -> Result<Arc<FBuf>, StorageError> {
+        let mut buffer = FBuf::with_capacity(locations.iter().map(|location| location.size).sum());
+        for location in locations {
+            buffer.extend_from_slice(&self.read_block(*location)?);
+        }
+        Ok(Arc::new(buffer))
+    }
+
+    /// Reads the `size` bytes that start `distance` bytes before the end of
+    /// the file.  `before_start` says whether to clamp or reject a block that
+    /// would start before the beginning of the file, as described for
+    /// [BlockLocation::from_end].
+    fn read_block_from_end(
+        &self,
+        distance: u64,
+        size: usize,
+        before_start: BeforeStart,
+    ) -> Result<Arc<FBuf>, StorageError> {
+        let location = BlockLocation::from_end(self.get_size()?, distance, size, before_start)?;
+        self.read_block(location)
+    }
+
+    /// Reads data starting at `offset` into `bufs`, filling each buffer in
+    /// turn before moving on to the next one.  Unlike
+    /// [read_block](Self::read_block), `offset` and the buffer sizes need not
+    /// be aligned.
+    ///
+    /// Returns the total number of bytes read, which is less than the total
+    /// size of `bufs` only if the read reached the end of the file.
+    fn read_scattered(&self, offset: u64, bufs: &mut [&mut [u8]]) -> Result<usize, StorageError> {
+        let _ = (offset, bufs);
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Advises the backend that the file won't be read again soon, so that
+    /// it can drop any of the file's data that it caches, such as pages in
+    /// the operating system's page cache.  This is useful for files that
+    /// have just been written for a checkpoint.
+    ///
+    /// This is only a hint.  The default implementation does nothing.
+    fn advise_dontneed(&self) {}
+
+    /// Advises the backend that the file is about to be read sequentially,
+    /// so that it can read ahead more aggressively.  Reading a file with
+    /// `blocks` calls this.
+    ///
+    /// This is only a hint.  The default implementation does nothing.
+    fn advise_sequential(&self) {}
+
+    /// Advises the backend that `locations` will be read soon, so that it
+    /// can start reading them in the background and later reads of them
+    /// finish sooner.  Reading a file with `blocks` calls this for the blocks
+    /// just ahead of the one being read.
+    ///
+    /// This is only a hint.  The default implementation does nothing.
+    fn prefetch(&self, locations: &[BlockLocation]) {
+        let _ = locations;
+    }
+
     /// Returns the file's size in bytes.
     fn get_size(&self) -> Result<u64, StorageError>;
+
+    /// Returns the time at which the file was created.
+    ///
+    /// Backends record this when the file is created, because filesystem
+    /// access and modification times can change later.  A backend that has no
+    /// creation time for a file, e.g. because the file predates the backend
+    /// recording it, fails with [ErrorKind::Unsupported] rather than
+    /// reporting some other time.
+    fn created_at(&self) -> Result<SystemTime, StorageError> {
+        Err(StorageError::StdIo(ErrorKind::Unsupported))
+    }
+
+    /// Returns statistics for reads and writes of this file, including the
+    /// writes through the [FileWriter] that created it, if any.
+    ///
+    /// The default implementation doesn't keep statistics and returns all
+    /// zeros.
+    fn stats(&self) -> FileStats {
+        FileStats::default()
+    }
+}
+
+impl dyn FileReader {
+    /// Returns an iterator that reads the file from beginning to end in blocks
+    /// of `block_size` bytes, which must be a positive multiple of 512.  The
+    /// final block may be shorter.
+    pub fn blocks(&self, block_size: usize) -> Blocks<'_> {
+        Blocks::new(self, block_size)
+    }
+
+    /// Reads each of `locations`, returning the blocks in the same order.
+    /// Blocks up to [DEFAULT_COALESCE_GAP] bytes apart may be read together;
+    /// see [FileReader::read_blocks].
+    ///
+    /// Unlike [FileReader::read_blocks], this fails as a whole if reading any
+    /// of the blocks fails, reporting the first such block's offset in
+    /// [StorageError::BlockReadFailed].
+    pub fn read_batch(&self, locations: &[BlockLocation]) -> Result<Vec<Arc<FBuf>>, StorageError> {
+        locations
+            .iter()
+            .zip(self.read_blocks(locations, DEFAULT_COALESCE_GAP))
+            .map(|(location, result)| {
+                result.map_err(|error| StorageError::BlockReadFailed {
+                    offset: location.offset,
+                    kind: error.kind(),
+                })
+            })
+            .collect()
+    }
+
+    /// Copies the whole file into `dst`, reading it in blocks of `block_size`
+    /// bytes, which must be a positive multiple of 512.  Returns the number of
+    /// bytes copied.
+    ///
+    /// Errors reading the file are reported as usual, whereas errors writing
+    /// to `dst` are reported as [StorageError::SinkWrite].
+    pub fn read_to(&self, dst: &mut dyn Write, block_size: usize) -> Result<u64, StorageError> {
+        let mut total = 0;
+        for block in self.blocks(block_size) {
+            let block = block?;
+            dst.write_all(block.as_slice())
+                .map_err(|error| StorageError::SinkWrite(error.kind()))?;
+            total += block.len() as u64;
+        }
+        Ok(total)
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// Returns true if `a` and `b` have the same contents.
+///
+/// This compares the files' sizes first, then reads and compares them one
+/// block at a time, stopping at the first difference.
+pub fn files_equal(a: &dyn FileReader, b: &dyn FileReader) -> Result<bool, StorageError> {
+    const BLOCK_SIZE: usize = 1024 * 1024;
+
+    if a.get_size()? != b.get_size()? {
+        return Ok(false);
+    }
+    for (a_block, b_block) in a.blocks(BLOCK_SIZE).zip(b.blocks(BLOCK_SIZE)) {
+        if a_block?.as_slice() != b_block?.as_slice() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Copies the file named `src_name` in `src` to `dst_name` in `dst`, which
+/// may be any two backends, such as a local one and a remote one.  Reads and
+/// writes blocks of `block_size` bytes, which must be a positive multiple of
+/// 512; the final block may be shorter.  Like [StorageBackend::write], this
+/// marks the copy for a checkpoint, so that it stays in `dst`.  Returns the
+/// number of bytes copied.
+///
+/// If reading or writing fails, this aborts the copy, so that nothing is
+/// left behind at `dst_name`, and returns the error.
+pub fn copy_file(
+    src: &dyn StorageBackend,
+    src_name: &StoragePath,
+    dst: &dyn StorageBackend,
+    dst_name: &StoragePath,
+    block_size: usize,
+) -> Result<u64, StorageError> {
+    if block_size == 0 || block_size % 512 != 0 {
+        return Err(StorageError::StdIo(ErrorKind::InvalidInput));
+    }
+    let reader = src.open(src_name)?;
+    let size = reader.get_size()?;
+    let mut writer = dst.create_named(dst_name)?;
+    let mut copied = 0;
+    for block in reader.blocks(block_size) {
+        let result = block.and_then(|block| writer.write_block(Arc::unwrap_or_clone(block)));
+        match result {
+            Ok(block) => copied += block.len() as u64,
+            Err(error) => {
+                let _ = writer.abort();
+                return Err(error);
+            }
+        }
+    }
+    if copied != size {
+        let _ = writer.abort();
+        return Err(StorageError::StdIo(ErrorKind::UnexpectedEof));
+    }
+    let (copy, _path) = writer.complete()?;
+    copy.mark_for_checkpoint();
+    Ok(copied)
+}
+
+/// Returns the first page of at most `limit` of `entries`, which must be
+/// sorted by name, along with the continuation token that
+/// [StorageBackend::list_paginated] returns for it.
+pub fn paginate(
+    mut entries: Vec<(StoragePath, StorageFileType)>,
+    limit: usize,
+) -> (Vec<(StoragePath, StorageFileType)>, Option<StoragePath>) {
+    if entries.len() <= limit {
+        return (entries, None);
+    }
+    entries.truncate(limit);
+    let token = entries.last().map(|(name, _file_type)| name.clone());
+    (entries, token)
+}
+
+/// What a [StorageBackend] supports, as reported by
+/// [StorageBackend::capabilities].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageCapabilities {
+    /// Whether the backend reads and writes with direct I/O, bypassing the
+    /// operating system's page cache.
+    pub supports_direct_io: bool,
+
+    /// The largest block the backend can read or write in one operation, if
+    /// it has a limit.
+    pub max_block_size: Option<usize>,
+
+    /// Whether [StorageBackend::rename_subtree] is atomic and cheap, as with
+    /// a rename in a local filesystem.
+    pub atomic_rename: bool,
+
+    /// Whether the backend can record checksums for blocks and verify them
+    /// on read.
+    pub supports_checksums: bool,
+
+    /// Whether the backend stores data remotely, so that each operation has
+    /// network latency and may be billed.
+    pub is_remote: bool,
+}
+
+/// Statistics for reads and writes of a single file, as reported by
+/// [FileReader::stats].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// Number of bytes successfully read from storage.
+    pub bytes_read: u64,
+
+    /// Number of bytes of data written to storage.
+    pub bytes_written: u64,
+
+    /// Number of read operations, each of which may read multiple blocks,
+    /// e.g. for [FileReader::read_regions] or a coalesced run in
+    /// [FileReader::read_blocks].
+    pub read_count: u64,
+
+    /// Number of blocks written with [FileWriter::write_block] or
+    /// [FileWriter::fill_reserved], plus the number of calls to
+    /// [FileWriter::write_all].
+    pub write_count: u64,
+
+    /// Number of blocks successfully read.
+    pub blocks_read: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum StorageFileType {
     /// A regular file.
     File {
         /// File size in bytes.
         size: u64,
+
+        /// Number of bytes of storage allocated to the file.  This is less
+        /// than `size` for a sparse file, and it can be somewhat more for
+        /// other files because storage is allocated in blocks.  Backends that
+        /// can't tell how much storage is allocated, such as those where
+        /// `st_blocks` is unavailable, report `size`.
+        allocated: u64,
     },
 
     /// A directory.
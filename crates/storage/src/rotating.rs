@@ -0,0 +1,93 @@
+//! A continuous append-only stream stored as a sequence of segment files.
+
+use std::sync::Arc;
+
+use crate::error::StorageError;
+use crate::fbuf::FBuf;
+use crate::{FileWriter, StorageBackend, StoragePath};
+
+/// Writes one logical append-only stream, such as a write-ahead log, as a
+/// sequence of segment files that it rotates automatically by size.
+///
+/// Segment `n` (starting from 1) is named `<base>.<n>`, with `n` zero-padded
+/// to six digits, so that segments sort in the order they were written.  Each
+/// segment is marked for checkpointing when it is completed, so that it
+/// outlives the writer.
+pub struct RotatingWriter {
+    backend: Arc<dyn StorageBackend>,
+    base: StoragePath,
+    segment_max_bytes: u64,
+
+    /// Completed segments, in order.
+    segments: Vec<StoragePath>,
+
+    /// The segment being written, if any, and the number of bytes in it.
+    current: Option<(Box<dyn FileWriter>, u64)>,
+}
+
+impl RotatingWriter {
+    /// Creates a new writer for segments named after `base` in `backend`.
+    /// Each segment is completed as soon as it holds at least
+    /// `segment_max_bytes` bytes.  A single append is never split across
+    /// segments, so a segment can exceed `segment_max_bytes` by up to the size
+    /// of the last block appended to it.
+    pub fn new(
+        backend: Arc<dyn StorageBackend>,
+        base: StoragePath,
+        segment_max_bytes: u64,
+    ) -> Self {
+        Self {
+            backend,
+            base,
+            segment_max_bytes,
+            segments: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Returns the name of segment `index`, counting from 1.
+    pub fn segment_name(&self, index: usize) -> StoragePath {
+        format!("{}.{index:06}", self.base).into()
+    }
+
+    /// Appends `data`, whose length must be a multiple of 512, to the stream.
+    pub fn append(&mut self, data: FBuf) -> Result<(), StorageError> {
+        if self.current.is_none() {
+            let name = self.segment_name(self.segments.len() + 1);
+            self.current = Some((self.backend.create_named(&name)?, 0));
+        }
+        let (writer, len) = self.current.as_mut().unwrap();
+        let size = data.len() as u64;
+        writer.write_block(data)?;
+        *len += size;
+        if *len >= self.segment_max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Completes the current segment, if there is one, so that the next
+    /// append starts a new segment.
+    pub fn rotate(&mut self) -> Result<(), StorageError> {
+        if let Some((writer, _len)) = self.current.take() {
+            let (reader, name) = writer.complete()?;
+            reader.mark_for_checkpoint();
+            self.segments.push(name);
+        }
+        Ok(())
+    }
+
+    /// Returns the completed segments, in the order to replay them.  This
+    /// does not include the segment currently being written, which can't be
+    /// read until it is completed.
+    pub fn segments(&self) -> Vec<StoragePath> {
+        self.segments.clone()
+    }
+
+    /// Completes the current segment and returns all of the segments, in the
+    /// order to replay them.
+    pub fn finish(mut self) -> Result<Vec<StoragePath>, StorageError> {
+        self.rotate()?;
+        Ok(self.segments)
+    }
+}
@@ -0,0 +1,155 @@
+//! Point-in-time inventories of storage, for debugging.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::error::StorageError;
+use crate::{FileReader, StorageBackend, StorageFileType, StoragePath, MUTABLE_EXTENSION};
+
+/// Block size for reading files to checksum them.
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// A structural inventory of everything in a [StorageBackend], suitable for
+/// attaching to a bug report.  See [StorageBackend::snapshot].
+///
+/// The snapshot is taken by walking the backend, so it is only consistent if
+/// nothing modifies the backend while it is taken.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StorageSnapshot {
+    /// Every file and directory in the backend, with directories preceding
+    /// their contents.
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// One file or directory in a [StorageSnapshot].
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotEntry {
+    /// Name within the backend.
+    pub path: String,
+
+    /// Kind of entry, including the size of regular files.
+    pub file_type: StorageFileType,
+
+    /// Whether this is a file that is still being written (or that was being
+    /// written when the process that wrote it died).
+    pub mutable: bool,
+
+    /// When the file was created, if the backend records it.
+    pub created_at: Option<SystemTime>,
+
+    /// CRC32C checksum of the file's contents.  This is `None` for entries
+    /// that are not regular files and for files that could not be read.
+    pub crc32c: Option<u32>,
+
+    /// Error encountered reading the file, if any.
+    pub error: Option<String>,
+}
+
+impl SnapshotEntry {
+    fn new(path: &StoragePath, file_type: StorageFileType) -> Self {
+        Self {
+            path: path.to_string(),
+            file_type,
+            mutable: path.as_ref().ends_with(MUTABLE_EXTENSION),
+            created_at: None,
+            crc32c: None,
+            error: None,
+        }
+    }
+
+    fn read(&mut self, reader: &dyn FileReader) -> Result<(), StorageError> {
+        self.created_at = reader.created_at().ok();
+        let mut crc = 0;
+        for block in reader.blocks(BLOCK_SIZE) {
+            crc = crc32c::crc32c_append(crc, block?.as_slice());
+        }
+        self.crc32c = Some(crc);
+        Ok(())
+    }
+}
+
+/// Returns a snapshot of `backend`, reading up to `parallelism` files at a
+/// time.  See [StorageBackend::snapshot_with_parallelism].
+pub(crate) fn snapshot<B>(backend: &B, parallelism: usize) -> Result<StorageSnapshot, StorageError>
+where
+    B: StorageBackend + ?Sized,
+{
+    /// Appends the entries under `parent` to `entries`, and the indexes and
+    /// names of those that are regular files to `files`.
+    fn walk<B>(
+        backend: &B,
+        parent: &StoragePath,
+        entries: &mut Vec<SnapshotEntry>,
+        files: &mut Vec<(usize, StoragePath)>,
+    ) -> Result<(), StorageError>
+    where
+        B: StorageBackend + ?Sized,
+    {
+        let mut children = Vec::new();
+        backend.list(parent, &mut |path, file_type| {
+            children.push((path.clone(), file_type))
+        })?;
+        children.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+        for (path, file_type) in children {
+            entries.push(SnapshotEntry::new(&path, file_type));
+            match file_type {
+                StorageFileType::File { .. } => files.push((entries.len() - 1, path)),
+                StorageFileType::Directory => walk(backend, &path, entries, files)?,
+                StorageFileType::Other => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `entry` updated with the contents of file `path`.
+    fn read<B>(backend: &B, path: &StoragePath, entry: &SnapshotEntry) -> SnapshotEntry
+    where
+        B: StorageBackend + ?Sized,
+    {
+        let mut entry = entry.clone();
+        if let Err(error) = backend
+            .open(path)
+            .and_then(|reader| entry.read(reader.as_ref()))
+        {
+            entry.error = Some(error.to_string());
+        }
+        entry
+    }
+
+    let mut entries = Vec::new();
+    let mut files = Vec::new();
+    walk(backend, &StoragePath::default(), &mut entries, &mut files)?;
+
+    // Each worker claims the next unread file until there are none left, so at
+    // most `parallelism` files are in flight at once.  Results carry their
+    // index, so they land in order regardless of which worker finishes first.
+    let next = AtomicUsize::new(0);
+    let worker = || {
+        let mut results = Vec::new();
+        while let Some((index, path)) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+            results.push((*index, read(backend, path, &entries[*index])));
+        }
+        results
+    };
+    let n_workers = parallelism.clamp(1, files.len().max(1));
+    let results = if n_workers == 1 {
+        worker()
+    } else {
+        thread::scope(|scope| {
+            let workers = (0..n_workers)
+                .map(|_| scope.spawn(worker))
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        })
+    };
+    for (index, entry) in results {
+        entries[index] = entry;
+    }
+    Ok(StorageSnapshot { entries })
+}